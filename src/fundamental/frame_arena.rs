@@ -0,0 +1,236 @@
+use std::{mem, ptr::NonNull};
+use log::info;
+
+use crate::graphics::K_MAX_SWAPCHAIN_IMAGES;
+
+use super::memory::{AllocError, Allocator, LinearAllocator, MemoryStatistics};
+
+#[derive(Debug, Clone)]
+pub(crate) struct FrameArenaConfiguration {
+    pub num_threads: usize,
+    pub frames_in_flight: usize,
+    pub arena_size: usize, // Per thread, per frame.
+}
+
+impl Default for FrameArenaConfiguration {
+    fn default() -> Self {
+        FrameArenaConfiguration {
+            num_threads: 1,
+            frames_in_flight: K_MAX_SWAPCHAIN_IMAGES,
+            arena_size: 1024 * 1024,
+        }
+    }
+}
+
+// Transient per frame memory. Every worker thread gets its own LinearAllocator for each frame in flight,
+// so memory handed out during frame N stays untouched until frame N + frames_in_flight recycles the slot.
+// The owner drives the frames: GpuDevice::new_frame waits on the fence of the frame that last used the slot,
+// reports it through frame_completed, then starts its frame here. Threads take their arena out of thread_arenas,
+// so allocating never locks.
+pub(crate) struct FrameArenas {
+    // Indexed by frame slot * num_threads + thread index.
+    arenas: Vec<LinearAllocator>,
+    // Absolute frame that last allocated from each slot.
+    slot_frames: Vec<Option<u64>>,
+    num_threads: usize,
+    frames_in_flight: usize,
+    absolute_frame: u64,
+    // Last absolute frame the GPU is known to have finished.
+    completed_frame: Option<u64>,
+}
+
+impl FrameArenas {
+    pub(crate) fn new() -> Self {
+        FrameArenas {
+            arenas: Vec::new(),
+            slot_frames: Vec::new(),
+            num_threads: 0,
+            frames_in_flight: 0,
+            absolute_frame: 0,
+            completed_frame: None,
+        }
+    }
+
+    pub(crate) fn init(&mut self, configuration: &FrameArenaConfiguration) {
+        assert!(configuration.num_threads > 0, "FrameArenas need at least one thread");
+        assert!(configuration.frames_in_flight > 0, "FrameArenas need at least one frame in flight");
+
+        self.num_threads = configuration.num_threads;
+        self.frames_in_flight = configuration.frames_in_flight;
+        self.arenas = (0..self.num_threads * self.frames_in_flight).map(|_| {
+            let mut arena = LinearAllocator::new();
+            arena.init(configuration.arena_size);
+            arena
+        }).collect();
+        self.slot_frames = vec![None; self.frames_in_flight];
+        self.absolute_frame = 0;
+        self.completed_frame = None;
+        info!("FrameArenas initialized, {} threads, {} frames in flight, {} bytes each", self.num_threads, self.frames_in_flight, configuration.arena_size);
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        let statistics = self.statistics();
+        info!("FrameArenas shutdown, peak usage of a single arena {} bytes out of {}", self.peak_arena_bytes(), statistics.total_bytes / self.arenas.len().max(1));
+        for arena in self.arenas.iter_mut() {
+            arena.shutdown();
+        }
+        self.arenas.clear();
+        self.slot_frames.clear();
+    }
+
+    #[inline]
+    pub(crate) fn absolute_frame(&self) -> u64 {
+        self.absolute_frame
+    }
+
+    #[inline]
+    pub(crate) fn frames_in_flight(&self) -> usize {
+        self.frames_in_flight
+    }
+
+    #[inline]
+    fn current_slot(&self) -> usize {
+        (self.absolute_frame % self.frames_in_flight as u64) as usize
+    }
+
+    // Tell the arenas the GPU has finished this frame, e.g. after its fence was seen signalled.
+    pub(crate) fn frame_completed(&mut self, absolute_frame: u64) {
+        self.completed_frame = Some(self.completed_frame.map_or(absolute_frame, |frame| frame.max(absolute_frame)));
+    }
+
+    // Starts absolute_frame and recycles the arenas of its slot. The frame that last used the slot has to be
+    // reported through frame_completed first, recycling memory the GPU may still read is a bug in the caller.
+    pub(crate) fn new_frame(&mut self, absolute_frame: u64) {
+        let slot = (absolute_frame % self.frames_in_flight as u64) as usize;
+        if let Some(previous_frame) = self.slot_frames[slot] {
+            assert!(previous_frame < absolute_frame, "frame {} started after frame {}", absolute_frame, previous_frame);
+            assert!(self.completed_frame.is_some_and(|frame| frame >= previous_frame),
+                "frame {} recycles the arenas of frame {} before it completed", absolute_frame, previous_frame);
+        }
+
+        self.absolute_frame = absolute_frame;
+        for arena in self.thread_arenas() {
+            arena.clear();
+        }
+        self.slot_frames[slot] = Some(absolute_frame);
+    }
+
+    // The arenas of the current frame, one per thread. Split them up, e.g. with iter_mut, to hand each job thread
+    // its own arena for the frame.
+    pub(crate) fn thread_arenas(&mut self) -> &mut [LinearAllocator] {
+        let start = self.current_slot() * self.num_threads;
+        &mut self.arenas[start..start + self.num_threads]
+    }
+
+    // Memory stays valid until this slot comes around again, frames_in_flight frames from now.
+    pub(crate) fn allocate(&mut self, thread_index: usize, size: usize, alignment: usize) -> Result<NonNull<u8>, AllocError> {
+        let num_threads = self.num_threads;
+        let arena = self.thread_arenas().get_mut(thread_index).ok_or(AllocError::InvalidThreadIndex { thread_index, num_threads })?;
+        arena.try_allocate_at(size, alignment, None)
+    }
+
+    // Copies values into the current frame, same lifetime as allocate.
+    pub(crate) fn alloc_copy<T: Copy>(&mut self, thread_index: usize, values: &[T]) -> Result<NonNull<[T]>, AllocError> {
+        let size = mem::size_of_val(values);
+        if size == 0 {
+            return Ok(NonNull::slice_from_raw_parts(NonNull::dangling(), values.len()));
+        }
+        let pointer = self.allocate(thread_index, size, mem::align_of::<T>())?.cast::<T>();
+        unsafe {
+            pointer.as_ptr().copy_from_nonoverlapping(values.as_ptr(), values.len());
+        }
+        Ok(NonNull::slice_from_raw_parts(pointer, values.len()))
+    }
+
+    // Sum over every arena.
+    pub(crate) fn statistics(&self) -> MemoryStatistics {
+        let mut statistics = MemoryStatistics::default();
+        for arena in &self.arenas {
+            let arena_statistics = arena.statistics();
+            statistics.allocated_bytes += arena_statistics.allocated_bytes;
+            statistics.peak_bytes += arena_statistics.peak_bytes;
+            statistics.total_bytes += arena_statistics.total_bytes;
+            statistics.allocation_count += arena_statistics.allocation_count;
+        }
+        statistics
+    }
+
+    // Highest usage any single arena reached, what arena_size has to be tuned against.
+    pub(crate) fn peak_arena_bytes(&self) -> usize {
+        self.arenas.iter().map(|arena| arena.statistics().peak_bytes).max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arenas(num_threads: usize, frames_in_flight: usize) -> FrameArenas {
+        let mut arenas = FrameArenas::new();
+        arenas.init(&FrameArenaConfiguration { num_threads, frames_in_flight, arena_size: 256 });
+        arenas
+    }
+
+    #[test]
+    fn first_frames_need_no_completed_frame() {
+        let mut arenas = arenas(1, 3);
+        for frame in 0..3 {
+            arenas.new_frame(frame);
+            arenas.allocate(0, 16, 8).unwrap();
+        }
+        assert_eq!(arenas.statistics().allocation_count, 3);
+        arenas.shutdown();
+    }
+
+    #[test]
+    #[should_panic(expected = "before it completed")]
+    fn recycling_an_incomplete_slot_panics() {
+        let mut arenas = arenas(1, 2);
+        arenas.new_frame(0);
+        arenas.new_frame(1);
+        arenas.new_frame(2);
+    }
+
+    #[test]
+    fn completed_slots_are_cleared_and_reused() {
+        let mut arenas = arenas(2, 2);
+        arenas.new_frame(0);
+        let first = arenas.allocate(0, 32, 8).unwrap();
+        arenas.allocate(1, 64, 8).unwrap();
+        arenas.new_frame(1);
+        arenas.allocate(0, 16, 8).unwrap();
+        assert_eq!(arenas.statistics().allocation_count, 3);
+
+        arenas.frame_completed(0);
+        arenas.new_frame(2);
+        // Only frame 1 is left, frame 2 starts over at the beginning of frame 0's arenas.
+        assert_eq!(arenas.statistics().allocation_count, 1);
+        assert_eq!(arenas.statistics().allocated_bytes, 16);
+        assert_eq!(arenas.allocate(0, 32, 8).unwrap(), first);
+        assert_eq!(arenas.peak_arena_bytes(), 64);
+        arenas.shutdown();
+    }
+
+    #[test]
+    fn allocate_rejects_unknown_threads() {
+        let mut arenas = arenas(2, 2);
+        arenas.new_frame(0);
+        assert_eq!(arenas.allocate(2, 16, 8), Err(AllocError::InvalidThreadIndex { thread_index: 2, num_threads: 2 }));
+        assert!(arenas.thread_arenas()[1].statistics().allocation_count == 0);
+        arenas.shutdown();
+    }
+
+    #[test]
+    fn alloc_copy_copies_into_the_frame() {
+        let mut arenas = arenas(1, 2);
+        arenas.new_frame(0);
+        let values = arenas.alloc_copy(0, &[1u32, 2, 3]).unwrap();
+        assert_eq!(unsafe { values.as_ref() }, &[1, 2, 3]);
+        assert_eq!(values.cast::<u32>().as_ptr() as usize % mem::align_of::<u32>(), 0);
+
+        let empty = arenas.alloc_copy::<u64>(0, &[]).unwrap();
+        assert_eq!(empty.len(), 0);
+        assert_eq!(arenas.statistics().allocation_count, 1);
+        arenas.shutdown();
+    }
+}
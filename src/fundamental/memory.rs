@@ -1,5 +1,7 @@
-use std::{alloc::{alloc, dealloc, Layout}, ffi::c_void, os::raw::c_char};
+use std::{alloc::{alloc, dealloc, Layout}, cell::RefCell, collections::{BTreeMap, HashMap}, fmt, mem, os::raw::c_char, ptr::{self, NonNull}};
 use log::{debug, error, log_enabled, info, Level};
+
+use super::tlsf::{self, Tlsf};
 // Define constants for alignment and memory size
 const KILO: usize = 1024;
 const MEGA: usize = KILO * KILO;
@@ -17,17 +19,252 @@ impl Default for MemoryServiceConfiguration {
         }
     }
 }
-#[derive(Debug)]
-struct MemoryStatistics {
-    allocated_bytes: usize,
-    total_bytes: usize,
-    allocation_count: u32,
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MemoryStatistics {
+    pub allocated_bytes: usize,
+    pub peak_bytes: usize,
+    pub total_bytes: usize,
+    pub allocation_count: u32,
+}
+
+impl MemoryStatistics {
+    fn add(&mut self, size: usize) {
+        self.allocated_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.allocated_bytes);
+        self.allocation_count += 1;
+    }
+
+    fn remove(&mut self, size: usize, count: u32) {
+        self.allocated_bytes -= size;
+        self.allocation_count -= count;
+    }
+}
+
+// Where an allocation was requested from, filled by the ralloca!/rallocam! macros.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SourceLocation {
+    file: &'static str,
+    line: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AllocationRecord {
+    address: usize,
+    size: usize,
+    location: Option<SourceLocation>,
+}
+
+// Stack style allocators push their allocations on a lane, one per end, and pop them in order when rolled back.
+pub(crate) const LANE_BOTTOM: usize = 0;
+pub(crate) const LANE_TOP: usize = 1;
+
+// Live allocations of an allocator, each recorded with where it came from so leaks can be listed.
+// Heap style allocations are looked up by address, stack style ones by lane.
+#[derive(Debug, Default)]
+pub(crate) struct AllocationTracker {
+    statistics: MemoryStatistics,
+    records: BTreeMap<usize, AllocationRecord>,
+    lane_records: [Vec<AllocationRecord>; 2],
+}
+
+impl AllocationTracker {
+    fn new(total_bytes: usize) -> Self {
+        AllocationTracker {
+            statistics: MemoryStatistics { total_bytes, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    // Heap style allocation, the allocator hands the same size back to untrack.
+    fn track(&mut self, pointer: *mut u8, size: usize, location: Option<SourceLocation>) {
+        self.statistics.add(size);
+        self.records.insert(pointer as usize, AllocationRecord { address: pointer as usize, size, location });
+    }
+
+    fn untrack(&mut self, pointer: *mut u8, size: usize) {
+        let record = self.records.remove(&(pointer as usize));
+        debug_assert!(record.is_some_and(|record| record.size == size), "untracking unknown allocation {:p} of {} bytes", pointer, size);
+        self.statistics.remove(size, 1);
+    }
+
+    // Stack style allocation, size covers the alignment padding in front of it so popping a range of the stack
+    // removes exactly its length.
+    fn push(&mut self, lane: usize, pointer: *mut u8, size: usize, location: Option<SourceLocation>) {
+        self.statistics.add(size);
+        self.lane_records[lane].push(AllocationRecord { address: pointer as usize, size, location });
+    }
+
+    // Pops the last count allocations of the lane, size bytes in total.
+    fn pop(&mut self, lane: usize, count: u32, size: usize) {
+        let records = &mut self.lane_records[lane];
+        debug_assert!(count as usize <= records.len(), "popping {} allocations from a lane holding {}", count, records.len());
+        let popped = records.split_off(records.len() - count as usize);
+        debug_assert_eq!(popped.iter().map(|record| record.size).sum::<usize>(), size, "popped allocations don't cover the freed range");
+        self.statistics.remove(size, count);
+    }
+
+    // Pops the last allocations of the lane that make up size bytes, which has to end on an allocation boundary.
+    fn pop_bytes(&mut self, lane: usize, size: usize) {
+        let mut count = 0;
+        let mut popped_size = 0;
+        for record in self.lane_records[lane].iter().rev() {
+            if popped_size >= size {
+                break;
+            }
+            popped_size += record.size;
+            count += 1;
+        }
+        self.pop(lane, count, size);
+    }
+
+    #[inline]
+    fn lane_count(&self, lane: usize) -> u32 {
+        self.lane_records[lane].len() as u32
+    }
+
+    fn clear(&mut self) {
+        self.statistics.allocated_bytes = 0;
+        self.statistics.allocation_count = 0;
+        self.records.clear();
+        self.lane_records.iter_mut().for_each(Vec::clear);
+    }
+
+    fn records(&self) -> impl Iterator<Item = &AllocationRecord> {
+        self.records.values().chain(self.lane_records.iter().flatten())
+    }
+}
+
+// Where a marker allocator was, with how many allocations it held then so rolling back keeps the counters exact.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Marker {
+    pub offset: usize,
+    pub allocation_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AllocError {
+    // The allocator has no room left for the request.
+    OutOfMemory { size: usize, alignment: usize },
+    // Alignment must be a non zero power of two.
+    InvalidAlignment(usize),
+    // init was never called, or the allocator has been shut down.
+    Uninitialized,
+    // init was called on an allocator that already owns its memory.
+    AlreadyInitialized,
+    // The allocator can't be created with this many bytes.
+    InvalidSize(usize),
+    // Per thread allocators only exist for num_threads threads.
+    InvalidThreadIndex { thread_index: usize, num_threads: usize },
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::OutOfMemory { size, alignment } => write!(f, "out of memory allocating {} bytes aligned to {}", size, alignment),
+            AllocError::InvalidAlignment(alignment) => write!(f, "invalid alignment {}", alignment),
+            AllocError::Uninitialized => write!(f, "allocator used before init"),
+            AllocError::AlreadyInitialized => write!(f, "allocator initialized twice"),
+            AllocError::InvalidSize(size) => write!(f, "invalid allocator size {}", size),
+            AllocError::InvalidThreadIndex { thread_index, num_threads } => write!(f, "thread index {} out of range, created for {} threads", thread_index, num_threads),
+        }
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+#[inline]
+fn check_alignment(alignment: usize) -> Result<(), AllocError> {
+    if alignment.is_power_of_two() {
+        Ok(())
+    } else {
+        Err(AllocError::InvalidAlignment(alignment))
+    }
 }
 
 // Define a trait for Allocator
-trait Allocator {
-    fn allocate(&mut self, size: usize, alignment: usize) -> *mut u8;
+pub(crate) trait Allocator {
+    fn allocate(&mut self, size: usize, alignment: usize) -> *mut u8 {
+        self.allocate_at(size, alignment, None)
+    }
+    fn allocate_tracked(&mut self, size: usize, alignment: usize, file: &'static str, line: u32) -> *mut u8 {
+        self.allocate_at(size, alignment, Some(SourceLocation { file, line }))
+    }
+    // Raw entry point, returns null if the allocation can't be satisfied.
+    fn allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> *mut u8 {
+        match self.try_allocate_at(size, alignment, location) {
+            Ok(pointer) => pointer.as_ptr(),
+            Err(error) => {
+                error!("{}", error);
+                ptr::null_mut()
+            }
+        }
+    }
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError>;
     fn deallocate(&mut self, pointer: *mut u8);
+
+    fn tracker(&self) -> &AllocationTracker;
+
+    fn statistics(&self) -> MemoryStatistics {
+        self.tracker().statistics
+    }
+}
+
+impl<A: Allocator + ?Sized> Allocator for &mut A {
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        (**self).try_allocate_at(size, alignment, location)
+    }
+
+    fn deallocate(&mut self, pointer: *mut u8) {
+        (**self).deallocate(pointer)
+    }
+
+    fn tracker(&self) -> &AllocationTracker {
+        (**self).tracker()
+    }
+}
+
+// Allocators that release everything allocated after a marker in one go.
+pub(crate) trait MarkerAllocator: Allocator {
+    fn get_marker(&self) -> Marker;
+    fn free_marker(&mut self, marker: Marker);
+}
+
+impl<A: MarkerAllocator + ?Sized> MarkerAllocator for &mut A {
+    fn get_marker(&self) -> Marker {
+        (**self).get_marker()
+    }
+
+    fn free_marker(&mut self, marker: Marker) {
+        (**self).free_marker(marker)
+    }
+}
+
+// Print statistics for an allocator and every allocation still alive with the place it came from.
+// Returns true when something leaked.
+fn report_allocations(name: &str, allocator: &dyn Allocator) -> bool {
+    let tracker = allocator.tracker();
+    let statistics = tracker.statistics;
+    info!("{}: {} live allocations, {} bytes in use, {} bytes peak, {} bytes total",
+        name, statistics.allocation_count, statistics.allocated_bytes, statistics.peak_bytes, statistics.total_bytes);
+
+    if statistics.allocation_count == 0 {
+        return false;
+    }
+
+    error!("{}: memory leak detected, {} allocations ({} bytes) still alive", name, statistics.allocation_count, statistics.allocated_bytes);
+    for record in tracker.records() {
+        match record.location {
+            Some(location) => error!("    {:#x} {} bytes allocated at {}", record.address, record.size, location),
+            None => error!("    {:#x} {} bytes allocated at unknown location", record.address, record.size),
+        }
+    }
+    true
 }
 
 // Implement Allocator trait for HeapAllocator
@@ -35,7 +272,8 @@ pub (crate) struct HeapAllocator {
     memory: *mut u8,
     allocated_size: usize,
     max_size: usize,
-    tlsf_handle: *mut c_void, // Placeholder for tlsf handle type
+    tlsf_handle: *mut Tlsf,
+    tracker: AllocationTracker,
 }
 
 impl HeapAllocator {
@@ -45,59 +283,123 @@ impl HeapAllocator {
             allocated_size: 0,
             max_size: 0,
             tlsf_handle: std::ptr::null_mut(),
+            tracker: AllocationTracker::default(),
         }
     }
 
-    pub (crate) fn init(&mut self, size: usize) {
-        let layout = Layout::from_size_align(size, 1).unwrap();
-        self.memory = unsafe{ alloc(layout) };
-        self.allocated_size = size;
+    // Reserves size bytes and builds the TLSF pool in them. Calling it again before shutdown is an error,
+    // the allocator keeps its current memory.
+    pub (crate) fn init(&mut self, size: usize) -> Result<(), AllocError> {
+        if !self.memory.is_null() {
+            error!("HeapAllocator already initialized with size {}", self.max_size);
+            return Err(AllocError::AlreadyInitialized);
+        }
+        // Reserve the whole block up front, the control structure lives at the start of it.
+        let layout = match Layout::from_size_align(size, tlsf::ALIGN_SIZE) {
+            Ok(layout) if size != 0 => layout,
+            _ => {
+                error!("HeapAllocator can't be created with size {}", size);
+                return Err(AllocError::InvalidSize(size));
+            }
+        };
+        let memory = unsafe{ alloc(layout) };
+        if memory.is_null() {
+            error!("HeapAllocator of size {} could not reserve its memory", size);
+            return Err(AllocError::OutOfMemory { size, alignment: tlsf::ALIGN_SIZE });
+        }
+        let tlsf_handle = unsafe { Tlsf::create_with_pool(memory, size) };
+        if tlsf_handle.is_null() {
+            error!("HeapAllocator of size {} could not create tlsf pool", size);
+            unsafe { dealloc(memory, layout) };
+            return Err(AllocError::InvalidSize(size));
+        }
+        self.memory = memory;
+        self.tlsf_handle = tlsf_handle;
+        self.allocated_size = 0;
         self.max_size = size;
-        self.tlsf_handle = std::ptr::null_mut(); // Initialize tlsf handle as needed
+        self.tracker = AllocationTracker::new(size);
+        info!("HeapAllocator of size {} created", size);
+        Ok(())
     }
 
     fn shutdown(&mut self) {
-        let layout = Layout::from_size_align(self.allocated_size, 1).unwrap();
+        if self.memory.is_null() {
+            return;
+        }
+
+        // Leaks are reported by the owner, see MemoryService::shutdown.
+        info!("HeapAllocator Shutdown");
+        let layout = Layout::from_size_align(self.max_size, tlsf::ALIGN_SIZE).unwrap();
         unsafe { dealloc(self.memory, layout) };
         self.memory = std::ptr::null_mut();
+        self.tlsf_handle = std::ptr::null_mut();
         self.allocated_size = 0;
         self.max_size = 0;
-        // Shutdown tlsf handle if needed
+        self.tracker.clear();
     }
 }
 
 impl Allocator for HeapAllocator {
-    fn allocate(&mut self, size: usize, _alignment: usize) -> *mut u8 {
-        let layout = Layout::from_size_align(size, 1).unwrap();
-        unsafe { alloc(layout) }
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        check_alignment(alignment)?;
+        if self.tlsf_handle.is_null() {
+            return Err(AllocError::Uninitialized);
+        }
+        let tlsf = unsafe { &mut *self.tlsf_handle };
+        let allocated_memory = unsafe {
+            if alignment <= tlsf::ALIGN_SIZE {
+                tlsf.malloc(size)
+            } else {
+                tlsf.memalign(alignment, size)
+            }
+        };
+        let Some(allocated_memory) = NonNull::new(allocated_memory) else {
+            return Err(AllocError::OutOfMemory { size, alignment });
+        };
+        let actual_size = unsafe { Tlsf::block_size(allocated_memory.as_ptr()) };
+        self.allocated_size += actual_size;
+        self.tracker.track(allocated_memory.as_ptr(), actual_size, location);
+        Ok(allocated_memory)
     }
 
     fn deallocate(&mut self, pointer: *mut u8) {
-        let layout = Layout::from_size_align(self.allocated_size, 1).unwrap();
-        unsafe { dealloc(pointer, layout);  }
+        if pointer.is_null() || self.tlsf_handle.is_null() {
+            return;
+        }
+        let size = unsafe { Tlsf::block_size(pointer) };
+        self.allocated_size -= size;
+        self.tracker.untrack(pointer, size);
+        unsafe { (*self.tlsf_handle).free(pointer) };
+    }
+
+    fn tracker(&self) -> &AllocationTracker {
+        &self.tracker
     }
 }
 
 
 struct MemoryService {
-    scratch_allocator: LinearAllocator,
     system_allocator: HeapAllocator,
 }
 
 impl MemoryService {
     fn new() -> Self {
         MemoryService {
-            scratch_allocator: LinearAllocator::new(),
-            system_allocator: HeapAllocator::new(), 
+            system_allocator: HeapAllocator::new(),
         }
     }
 
-    fn init(&mut self, configuration: &MemoryServiceConfiguration) {
-        self.system_allocator.init(configuration.maximum_dynamic_size);
+    fn init(&mut self, configuration: &MemoryServiceConfiguration) -> Result<(), AllocError> {
+        self.system_allocator.init(configuration.maximum_dynamic_size)?;
         info!("MemoryService initialized");
+        Ok(())
     }
 
     fn shutdown(&mut self) {
+        // Check memory at the application exit.
+        if !report_allocations("System allocator", &self.system_allocator) {
+            info!("System allocator - all memory freed");
+        }
         self.system_allocator.shutdown();
         info!("MemoryService shutdown");
     }
@@ -116,19 +418,19 @@ impl MemoryService {
 // Define macro helpers
 macro_rules! ralloca {
     ($size:expr, $allocator:expr) => {
-        unsafe { (*$allocator).allocate($size, 1, file!(), line!()) }
+        unsafe { (*$allocator).allocate_tracked($size, 1, file!(), line!()) }
     };
 }
 
 macro_rules! rallocam {
     ($size:expr, $allocator:expr) => {
-        unsafe { (*$allocator).allocate($size, 1, file!(), line!()) as *mut u8 }
+        unsafe { (*$allocator).allocate_tracked($size, 1, file!(), line!()) as *mut u8 }
     };
 }
 
 macro_rules! rallocat {
     ($type:ty, $allocator:expr) => {
-        unsafe { (*$allocator).allocate(std::mem::size_of::<$type>(), 1) as *mut $type }
+        unsafe { (*$allocator).allocate_tracked(std::mem::size_of::<$type>(), std::mem::align_of::<$type>(), file!(), line!()) as *mut $type }
     };
 }
 
@@ -143,6 +445,7 @@ struct DoubleStackAllocator {
     top: usize,
     bottom: usize,
     total_size: usize,
+    tracker: AllocationTracker,
 }
 
 impl DoubleStackAllocator {
@@ -152,6 +455,7 @@ impl DoubleStackAllocator {
             top: size,
             bottom: 0,
             total_size: size,
+            tracker: AllocationTracker::new(size),
         }
     }
 
@@ -160,6 +464,7 @@ impl DoubleStackAllocator {
         self.top = size;
         self.bottom = 0;
         self.total_size = size;
+        self.tracker = AllocationTracker::new(size);
         info!("DoubleStackAllocator initialized with size {}", size);
     }
 
@@ -170,88 +475,203 @@ impl DoubleStackAllocator {
         self.memory = std::ptr::null_mut();
         self.top = 0;
         self.bottom = 0;
+        self.tracker.clear();
         info!("DoubleStackAllocator shutdown");
     }
 
+    #[inline]
+    fn address(&self, offset: usize) -> usize {
+        self.memory as usize + offset
+    }
+
     fn allocate_top(&mut self, size: usize, alignment: usize) -> *mut u8 {
-        let new_start = memory_align(self.top - size, alignment);
+        self.allocate_top_at(size, alignment, None)
+    }
+
+    fn allocate_top_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> *mut u8 {
+        self.try_allocate_top_at(size, alignment, location).map_or_else(|error| {
+            error!("Overflow crossing in allocate_top: {}", error);
+            ptr::null_mut()
+        }, NonNull::as_ptr)
+    }
+
+    fn try_allocate_top_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        check_alignment(alignment)?;
+        if self.memory.is_null() {
+            return Err(AllocError::Uninitialized);
+        }
+        let out_of_memory = AllocError::OutOfMemory { size, alignment };
+        let unaligned_start = self.top.checked_sub(size).ok_or(out_of_memory)?;
+        let new_start = memory_align_down(self.address(unaligned_start), alignment).checked_sub(self.memory as usize).ok_or(out_of_memory)?;
         if new_start <= self.bottom {
-            panic!("Overflow crossing in allocate_top");
+            return Err(out_of_memory);
         }
+        let pointer = unsafe{self.memory.add(new_start) };
+        self.tracker.push(LANE_TOP, pointer, self.top - new_start, location);
         self.top = new_start;
-        unsafe{self.memory.offset(new_start as isize) }
+        Ok(unsafe { NonNull::new_unchecked(pointer) })
     }
 
     fn allocate_bottom(&mut self, size: usize, alignment: usize) -> *mut u8 {
-        let new_start = memory_align(self.bottom, alignment);
-        let new_allocated_size = new_start + size;
+        self.allocate_bottom_at(size, alignment, None)
+    }
+
+    fn allocate_bottom_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> *mut u8 {
+        self.try_allocate_bottom_at(size, alignment, location).map_or_else(|error| {
+            error!("Overflow crossing in allocate_bottom: {}", error);
+            ptr::null_mut()
+        }, NonNull::as_ptr)
+    }
+
+    fn try_allocate_bottom_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        check_alignment(alignment)?;
+        if self.memory.is_null() {
+            return Err(AllocError::Uninitialized);
+        }
+        let out_of_memory = AllocError::OutOfMemory { size, alignment };
+        let new_start = memory_align_offset(self.memory, self.bottom, alignment);
+        let new_allocated_size = new_start.checked_add(size).ok_or(out_of_memory)?;
         if new_allocated_size >= self.top {
-            panic!("Overflow crossing in allocate_bottom");
+            return Err(out_of_memory);
         }
+        let pointer = unsafe{self.memory.add(new_start)};
+        self.tracker.push(LANE_BOTTOM, pointer, new_allocated_size - self.bottom, location);
         self.bottom = new_allocated_size;
-        unsafe{self.memory.offset(new_start as isize)}
+        Ok(unsafe { NonNull::new_unchecked(pointer) })
     }
 
+    // Pops the last top allocations covering size bytes, alignment padding included. Freeing more empties the top end.
     fn deallocate_top(&mut self, size: usize) {
-        if size > self.total_size - self.top {
-            self.top = self.total_size;
-        } else {
-            self.top += size;
+        if size >= self.total_size - self.top {
+            self.clear_top();
+            return;
         }
+        self.tracker.pop_bytes(LANE_TOP, size);
+        self.top += size;
     }
 
+    // Pops the last bottom allocations covering size bytes, alignment padding included. Freeing more empties the bottom end.
     fn deallocate_bottom(&mut self, size: usize) {
-        if size > self.bottom {
-            self.bottom = 0;
-        } else {
-            self.bottom -= size;
+        if size >= self.bottom {
+            self.clear_bottom();
+            return;
         }
+        self.tracker.pop_bytes(LANE_BOTTOM, size);
+        self.bottom -= size;
     }
 
-    fn get_top_marker(&self) -> usize {
-        self.top
+    fn get_top_marker(&self) -> Marker {
+        Marker { offset: self.top, allocation_count: self.tracker.lane_count(LANE_TOP) }
     }
 
-    fn get_bottom_marker(&self) -> usize {
-        self.bottom
+    fn get_bottom_marker(&self) -> Marker {
+        Marker { offset: self.bottom, allocation_count: self.tracker.lane_count(LANE_BOTTOM) }
     }
 
-    fn free_top_marker(&mut self, marker: usize) {
-        if marker > self.top && marker < self.total_size {
-            self.top = marker;
+    fn free_top_marker(&mut self, marker: Marker) {
+        if marker.offset > self.top && marker.offset <= self.total_size {
+            let count = self.tracker.lane_count(LANE_TOP).saturating_sub(marker.allocation_count);
+            self.tracker.pop(LANE_TOP, count, marker.offset - self.top);
+            self.top = marker.offset;
         }
     }
 
-    fn free_bottom_marker(&mut self, marker: usize) {
-        if marker < self.bottom {
-            self.bottom = marker;
+    fn free_bottom_marker(&mut self, marker: Marker) {
+        if marker.offset < self.bottom {
+            let count = self.tracker.lane_count(LANE_BOTTOM).saturating_sub(marker.allocation_count);
+            self.tracker.pop(LANE_BOTTOM, count, self.bottom - marker.offset);
+            self.bottom = marker.offset;
         }
     }
 
     fn clear_top(&mut self) {
+        self.tracker.pop(LANE_TOP, self.tracker.lane_count(LANE_TOP), self.total_size - self.top);
         self.top = self.total_size;
     }
 
     fn clear_bottom(&mut self) {
+        self.tracker.pop(LANE_BOTTOM, self.tracker.lane_count(LANE_BOTTOM), self.bottom);
         self.bottom = 0;
     }
 }
 
 impl Allocator for DoubleStackAllocator {
-    fn allocate(&mut self, size: usize, alignment: usize) -> *mut u8 {
-        self.allocate_top(size, alignment)
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        self.try_allocate_top_at(size, alignment, location)
     }
 
     fn deallocate(&mut self, _pointer: *mut u8) {
         // Deallocate method not implemented for DoubleStackAllocator in original C++ code.
         // Implement if necessary.
     }
+
+    fn tracker(&self) -> &AllocationTracker {
+        &self.tracker
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum DoubleStackEnd {
+    Top,
+    Bottom,
+}
+
+// One end of a DoubleStackAllocator, so each end can be scoped on its own.
+struct DoubleStackSide<'a> {
+    allocator: &'a mut DoubleStackAllocator,
+    end: DoubleStackEnd,
+}
+
+impl Allocator for DoubleStackSide<'_> {
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        match self.end {
+            DoubleStackEnd::Top => self.allocator.try_allocate_top_at(size, alignment, location),
+            DoubleStackEnd::Bottom => self.allocator.try_allocate_bottom_at(size, alignment, location),
+        }
+    }
+
+    fn deallocate(&mut self, _pointer: *mut u8) {
+        // Memory is given back by the scope marker.
+    }
+
+    fn tracker(&self) -> &AllocationTracker {
+        &self.allocator.tracker
+    }
+}
+
+impl MarkerAllocator for DoubleStackSide<'_> {
+    fn get_marker(&self) -> Marker {
+        match self.end {
+            DoubleStackEnd::Top => self.allocator.get_top_marker(),
+            DoubleStackEnd::Bottom => self.allocator.get_bottom_marker(),
+        }
+    }
+
+    fn free_marker(&mut self, marker: Marker) {
+        match self.end {
+            DoubleStackEnd::Top => self.allocator.free_top_marker(marker),
+            DoubleStackEnd::Bottom => self.allocator.free_bottom_marker(marker),
+        }
+    }
+}
+
+impl DoubleStackAllocator {
+    // Scratch scope on the top end, rolled back when the scope is dropped.
+    fn scope_top(&mut self) -> AllocatorScope<DoubleStackSide<'_>> {
+        AllocatorScope::new(DoubleStackSide { allocator: self, end: DoubleStackEnd::Top })
+    }
+
+    // Scratch scope on the bottom end, rolled back when the scope is dropped.
+    fn scope_bottom(&mut self) -> AllocatorScope<DoubleStackSide<'_>> {
+        AllocatorScope::new(DoubleStackSide { allocator: self, end: DoubleStackEnd::Bottom })
+    }
 }
 
 pub struct StackAllocator {
     memory: *mut u8,
     total_size: usize,
     allocated_size: usize,
+    tracker: AllocationTracker,
 }
 
 impl StackAllocator {
@@ -260,6 +680,7 @@ impl StackAllocator {
             memory: std::ptr::null_mut(),
             total_size: size,
             allocated_size: 0,
+            tracker: AllocationTracker::new(size),
         }
     }
 
@@ -270,6 +691,7 @@ impl StackAllocator {
         };
         self.total_size = size;
         self.allocated_size = 0;
+        self.tracker = AllocationTracker::new(size);
     }
 
     pub fn shutdown(&mut self) {
@@ -280,39 +702,68 @@ impl StackAllocator {
         self.memory = std::ptr::null_mut();
         self.total_size = 0;
         self.allocated_size = 0;
+        self.tracker.clear();
     }
 
 
 
-    pub fn get_marker(&self) -> usize {
-        self.allocated_size
+    pub(crate) fn get_marker(&self) -> Marker {
+        Marker { offset: self.allocated_size, allocation_count: self.tracker.lane_count(LANE_BOTTOM) }
     }
 
-    pub fn free_marker(&mut self, marker: usize) {
-        if marker <= self.allocated_size {
-            self.allocated_size = marker;
+    pub(crate) fn free_marker(&mut self, marker: Marker) {
+        if marker.offset <= self.allocated_size {
+            let count = self.tracker.lane_count(LANE_BOTTOM).saturating_sub(marker.allocation_count);
+            self.tracker.pop(LANE_BOTTOM, count, self.allocated_size - marker.offset);
+            self.allocated_size = marker.offset;
         }
     }
 
     pub fn clear(&mut self) {
         self.allocated_size = 0;
+        self.tracker.clear();
+    }
+
+    // Scratch scope, everything allocated through it is released when it is dropped.
+    pub fn scope(&mut self) -> AllocatorScope<&mut StackAllocator> {
+        AllocatorScope::new(self)
+    }
+}
+impl MarkerAllocator for StackAllocator {
+    fn get_marker(&self) -> Marker {
+        StackAllocator::get_marker(self)
+    }
+
+    fn free_marker(&mut self, marker: Marker) {
+        StackAllocator::free_marker(self, marker)
     }
 }
 impl Allocator for StackAllocator {
-    fn allocate(&mut self, size: usize, alignment: usize) -> *mut u8 {
-        let new_start = self.memory.align_offset(alignment);
-        let new_allocated_size = new_start + size;
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        check_alignment(alignment)?;
+        if self.memory.is_null() {
+            return Err(AllocError::Uninitialized);
+        }
+        let out_of_memory = AllocError::OutOfMemory { size, alignment };
+        let new_start = memory_align_offset(self.memory, self.allocated_size, alignment);
+        let new_allocated_size = new_start.checked_add(size).ok_or(out_of_memory)?;
         if new_allocated_size > self.total_size {
-            panic!("StackAllocator: Out of memory!");
+            return Err(out_of_memory);
         }
 
+        let pointer = unsafe { self.memory.add(new_start) };
+        self.tracker.push(LANE_BOTTOM, pointer, new_allocated_size - self.allocated_size, location);
         self.allocated_size = new_allocated_size;
-        unsafe { self.memory.add(new_start) }
+        Ok(unsafe { NonNull::new_unchecked(pointer) })
     }
 
     fn deallocate(&mut self, _pointer: *mut u8) {
         // No-op in StackAllocator as deallocation happens implicitly by resetting allocated_size
     }
+
+    fn tracker(&self) -> &AllocationTracker {
+        &self.tracker
+    }
 }
 impl Drop for StackAllocator {
     fn drop(&mut self) {
@@ -325,85 +776,116 @@ impl Drop for StackAllocator {
     }
 }
 
-struct LinearAllocator {
+pub(crate) struct LinearAllocator {
     memory: *mut u8,
     total_size: usize,
     allocated_size: usize,
+    tracker: AllocationTracker,
 }
 
+// The allocator owns its block exclusively, so it can be handed to another thread.
+unsafe impl Send for LinearAllocator {}
+
 impl LinearAllocator {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         LinearAllocator {
             memory: std::ptr::null_mut(),
             total_size: 0,
             allocated_size: 0,
+            tracker: AllocationTracker::default(),
         }
     }
 
-    fn init(&mut self, size: usize) {
+    pub(crate) fn init(&mut self, size: usize) {
         self.memory = unsafe { alloc(Layout::from_size_align(size, 1).unwrap()) as *mut u8 };
         self.total_size = size;
         self.allocated_size = 0;
+        self.tracker = AllocationTracker::new(size);
         info!("LinearAllocator initialized with size {}", size);
     }
 
-    fn shutdown(&mut self) {
+    pub(crate) fn shutdown(&mut self) {
+        if self.memory.is_null() {
+            return;
+        }
         unsafe {
             dealloc(self.memory as *mut u8, Layout::from_size_align(self.total_size, 1).unwrap());
         }
         self.memory = std::ptr::null_mut();
         self.total_size = 0;
         self.allocated_size = 0;
+        self.tracker.clear();
         info!("LinearAllocator shutdown");
     }
 
-    fn allocate(&mut self, size: usize, alignment: usize) -> *mut u8 {
-        let new_start = memory_align(self.allocated_size, alignment);
-        assert!(new_start < self.total_size);
-        let new_allocated_size = new_start + size;
-        assert!(new_allocated_size <= self.total_size);
-        self.allocated_size = new_allocated_size;
-        unsafe {self.memory.offset(new_start as isize)}
-    }
-
-    fn deallocate(&mut self, _pointer: *mut u8) {
-        // Deallocate method not implemented for LinearAllocator in original C++ code.
-        // Implement if necessary.
-    }
-
-    fn clear(&mut self) {
+    pub(crate) fn clear(&mut self) {
         self.allocated_size = 0;
+        self.tracker.clear();
     }
 }
 
 impl Allocator for LinearAllocator {
-    fn allocate(&mut self, size: usize, alignment: usize) -> *mut u8 {
-        self.allocate(size, alignment)
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        check_alignment(alignment)?;
+        if self.memory.is_null() {
+            return Err(AllocError::Uninitialized);
+        }
+        let out_of_memory = AllocError::OutOfMemory { size, alignment };
+        let new_start = memory_align_offset(self.memory, self.allocated_size, alignment);
+        let new_allocated_size = new_start.checked_add(size).ok_or(out_of_memory)?;
+        if new_allocated_size > self.total_size {
+            return Err(out_of_memory);
+        }
+        let pointer = unsafe {self.memory.add(new_start)};
+        self.tracker.push(LANE_BOTTOM, pointer, new_allocated_size - self.allocated_size, location);
+        self.allocated_size = new_allocated_size;
+        Ok(unsafe { NonNull::new_unchecked(pointer) })
     }
 
     fn deallocate(&mut self, _pointer: *mut u8) {
         // Deallocate method not implemented for LinearAllocator in original C++ code.
         // Implement if necessary.
     }
+
+    fn tracker(&self) -> &AllocationTracker {
+        &self.tracker
+    }
 }
 
-struct MallocAllocator;
+#[derive(Default)]
+struct MallocAllocator {
+    // Layout of every live allocation, dealloc needs it back.
+    layouts: HashMap<usize, Layout>,
+    tracker: AllocationTracker,
+}
 
 impl Allocator for MallocAllocator {
-    fn allocate(&mut self, size: usize, _alignment: usize) -> *mut u8 {
-        let layout = Layout::from_size_align(size, 1).unwrap();
-        unsafe { alloc(layout) }
+    fn try_allocate_at(&mut self, size: usize, alignment: usize, location: Option<SourceLocation>) -> Result<NonNull<u8>, AllocError> {
+        check_alignment(alignment)?;
+        let out_of_memory = AllocError::OutOfMemory { size, alignment };
+        let layout = Layout::from_size_align(size.max(1), alignment).map_err(|_| out_of_memory)?;
+        let pointer = NonNull::new(unsafe { alloc(layout) }).ok_or(out_of_memory)?;
+        self.layouts.insert(pointer.as_ptr() as usize, layout);
+        self.tracker.track(pointer.as_ptr(), layout.size(), location);
+        Ok(pointer)
     }
 
     fn deallocate(&mut self, pointer: *mut u8) {
-        let layout = Layout::from_size_align(std::mem::size_of_val(&pointer), 1 ).unwrap();
+        let Some(layout) = self.layouts.remove(&(pointer as usize)) else {
+            return;
+        };
+        self.tracker.untrack(pointer, layout.size());
         unsafe { dealloc(pointer, layout) };
     }
+
+    fn tracker(&self) -> &AllocationTracker {
+        &self.tracker
+    }
 }
 
 
 #[inline]
-fn memory_align(size: usize, alignment: usize) -> usize {
+pub(crate) fn memory_align(size: usize, alignment: usize) -> usize {
     // Calculate the aligned size
     let aligned_size = if size % alignment == 0 {
         size
@@ -413,3 +895,605 @@ fn memory_align(size: usize, alignment: usize) -> usize {
 
     aligned_size
 }
+
+#[inline]
+fn memory_align_down(size: usize, alignment: usize) -> usize {
+    size - size % alignment
+}
+
+// Offset from memory of the first properly aligned address at or after memory + offset.
+#[inline]
+fn memory_align_offset(memory: *mut u8, offset: usize, alignment: usize) -> usize {
+    memory_align(memory as usize + offset, alignment) - memory as usize
+}
+
+// Shares an allocator through &self, so values allocated from it can borrow the cell while more allocations are made.
+// Clearing or rolling back the allocator needs get_mut, which the borrow checker refuses while any of those values are alive.
+pub(crate) struct AllocatorCell<A: Allocator> {
+    allocator: RefCell<A>,
+}
+
+impl<A: Allocator> AllocatorCell<A> {
+    pub(crate) fn new(allocator: A) -> Self {
+        AllocatorCell {
+            allocator: RefCell::new(allocator),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut A {
+        self.allocator.get_mut()
+    }
+
+    pub(crate) fn into_inner(self) -> A {
+        self.allocator.into_inner()
+    }
+
+    pub(crate) fn statistics(&self) -> MemoryStatistics {
+        self.allocator.borrow().statistics()
+    }
+
+    pub(crate) fn try_allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            // Zero sized requests never touch the allocator, any well aligned pointer will do.
+            let dangling = unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        let pointer = self.allocator.borrow_mut().try_allocate_at(layout.size(), layout.align(), None)?;
+        Ok(NonNull::slice_from_raw_parts(pointer, layout.size()))
+    }
+
+    // Moves value into the allocator. Like every arena allocation its destructor is never run,
+    // the memory goes away when the allocator is cleared, rolled back or, for a heap, shut down.
+    // SAFETY: every call hands out a fresh allocation, so the returned references never alias. Memory is only
+    // reused after clear, free_marker or deallocate, which need get_mut or a pointer this cell never gives out.
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) fn alloc_value<T>(&self, value: T) -> Result<&mut T, AllocError> {
+        let pointer = self.try_allocate(Layout::new::<T>())?.cast::<T>();
+        unsafe {
+            pointer.as_ptr().write(value);
+            Ok(&mut *pointer.as_ptr())
+        }
+    }
+
+    // Allocates len default initialized values, same lifetime and aliasing rules as alloc_value.
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) fn alloc_slice<T: Default>(&self, len: usize) -> Result<&mut [T], AllocError> {
+        let size = mem::size_of::<T>().checked_mul(len).ok_or(AllocError::OutOfMemory { size: usize::MAX, alignment: mem::align_of::<T>() })?;
+        let layout = Layout::from_size_align(size, mem::align_of::<T>()).map_err(|_| AllocError::OutOfMemory { size, alignment: mem::align_of::<T>() })?;
+        let pointer = self.try_allocate(layout)?.cast::<T>();
+        unsafe {
+            for i in 0..len {
+                pointer.as_ptr().add(i).write(T::default());
+            }
+            Ok(std::slice::from_raw_parts_mut(pointer.as_ptr(), len))
+        }
+    }
+}
+
+// Takes a marker on creation and frees back to it on drop. Allocations go through the AllocatorCell
+// it derefs to, so they borrow the scope and can't be used once it has rolled back.
+pub(crate) struct AllocatorScope<A: MarkerAllocator> {
+    arena: AllocatorCell<A>,
+    marker: Marker,
+}
+
+impl<A: MarkerAllocator> AllocatorScope<A> {
+    pub(crate) fn new(allocator: A) -> Self {
+        let marker = allocator.get_marker();
+        AllocatorScope {
+            arena: AllocatorCell::new(allocator),
+            marker,
+        }
+    }
+
+    // Nested scope. Needs the parent mutably, so nothing allocated from the parent can be alive while it exists.
+    pub(crate) fn scope(&mut self) -> AllocatorScope<&mut A> {
+        AllocatorScope::new(self.arena.get_mut())
+    }
+}
+
+impl<A: MarkerAllocator> std::ops::Deref for AllocatorScope<A> {
+    type Target = AllocatorCell<A>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.arena
+    }
+}
+
+impl<A: MarkerAllocator> Drop for AllocatorScope<A> {
+    fn drop(&mut self) {
+        let marker = self.marker;
+        self.arena.get_mut().free_marker(marker);
+    }
+}
+
+unsafe impl<A: MarkerAllocator> allocator_api2::alloc::Allocator for &AllocatorScope<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        (&self.arena).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, pointer: NonNull<u8>, layout: Layout) {
+        (&self.arena).deallocate(pointer, layout)
+    }
+}
+
+// Lets any of our allocators back allocator_api2::vec::Vec and allocator_api2::boxed::Box, e.g. Vec::new_in(&frame_arena).
+unsafe impl<A: Allocator> allocator_api2::alloc::Allocator for &AllocatorCell<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, allocator_api2::alloc::AllocError> {
+        self.try_allocate(layout).map_err(|_| allocator_api2::alloc::AllocError)
+    }
+
+    unsafe fn deallocate(&self, pointer: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.allocator.borrow_mut().deallocate(pointer.as_ptr());
+        }
+    }
+}
+
+// Handles into a ResourcePool. The generation is bumped every time a slot is released,
+// so a handle kept around after its resource was destroyed no longer resolves.
+pub(crate) trait PoolHandle: Copy {
+    fn from_parts(index: u32, generation: u32) -> Self;
+    fn index(&self) -> u32;
+    fn generation(&self) -> u32;
+}
+
+struct PoolSlot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+// Fixed capacity pool of resources addressed by generational handles.
+pub(crate) struct ResourcePool<T, H: PoolHandle> {
+    slots: Vec<PoolSlot<T>>,
+    free_indices: Vec<u32>,
+    used_indices: u32,
+    pool_size: u32,
+    _handle: std::marker::PhantomData<H>,
+}
+
+impl<T, H: PoolHandle> ResourcePool<T, H> {
+    pub(crate) fn new() -> Self {
+        ResourcePool {
+            slots: Vec::new(),
+            free_indices: Vec::new(),
+            used_indices: 0,
+            pool_size: 0,
+            _handle: std::marker::PhantomData,
+        }
+    }
+
+    pub(crate) fn init(&mut self, pool_size: u32) {
+        self.pool_size = pool_size;
+        // Generation 0 is never handed out, so default constructed handles never resolve.
+        self.slots = (0..pool_size).map(|_| PoolSlot { generation: 1, value: None }).collect();
+        // Pop from the back, so indices are handed out in increasing order.
+        self.free_indices = (0..pool_size).rev().collect();
+        self.used_indices = 0;
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        if self.used_indices != 0 {
+            error!("Resource pool has unfreed resources.");
+            for (index, slot) in self.slots.iter().enumerate() {
+                if slot.value.is_some() {
+                    error!("\tResource {}, generation {}", index, slot.generation);
+                }
+            }
+        }
+        self.slots.clear();
+        self.free_indices.clear();
+        self.used_indices = 0;
+        self.pool_size = 0;
+    }
+
+    // Stores value in a free slot, None when the pool is full.
+    pub(crate) fn obtain_resource(&mut self, value: T) -> Option<H> {
+        let Some(index) = self.free_indices.pop() else {
+            error!("Error: no more resources left!");
+            return None;
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.value = Some(value);
+        self.used_indices += 1;
+        Some(H::from_parts(index, slot.generation))
+    }
+
+    // Takes the resource out of the pool. Stale or invalid handles are ignored.
+    pub(crate) fn release_resource(&mut self, handle: H) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index() as usize)?;
+        if slot.generation != handle.generation() {
+            error!("Releasing stale resource handle {}, generation {} but slot is at {}", handle.index(), handle.generation(), slot.generation);
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1).max(1);
+        self.free_indices.push(handle.index());
+        self.used_indices -= 1;
+        Some(value)
+    }
+
+    pub(crate) fn free_all_resources(&mut self) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.value.take().is_some() {
+                slot.generation = slot.generation.wrapping_add(1).max(1);
+                self.free_indices.push(index as u32);
+            }
+        }
+        self.used_indices = 0;
+    }
+
+    pub(crate) fn access_resource(&self, handle: H) -> Option<&T> {
+        let slot = self.slots.get(handle.index() as usize)?;
+        if slot.generation != handle.generation() {
+            return None;
+        }
+        slot.value.as_ref()
+    }
+
+    pub(crate) fn access_resource_mut(&mut self, handle: H) -> Option<&mut T> {
+        let slot = self.slots.get_mut(handle.index() as usize)?;
+        if slot.generation != handle.generation() {
+            return None;
+        }
+        slot.value.as_mut()
+    }
+
+    #[inline]
+    pub(crate) fn is_valid(&self, handle: H) -> bool {
+        self.access_resource(handle).is_some()
+    }
+
+    #[inline]
+    pub(crate) fn used_indices(&self) -> u32 {
+        self.used_indices
+    }
+
+    #[inline]
+    pub(crate) fn pool_size(&self) -> u32 {
+        self.pool_size
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (H, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|value| (H::from_parts(index as u32, slot.generation), value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scope_rolls_back_its_allocations() {
+        let mut stack = StackAllocator::new(0);
+        stack.init(1024);
+        let kept = stack.allocate(16, 8);
+        assert!(!kept.is_null());
+        let before = stack.statistics();
+
+        {
+            let scope = stack.scope();
+            *scope.alloc_value(7u64).unwrap() += 1;
+            assert_eq!(scope.alloc_slice::<u32>(10).unwrap().len(), 10);
+            assert_eq!(scope.statistics().allocation_count, before.allocation_count + 2);
+        }
+
+        let after = stack.statistics();
+        assert_eq!(after.allocation_count, before.allocation_count);
+        assert_eq!(after.allocated_bytes, before.allocated_bytes);
+        assert_eq!(stack.get_marker().offset, 16);
+    }
+
+    #[test]
+    fn nested_scope_only_rolls_back_itself() {
+        let mut stack = StackAllocator::new(0);
+        stack.init(1024);
+        {
+            let mut outer = stack.scope();
+            outer.alloc_value(1u32).unwrap();
+            let outer_statistics = outer.statistics();
+            {
+                let inner = outer.scope();
+                inner.alloc_slice::<u64>(4).unwrap();
+                assert_eq!(inner.statistics().allocation_count, 2);
+            }
+            assert_eq!(outer.statistics().allocation_count, outer_statistics.allocation_count);
+            assert_eq!(outer.statistics().allocated_bytes, outer_statistics.allocated_bytes);
+        }
+        assert_eq!(stack.statistics().allocation_count, 0);
+        assert_eq!(stack.statistics().allocated_bytes, 0);
+    }
+
+    #[test]
+    fn double_stack_scopes_keep_the_other_end() {
+        let mut stack = DoubleStackAllocator::new(0);
+        stack.init(1024);
+        assert!(!stack.allocate_bottom(32, 8).is_null());
+        {
+            let scope = stack.scope_top();
+            scope.alloc_slice::<u8>(64).unwrap();
+        }
+        assert_eq!(stack.statistics().allocation_count, 1);
+        assert_eq!(stack.statistics().allocated_bytes, 32);
+        {
+            let scope = stack.scope_bottom();
+            scope.alloc_value(3u16).unwrap();
+        }
+        assert_eq!(stack.statistics().allocation_count, 1);
+        assert_eq!(stack.get_bottom_marker().offset, 32);
+        stack.shutdown();
+    }
+
+    #[test]
+    fn heap_init_rejects_bad_sizes_and_reinit() {
+        let mut heap = HeapAllocator::new();
+        assert_eq!(heap.init(0), Err(AllocError::InvalidSize(0)));
+        assert_eq!(heap.init(usize::MAX), Err(AllocError::InvalidSize(usize::MAX)));
+        // Too small to hold the TLSF control structure, the block is given back.
+        assert_eq!(heap.init(64), Err(AllocError::InvalidSize(64)));
+        assert_eq!(heap.try_allocate_at(16, 8, None), Err(AllocError::Uninitialized));
+
+        heap.init(MEGA).unwrap();
+        assert_eq!(heap.init(MEGA), Err(AllocError::AlreadyInitialized));
+        let pointer = heap.allocate(128, 16);
+        assert!(!pointer.is_null());
+        heap.deallocate(pointer);
+        heap.shutdown();
+        heap.init(MEGA).unwrap();
+        heap.shutdown();
+    }
+
+    #[test]
+    fn heap_statistics_follow_allocations() {
+        let mut heap = HeapAllocator::new();
+        heap.init(MEGA).unwrap();
+        let first = heap.allocate(100, 8);
+        let second = heap.allocate(300, 64);
+        let both = heap.statistics();
+        assert_eq!(both.allocation_count, 2);
+        assert!(both.allocated_bytes >= 400);
+        assert_eq!(both.peak_bytes, both.allocated_bytes);
+        assert_eq!(both.total_bytes, MEGA);
+
+        heap.deallocate(first);
+        let one = heap.statistics();
+        assert_eq!(one.allocation_count, 1);
+        assert!(one.allocated_bytes >= 300 && one.allocated_bytes < both.allocated_bytes);
+        assert_eq!(one.peak_bytes, both.peak_bytes);
+
+        heap.deallocate(second);
+        assert_eq!(heap.statistics().allocation_count, 0);
+        assert_eq!(heap.statistics().allocated_bytes, 0);
+        assert_eq!(heap.statistics().peak_bytes, both.peak_bytes);
+        heap.shutdown();
+    }
+
+    #[test]
+    fn linear_statistics_count_padding_and_reset_on_clear() {
+        let mut linear = LinearAllocator::new();
+        linear.init(1024);
+        let first = linear.allocate(3, 1);
+        linear.allocate(8, 8);
+        let statistics = linear.statistics();
+        assert_eq!(statistics.allocation_count, 2);
+        // The second allocation starts at the next 8 byte boundary.
+        let padding = memory_align(first as usize + 3, 8) - (first as usize + 3);
+        assert_eq!(statistics.allocated_bytes, 3 + padding + 8);
+        assert_eq!(statistics.total_bytes, 1024);
+
+        linear.clear();
+        assert_eq!(linear.statistics().allocation_count, 0);
+        assert_eq!(linear.statistics().allocated_bytes, 0);
+        assert_eq!(linear.statistics().peak_bytes, statistics.allocated_bytes);
+        linear.shutdown();
+    }
+
+    #[test]
+    fn malloc_statistics_follow_allocations() {
+        let mut malloc = MallocAllocator::default();
+        let first = malloc.allocate(64, 16);
+        let second = malloc.allocate(32, 8);
+        assert_eq!(malloc.statistics().allocation_count, 2);
+        assert_eq!(malloc.statistics().allocated_bytes, 96);
+
+        malloc.deallocate(first);
+        assert_eq!(malloc.statistics().allocation_count, 1);
+        assert_eq!(malloc.statistics().allocated_bytes, 32);
+        malloc.deallocate(second);
+        assert_eq!(malloc.statistics().allocation_count, 0);
+        assert_eq!(malloc.statistics().allocated_bytes, 0);
+        assert_eq!(malloc.statistics().peak_bytes, 96);
+    }
+
+    #[test]
+    fn report_allocations_flags_leaks() {
+        let mut malloc = MallocAllocator::default();
+        assert!(!report_allocations("Test allocator", &malloc));
+        let leaked = malloc.allocate_tracked(16, 8, file!(), line!());
+        assert!(report_allocations("Test allocator", &malloc));
+        let record = malloc.tracker().records().next().unwrap();
+        assert_eq!(record.address, leaked as usize);
+        assert_eq!(record.location.unwrap().file, file!());
+        malloc.deallocate(leaked);
+        assert!(!report_allocations("Test allocator", &malloc));
+    }
+
+    #[test]
+    fn double_stack_deallocate_pops_whole_allocations() {
+        let mut stack = DoubleStackAllocator::new(0);
+        stack.init(1024);
+        stack.allocate_top(16, 8);
+        let before = stack.get_top_marker();
+        stack.allocate_top(24, 8);
+        stack.allocate_top(8, 8);
+        stack.deallocate_top(before.offset - stack.get_top_marker().offset);
+        assert_eq!(stack.get_top_marker(), before);
+        assert_eq!(stack.statistics().allocation_count, 1);
+        assert_eq!(stack.statistics().allocated_bytes, 16);
+        stack.shutdown();
+    }
+
+    #[test]
+    fn huge_allocations_fail_instead_of_wrapping() {
+        let huge = usize::MAX - 4;
+        let mut stack = StackAllocator::new(0);
+        stack.init(1024);
+        stack.allocate(8, 8);
+        assert_eq!(stack.try_allocate_at(huge, 8, None), Err(AllocError::OutOfMemory { size: huge, alignment: 8 }));
+        assert_eq!(stack.statistics().allocation_count, 1);
+
+        let mut double_stack = DoubleStackAllocator::new(0);
+        double_stack.init(1024);
+        double_stack.allocate_bottom(8, 8);
+        assert_eq!(double_stack.try_allocate_bottom_at(huge, 8, None), Err(AllocError::OutOfMemory { size: huge, alignment: 8 }));
+        assert_eq!(double_stack.try_allocate_top_at(huge, 8, None), Err(AllocError::OutOfMemory { size: huge, alignment: 8 }));
+        assert_eq!(double_stack.statistics().allocation_count, 1);
+        double_stack.shutdown();
+
+        let mut linear = LinearAllocator::new();
+        linear.init(1024);
+        linear.allocate(8, 8);
+        assert_eq!(linear.try_allocate_at(huge, 8, None), Err(AllocError::OutOfMemory { size: huge, alignment: 8 }));
+        assert_eq!(linear.statistics().allocation_count, 1);
+        linear.shutdown();
+    }
+
+    // Pushes past the initial capacity so the Vec has to reallocate.
+    fn grow_vec<A: Allocator>(cell: &AllocatorCell<A>) -> usize {
+        let mut values = allocator_api2::vec::Vec::with_capacity_in(4, cell);
+        values.extend(0..64u32);
+        assert!(values.iter().copied().eq(0..64));
+        values.capacity()
+    }
+
+    #[test]
+    fn stack_backs_vec_and_box() {
+        let mut stack = StackAllocator::new(0);
+        stack.init(4096);
+        let cell = AllocatorCell::new(stack);
+        let capacity = grow_vec(&cell);
+        // Growing never gives memory back to a stack, every buffer the Vec went through is still counted.
+        let statistics = cell.statistics();
+        assert!(statistics.allocation_count > 1);
+        assert!(statistics.allocated_bytes >= capacity * mem::size_of::<u32>());
+
+        let boxed = allocator_api2::boxed::Box::new_in(7u64, &cell);
+        assert_eq!(*boxed, 7);
+        assert_eq!(cell.statistics().allocation_count, statistics.allocation_count + 1);
+        drop(boxed);
+
+        let mut stack = cell.into_inner();
+        stack.clear();
+        assert_eq!(stack.statistics().allocated_bytes, 0);
+        assert_eq!(stack.statistics().peak_bytes, statistics.allocated_bytes + mem::size_of::<u64>());
+    }
+
+    #[test]
+    fn linear_backs_vec_and_box() {
+        let mut linear = LinearAllocator::new();
+        linear.init(4096);
+        let mut cell = AllocatorCell::new(linear);
+        let capacity = grow_vec(&cell);
+        let statistics = cell.statistics();
+        assert!(statistics.allocation_count > 1);
+        assert!(statistics.allocated_bytes >= capacity * mem::size_of::<u32>());
+
+        let boxed = allocator_api2::boxed::Box::new_in([1u16; 3], &cell);
+        assert_eq!(*boxed, [1, 1, 1]);
+        drop(boxed);
+        assert_eq!(cell.statistics().allocation_count, statistics.allocation_count + 1);
+
+        cell.get_mut().clear();
+        assert_eq!(cell.statistics().allocation_count, 0);
+        cell.get_mut().shutdown();
+    }
+
+    #[test]
+    fn heap_backs_vec_and_box() {
+        let mut heap = HeapAllocator::new();
+        heap.init(MEGA).unwrap();
+        let mut cell = AllocatorCell::new(heap);
+        {
+            let mut values = allocator_api2::vec::Vec::with_capacity_in(4, &cell);
+            values.extend(0..64u32);
+            // The heap frees the buffers the Vec grew out of, only the current one is alive.
+            let statistics = cell.statistics();
+            assert_eq!(statistics.allocation_count, 1);
+            assert!(statistics.allocated_bytes >= values.capacity() * mem::size_of::<u32>());
+            assert!(statistics.peak_bytes > statistics.allocated_bytes);
+
+            let boxed = allocator_api2::boxed::Box::new_in(3u32, &cell);
+            assert_eq!(cell.statistics().allocation_count, 2);
+            drop(boxed);
+            assert_eq!(cell.statistics().allocation_count, 1);
+        }
+        assert_eq!(cell.statistics().allocation_count, 0);
+        assert_eq!(cell.statistics().allocated_bytes, 0);
+        cell.get_mut().shutdown();
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct TestHandle {
+        index: u32,
+        generation: u32,
+    }
+
+    impl PoolHandle for TestHandle {
+        fn from_parts(index: u32, generation: u32) -> Self {
+            TestHandle { index, generation }
+        }
+
+        fn index(&self) -> u32 {
+            self.index
+        }
+
+        fn generation(&self) -> u32 {
+            self.generation
+        }
+    }
+
+    #[test]
+    fn pool_release_bumps_generation() {
+        let mut pool: ResourcePool<u32, TestHandle> = ResourcePool::new();
+        pool.init(4);
+        let first = pool.obtain_resource(10).unwrap();
+        assert_eq!(pool.release_resource(first), Some(10));
+
+        let second = pool.obtain_resource(20).unwrap();
+        assert_eq!(second.index, first.index);
+        assert_ne!(second.generation, first.generation);
+        assert_eq!(pool.access_resource(second), Some(&20));
+        pool.free_all_resources();
+        assert!(!pool.is_valid(second));
+    }
+
+    #[test]
+    fn pool_rejects_stale_handles() {
+        let mut pool: ResourcePool<u32, TestHandle> = ResourcePool::new();
+        pool.init(2);
+        let stale = pool.obtain_resource(1).unwrap();
+        pool.release_resource(stale);
+        let current = pool.obtain_resource(2).unwrap();
+
+        assert!(!pool.is_valid(stale));
+        assert!(pool.access_resource_mut(stale).is_none());
+        assert_eq!(pool.release_resource(stale), None);
+        assert_eq!(pool.access_resource(current), Some(&2));
+        assert_eq!(pool.used_indices(), 1);
+        // Generation 0 is never handed out.
+        assert!(!pool.is_valid(TestHandle::from_parts(current.index, 0)));
+    }
+
+    #[test]
+    fn pool_full_returns_none() {
+        let mut pool: ResourcePool<u32, TestHandle> = ResourcePool::new();
+        pool.init(2);
+        let first = pool.obtain_resource(1).unwrap();
+        pool.obtain_resource(2).unwrap();
+        assert!(pool.obtain_resource(3).is_none());
+        pool.release_resource(first);
+        assert!(pool.obtain_resource(3).is_some());
+    }
+}
@@ -2,7 +2,10 @@ mod camera;
 mod time;
 mod service;
 mod memory;
+mod frame_arena;
+mod tlsf;
 mod string;
-pub(crate) use memory::{StackAllocator, HeapAllocator};
+pub(crate) use memory::{memory_align, Allocator, AllocatorCell, AllocatorScope, AllocError, MarkerAllocator, PoolHandle, ResourcePool, StackAllocator, HeapAllocator, LinearAllocator};
 pub use camera::Camera;
-pub(crate) use string::StringBuffer;
\ No newline at end of file
+pub(crate) use string::StringBuffer;
+pub(crate) use frame_arena::{FrameArenaConfiguration, FrameArenas};
\ No newline at end of file
@@ -6,7 +6,7 @@ pub(crate) struct StringBuffer {
 }
 
 impl StringBuffer {
-    fn new(size: usize, allocator: *mut HeapAllocator) -> Self {
+    pub(crate) fn new(size: usize, allocator: *mut HeapAllocator) -> Self {
         let mut data = Vec::with_capacity(size);
         data.resize(size, 0); // Allocate buffer with zeros
 
@@ -1,6 +1,7 @@
 use std::time::{Instant, Duration};
 
 use lazy_static::lazy_static;
+#[cfg(target_os = "windows")]
 use winapi::um::winnt::LARGE_INTEGER;
 
 // Cached frequency.
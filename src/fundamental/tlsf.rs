@@ -0,0 +1,660 @@
+// Two-Level Segregated Fit allocator.
+// Port of Matthew Conte's tlsf (http://tlsf.baisoku.org), the same allocator the
+// original C++ HeapAllocator sits on. All operations are O(1): blocks are binned
+// by a first level (power of two) and a second level (linear subdivision) index,
+// and two bitmaps tell us which bins have free blocks.
+use std::mem::size_of;
+use std::ptr;
+
+// log2 of the number of linear subdivisions of block sizes.
+const SL_INDEX_COUNT_LOG2: usize = 5;
+// All allocation sizes and addresses are aligned to this.
+const ALIGN_SIZE_LOG2: usize = 3;
+pub(crate) const ALIGN_SIZE: usize = 1 << ALIGN_SIZE_LOG2;
+
+// Supports pools up to 4Gb, same as the C implementation on 64 bit targets.
+const FL_INDEX_MAX: usize = 32;
+const SL_INDEX_COUNT: usize = 1 << SL_INDEX_COUNT_LOG2;
+const FL_INDEX_SHIFT: usize = SL_INDEX_COUNT_LOG2 + ALIGN_SIZE_LOG2;
+const FL_INDEX_COUNT: usize = FL_INDEX_MAX - FL_INDEX_SHIFT + 1;
+const SMALL_BLOCK_SIZE: usize = 1 << FL_INDEX_SHIFT;
+
+// Block header. prev_phys_block is only valid if the previous block is free and
+// actually lives at the tail of the previous block. next_free/prev_free are only
+// valid if this block is free and overlap the user data otherwise.
+#[repr(C)]
+struct BlockHeader {
+    prev_phys_block: *mut BlockHeader,
+    size: usize,
+    next_free: *mut BlockHeader,
+    prev_free: *mut BlockHeader,
+}
+
+// Sizes are always a multiple of ALIGN_SIZE so the two low bits are used as flags.
+const BLOCK_HEADER_FREE_BIT: usize = 1 << 0;
+const BLOCK_HEADER_PREV_FREE_BIT: usize = 1 << 1;
+
+// Only the size field is overhead for a used block, prev_phys_block is stored in the previous block.
+const BLOCK_HEADER_OVERHEAD: usize = size_of::<usize>();
+// User data starts right after the size field.
+const BLOCK_START_OFFSET: usize = size_of::<*mut BlockHeader>() + size_of::<usize>();
+// A free block must be able to store its header minus prev_phys_block.
+const BLOCK_SIZE_MIN: usize = size_of::<BlockHeader>() - size_of::<*mut BlockHeader>();
+const BLOCK_SIZE_MAX: usize = 1 << FL_INDEX_MAX;
+
+// Pool overhead: the first block header and the zero sized sentinel at the end.
+const POOL_OVERHEAD: usize = 2 * BLOCK_HEADER_OVERHEAD;
+
+// Control structure. Lives at the beginning of the memory handed to create_with_pool.
+#[repr(C)]
+pub(crate) struct Tlsf {
+    // Empty lists point at this block to mark them as free.
+    block_null: BlockHeader,
+    fl_bitmap: u32,
+    sl_bitmap: [u32; FL_INDEX_COUNT],
+    blocks: [[*mut BlockHeader; SL_INDEX_COUNT]; FL_INDEX_COUNT],
+}
+
+#[inline]
+fn ffs(word: u32) -> usize {
+    word.trailing_zeros() as usize
+}
+
+#[inline]
+fn fls(size: usize) -> usize {
+    (usize::BITS - 1 - size.leading_zeros()) as usize
+}
+
+#[inline]
+fn align_up(x: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    (x + (align - 1)) & !(align - 1)
+}
+
+#[inline]
+fn align_down(x: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    x - (x & (align - 1))
+}
+
+#[inline]
+fn align_ptr(pointer: *mut u8, align: usize) -> *mut u8 {
+    let aligned = align_up(pointer as usize, align);
+    pointer.wrapping_add(aligned - pointer as usize)
+}
+
+// Adjust an allocation size to be aligned to word size, and no smaller than the internal minimum.
+#[inline]
+fn adjust_request_size(size: usize, align: usize) -> usize {
+    if size == 0 || size >= BLOCK_SIZE_MAX {
+        return 0;
+    }
+    align_up(size, align).max(BLOCK_SIZE_MIN)
+}
+
+// Map a size to its first and second level indices.
+#[inline]
+fn mapping_insert(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK_SIZE {
+        // Store small blocks in first list.
+        (0, size / (SMALL_BLOCK_SIZE / SL_INDEX_COUNT))
+    } else {
+        let fl = fls(size);
+        let sl = (size >> (fl - SL_INDEX_COUNT_LOG2)) ^ (1 << SL_INDEX_COUNT_LOG2);
+        (fl - (FL_INDEX_SHIFT - 1), sl)
+    }
+}
+
+// Same as mapping_insert, but rounds up to the next block size so any block found is big enough.
+#[inline]
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size >= SMALL_BLOCK_SIZE {
+        let round = (1 << (fls(size) - SL_INDEX_COUNT_LOG2)) - 1;
+        return mapping_insert(size + round);
+    }
+    mapping_insert(size)
+}
+
+impl BlockHeader {
+    #[inline]
+    unsafe fn size(block: *const BlockHeader) -> usize {
+        (*block).size & !(BLOCK_HEADER_FREE_BIT | BLOCK_HEADER_PREV_FREE_BIT)
+    }
+
+    #[inline]
+    unsafe fn set_size(block: *mut BlockHeader, size: usize) {
+        let flags = (*block).size & (BLOCK_HEADER_FREE_BIT | BLOCK_HEADER_PREV_FREE_BIT);
+        (*block).size = size | flags;
+    }
+
+    #[inline]
+    unsafe fn is_last(block: *const BlockHeader) -> bool {
+        BlockHeader::size(block) == 0
+    }
+
+    #[inline]
+    unsafe fn is_free(block: *const BlockHeader) -> bool {
+        (*block).size & BLOCK_HEADER_FREE_BIT != 0
+    }
+
+    #[inline]
+    unsafe fn set_free(block: *mut BlockHeader) {
+        (*block).size |= BLOCK_HEADER_FREE_BIT;
+    }
+
+    #[inline]
+    unsafe fn set_used(block: *mut BlockHeader) {
+        (*block).size &= !BLOCK_HEADER_FREE_BIT;
+    }
+
+    #[inline]
+    unsafe fn is_prev_free(block: *const BlockHeader) -> bool {
+        (*block).size & BLOCK_HEADER_PREV_FREE_BIT != 0
+    }
+
+    #[inline]
+    unsafe fn set_prev_free(block: *mut BlockHeader) {
+        (*block).size |= BLOCK_HEADER_PREV_FREE_BIT;
+    }
+
+    #[inline]
+    unsafe fn set_prev_used(block: *mut BlockHeader) {
+        (*block).size &= !BLOCK_HEADER_PREV_FREE_BIT;
+    }
+
+    #[inline]
+    fn from_ptr(pointer: *const u8) -> *mut BlockHeader {
+        pointer.wrapping_sub(BLOCK_START_OFFSET) as *mut BlockHeader
+    }
+
+    #[inline]
+    fn to_ptr(block: *const BlockHeader) -> *mut u8 {
+        (block as *mut u8).wrapping_add(BLOCK_START_OFFSET)
+    }
+
+    // Return location of next block after block of given size.
+    #[inline]
+    fn offset_to_block(pointer: *const u8, size: isize) -> *mut BlockHeader {
+        pointer.wrapping_offset(size) as *mut BlockHeader
+    }
+
+    // Return location of previous block.
+    #[inline]
+    unsafe fn prev(block: *const BlockHeader) -> *mut BlockHeader {
+        debug_assert!(BlockHeader::is_prev_free(block), "previous block must be free");
+        (*block).prev_phys_block
+    }
+
+    // Return location of next existing block.
+    #[inline]
+    unsafe fn next(block: *const BlockHeader) -> *mut BlockHeader {
+        debug_assert!(!BlockHeader::is_last(block));
+        BlockHeader::offset_to_block(BlockHeader::to_ptr(block), (BlockHeader::size(block) - BLOCK_HEADER_OVERHEAD) as isize)
+    }
+
+    // Link a new block with its physical neighbor, return the neighbor.
+    #[inline]
+    unsafe fn link_next(block: *mut BlockHeader) -> *mut BlockHeader {
+        let next = BlockHeader::next(block);
+        (*next).prev_phys_block = block;
+        next
+    }
+
+    #[inline]
+    unsafe fn mark_as_free(block: *mut BlockHeader) {
+        // Link the block to the next block, first.
+        let next = BlockHeader::link_next(block);
+        BlockHeader::set_prev_free(next);
+        BlockHeader::set_free(block);
+    }
+
+    #[inline]
+    unsafe fn mark_as_used(block: *mut BlockHeader) {
+        let next = BlockHeader::next(block);
+        BlockHeader::set_prev_used(next);
+        BlockHeader::set_used(block);
+    }
+
+    #[inline]
+    unsafe fn can_split(block: *const BlockHeader, size: usize) -> bool {
+        BlockHeader::size(block) >= size_of::<BlockHeader>() + size
+    }
+
+    // Split a block into two, the second of which is free.
+    unsafe fn split(block: *mut BlockHeader, size: usize) -> *mut BlockHeader {
+        // Calculate the amount of space left in the remaining block.
+        let remaining = BlockHeader::offset_to_block(BlockHeader::to_ptr(block), size as isize - BLOCK_HEADER_OVERHEAD as isize);
+        let remain_size = BlockHeader::size(block) - (size + BLOCK_HEADER_OVERHEAD);
+
+        debug_assert!((BlockHeader::to_ptr(remaining) as usize).is_multiple_of(ALIGN_SIZE), "remaining block not aligned properly");
+        debug_assert!(BlockHeader::size(block) == remain_size + size + BLOCK_HEADER_OVERHEAD);
+        BlockHeader::set_size(remaining, remain_size);
+        debug_assert!(BlockHeader::size(remaining) >= BLOCK_SIZE_MIN, "block split with invalid size");
+
+        BlockHeader::set_size(block, size);
+        BlockHeader::mark_as_free(remaining);
+
+        remaining
+    }
+
+    // Absorb a free block's storage into an adjacent previous free block.
+    unsafe fn absorb(prev: *mut BlockHeader, block: *mut BlockHeader) -> *mut BlockHeader {
+        debug_assert!(!BlockHeader::is_last(prev), "previous block can't be last");
+        // Note: Leaves flags untouched.
+        (*prev).size += BlockHeader::size(block) + BLOCK_HEADER_OVERHEAD;
+        BlockHeader::link_next(prev);
+        prev
+    }
+}
+
+impl Tlsf {
+    // Create the control structure at the start of `memory` and hand the rest of it to the allocator as a pool.
+    // Returns null if the memory is misaligned or too small.
+    pub(crate) unsafe fn create_with_pool(memory: *mut u8, bytes: usize) -> *mut Tlsf {
+        if !(memory as usize).is_multiple_of(ALIGN_SIZE) {
+            log::error!("tlsf_create: memory must be aligned to {} bytes.", ALIGN_SIZE);
+            return ptr::null_mut();
+        }
+        if bytes <= Tlsf::size() {
+            log::error!("tlsf_create: memory size {} is smaller than the control structure.", bytes);
+            return ptr::null_mut();
+        }
+
+        let tlsf = memory as *mut Tlsf;
+        Tlsf::construct(tlsf);
+        if (*tlsf).add_pool(memory.add(Tlsf::size()), bytes - Tlsf::size()).is_null() {
+            return ptr::null_mut();
+        }
+        tlsf
+    }
+
+    // Size of the control structure, rounded so the pool that follows stays aligned.
+    #[inline]
+    pub(crate) fn size() -> usize {
+        align_up(size_of::<Tlsf>(), ALIGN_SIZE)
+    }
+
+    // Pool memory starts right after the control structure.
+    #[inline]
+    pub(crate) fn get_pool(tlsf: *mut Tlsf) -> *mut u8 {
+        (tlsf as *mut u8).wrapping_add(Tlsf::size())
+    }
+
+    unsafe fn construct(tlsf: *mut Tlsf) {
+        let block_null = ptr::addr_of_mut!((*tlsf).block_null);
+        (*block_null).prev_phys_block = ptr::null_mut();
+        (*block_null).size = 0;
+        (*block_null).next_free = block_null;
+        (*block_null).prev_free = block_null;
+
+        (*tlsf).fl_bitmap = 0;
+        (*tlsf).sl_bitmap = [0; FL_INDEX_COUNT];
+        (*tlsf).blocks = [[block_null; SL_INDEX_COUNT]; FL_INDEX_COUNT];
+    }
+
+    #[inline]
+    fn block_null(&mut self) -> *mut BlockHeader {
+        ptr::addr_of_mut!(self.block_null)
+    }
+
+    unsafe fn add_pool(&mut self, memory: *mut u8, bytes: usize) -> *mut u8 {
+        let pool_bytes = align_down(bytes.saturating_sub(POOL_OVERHEAD), ALIGN_SIZE);
+
+        if !(memory as usize).is_multiple_of(ALIGN_SIZE) {
+            log::error!("tlsf_add_pool: memory must be aligned by {} bytes.", ALIGN_SIZE);
+            return ptr::null_mut();
+        }
+
+        if !(BLOCK_SIZE_MIN..=BLOCK_SIZE_MAX).contains(&pool_bytes) {
+            log::error!("tlsf_add_pool: memory size must be between {} and {} bytes.", POOL_OVERHEAD + BLOCK_SIZE_MIN, POOL_OVERHEAD + BLOCK_SIZE_MAX);
+            return ptr::null_mut();
+        }
+
+        // Create the main free block. Offset the start of the block slightly
+        // so that the prev_phys_block field falls outside of the pool -
+        // it will never be used.
+        let block = BlockHeader::offset_to_block(memory, -(BLOCK_HEADER_OVERHEAD as isize));
+        (*block).size = 0;
+        BlockHeader::set_size(block, pool_bytes);
+        BlockHeader::set_free(block);
+        BlockHeader::set_prev_used(block);
+        self.block_insert(block);
+
+        // Split the block to create a zero-size sentinel block.
+        let next = BlockHeader::link_next(block);
+        (*next).size = 0;
+        BlockHeader::set_used(next);
+        BlockHeader::set_prev_free(next);
+
+        memory
+    }
+
+    // Find the first free block at or above the given indices. Updates fl/sl to the bin the block came from.
+    unsafe fn search_suitable_block(&mut self, fl: &mut usize, sl: &mut usize) -> *mut BlockHeader {
+        // First, search for a block in the list associated with the given fl/sl index.
+        let mut sl_map = self.sl_bitmap[*fl] & (!0u32 << *sl);
+        if sl_map == 0 {
+            // No block exists. Search in the next largest first-level list.
+            let fl_map = if *fl + 1 < u32::BITS as usize { self.fl_bitmap & (!0u32 << (*fl + 1)) } else { 0 };
+            if fl_map == 0 {
+                // No free blocks available, memory has been exhausted.
+                return ptr::null_mut();
+            }
+
+            *fl = ffs(fl_map);
+            sl_map = self.sl_bitmap[*fl];
+        }
+        debug_assert!(sl_map != 0, "internal error - second level bitmap is null");
+        *sl = ffs(sl_map);
+
+        // Return the first block in the free list.
+        self.blocks[*fl][*sl]
+    }
+
+    // Remove a free block from the free list.
+    unsafe fn remove_free_block(&mut self, block: *mut BlockHeader, fl: usize, sl: usize) {
+        let prev = (*block).prev_free;
+        let next = (*block).next_free;
+        debug_assert!(!prev.is_null(), "prev_free field can not be null");
+        debug_assert!(!next.is_null(), "next_free field can not be null");
+        (*next).prev_free = prev;
+        (*prev).next_free = next;
+
+        // If this block is the head of the free list, set new head.
+        if self.blocks[fl][sl] == block {
+            self.blocks[fl][sl] = next;
+
+            // If the new head is null, clear the bitmap.
+            if next == self.block_null() {
+                self.sl_bitmap[fl] &= !(1u32 << sl);
+
+                // If the second bitmap is now empty, clear the fl bitmap.
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1u32 << fl);
+                }
+            }
+        }
+    }
+
+    // Insert a free block into the free block list.
+    unsafe fn insert_free_block(&mut self, block: *mut BlockHeader, fl: usize, sl: usize) {
+        let current = self.blocks[fl][sl];
+        debug_assert!(!current.is_null(), "free list cannot have a null entry");
+        debug_assert!(!block.is_null(), "cannot insert a null entry into the free list");
+        (*block).next_free = current;
+        (*block).prev_free = self.block_null();
+        (*current).prev_free = block;
+
+        debug_assert!((BlockHeader::to_ptr(block) as usize).is_multiple_of(ALIGN_SIZE), "block not aligned properly");
+
+        // Insert the new block at the head of the list, and mark the first- and second-level bitmaps appropriately.
+        self.blocks[fl][sl] = block;
+        self.fl_bitmap |= 1u32 << fl;
+        self.sl_bitmap[fl] |= 1u32 << sl;
+    }
+
+    unsafe fn block_remove(&mut self, block: *mut BlockHeader) {
+        let (fl, sl) = mapping_insert(BlockHeader::size(block));
+        self.remove_free_block(block, fl, sl);
+    }
+
+    unsafe fn block_insert(&mut self, block: *mut BlockHeader) {
+        let (fl, sl) = mapping_insert(BlockHeader::size(block));
+        self.insert_free_block(block, fl, sl);
+    }
+
+    // Merge a just-freed block with an adjacent previous free block.
+    unsafe fn block_merge_prev(&mut self, mut block: *mut BlockHeader) -> *mut BlockHeader {
+        if BlockHeader::is_prev_free(block) {
+            let prev = BlockHeader::prev(block);
+            debug_assert!(!prev.is_null(), "prev physical block can't be null");
+            debug_assert!(BlockHeader::is_free(prev), "prev block is not free though marked as such");
+            self.block_remove(prev);
+            block = BlockHeader::absorb(prev, block);
+        }
+        block
+    }
+
+    // Merge a just-freed block with an adjacent free block.
+    unsafe fn block_merge_next(&mut self, mut block: *mut BlockHeader) -> *mut BlockHeader {
+        let next = BlockHeader::next(block);
+        debug_assert!(!next.is_null(), "next physical block can't be null");
+
+        if BlockHeader::is_free(next) {
+            debug_assert!(!BlockHeader::is_last(block), "previous block can't be last");
+            self.block_remove(next);
+            block = BlockHeader::absorb(block, next);
+        }
+        block
+    }
+
+    // Trim any trailing block space off the end of a block, return to pool.
+    unsafe fn block_trim_free(&mut self, block: *mut BlockHeader, size: usize) {
+        debug_assert!(BlockHeader::is_free(block), "block must be free");
+        if BlockHeader::can_split(block, size) {
+            let remaining = BlockHeader::split(block, size);
+            BlockHeader::link_next(block);
+            BlockHeader::set_prev_free(remaining);
+            self.block_insert(remaining);
+        }
+    }
+
+    // Trim leading block space, return to pool.
+    unsafe fn block_trim_free_leading(&mut self, block: *mut BlockHeader, size: usize) -> *mut BlockHeader {
+        let mut remaining = block;
+        if BlockHeader::can_split(block, size) {
+            // We want the 2nd block.
+            remaining = BlockHeader::split(block, size - BLOCK_HEADER_OVERHEAD);
+            BlockHeader::set_prev_free(remaining);
+
+            BlockHeader::link_next(block);
+            self.block_insert(block);
+        }
+        remaining
+    }
+
+    unsafe fn block_locate_free(&mut self, size: usize) -> *mut BlockHeader {
+        if size == 0 {
+            return ptr::null_mut();
+        }
+
+        let (mut fl, mut sl) = mapping_search(size);
+
+        // mapping_search can futz with the size, so for excessively large sizes it can sometimes wind up
+        // with indices that are off the end of the block array. So, we protect against that here.
+        if fl >= FL_INDEX_COUNT {
+            return ptr::null_mut();
+        }
+
+        let block = self.search_suitable_block(&mut fl, &mut sl);
+        if !block.is_null() {
+            debug_assert!(BlockHeader::size(block) >= size);
+            self.remove_free_block(block, fl, sl);
+        }
+        block
+    }
+
+    unsafe fn block_prepare_used(&mut self, block: *mut BlockHeader, size: usize) -> *mut u8 {
+        if block.is_null() {
+            return ptr::null_mut();
+        }
+        debug_assert!(size != 0, "size must be non-zero");
+        self.block_trim_free(block, size);
+        BlockHeader::mark_as_used(block);
+        BlockHeader::to_ptr(block)
+    }
+
+    pub(crate) unsafe fn malloc(&mut self, size: usize) -> *mut u8 {
+        let adjust = adjust_request_size(size, ALIGN_SIZE);
+        let block = self.block_locate_free(adjust);
+        self.block_prepare_used(block, adjust)
+    }
+
+    pub(crate) unsafe fn memalign(&mut self, align: usize, size: usize) -> *mut u8 {
+        debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+        let adjust = adjust_request_size(size, ALIGN_SIZE);
+
+        // We must allocate an additional minimum block size bytes so that if our free block will leave an
+        // alignment gap which is smaller, we can trim a leading free block and release it back to the pool.
+        // We must do this because the previous physical block is in use, therefore the prev_phys_block field
+        // is not valid, and we can't simply adjust the size of that block.
+        let gap_minimum = size_of::<BlockHeader>();
+        let size_with_gap = adjust_request_size(adjust + align + gap_minimum, align);
+
+        // If alignment is less than or equals base alignment, we're done.
+        // If we requested 0 bytes, return null, as tlsf_malloc(0) does.
+        let aligned_size = if adjust != 0 && align > ALIGN_SIZE { size_with_gap } else { adjust };
+
+        let mut block = self.block_locate_free(aligned_size);
+
+        if !block.is_null() {
+            let pointer = BlockHeader::to_ptr(block);
+            let mut aligned = align_ptr(pointer, align);
+            let mut gap = aligned as usize - pointer as usize;
+
+            // If gap size is too small, offset to next aligned boundary.
+            if gap != 0 && gap < gap_minimum {
+                let gap_remain = gap_minimum - gap;
+                let offset = gap_remain.max(align);
+                let next_aligned = aligned.wrapping_add(offset);
+
+                aligned = align_ptr(next_aligned, align);
+                gap = aligned as usize - pointer as usize;
+            }
+
+            if gap != 0 {
+                debug_assert!(gap >= gap_minimum, "gap size too small");
+                block = self.block_trim_free_leading(block, gap);
+            }
+        }
+
+        self.block_prepare_used(block, adjust)
+    }
+
+    pub(crate) unsafe fn free(&mut self, pointer: *mut u8) {
+        // Don't attempt to free a null pointer.
+        if pointer.is_null() {
+            return;
+        }
+        let mut block = BlockHeader::from_ptr(pointer);
+        debug_assert!(!BlockHeader::is_free(block), "block already marked as free");
+        BlockHeader::mark_as_free(block);
+        block = self.block_merge_prev(block);
+        block = self.block_merge_next(block);
+        self.block_insert(block);
+    }
+
+    // Usable size of an allocated block, which can be larger than what was requested.
+    pub(crate) unsafe fn block_size(pointer: *const u8) -> usize {
+        if pointer.is_null() {
+            return 0;
+        }
+        BlockHeader::size(BlockHeader::from_ptr(pointer))
+    }
+
+    // Visit every physical block of a pool, in address order.
+    pub(crate) unsafe fn walk_pool<F: FnMut(*mut u8, usize, bool)>(pool: *mut u8, mut walker: F) {
+        let mut block = BlockHeader::offset_to_block(pool, -(BLOCK_HEADER_OVERHEAD as isize));
+
+        while !block.is_null() && !BlockHeader::is_last(block) {
+            walker(BlockHeader::to_ptr(block), BlockHeader::size(block), !BlockHeader::is_free(block));
+            block = BlockHeader::next(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POOL_BYTES: usize = 64 * 1024;
+
+    // u64 backing keeps the memory aligned to ALIGN_SIZE.
+    fn create(memory: &mut Vec<u64>) -> &mut Tlsf {
+        *memory = vec![0; POOL_BYTES / size_of::<u64>()];
+        let tlsf = unsafe { Tlsf::create_with_pool(memory.as_mut_ptr() as *mut u8, POOL_BYTES) };
+        assert!(!tlsf.is_null());
+        unsafe { &mut *tlsf }
+    }
+
+    // (size, used) of every block, in address order.
+    fn blocks(tlsf: &mut Tlsf) -> Vec<(usize, bool)> {
+        let mut blocks = Vec::new();
+        unsafe { Tlsf::walk_pool(Tlsf::get_pool(tlsf), |_, size, used| blocks.push((size, used))) };
+        blocks
+    }
+
+    #[test]
+    fn malloc_returns_aligned_disjoint_blocks() {
+        let mut memory = Vec::new();
+        let tlsf = create(&mut memory);
+        let a = unsafe { tlsf.malloc(100) };
+        let b = unsafe { tlsf.malloc(200) };
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(a as usize % ALIGN_SIZE, 0);
+        assert_eq!(b as usize % ALIGN_SIZE, 0);
+        let a_size = unsafe { Tlsf::block_size(a) };
+        assert!(a_size >= 100);
+        assert!(unsafe { Tlsf::block_size(b) } >= 200);
+        assert!(a as usize + a_size <= b as usize || b as usize + 200 <= a as usize);
+        assert!(unsafe { tlsf.malloc(0) }.is_null());
+    }
+
+    #[test]
+    fn memalign_honours_alignment() {
+        let mut memory = Vec::new();
+        let tlsf = create(&mut memory);
+        // Moves the first free block off any large alignment.
+        let small = unsafe { tlsf.malloc(8) };
+        for align in [16, 64, 256, 4096] {
+            let pointer = unsafe { tlsf.memalign(align, 40) };
+            assert!(!pointer.is_null());
+            assert_eq!(pointer as usize % align, 0);
+            assert!(unsafe { Tlsf::block_size(pointer) } >= 40);
+            unsafe { tlsf.free(pointer) };
+        }
+        unsafe { tlsf.free(small) };
+        assert_eq!(blocks(tlsf).len(), 1);
+    }
+
+    #[test]
+    fn free_coalesces_neighbours() {
+        let mut memory = Vec::new();
+        let tlsf = create(&mut memory);
+        let initial = blocks(tlsf);
+        assert_eq!(initial.len(), 1);
+        assert!(!initial[0].1);
+
+        let pointers: Vec<_> = (0..3).map(|_| unsafe { tlsf.malloc(128) }).collect();
+        assert_eq!(blocks(tlsf).iter().filter(|(_, used)| *used).count(), 3);
+        // Freeing the ends first leaves the middle to merge with both sides.
+        unsafe {
+            tlsf.free(pointers[0]);
+            tlsf.free(pointers[2]);
+            assert_eq!(blocks(tlsf).len(), 3);
+            tlsf.free(pointers[1]);
+        }
+        assert_eq!(blocks(tlsf), initial);
+    }
+
+    #[test]
+    fn exhausted_pool_returns_null() {
+        let mut memory = Vec::new();
+        let tlsf = create(&mut memory);
+        assert!(unsafe { tlsf.malloc(POOL_BYTES) }.is_null());
+
+        let mut pointers = Vec::new();
+        loop {
+            let pointer = unsafe { tlsf.malloc(1024) };
+            if pointer.is_null() {
+                break;
+            }
+            pointers.push(pointer);
+        }
+        assert!(!pointers.is_empty());
+        assert!(pointers.len() < POOL_BYTES / 1024);
+
+        unsafe { tlsf.free(pointers.pop().unwrap()) };
+        assert!(!unsafe { tlsf.malloc(1024) }.is_null());
+    }
+}
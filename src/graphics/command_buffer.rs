@@ -1,15 +1,108 @@
-use crate::graphics::{GpuDevice, RenderPass, Pipeline, BufferHandle, DescriptorSetHandle, ExecutionBarrier};
+use crate::fundamental::PoolHandle;
+use crate::graphics::{GpuDevice, BufferHandle, DescriptorSetHandle};
 use ash::vk;
+use log::error;
+use std::{fmt, sync::{atomic::{AtomicU32, Ordering}, Mutex}};
 
-use super::{queue_type, RenderPassHandle, ResourceHandle};
-pub struct CommandBuffer<'a> {
+use super::{command_type, queue_type, render_pass_operation, render_pass_type, texture_format, util_determine_pipeline_stage_flags, util_determine_pipeline_stage_flags2, util_to_vk_access_flags, util_to_vk_access_flags2, util_sampled_state, util_to_vk_image_layout, DescriptorResource, PipelineHandle, Rect2DInt, RenderPass, RenderPassHandle, ResourceHandle, ResourceState, TextureHandle, Viewport, K_BINDLESS_SET_INDEX, K_MAX_DESCRIPTORS_PER_SET, K_MAX_SWAPCHAIN_IMAGES, K_MAX_THREADS};
+
+// Recording misuse, the command is not recorded.
+#[derive(Debug)]
+pub(crate) enum CommandBufferError {
+    NotRecording(command_type::Enum),
+    InvalidHandle(command_type::Enum),
+    NoRenderPass(command_type::Enum),
+    InsideRenderPass(command_type::Enum),
+    NoPipeline(command_type::Enum),
+    WrongPipelineType(command_type::Enum),
+    Vulkan(vk::Result),
+}
+
+impl fmt::Display for CommandBufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandBufferError::NotRecording(command) => write!(f, "{} recorded outside of begin and end", command_type::to_string(*command)),
+            CommandBufferError::InvalidHandle(command) => write!(f, "{} given an invalid or destroyed handle", command_type::to_string(*command)),
+            CommandBufferError::NoRenderPass(command) => write!(f, "{} needs a graphics render pass bound", command_type::to_string(*command)),
+            CommandBufferError::InsideRenderPass(command) => write!(f, "{} is not allowed inside a graphics render pass", command_type::to_string(*command)),
+            CommandBufferError::NoPipeline(command) => write!(f, "{} needs a pipeline bound", command_type::to_string(*command)),
+            CommandBufferError::WrongPipelineType(command) => write!(f, "{} does not match the bound pipeline type", command_type::to_string(*command)),
+            CommandBufferError::Vulkan(result) => write!(f, "Vulkan error {}", result),
+        }
+    }
+}
+
+impl std::error::Error for CommandBufferError {}
+
+impl From<vk::Result> for CommandBufferError {
+    fn from(result: vk::Result) -> Self {
+        CommandBufferError::Vulkan(result)
+    }
+}
+
+fn to_vk_load_op(operation: render_pass_operation::Enum) -> vk::AttachmentLoadOp {
+    match operation {
+        render_pass_operation::Enum::Load => vk::AttachmentLoadOp::LOAD,
+        render_pass_operation::Enum::Clear => vk::AttachmentLoadOp::CLEAR,
+        _ => vk::AttachmentLoadOp::DONT_CARE,
+    }
+}
+
+// Y is flipped with a negative height, the origin moves to the bottom edge of the rect so it still covers it.
+fn to_flipped_vk_viewport(viewport: &Viewport) -> vk::Viewport {
+    vk::Viewport {
+        x: viewport.rect.x as f32,
+        y: viewport.rect.y as f32 + viewport.rect.height as f32,
+        width: viewport.rect.width as f32,
+        height: -(viewport.rect.height as f32),
+        min_depth: viewport.min_depth,
+        max_depth: viewport.max_depth,
+    }
+}
+
+// Reads of the same state need no barrier, writes and layout changes always do. Layout is the one of textures.
+fn state_needs_transition(state: ResourceState, layout: Option<vk::ImageLayout>, new_state: ResourceState) -> bool {
+    state.is_write() || new_state.is_write() || !state.contains(new_state)
+        || layout.is_some_and(|layout| layout != util_to_vk_image_layout(new_state))
+}
+
+fn image_aspect_mask(format: vk::Format) -> vk::ImageAspectFlags {
+    let mut aspect_mask = vk::ImageAspectFlags::empty();
+    if texture_format::has_depth(format) {
+        aspect_mask |= vk::ImageAspectFlags::DEPTH;
+    }
+    if texture_format::has_stencil(format) {
+        aspect_mask |= vk::ImageAspectFlags::STENCIL;
+    }
+    if aspect_mask.is_empty() {
+        aspect_mask = vk::ImageAspectFlags::COLOR;
+    }
+    aspect_mask
+}
+
+// State a descriptor of type ty uses its resource in, format is the one of textures. Sampled textures are in the
+// state their descriptor's image layout was written for.
+fn descriptor_resource_state(ty: vk::DescriptorType, format: Option<vk::Format>) -> ResourceState {
+    match (ty, format) {
+        (vk::DescriptorType::STORAGE_IMAGE | vk::DescriptorType::STORAGE_BUFFER | vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, _) => ResourceState::RESOURCE_STATE_UNORDERED_ACCESS,
+        (vk::DescriptorType::UNIFORM_BUFFER | vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, _) => ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER,
+        (_, Some(format)) => util_sampled_state(format),
+        _ => ResourceState::RESOURCE_STATE_SHADER_RESOURCE,
+    }
+}
+
+pub struct CommandBuffer<'d, 'a> {
     vk_command_buffer: vk::CommandBuffer, 
-    device: &'a mut GpuDevice, 
-    vk_descriptor_sets: [vk::DescriptorSet; 16], 
+    device: &'d mut GpuDevice<'a>,
+    vk_descriptor_sets: [vk::DescriptorSet; K_MAX_DESCRIPTORS_PER_SET],
 
-    current_render_pass: Option<&'a mut RenderPass>, 
-    current_pipeline: Option<&'a mut Pipeline<'a>>, 
+    current_render_pass: Option<RenderPassHandle>,
+    // Graphics passes begin at their first draw, until then barriers can still be recorded.
+    render_pass_begun: bool,
+    current_pipeline: Option<PipelineHandle>,
     clears: [vk::ClearValue; 2], 
+    // Clears of the bound pass, taken when it was bound.
+    pass_clears: [vk::ClearValue; 2],
 
     is_recording: bool,
     handle: u32,
@@ -22,17 +115,19 @@ pub struct CommandBuffer<'a> {
     baked: bool,
 }
 
-impl<'a> CommandBuffer<'a> {
-    pub fn new(device: &'a mut GpuDevice, handle: u32, buffer_size: u32, baked: bool) -> Self {
-        // Initialize Vulkan command buffer, descriptor sets, and other members
+impl<'d, 'a> CommandBuffer<'d, 'a> {
+    pub fn new(device: &'d mut GpuDevice<'a>, vk_command_buffer: vk::CommandBuffer, handle: u32, buffer_size: u32, baked: bool) -> Self {
         CommandBuffer {
-            vk_command_buffer: unsafe { std::mem::zeroed() }, 
+            vk_command_buffer,
             device,
-            vk_descriptor_sets: [unsafe { std::mem::zeroed() }; 16], 
+            vk_descriptor_sets: [vk::DescriptorSet::null(); K_MAX_DESCRIPTORS_PER_SET],
 
             current_render_pass: None,
+            render_pass_begun: false,
             current_pipeline: None,
-            clears: [unsafe { std::mem::zeroed() }; 2], 
+            // Black color, far depth.
+            clears: [vk::ClearValue::default(), vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } }],
+            pass_clears: [vk::ClearValue::default(); 2],
 
             is_recording: false,
             handle,
@@ -46,19 +141,804 @@ impl<'a> CommandBuffer<'a> {
         }
     }
 
-    pub fn bind_pass(&mut self, handle: RenderPassHandle) {
+    pub fn begin(&mut self) -> Result<(), CommandBufferError> {
+        self.reset();
+        let begin_info = vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { self.device.vulkan_device().begin_command_buffer(self.vk_command_buffer, &begin_info)? };
         self.is_recording = true;
-        self.current_render_pass = self.device.get_render_pass(handle);
+        Ok(())
+    }
+
+    // Ends the pass still bound, the command buffer is then ready to submit.
+    pub fn end(&mut self) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::EndPass)?;
+        self.end_current_render_pass();
+        self.is_recording = false;
+        unsafe { self.device.vulkan_device().end_command_buffer(self.vk_command_buffer)? };
+        Ok(())
+    }
+
+    // Ends recording if still open and hands the command buffer to the device, submitted by the next present.
+    pub fn queue(mut self) -> Result<(), CommandBufferError> {
+        if self.is_recording {
+            self.end()?;
+        }
+        self.device.queue_command_buffer(self.vk_command_buffer);
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.is_recording = false;
+        self.current_render_pass = None;
+        self.render_pass_begun = false;
+        self.current_pipeline = None;
+        self.current_command = 0;
+    }
+
+    fn check_recording(&mut self, command: command_type::Enum) -> Result<(), CommandBufferError> {
+        if !self.is_recording {
+            return Err(CommandBufferError::NotRecording(command));
+        }
+        self.current_command += 1;
+        Ok(())
+    }
+
+    fn is_inside_graphics_pass(&self) -> bool {
+        self.current_render_pass
+            .and_then(|handle| self.device.access_render_pass(handle))
+            .is_some_and(|render_pass| render_pass.ty != render_pass_type::Enum::Compute)
+    }
+
+    // Draws need a graphics pass and pipeline, dispatches a compute pipeline outside of graphics passes.
+    fn check_pipeline(&self, command: command_type::Enum, graphics: bool) -> Result<(), CommandBufferError> {
+        if graphics && !self.is_inside_graphics_pass() {
+            return Err(CommandBufferError::NoRenderPass(command));
+        }
+        if !graphics && self.is_inside_graphics_pass() {
+            return Err(CommandBufferError::InsideRenderPass(command));
+        }
+        let pipeline = self.current_pipeline
+            .and_then(|handle| self.device.access_pipeline(handle))
+            .ok_or(CommandBufferError::NoPipeline(command))?;
+        if pipeline.graphics_pipeline != graphics {
+            return Err(CommandBufferError::WrongPipelineType(command));
+        }
+        Ok(())
+    }
+
+    // Stream buffers live in their parent dynamic buffer, at their global offset.
+    fn resolve_buffer(&self, handle: BufferHandle, command: command_type::Enum) -> Result<(vk::Buffer, vk::DeviceSize), CommandBufferError> {
+        let buffer = self.device.access_buffer(handle).ok_or(CommandBufferError::InvalidHandle(command))?;
+        Ok(match self.device.access_buffer(buffer.parent_buffer) {
+            Some(parent) => (parent.vk_buffer, buffer.global_offset as vk::DeviceSize),
+            None => (buffer.vk_buffer, 0),
+        })
+    }
+
+    fn needs_transition(&self, resource: DescriptorResource, new_state: ResourceState, command: command_type::Enum) -> Result<bool, CommandBufferError> {
+        let (state, layout) = match resource {
+            DescriptorResource::Texture(handle) => {
+                let texture = self.device.access_texture(handle).ok_or(CommandBufferError::InvalidHandle(command))?;
+                (texture.state, Some(texture.vk_image_layout))
+            }
+            DescriptorResource::Buffer(handle) => (self.device.access_buffer(handle).ok_or(CommandBufferError::InvalidHandle(command))?.state, None),
+            DescriptorResource::Empty => return Ok(false),
+        };
+        Ok(state_needs_transition(state, layout, new_state))
+    }
+
+    // Discarding starts images from the undefined layout, for outputs that are cleared or not loaded anyway.
+    // Recorded with synchronization2 when the device has it, which keeps pixel and non pixel shader reads apart.
+    fn emit_transition(&mut self, resource: DescriptorResource, new_state: ResourceState, discard: bool, command: command_type::Enum) -> Result<(), CommandBufferError> {
+        let vulkan_device = self.device.vulkan_device();
+        let synchronization2 = self.device.is_synchronization2_enabled();
+        let old_state = match resource {
+            DescriptorResource::Texture(handle) => {
+                let texture = self.device.access_texture(handle).ok_or(CommandBufferError::InvalidHandle(command))?;
+                let aspect_mask = image_aspect_mask(texture.vk_format);
+                let old_layout = if discard { vk::ImageLayout::UNDEFINED } else { texture.vk_image_layout };
+                let subresource_range = vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: 0,
+                    level_count: vk::REMAINING_MIP_LEVELS,
+                    base_array_layer: 0,
+                    layer_count: vk::REMAINING_ARRAY_LAYERS,
+                };
+                if synchronization2 {
+                    let barrier = vk::ImageMemoryBarrier2::default()
+                        .src_stage_mask(util_determine_pipeline_stage_flags2(texture.state, self.type_))
+                        .src_access_mask(util_to_vk_access_flags2(texture.state))
+                        .dst_stage_mask(util_determine_pipeline_stage_flags2(new_state, self.type_))
+                        .dst_access_mask(util_to_vk_access_flags2(new_state))
+                        .old_layout(old_layout)
+                        .new_layout(util_to_vk_image_layout(new_state))
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(texture.vk_image)
+                        .subresource_range(subresource_range);
+                    let dependency_info = vk::DependencyInfo::default().image_memory_barriers(std::slice::from_ref(&barrier));
+                    unsafe { vulkan_device.cmd_pipeline_barrier2(self.vk_command_buffer, &dependency_info) };
+                } else {
+                    let barrier = vk::ImageMemoryBarrier::default()
+                        .src_access_mask(util_to_vk_access_flags(texture.state))
+                        .dst_access_mask(util_to_vk_access_flags(new_state))
+                        .old_layout(old_layout)
+                        .new_layout(util_to_vk_image_layout(new_state))
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(texture.vk_image)
+                        .subresource_range(subresource_range);
+                    unsafe {
+                        vulkan_device.cmd_pipeline_barrier(self.vk_command_buffer,
+                                                           util_determine_pipeline_stage_flags(barrier.src_access_mask, self.type_),
+                                                           util_determine_pipeline_stage_flags(barrier.dst_access_mask, self.type_),
+                                                           vk::DependencyFlags::empty(), &[], &[], &[barrier])
+                    };
+                }
+                texture.state
+            }
+            DescriptorResource::Buffer(handle) => {
+                let buffer = self.device.access_buffer(handle).ok_or(CommandBufferError::InvalidHandle(command))?;
+                let (vk_buffer, offset) = self.resolve_buffer(handle, command)?;
+                if synchronization2 {
+                    let barrier = vk::BufferMemoryBarrier2::default()
+                        .src_stage_mask(util_determine_pipeline_stage_flags2(buffer.state, self.type_))
+                        .src_access_mask(util_to_vk_access_flags2(buffer.state))
+                        .dst_stage_mask(util_determine_pipeline_stage_flags2(new_state, self.type_))
+                        .dst_access_mask(util_to_vk_access_flags2(new_state))
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(vk_buffer)
+                        .offset(offset)
+                        .size(buffer.size as vk::DeviceSize);
+                    let dependency_info = vk::DependencyInfo::default().buffer_memory_barriers(std::slice::from_ref(&barrier));
+                    unsafe { vulkan_device.cmd_pipeline_barrier2(self.vk_command_buffer, &dependency_info) };
+                } else {
+                    let barrier = vk::BufferMemoryBarrier::default()
+                        .src_access_mask(util_to_vk_access_flags(buffer.state))
+                        .dst_access_mask(util_to_vk_access_flags(new_state))
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(vk_buffer)
+                        .offset(offset)
+                        .size(buffer.size as vk::DeviceSize);
+                    unsafe {
+                        vulkan_device.cmd_pipeline_barrier(self.vk_command_buffer,
+                                                           util_determine_pipeline_stage_flags(barrier.src_access_mask, self.type_),
+                                                           util_determine_pipeline_stage_flags(barrier.dst_access_mask, self.type_),
+                                                           vk::DependencyFlags::empty(), &[], &[barrier], &[])
+                    };
+                }
+                buffer.state
+            }
+            DescriptorResource::Empty => return Ok(()),
+        };
+        if old_state != new_state {
+            self.set_tracked_state(resource, new_state);
+        }
+        Ok(())
+    }
+
+    fn set_tracked_state(&mut self, resource: DescriptorResource, state: ResourceState) {
+        match resource {
+            DescriptorResource::Texture(handle) => {
+                let Some(texture) = self.device.access_texture_mut(handle) else {
+                    return;
+                };
+                let was_undefined = texture.state == ResourceState::RESOURCE_STATE_UNDEFINED;
+                texture.state = state;
+                texture.vk_image_layout = util_to_vk_image_layout(state);
+                // The bindless element points at the dummy texture until the contents are defined.
+                if was_undefined && state != ResourceState::RESOURCE_STATE_UNDEFINED {
+                    self.device.write_bindless_texture(handle.index(), handle);
+                }
+            }
+            DescriptorResource::Buffer(handle) => {
+                if let Some(buffer) = self.device.access_buffer_mut(handle) {
+                    buffer.state = state;
+                }
+            }
+            DescriptorResource::Empty => {}
+        }
+    }
+
+    // Moves a texture or buffer from its tracked state to new_state, barriers are not allowed once a graphics pass
+    // has begun, at its first draw.
+    pub fn transition(&mut self, handle: impl Into<DescriptorResource>, new_state: ResourceState) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::Barrier)?;
+        if self.render_pass_begun {
+            return Err(CommandBufferError::InsideRenderPass(command_type::Enum::Barrier));
+        }
+        let resource = handle.into();
+        if self.needs_transition(resource, new_state, command_type::Enum::Barrier)? {
+            self.emit_transition(resource, new_state, false, command_type::Enum::Barrier)?;
+        }
+        Ok(())
+    }
+
+    // Only with auto barriers enabled. Resources bound after bind_pass and before the first draw of the pass are
+    // transitioned, once the pass has begun the barrier cannot be recorded anymore.
+    fn auto_transition(&mut self, resource: DescriptorResource, new_state: ResourceState, command: command_type::Enum) -> Result<(), CommandBufferError> {
+        if !self.device.is_auto_barriers_enabled() || !self.needs_transition(resource, new_state, command)? {
+            return Ok(());
+        }
+        if self.render_pass_begun {
+            error!("{} needs {:?} in state {:?} after the pass began, bind it before the first draw", command_type::to_string(command), resource, new_state);
+            return Err(CommandBufferError::InsideRenderPass(command));
+        }
+        self.emit_transition(resource, new_state, false, command)
+    }
+
+    // Ends the current pass and makes the new one current. Graphics passes begin at their first draw, so resources
+    // bound in between still get their barriers. Clears set before binding are the ones used by the pass.
+    pub fn bind_pass(&mut self, handle: RenderPassHandle) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::BeginPass)?;
+        if self.current_render_pass == Some(handle) {
+            return Ok(());
+        }
+        if self.device.access_render_pass(handle).is_none() {
+            return Err(CommandBufferError::InvalidHandle(command_type::Enum::BeginPass));
+        }
+        self.end_current_render_pass();
+        self.current_render_pass = Some(handle);
+        self.render_pass_begun = false;
+        self.pass_clears = self.clears;
+        Ok(())
+    }
+
+    // Begins the bound graphics pass if it has not begun yet. Outputs are moved to attachment states before dynamic
+    // rendering or with auto barriers, render pass objects do it themselves otherwise.
+    fn begin_current_render_pass(&mut self, command: command_type::Enum) -> Result<(), CommandBufferError> {
+        if self.render_pass_begun || !self.is_inside_graphics_pass() {
+            return Ok(());
+        }
+        let handle = self.current_render_pass.unwrap();
+        let render_pass = self.device.access_render_pass(handle).ok_or(CommandBufferError::InvalidHandle(command))?;
+        let color_outputs: Vec<TextureHandle> = render_pass.output_textures[..render_pass.num_render_targets as usize].to_vec();
+        let depth_output = render_pass.output_depth;
+        let output = render_pass.output;
+
+        let mut outputs: Vec<(DescriptorResource, ResourceState, bool)> = color_outputs.iter()
+            .map(|texture| (DescriptorResource::Texture(*texture), ResourceState::RESOURCE_STATE_RENDER_TARGET,
+                            output.color_operation != render_pass_operation::Enum::Load))
+            .collect();
+        if output.depth_stencil_format != vk::Format::UNDEFINED {
+            let discard = output.depth_operation != render_pass_operation::Enum::Load && output.stencil_operation != render_pass_operation::Enum::Load;
+            outputs.push((DescriptorResource::Texture(depth_output), ResourceState::RESOURCE_STATE_DEPTH_WRITE, discard));
+        }
+        outputs.retain(|(resource, _, _)| matches!(resource, DescriptorResource::Texture(texture) if self.device.access_texture(*texture).is_some()));
+
+        if self.device.is_auto_barriers_enabled() || self.device.is_dynamic_rendering_enabled() {
+            for (resource, state, discard) in &outputs {
+                self.emit_transition(*resource, *state, *discard, command)?;
+            }
+        }
+        let render_pass = self.device.access_render_pass(handle).unwrap();
+        if self.device.is_dynamic_rendering_enabled() {
+            self.begin_rendering(render_pass);
+        } else {
+            self.begin_render_pass(render_pass);
+        }
+        // Render pass objects leave their outputs as attachments too.
+        for (resource, state, _) in &outputs {
+            self.set_tracked_state(*resource, *state);
+        }
+        self.render_pass_begun = true;
+        Ok(())
+    }
+
+    fn begin_render_pass(&self, render_pass: &RenderPass) {
+        let framebuffer = if render_pass.ty == render_pass_type::Enum::Swapchain {
+            let (image, _, framebuffer) = self.device.current_swapchain_attachment();
+            // A loading swapchain pass starts from the present layout, which an image never presented is not in yet.
+            if render_pass.output.color_operation == render_pass_operation::Enum::Load
+                && self.device.current_swapchain_image_layout() == vk::ImageLayout::UNDEFINED {
+                let barrier = vk::ImageMemoryBarrier::default()
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(image)
+                    .subresource_range(vk::ImageSubresourceRange { aspect_mask: vk::ImageAspectFlags::COLOR, base_mip_level: 0, level_count: 1, base_array_layer: 0, layer_count: 1 });
+                unsafe {
+                    self.device.vulkan_device().cmd_pipeline_barrier(self.vk_command_buffer, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                                     vk::DependencyFlags::empty(), &[], &[], &[barrier])
+                };
+            }
+            framebuffer
+        } else {
+            render_pass.vk_frame_buffer
+        };
+        let mut clear_values = vec![self.pass_clears[0]; render_pass.output.num_color_formats as usize];
+        if render_pass.output.depth_stencil_format != vk::Format::UNDEFINED {
+            clear_values.push(self.pass_clears[1]);
+        }
+        let begin_info = vk::RenderPassBeginInfo::default()
+            .render_pass(render_pass.vk_render_pass)
+            .framebuffer(framebuffer)
+            .render_area(vk::Rect2D { offset: vk::Offset2D { x: 0, y: 0 }, extent: vk::Extent2D { width: render_pass.width as u32, height: render_pass.height as u32 } })
+            .clear_values(&clear_values);
+        unsafe { self.device.vulkan_device().cmd_begin_render_pass(self.vk_command_buffer, &begin_info, vk::SubpassContents::INLINE) };
+    }
+
+    fn begin_rendering(&self, render_pass: &RenderPass) {
+        let output = &render_pass.output;
+        let is_swapchain = render_pass.ty == render_pass_type::Enum::Swapchain;
+
+        // Views of every color output, then of the depth stencil one. Their layouts were set by bind_pass.
+        let mut color_views = Vec::with_capacity(render_pass.num_render_targets as usize);
+        let vulkan_device = self.device.vulkan_device();
+        if is_swapchain {
+            let (image, view, _) = self.device.current_swapchain_attachment();
+            color_views.push(view);
+
+            // The swapchain image is not tracked, a loaded one is where the last present left it, undefined before.
+            let old_layout = match output.color_operation {
+                render_pass_operation::Enum::Load => self.device.current_swapchain_image_layout(),
+                _ => vk::ImageLayout::UNDEFINED,
+            };
+            let barrier = vk::ImageMemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .old_layout(old_layout)
+                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange { aspect_mask: vk::ImageAspectFlags::COLOR, base_mip_level: 0, level_count: 1, base_array_layer: 0, layer_count: 1 });
+            unsafe {
+                vulkan_device.cmd_pipeline_barrier(self.vk_command_buffer, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                   vk::DependencyFlags::empty(), &[], &[], &[barrier])
+            };
+        } else {
+            for texture in &render_pass.output_textures[..render_pass.num_render_targets as usize] {
+                if let Some(texture) = self.device.access_texture(*texture) {
+                    color_views.push(texture.vk_image_view);
+                }
+            }
+        }
+        let depth_view = self.device.access_texture(render_pass.output_depth)
+            .filter(|_| output.depth_stencil_format != vk::Format::UNDEFINED)
+            .map(|texture| texture.vk_image_view);
+        let depth_stencil_format = output.depth_stencil_format;
 
+        let color_attachments: Vec<vk::RenderingAttachmentInfo> = color_views.iter().map(|view| vk::RenderingAttachmentInfo::default()
+            .image_view(*view)
+            .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .load_op(to_vk_load_op(output.color_operation))
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(self.pass_clears[0])).collect();
+        let depth_attachment = depth_view.filter(|_| texture_format::has_depth(depth_stencil_format)).map(|view| vk::RenderingAttachmentInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .load_op(to_vk_load_op(output.depth_operation))
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(self.pass_clears[1]));
+        let stencil_attachment = depth_view.filter(|_| texture_format::has_stencil(depth_stencil_format)).map(|view| vk::RenderingAttachmentInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .load_op(to_vk_load_op(output.stencil_operation))
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(self.pass_clears[1]));
 
+        let mut rendering_info = vk::RenderingInfo::default()
+            .render_area(vk::Rect2D { offset: vk::Offset2D { x: 0, y: 0 }, extent: vk::Extent2D { width: render_pass.width as u32, height: render_pass.height as u32 } })
+            .layer_count(1)
+            .color_attachments(&color_attachments);
+        if let Some(depth_attachment) = &depth_attachment {
+            rendering_info = rendering_info.depth_attachment(depth_attachment);
+        }
+        if let Some(stencil_attachment) = &stencil_attachment {
+            rendering_info = rendering_info.stencil_attachment(stencil_attachment);
+        }
+        unsafe { vulkan_device.cmd_begin_rendering(self.vk_command_buffer, &rendering_info) };
+    }
+
+    // A graphics pass without draws still begins here, for its clears. With dynamic rendering the swapchain image is
+    // also moved to the present layout, as the swapchain render pass would.
+    pub fn end_current_render_pass(&mut self) {
+        if self.is_recording {
+            if let Err(error) = self.begin_current_render_pass(command_type::Enum::EndPass) {
+                error!("Could not begin the pass being ended: {}", error);
+            }
+        }
+        let begun = std::mem::take(&mut self.render_pass_begun);
+        let Some(handle) = self.current_render_pass.take() else {
+            return;
+        };
+        if !begun {
+            return;
+        }
+        let Some(render_pass) = self.device.access_render_pass(handle) else {
+            return;
+        };
+        if !self.is_recording || render_pass.ty == render_pass_type::Enum::Compute {
+            return;
+        }
+
+        let vulkan_device = self.device.vulkan_device();
+        if !self.device.is_dynamic_rendering_enabled() {
+            unsafe { vulkan_device.cmd_end_render_pass(self.vk_command_buffer) };
+            return;
+        }
+        unsafe { vulkan_device.cmd_end_rendering(self.vk_command_buffer) };
+        if render_pass.ty == render_pass_type::Enum::Swapchain {
+            let barrier = vk::ImageMemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::empty())
+                .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(self.device.current_swapchain_attachment().0)
+                .subresource_range(vk::ImageSubresourceRange { aspect_mask: vk::ImageAspectFlags::COLOR, base_mip_level: 0, level_count: 1, base_array_layer: 0, layer_count: 1 });
+            unsafe {
+                vulkan_device.cmd_pipeline_barrier(self.vk_command_buffer, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                   vk::DependencyFlags::empty(), &[], &[], &[barrier])
+            };
+        }
+    }
+
+    // Pipelines using the bindless layout get the bindless set bound with them.
+    pub fn bind_pipeline(&mut self, handle: PipelineHandle) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::BindPipeline)?;
+        let pipeline = self.device.access_pipeline(handle).ok_or(CommandBufferError::InvalidHandle(command_type::Enum::BindPipeline))?;
+        let vulkan_device = self.device.vulkan_device();
+        unsafe { vulkan_device.cmd_bind_pipeline(self.vk_command_buffer, pipeline.vk_bind_point, pipeline.vk_pipeline) };
+
+        let bindless_layout = self.device.bindless_descriptor_set_layout();
+        let uses_bindless = pipeline.num_active_layouts > K_BINDLESS_SET_INDEX
+            && bindless_layout.is_some_and(|layout| pipeline.descriptor_set_layout_handle[K_BINDLESS_SET_INDEX as usize] == layout);
+        if let (true, Some(bindless_set)) = (uses_bindless, self.device.bindless_descriptor_set()) {
+            unsafe {
+                vulkan_device.cmd_bind_descriptor_sets(self.vk_command_buffer, pipeline.vk_bind_point, pipeline.vk_pipeline_layout,
+                                                       K_BINDLESS_SET_INDEX, &[bindless_set], &[])
+            };
+        }
+        self.current_pipeline = Some(handle);
+        Ok(())
     }
 
-    pub fn bind_pipeline(&mut self, handle: PipelineHandle) {
-        // Implement bind_pipeline functionality
+    // Stream buffers are bound at their offset in the parent buffer.
+    pub fn bind_vertex_buffer(&mut self, handle: BufferHandle, binding: u32, offset: u32) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::BindVertexBuffer)?;
+        self.auto_transition(handle.into(), ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER, command_type::Enum::BindVertexBuffer)?;
+        let (vk_buffer, base_offset) = self.resolve_buffer(handle, command_type::Enum::BindVertexBuffer)?;
+        unsafe {
+            self.device.vulkan_device().cmd_bind_vertex_buffers(self.vk_command_buffer, binding, &[vk_buffer], &[base_offset + offset as vk::DeviceSize])
+        };
+        Ok(())
+    }
+
+    pub fn bind_index_buffer(&mut self, handle: BufferHandle, offset: u32, index_type: vk::IndexType) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::BindIndexBuffer)?;
+        self.auto_transition(handle.into(), ResourceState::RESOURCE_STATE_INDEX_BUFFER, command_type::Enum::BindIndexBuffer)?;
+        let (vk_buffer, base_offset) = self.resolve_buffer(handle, command_type::Enum::BindIndexBuffer)?;
+        unsafe {
+            self.device.vulkan_device().cmd_bind_index_buffer(self.vk_command_buffer, vk_buffer, base_offset + offset as vk::DeviceSize, index_type)
+        };
+        Ok(())
+    }
+
+    // Sets are bound from set 0 on the current pipeline, offsets are the ones of its dynamic buffer bindings.
+    pub fn bind_descriptor_set(&mut self, handles: &[DescriptorSetHandle], offsets: &[u32]) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::BindResourceSet)?;
+        let pipeline = self.current_pipeline
+            .and_then(|handle| self.device.access_pipeline(handle))
+            .ok_or(CommandBufferError::NoPipeline(command_type::Enum::BindResourceSet))?;
+        if handles.len() > self.vk_descriptor_sets.len() {
+            error!("Binding {} descriptor sets, only {} are supported", handles.len(), self.vk_descriptor_sets.len());
+            return Err(CommandBufferError::InvalidHandle(command_type::Enum::BindResourceSet));
+        }
+        let (vk_bind_point, vk_pipeline_layout) = (pipeline.vk_bind_point, pipeline.vk_pipeline_layout);
+        let auto_barriers = self.device.is_auto_barriers_enabled();
+        let mut used_resources = Vec::new();
+        for (vk_descriptor_set, handle) in self.vk_descriptor_sets.iter_mut().zip(handles) {
+            let descriptor_set = self.device.access_descriptor_set(*handle).ok_or(CommandBufferError::InvalidHandle(command_type::Enum::BindResourceSet))?;
+            *vk_descriptor_set = descriptor_set.vk_descriptor_set;
+            if !auto_barriers {
+                continue;
+            }
+
+            let bindings = self.device.access_descriptor_set_layout(descriptor_set.layout).and_then(|layout| layout.bindings.as_ref());
+            for binding in bindings.into_iter().flatten() {
+                let Some(resource) = descriptor_set.resources.get(binding.start as usize) else {
+                    continue;
+                };
+                let format = match resource {
+                    DescriptorResource::Texture(texture) => self.device.access_texture(*texture).map(|texture| texture.vk_format),
+                    _ => None,
+                };
+                used_resources.push((*resource, descriptor_resource_state(binding.ty, format)));
+            }
+        }
+        for (resource, state) in used_resources {
+            self.auto_transition(resource, state, command_type::Enum::BindResourceSet)?;
+        }
+        unsafe {
+            self.device.vulkan_device().cmd_bind_descriptor_sets(self.vk_command_buffer, vk_bind_point, vk_pipeline_layout,
+                                                                 0, &self.vk_descriptor_sets[..handles.len()], offsets)
+        };
+        Ok(())
     }
-    
+
+    // Without a viewport the whole current pass, or the swapchain outside of passes, is used. Y is flipped.
+    pub fn set_viewport(&mut self, viewport: Option<&Viewport>) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::SetViewport)?;
+        let vk_viewport = match viewport {
+            Some(viewport) => to_flipped_vk_viewport(viewport),
+            None => {
+                let (width, height) = self.current_extent();
+                vk::Viewport { x: 0.0, y: height as f32, width: width as f32, height: -(height as f32), min_depth: 0.0, max_depth: 1.0 }
+            }
+        };
+        unsafe { self.device.vulkan_device().cmd_set_viewport(self.vk_command_buffer, 0, &[vk_viewport]) };
+        Ok(())
+    }
+
+    // Without a rect the whole current pass, or the swapchain outside of passes, is used.
+    pub fn set_scissor(&mut self, rect: Option<&Rect2DInt>) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::SetScissor)?;
+        let vk_scissor = match rect {
+            Some(rect) => vk::Rect2D {
+                offset: vk::Offset2D { x: rect.x as i32, y: rect.y as i32 },
+                extent: vk::Extent2D { width: rect.width as u32, height: rect.height as u32 },
+            },
+            None => {
+                let (width, height) = self.current_extent();
+                vk::Rect2D { offset: vk::Offset2D { x: 0, y: 0 }, extent: vk::Extent2D { width: width as u32, height: height as u32 } }
+            }
+        };
+        unsafe { self.device.vulkan_device().cmd_set_scissor(self.vk_command_buffer, 0, &[vk_scissor]) };
+        Ok(())
+    }
+
+    fn current_extent(&self) -> (u16, u16) {
+        self.current_render_pass
+            .and_then(|handle| self.device.access_render_pass(handle))
+            .map_or_else(|| self.device.swapchain_extent(), |render_pass| (render_pass.width, render_pass.height))
+    }
+
+    // Used by the next bound pass.
+    pub fn clear(&mut self, red: f32, green: f32, blue: f32, alpha: f32) {
+        self.clears[0] = vk::ClearValue { color: vk::ClearColorValue { float32: [red, green, blue, alpha] } };
+    }
+
+    pub fn clear_depth_stencil(&mut self, depth: f32, stencil: u8) {
+        self.clears[1] = vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth, stencil: stencil as u32 } };
+    }
+
+    pub fn draw(&mut self, first_vertex: u32, vertex_count: u32, first_instance: u32, instance_count: u32) -> Result<(), CommandBufferError> {
+        let command = if instance_count > 1 { command_type::Enum::DrawInstanced } else { command_type::Enum::Draw };
+        self.check_recording(command)?;
+        self.check_pipeline(command, true)?;
+        self.begin_current_render_pass(command)?;
+        unsafe { self.device.vulkan_device().cmd_draw(self.vk_command_buffer, vertex_count, instance_count, first_vertex, first_instance) };
+        Ok(())
+    }
+
+    pub fn draw_indexed(&mut self, index_count: u32, instance_count: u32, first_index: u32, vertex_offset: i32, first_instance: u32) -> Result<(), CommandBufferError> {
+        let command = if instance_count > 1 { command_type::Enum::DrawIndexedInstanced } else { command_type::Enum::DrawIndexed };
+        self.check_recording(command)?;
+        self.check_pipeline(command, true)?;
+        self.begin_current_render_pass(command)?;
+        unsafe {
+            self.device.vulkan_device().cmd_draw_indexed(self.vk_command_buffer, index_count, instance_count, first_index, vertex_offset, first_instance)
+        };
+        Ok(())
+    }
+
+    pub fn dispatch(&mut self, group_x: u32, group_y: u32, group_z: u32) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::Dispatch)?;
+        self.check_pipeline(command_type::Enum::Dispatch, false)?;
+        unsafe { self.device.vulkan_device().cmd_dispatch(self.vk_command_buffer, group_x, group_y, group_z) };
+        Ok(())
+    }
+
+    pub fn copy_buffer(&mut self, src: BufferHandle, src_offset: u32, dst: BufferHandle, dst_offset: u32, size: u32) -> Result<(), CommandBufferError> {
+        self.check_recording(command_type::Enum::CopyResource)?;
+        if self.is_inside_graphics_pass() {
+            return Err(CommandBufferError::InsideRenderPass(command_type::Enum::CopyResource));
+        }
+        self.auto_transition(src.into(), ResourceState::RESOURCE_STATE_COPY_SOURCE, command_type::Enum::CopyResource)?;
+        self.auto_transition(dst.into(), ResourceState::RESOURCE_STATE_COPY_DEST, command_type::Enum::CopyResource)?;
+        let (src_buffer, src_base) = self.resolve_buffer(src, command_type::Enum::CopyResource)?;
+        let (dst_buffer, dst_base) = self.resolve_buffer(dst, command_type::Enum::CopyResource)?;
+        let region = vk::BufferCopy {
+            src_offset: src_base + src_offset as vk::DeviceSize,
+            dst_offset: dst_base + dst_offset as vk::DeviceSize,
+            size: size as vk::DeviceSize,
+        };
+        unsafe { self.device.vulkan_device().cmd_copy_buffer(self.vk_command_buffer, src_buffer, dst_buffer, &[region]) };
+        Ok(())
+    }
+
     pub fn terminate(&mut self) {
         self.is_recording = false;
     }
+}
 
-}
\ No newline at end of file
+struct CommandPoolSlot {
+    vk_command_pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    num_used: u32,
+}
+
+// One command pool per recording thread per frame in flight. Each pool sits behind its own Mutex, taken by every
+// get_command_buffer and by reset_frame; with one thread per thread_index it is never contended. Recording into
+// a handed out buffer happens outside the lock. Shared with job threads through an Arc: they record the vk
+// command buffers with vulkan_device and queue them, present submits everything queued.
+// Raw recording bypasses CommandBuffer, so job threads get none of its misuse errors, resource state tracking
+// or automatic barriers. They must leave resources in the states the main thread expects, transitions of
+// shared resources are recorded on the main thread through CommandBuffer.
+pub(crate) struct CommandBufferRing {
+    vulkan_device: ash::Device,
+    // Indexed by frame * K_MAX_THREADS + thread_index.
+    pools: Vec<Mutex<CommandPoolSlot>>,
+    current_frame: AtomicU32,
+    queued_command_buffers: Mutex<Vec<vk::CommandBuffer>>,
+    num_allocated_command_buffers: AtomicU32,
+}
+
+impl CommandBufferRing {
+    pub fn new(vulkan_device: ash::Device) -> Self {
+        CommandBufferRing {
+            vulkan_device,
+            pools: (0..K_MAX_SWAPCHAIN_IMAGES * K_MAX_THREADS)
+                .map(|_| Mutex::new(CommandPoolSlot { vk_command_pool: vk::CommandPool::null(), command_buffers: Vec::new(), num_used: 0 }))
+                .collect(),
+            current_frame: AtomicU32::new(0),
+            queued_command_buffers: Mutex::new(Vec::new()),
+            num_allocated_command_buffers: AtomicU32::new(0),
+        }
+    }
+
+    pub fn init(&self, queue_family: u32, allocation_callbacks: Option<&vk::AllocationCallbacks>) -> Result<(), vk::Result> {
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .queue_family_index(queue_family)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        for pool in &self.pools {
+            pool.lock().unwrap().vk_command_pool = unsafe { self.vulkan_device.create_command_pool(&pool_info, allocation_callbacks)? };
+        }
+        Ok(())
+    }
+
+    // Only with the device idle, destroying the pools frees their command buffers.
+    pub fn shutdown(&self, allocation_callbacks: Option<&vk::AllocationCallbacks>) {
+        for pool in &self.pools {
+            let mut pool = pool.lock().unwrap();
+            if pool.vk_command_pool != vk::CommandPool::null() {
+                unsafe { self.vulkan_device.destroy_command_pool(pool.vk_command_pool, allocation_callbacks) };
+            }
+            pool.vk_command_pool = vk::CommandPool::null();
+            pool.command_buffers.clear();
+            pool.num_used = 0;
+        }
+        self.queued_command_buffers.lock().unwrap().clear();
+        self.num_allocated_command_buffers.store(0, Ordering::Relaxed);
+    }
+
+    // Makes frame current and resets every thread pool of it, once the GPU is done with the last use of the frame.
+    pub fn reset_frame(&self, frame: u32) -> Result<(), vk::Result> {
+        self.current_frame.store(frame, Ordering::Release);
+        let start = frame as usize * K_MAX_THREADS;
+        for pool in &self.pools[start..start + K_MAX_THREADS] {
+            let mut pool = pool.lock().unwrap();
+            unsafe { self.vulkan_device.reset_command_pool(pool.vk_command_pool, vk::CommandPoolResetFlags::empty())? };
+            pool.num_used = 0;
+        }
+        Ok(())
+    }
+
+    // A primary command buffer from the pool of thread_index for the current frame, valid until the frame comes
+    // around again. With begin it is already recording.
+    pub fn get_command_buffer(&self, thread_index: usize, begin: bool) -> Result<vk::CommandBuffer, vk::Result> {
+        if thread_index >= K_MAX_THREADS {
+            error!("Thread index {} out of the {} command buffer ring threads", thread_index, K_MAX_THREADS);
+            return Err(vk::Result::ERROR_INITIALIZATION_FAILED);
+        }
+        let frame = self.current_frame.load(Ordering::Acquire) as usize;
+        let mut pool = self.pools[frame * K_MAX_THREADS + thread_index].lock().unwrap();
+        let index = pool.num_used as usize;
+        if index == pool.command_buffers.len() {
+            let allocate_info = vk::CommandBufferAllocateInfo::default()
+                .command_pool(pool.vk_command_pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+            let vk_command_buffer = unsafe { self.vulkan_device.allocate_command_buffers(&allocate_info)?[0] };
+            pool.command_buffers.push(vk_command_buffer);
+            self.num_allocated_command_buffers.fetch_add(1, Ordering::Relaxed);
+        }
+        pool.num_used += 1;
+        let vk_command_buffer = pool.command_buffers[index];
+        if begin {
+            let begin_info = vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            unsafe { self.vulkan_device.begin_command_buffer(vk_command_buffer, &begin_info)? };
+        }
+        Ok(vk_command_buffer)
+    }
+
+    // Ended command buffers, from any thread. Submitted in queue order.
+    pub fn queue(&self, vk_command_buffer: vk::CommandBuffer) {
+        self.queued_command_buffers.lock().unwrap().push(vk_command_buffer);
+    }
+
+    pub(crate) fn take_queued(&self) -> Vec<vk::CommandBuffer> {
+        std::mem::take(&mut *self.queued_command_buffers.lock().unwrap())
+    }
+
+    #[inline]
+    pub fn vulkan_device(&self) -> &ash::Device {
+        &self.vulkan_device
+    }
+
+    #[inline]
+    pub fn num_allocated_command_buffers(&self) -> u32 {
+        self.num_allocated_command_buffers.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flipped_viewport_covers_its_rect() {
+        let viewport = Viewport { rect: Rect2DInt { x: 0, y: 360, width: 1280, height: 360 }, min_depth: 0.0, max_depth: 1.0 };
+        let vk_viewport = to_flipped_vk_viewport(&viewport);
+        assert_eq!((vk_viewport.x, vk_viewport.width), (0.0, 1280.0));
+        // A negative height covers [y + height, y], here the bottom half [360, 720].
+        assert_eq!(vk_viewport.y, 720.0);
+        assert_eq!(vk_viewport.height, -360.0);
+        assert_eq!(vk_viewport.y + vk_viewport.height, 360.0);
+
+        let viewport = Viewport { rect: Rect2DInt { x: 0, y: 0, width: 640, height: 480 }, min_depth: 0.0, max_depth: 1.0 };
+        assert_eq!(to_flipped_vk_viewport(&viewport).y, 480.0);
+    }
+
+    #[test]
+    fn reads_in_the_same_state_need_no_transition() {
+        let read = ResourceState::RESOURCE_STATE_SHADER_RESOURCE;
+        assert!(!state_needs_transition(read, Some(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL), read));
+        // Buffers have no layout, generic read covers every read state.
+        assert!(!state_needs_transition(ResourceState::RESOURCE_STATE_GENERIC_READ, None, ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER));
+        assert!(!state_needs_transition(ResourceState::RESOURCE_STATE_GENERIC_READ, None, ResourceState::RESOURCE_STATE_INDEX_BUFFER));
+        let depth_read = util_sampled_state(vk::Format::D32_SFLOAT);
+        assert!(!state_needs_transition(depth_read, Some(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL), depth_read));
+    }
+
+    #[test]
+    fn writes_new_reads_and_layout_changes_need_a_transition() {
+        let read = ResourceState::RESOURCE_STATE_SHADER_RESOURCE;
+        assert!(state_needs_transition(ResourceState::RESOURCE_STATE_UNDEFINED, Some(vk::ImageLayout::UNDEFINED), read));
+        // Write after write, and a render target sampled by the next pass.
+        let render_target = ResourceState::RESOURCE_STATE_RENDER_TARGET;
+        assert!(state_needs_transition(render_target, Some(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL), render_target));
+        assert!(state_needs_transition(render_target, Some(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL), read));
+        assert!(state_needs_transition(read, Some(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL), ResourceState::RESOURCE_STATE_UNORDERED_ACCESS));
+        // Pixel shader reads do not cover compute reads.
+        assert!(state_needs_transition(ResourceState::RESOURCE_STATE_PIXEL_SHADER_RESOURCE, None, read));
+        // Tracked state matches but the image is in another layout.
+        assert!(state_needs_transition(read, Some(vk::ImageLayout::GENERAL), read));
+        assert!(state_needs_transition(ResourceState::RESOURCE_STATE_COPY_SOURCE, None, ResourceState::RESOURCE_STATE_COPY_DEST));
+    }
+
+    #[test]
+    fn descriptors_use_their_resources_in_matching_states() {
+        assert_eq!(descriptor_resource_state(vk::DescriptorType::STORAGE_IMAGE, Some(vk::Format::R8G8B8A8_UNORM)), ResourceState::RESOURCE_STATE_UNORDERED_ACCESS);
+        assert_eq!(descriptor_resource_state(vk::DescriptorType::STORAGE_BUFFER, None), ResourceState::RESOURCE_STATE_UNORDERED_ACCESS);
+        assert_eq!(descriptor_resource_state(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, None), ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER);
+        assert_eq!(descriptor_resource_state(vk::DescriptorType::COMBINED_IMAGE_SAMPLER, Some(vk::Format::R8G8B8A8_UNORM)), ResourceState::RESOURCE_STATE_SHADER_RESOURCE);
+        let depth = descriptor_resource_state(vk::DescriptorType::SAMPLED_IMAGE, Some(vk::Format::D32_SFLOAT));
+        assert_eq!(util_to_vk_image_layout(depth), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+        assert_eq!(descriptor_resource_state(vk::DescriptorType::SAMPLED_IMAGE, None), ResourceState::RESOURCE_STATE_SHADER_RESOURCE);
+    }
+
+    #[test]
+    fn barriers_cover_every_aspect_of_the_format() {
+        assert_eq!(image_aspect_mask(vk::Format::R8G8B8A8_UNORM), vk::ImageAspectFlags::COLOR);
+        assert_eq!(image_aspect_mask(vk::Format::D32_SFLOAT), vk::ImageAspectFlags::DEPTH);
+        assert_eq!(image_aspect_mask(vk::Format::D24_UNORM_S8_UINT), vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL);
+        assert_eq!(image_aspect_mask(vk::Format::S8_UINT), vk::ImageAspectFlags::STENCIL);
+    }
+}
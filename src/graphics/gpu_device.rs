@@ -1,13 +1,33 @@
-use std::os::raw::c_char;
+use std::{collections::HashMap, ffi::{c_void, CStr, CString}, fmt, fs, mem::ManuallyDrop, ops::Range, os::raw::c_char, path::{Path, PathBuf}, process::Command, sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex}};
 
-use vk_mem::Allocator;
+use ash::{ext, khr, vk, vk::Handle};
+use log::{debug, error, info, log, warn, Level};
+use vk_mem::Alloc;
+use winit::raw_window_handle::{HasDisplayHandle, HasWindowHandle, RawDisplayHandle, RawWindowHandle};
 
-use ash::vk;
+use crate::fundamental::{memory_align, FrameArenaConfiguration, FrameArenas, HeapAllocator, PoolHandle, ResourcePool, StackAllocator, StringBuffer};
 
-use crate::fundamental::{StackAllocator, StringBuffer};
+use super::{fill_mode, logic_operation, render_pass_operation, render_pass_type, RenderPassCreation, K_MAX_IMAGE_OUTPUTS, topology_type, vertex_component_format, to_vk_cull_mode, to_vk_logic_op, to_vk_polygon_mode, to_vk_topology, to_vk_vertex_format, to_vk_vertex_input_rate, BlendState, DescriptorBinding, DescriptorSetLayoutCreation, PipelineCreation, StencilOperationState, K_INVALID_SHADER, present_mode, resource_deletion_type, shader_language, to_compiler_extension, to_stage_defines, resource_usage_type, texture_flags, texture_format, texture_type, to_vk_image_type, to_vk_image_view_type, Buffer, BufferCreation, BufferHandle, CommandBuffer, CommandBufferError, CommandBufferRing, DescriptorResource, DescriptorSet, DescriptorSetCreation, DescriptorSetHandle, DescriptorSetLayout, DescriptorSetLayoutHandle, DescriptorSetUpdate, MapBufferParameters, ShaderReloadConfiguration, ShaderReloadService, Pipeline, PipelineHandle, RenderPass, RenderPassHandle, RenderPassOutput, ResourceState, ResourceUpdate, util_sampled_state, util_to_vk_image_layout, Sampler, SamplerCreation, SamplerHandle, SamplerKey, ShaderStage, ShaderState, ShaderStateCreation, ShaderStateHandle, ParseResult, parse_binary, K_SPIRV_MAGIC, Texture, TextureCreation, TextureHandle, K_INVALID_BUFFER, K_INVALID_LAYOUT, K_INVALID_PASS, K_INVALID_SAMPLER, K_INVALID_TEXTURE, K_BINDLESS_SAMPLER_BINDING, K_BINDLESS_SET_INDEX, K_BINDLESS_TEXTURE_BINDING, K_MAX_DESCRIPTORS_PER_SET, K_MAX_SWAPCHAIN_IMAGES};
 
-use super::{present_mode, BufferHandle, CommandBuffer, DescriptorSetUpdate, RenderPassHandle, RenderPassOutput, ResourceUpdate, SamplerHandle, TextureHandle, K_MAX_SWAPCHAIN_IMAGES};
+// Resource pool sizes
+const K_BUFFERS_POOL_SIZE: u32 = 4096;
+const K_TEXTURES_POOL_SIZE: u32 = 512;
+const K_RENDER_PASSES_POOL_SIZE: u32 = 256;
+const K_DESCRIPTOR_SET_LAYOUTS_POOL_SIZE: u32 = 128;
+const K_PIPELINES_POOL_SIZE: u32 = 128;
+const K_SHADERS_POOL_SIZE: u32 = 128;
+const K_DESCRIPTOR_SETS_POOL_SIZE: u32 = 256;
+const K_SAMPLERS_POOL_SIZE: u32 = 32;
 
+// Descriptors of each type in the global descriptor pool.
+const K_GLOBAL_POOL_ELEMENTS: u32 = 128;
+
+// Every texture and sampler slot has an element in the bindless arrays.
+const K_MAX_BINDLESS_RESOURCES: u32 = K_TEXTURES_POOL_SIZE;
+const K_MAX_BINDLESS_SAMPLERS: u32 = K_SAMPLERS_POOL_SIZE;
+
+// Stream buffer memory each frame in flight gets from the dynamic buffer.
+const K_DYNAMIC_PER_FRAME_SIZE: u32 = 1024 * 1024 * 10;
 
 #[repr(C)]
 struct GPUTimestamp {
@@ -21,8 +41,14 @@ struct GPUTimestamp {
     name: *const c_char,
 }
 
+// Samplers with the same parameters share one handle, destroyed when the last user lets go of it.
+struct SamplerCacheEntry {
+    handle: SamplerHandle,
+    references: u32,
+}
+
 struct GPUTimestampManager {
-    allocator: *mut Allocator,
+    allocator: *mut HeapAllocator,
     timestamps: *mut GPUTimestamp,
     timestamps_data: *mut u64,
     queries_per_frame: u32,
@@ -32,18 +58,283 @@ struct GPUTimestampManager {
     current_frame_resolved: bool,
 }
 
-struct DeviceCreation {
-    allocator: *mut Allocator,
+pub(crate) struct DeviceCreation {
+    allocator: *mut HeapAllocator,
     temporary_allocator: *mut StackAllocator,
-    window: *mut std::ffi::c_void,
+    // No window means a headless device: no surface, no swapchain.
+    window: Option<(RawDisplayHandle, RawWindowHandle)>,
     width: u16,
     height: u16,
     gpu_time_queries_per_frame: u16,
     enable_gpu_time_queries: bool,
+    // Validation layer and debug utils, when installed.
     debug: bool,
+    // Panic on the first validation error instead of only logging it, for tests.
+    panic_on_validation_error: bool,
+    // Pipeline cache loaded at init and saved at shutdown. None keeps the cache in memory only.
+    pipeline_cache_path: Option<PathBuf>,
+    // Global texture array through descriptor indexing, only enabled when the device supports it.
+    bindless: bool,
+    // Vulkan 1.3 dynamic rendering instead of render pass and framebuffer objects, when the device supports it.
+    dynamic_rendering: bool,
+    // Vulkan 1.3 synchronization2 barriers, when the device supports it.
+    synchronization2: bool,
+    // Command buffers insert barriers when a pass, set or copy uses a resource in another state than the tracked one.
+    auto_barriers: bool,
+    // Per thread transient memory, recycled by new_frame once the GPU is done with the frame that used it.
+    // Keeps one slot per frame fence, K_MAX_SWAPCHAIN_IMAGES frames in flight.
+    frame_arenas: FrameArenaConfiguration,
+    // Shader states created from files are recompiled by new_frame when the files change.
+    shader_reload: Option<ShaderReloadConfiguration>,
+}
+
+impl Default for DeviceCreation {
+    fn default() -> Self {
+        DeviceCreation {
+            allocator: std::ptr::null_mut(),
+            temporary_allocator: std::ptr::null_mut(),
+            window: None,
+            width: 1,
+            height: 1,
+            gpu_time_queries_per_frame: 32,
+            enable_gpu_time_queries: false,
+            debug: false,
+            panic_on_validation_error: false,
+            pipeline_cache_path: None,
+            bindless: false,
+            dynamic_rendering: true,
+            synchronization2: true,
+            auto_barriers: false,
+            frame_arenas: FrameArenaConfiguration::default(),
+            shader_reload: None,
+        }
+    }
+}
+
+impl DeviceCreation {
+    // Sizes are 16 bit, larger ones are clamped.
+    fn set_size(&mut self, width: u32, height: u32) {
+        if width > u16::MAX as u32 || height > u16::MAX as u32 {
+            error!("Device size {}x{} is larger than {}, clamping it", width, height, u16::MAX);
+        }
+        self.width = width.min(u16::MAX as u32) as u16;
+        self.height = height.min(u16::MAX as u32) as u16;
+    }
+
+    pub(crate) fn set_window<W: HasWindowHandle + HasDisplayHandle>(&mut self, width: u32, height: u32, window: &W) -> &mut Self {
+        self.set_size(width, height);
+        self.window = match (window.display_handle(), window.window_handle()) {
+            (Ok(display), Ok(window)) => Some((display.as_raw(), window.as_raw())),
+            _ => {
+                error!("Window handles are not available, creating a headless device");
+                None
+            }
+        };
+        self
+    }
+
+    pub(crate) fn set_headless(&mut self, width: u32, height: u32) -> &mut Self {
+        self.set_size(width, height);
+        self.window = None;
+        self
+    }
+
+    pub(crate) fn set_allocator(&mut self, allocator: *mut HeapAllocator) -> &mut Self {
+        self.allocator = allocator;
+        self
+    }
+
+    pub(crate) fn set_linear_allocator(&mut self, allocator: *mut StackAllocator) -> &mut Self {
+        self.temporary_allocator = allocator;
+        self
+    }
+
+    pub(crate) fn set_debug(&mut self, debug: bool) -> &mut Self {
+        self.debug = debug;
+        self
+    }
+
+    // Implies debug.
+    pub(crate) fn set_panic_on_validation_error(&mut self, panic_on_validation_error: bool) -> &mut Self {
+        self.panic_on_validation_error = panic_on_validation_error;
+        self.debug |= panic_on_validation_error;
+        self
+    }
+
+    pub(crate) fn set_pipeline_cache_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.pipeline_cache_path = Some(path.into());
+        self
+    }
+
+    // Only a request, check GpuDevice::is_bindless_enabled.
+    pub(crate) fn set_bindless(&mut self, bindless: bool) -> &mut Self {
+        self.bindless = bindless;
+        self
+    }
+
+    // On by default, off forces render pass objects even where dynamic rendering is available.
+    pub(crate) fn set_dynamic_rendering(&mut self, dynamic_rendering: bool) -> &mut Self {
+        self.dynamic_rendering = dynamic_rendering;
+        self
+    }
+
+    // On by default, off keeps the original barriers even where synchronization2 is available.
+    pub(crate) fn set_synchronization2(&mut self, synchronization2: bool) -> &mut Self {
+        self.synchronization2 = synchronization2;
+        self
+    }
+
+    pub(crate) fn set_auto_barriers(&mut self, auto_barriers: bool) -> &mut Self {
+        self.auto_barriers = auto_barriers;
+        self
+    }
+
+    // Arena size is per thread and per frame in flight.
+    pub(crate) fn set_frame_arenas(&mut self, num_threads: usize, arena_size: usize) -> &mut Self {
+        self.frame_arenas.num_threads = num_threads.max(1);
+        self.frame_arenas.arena_size = arena_size;
+        self
+    }
+
+    pub(crate) fn set_shader_reload(&mut self, configuration: ShaderReloadConfiguration) -> &mut Self {
+        self.shader_reload = Some(configuration);
+        self
+    }
+}
+
+// Pipeline cache lookups of the pipelines created from one PipelineCreation, counted by name.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PipelineCacheStatistics {
+    pub hits: u32,
+    pub misses: u32,
+}
+
+// Size of VkPipelineCacheHeaderVersionOne.
+const K_PIPELINE_CACHE_HEADER_SIZE: usize = 32;
+
+// Initial data for the pipeline cache when the file at path was written by this driver for this device.
+// Anything else is discarded, the driver would only throw it away or worse.
+fn load_pipeline_cache_data(path: &Path, properties: &vk::PhysicalDeviceProperties) -> Option<Vec<u8>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(error) => {
+            info!("No pipeline cache loaded from {}: {}", path.display(), error);
+            return None;
+        }
+    };
+
+    // Header fields are little endian whatever the host is.
+    let read_u32 = |offset: usize| data.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    let header_size = read_u32(0).unwrap_or(0) as usize;
+    let header_version = read_u32(4).unwrap_or(0);
+    let valid = header_size >= K_PIPELINE_CACHE_HEADER_SIZE
+        && data.len() >= header_size
+        && header_version == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(8) == Some(properties.vendor_id)
+        && read_u32(12) == Some(properties.device_id)
+        && data[16..32] == properties.pipeline_cache_uuid;
+    if !valid {
+        info!("Discarding pipeline cache {}, it was written by another device or driver", path.display());
+        return None;
+    }
+    Some(data)
+}
+
+#[derive(Debug)]
+pub(crate) enum DeviceError {
+    Loading(ash::LoadingError),
+    UnsupportedWindow,
+    NoSuitableDevice,
+    ResourcePoolFull(resource_deletion_type::Enum),
+    InvalidCreation(&'static str),
+    ShaderCompilation(String),
+    Vulkan(vk::Result),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Loading(error) => write!(f, "could not load the Vulkan library: {}", error),
+            DeviceError::UnsupportedWindow => write!(f, "window system is not supported"),
+            DeviceError::NoSuitableDevice => write!(f, "no physical device with a graphics queue found"),
+            DeviceError::ResourcePoolFull(type_) => write!(f, "no more {:?} resources left", type_),
+            DeviceError::InvalidCreation(reason) => write!(f, "invalid creation parameters: {}", reason),
+            DeviceError::ShaderCompilation(reason) => write!(f, "shader compilation failed: {}", reason),
+            DeviceError::Vulkan(result) => write!(f, "Vulkan error {}", result),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<vk::Result> for DeviceError {
+    fn from(result: vk::Result) -> Self {
+        DeviceError::Vulkan(result)
+    }
+}
+
+// Keeps temp file names of concurrent shader compilations apart.
+static SHADER_TEMP_FILE_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+const K_VALIDATION_LAYER_NAME: &CStr = c"VK_LAYER_KHRONOS_validation";
+
+// Shared with the debug messenger callback through its user data, boxed so the address stays put.
+struct DebugMessengerState {
+    panic_on_validation_error: bool,
+    first_validation_error: Mutex<Option<String>>,
+}
+
+unsafe extern "system" fn debug_utils_callback(message_severity: vk::DebugUtilsMessageSeverityFlagsEXT, message_types: vk::DebugUtilsMessageTypeFlagsEXT,
+                                               callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT<'_>, user_data: *mut c_void) -> vk::Bool32 {
+    let callback_data = &*callback_data;
+    let message = callback_data.message_as_c_str().map_or("".into(), CStr::to_string_lossy);
+    let message_id = callback_data.message_id_name_as_c_str().map_or("".into(), CStr::to_string_lossy);
+
+    let level = if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        Level::Error
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        Level::Warn
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        Level::Info
+    } else {
+        Level::Trace
+    };
+    log!(target: "vulkan", level, "{:?} {} {}", message_types, message_id, message);
+
+    // Unwinding out of the driver is not allowed, so the error is kept and raised by check_validation_errors.
+    if level == Level::Error && message_types.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION) && !user_data.is_null() {
+        let state = &*(user_data as *const DebugMessengerState);
+        if state.panic_on_validation_error {
+            let mut first_validation_error = state.first_validation_error.lock().unwrap();
+            if first_validation_error.is_none() {
+                *first_validation_error = Some(format!("{} {}", message_id, message));
+            }
+        }
+    }
+    vk::FALSE
+}
+
+fn debug_messenger_create_info(state: &DebugMessengerState) -> vk::DebugUtilsMessengerCreateInfoEXT<'static> {
+    vk::DebugUtilsMessengerCreateInfoEXT::default()
+        .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+            | vk::DebugUtilsMessageSeverityFlagsEXT::INFO | vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE)
+        .message_type(vk::DebugUtilsMessageTypeFlagsEXT::GENERAL | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE)
+        .pfn_user_callback(Some(debug_utils_callback))
+        .user_data(state as *const DebugMessengerState as *mut c_void)
 }
 
 pub struct GpuDevice<'a> {
+    buffers: ResourcePool<Buffer, BufferHandle>,
+    textures: ResourcePool<Texture, TextureHandle>,
+    pipelines: ResourcePool<Pipeline<'a>, PipelineHandle>,
+    samplers: ResourcePool<Sampler, SamplerHandle>,
+    sampler_cache: HashMap<SamplerKey, SamplerCacheEntry>,
+    // Render passes are compatible when their outputs match, pipelines and passes share one per output.
+    render_pass_cache: HashMap<RenderPassOutput, vk::RenderPass>,
+    descriptor_set_layouts: ResourcePool<DescriptorSetLayout<'a>, DescriptorSetLayoutHandle>,
+    descriptor_sets: ResourcePool<DescriptorSet, DescriptorSetHandle>,
+    render_passes: ResourcePool<RenderPass, RenderPassHandle>,
+    shaders: ResourcePool<ShaderState<'a>, ShaderStateHandle>,
     fullscreen_vertex_buffer: BufferHandle,
     swapchain_pass: RenderPassHandle,
     default_sampler: SamplerHandle,
@@ -52,49 +343,3770 @@ pub struct GpuDevice<'a> {
     dummy_constant_buffer: BufferHandle,
     swapchain_output: RenderPassOutput,
     string_buffer: StringBuffer,
-    allocator: *mut Allocator, // Placeholder for Allocator type
-    temporary_allocator: *mut StackAllocator, // Placeholder for StackAllocator type
+    allocator: *mut HeapAllocator,
+    temporary_allocator: *mut StackAllocator,
     dynamic_max_per_frame_size: u32,
     dynamic_buffer: BufferHandle,
     dynamic_mapped_memory: *mut u8,
     dynamic_allocated_size: u32,
     dynamic_per_frame_size: u32,
-    queued_command_buffers: RefCell<Vec<Arc<CommandBuffer<'a>>,
-    num_allocated_command_buffers: u32,
-    num_queued_command_buffers: u32,
+    // Offsets handed out from the dynamic buffer are aligned to this.
+    dynamic_alignment: u32,
+    // Per thread command pools of every frame in flight, reset in new_frame once the GPU is done with the frame.
+    // Also holds the command buffers queued for present.
+    command_buffer_ring: Arc<CommandBufferRing>,
     present_mode: present_mode::Enum,
     current_frame: u32,
     previous_frame: u32,
     absolute_frame: u32,
+    swapchain_width: u16,
+    swapchain_height: u16,
+    headless: bool,
     depth_texture: TextureHandle,
-    vulkan_allocation_callbacks: vk::AllocationCallbacks<'a>,
-    vulkan_instance: vk::Instance,
+    // Color output of the swapchain pass on headless devices, where it is a regular geometry pass.
+    offscreen_texture: TextureHandle,
+    vulkan_allocation_callbacks: Option<&'a vk::AllocationCallbacks<'a>>,
+    vulkan_entry: ash::Entry,
+    vulkan_instance: ash::Instance,
     vulkan_physical_device: vk::PhysicalDevice,
     vulkan_physical_properties: vk::PhysicalDeviceProperties,
-    vulkan_device: vk::Device,
+    // Version used by both instance and device.
+    vulkan_api_version: u32,
+    vulkan_enabled_features: vk::PhysicalDeviceFeatures,
+    vulkan_device: ash::Device,
     vulkan_queue: vk::Queue,
     vulkan_queue_family: u32,
     vulkan_descriptor_pool: vk::DescriptorPool,
+    // Used for uploads that wait for the queue, like initial buffer data.
+    vulkan_upload_command_pool: vk::CommandPool,
+    vulkan_upload_command_buffer: vk::CommandBuffer,
     vulkan_swapchain_images: [vk::Image; K_MAX_SWAPCHAIN_IMAGES],
+    // Images start undefined, they are left in the present layout once presented.
+    vulkan_swapchain_images_presented: [bool; K_MAX_SWAPCHAIN_IMAGES],
     vulkan_swapchain_image_views: [vk::ImageView; K_MAX_SWAPCHAIN_IMAGES],
     vulkan_swapchain_framebuffers: [vk::Framebuffer; K_MAX_SWAPCHAIN_IMAGES],
     vulkan_timestamp_query_pool: *mut vk::QueryPool,
+    // One per swapchain image, presents wait on the one of their image.
     vulkan_render_complete_semaphore: [vk::Semaphore; K_MAX_SWAPCHAIN_IMAGES],
-    vulkan_image_acquired_semaphore: vk::Semaphore,
+    // One per frame in flight, like the fences.
+    vulkan_image_acquired_semaphore: [vk::Semaphore; K_MAX_SWAPCHAIN_IMAGES],
     vulkan_command_buffer_executed_fence: [vk::Fence; K_MAX_SWAPCHAIN_IMAGES],
+    vulkan_surface_loader: Option<khr::surface::Instance>,
+    vulkan_swapchain_loader: Option<khr::swapchain::Device>,
     vulkan_window_surface: vk::SurfaceKHR,
     vulkan_surface_format: vk::SurfaceFormatKHR,
     vulkan_present_mode: vk::PresentModeKHR,
     vulkan_swapchain: vk::SwapchainKHR,
     vulkan_swapchain_image_count: u32,
-    vulkan_debug_callback: vk::DebugReportCallbackEXT,
+    vulkan_debug_utils_instance: Option<ext::debug_utils::Instance>,
+    vulkan_debug_utils_device: Option<ext::debug_utils::Device>,
     vulkan_debug_utils_messenger: vk::DebugUtilsMessengerEXT,
+    debug_messenger_state: Box<DebugMessengerState>,
     vulkan_image_index: u32,
-    vma_allocator: vk_mem::Allocator,
+    // Destroyed by hand in shutdown, it has to go before the device.
+    vma_allocator: ManuallyDrop<vk_mem::Allocator>,
     resource_deletion_queue: Vec<ResourceUpdate>,
     descriptor_set_updates: Vec<DescriptorSetUpdate>,
     gpu_timestamp_frequency: f32,
     gpu_timestamp_reset: bool,
     debug_utils_extension_present: bool,
-    vulkan_binaries_path: [c_char; 512], // Adjust size as needed
-}
\ No newline at end of file
+    // Where glslangValidator and dxc live, from VULKAN_SDK. None searches the PATH.
+    vulkan_binaries_path: Option<PathBuf>,
+    vulkan_pipeline_cache: vk::PipelineCache,
+    pipeline_cache_path: Option<PathBuf>,
+    // Cache hits are only known with VK_EXT_pipeline_creation_feedback or Vulkan 1.3.
+    pipeline_creation_feedback: bool,
+    pipeline_cache_statistics: HashMap<String, PipelineCacheStatistics>,
+    // Pipelines created without a name only count toward the totals.
+    unnamed_pipeline_cache_statistics: PipelineCacheStatistics,
+    bindless_enabled: bool,
+    // Update after bind pool holding the one bindless set.
+    vulkan_bindless_descriptor_pool: vk::DescriptorPool,
+    vulkan_bindless_descriptor_set: vk::DescriptorSet,
+    bindless_descriptor_set_layout: DescriptorSetLayoutHandle,
+    // Render passes have no vk::RenderPass or vk::Framebuffer, command buffers begin rendering on the textures.
+    dynamic_rendering_enabled: bool,
+    // Command buffers record barriers with vkCmdPipelineBarrier2.
+    synchronization2_enabled: bool,
+    auto_barriers: bool,
+    frame_arenas: FrameArenas,
+    shader_reload: Option<ShaderReloadService>,
+}
+
+macro_rules! resource_access {
+    ($($pool:ident: $resource:ty, $handle:ty => $access:ident, $access_mut:ident;)*) => {
+        $(
+            // None when the handle is invalid or its resource was already destroyed.
+            pub(crate) fn $access(&self, handle: $handle) -> Option<&$resource> {
+                self.$pool.access_resource(handle)
+            }
+
+            pub(crate) fn $access_mut(&mut self, handle: $handle) -> Option<&mut $resource> {
+                self.$pool.access_resource_mut(handle)
+            }
+        )*
+    };
+}
+
+// Instance extension needed to create a surface for this kind of window.
+fn surface_extension_name(display: &RawDisplayHandle) -> Option<&'static CStr> {
+    match display {
+        RawDisplayHandle::Windows(_) => Some(khr::win32_surface::NAME),
+        RawDisplayHandle::Xlib(_) => Some(khr::xlib_surface::NAME),
+        RawDisplayHandle::Xcb(_) => Some(khr::xcb_surface::NAME),
+        RawDisplayHandle::Wayland(_) => Some(khr::wayland_surface::NAME),
+        _ => None,
+    }
+}
+
+unsafe fn create_window_surface(entry: &ash::Entry, instance: &ash::Instance, display: &RawDisplayHandle, window: &RawWindowHandle, allocation_callbacks: Option<&vk::AllocationCallbacks>) -> Result<vk::SurfaceKHR, DeviceError> {
+    let surface = match (display, window) {
+        (RawDisplayHandle::Windows(_), RawWindowHandle::Win32(window)) => {
+            let create_info = vk::Win32SurfaceCreateInfoKHR::default()
+                .hwnd(window.hwnd.get())
+                .hinstance(window.hinstance.map_or(0, |hinstance| hinstance.get()));
+            khr::win32_surface::Instance::new(entry, instance).create_win32_surface(&create_info, allocation_callbacks)?
+        }
+        (RawDisplayHandle::Xlib(display), RawWindowHandle::Xlib(window)) => {
+            let create_info = vk::XlibSurfaceCreateInfoKHR::default()
+                .dpy(display.display.map_or(std::ptr::null_mut(), |display| display.as_ptr()))
+                .window(window.window);
+            khr::xlib_surface::Instance::new(entry, instance).create_xlib_surface(&create_info, allocation_callbacks)?
+        }
+        (RawDisplayHandle::Xcb(display), RawWindowHandle::Xcb(window)) => {
+            let create_info = vk::XcbSurfaceCreateInfoKHR::default()
+                .connection(display.connection.map_or(std::ptr::null_mut(), |connection| connection.as_ptr()))
+                .window(window.window.get());
+            khr::xcb_surface::Instance::new(entry, instance).create_xcb_surface(&create_info, allocation_callbacks)?
+        }
+        (RawDisplayHandle::Wayland(display), RawWindowHandle::Wayland(window)) => {
+            let create_info = vk::WaylandSurfaceCreateInfoKHR::default()
+                .display(display.display.as_ptr())
+                .surface(window.surface.as_ptr());
+            khr::wayland_surface::Instance::new(entry, instance).create_wayland_surface(&create_info, allocation_callbacks)?
+        }
+        _ => return Err(DeviceError::UnsupportedWindow),
+    };
+    Ok(surface)
+}
+
+fn texture_aspect_flags(format: vk::Format) -> vk::ImageAspectFlags {
+    if texture_format::has_depth(format) {
+        vk::ImageAspectFlags::DEPTH
+    } else if texture_format::has_stencil(format) {
+        vk::ImageAspectFlags::STENCIL
+    } else {
+        vk::ImageAspectFlags::COLOR
+    }
+}
+
+fn color_image_barrier(image: vk::Image, mips: Range<u32>, layer_count: u32, old_layout: vk::ImageLayout, new_layout: vk::ImageLayout,
+                       src_access_mask: vk::AccessFlags, dst_access_mask: vk::AccessFlags) -> vk::ImageMemoryBarrier<'static> {
+    vk::ImageMemoryBarrier::default()
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: mips.start,
+            level_count: mips.end - mips.start,
+            base_array_layer: 0,
+            layer_count,
+        })
+}
+
+// Bytes mapped at offset of a dynamic buffer, size 0 meaning the rest of the buffer. None when the range does not fit.
+fn dynamic_map_size(buffer_size: u32, offset: u32, size: u32) -> Option<u32> {
+    let size = if size == 0 { buffer_size.checked_sub(offset)? } else { size };
+    offset.checked_add(size).filter(|end| *end <= buffer_size).map(|_| size)
+}
+
+fn shader_stage_code<'s>(stage: &ShaderStage<'s>) -> Result<&'s [u8], DeviceError> {
+    stage.code.and_then(|code| code.get(..stage.code_size as usize))
+        .ok_or(DeviceError::InvalidCreation("shader stage code is missing or smaller than code_size"))
+}
+
+fn spirv_words(code: &[u8]) -> Result<Vec<u32>, DeviceError> {
+    if !code.len().is_multiple_of(4) {
+        return Err(DeviceError::InvalidCreation("SPIR-V code size is not a multiple of 4"));
+    }
+    let mut words: Vec<u32> = code.chunks_exact(4).map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap())).collect();
+    // Binaries written on a machine of the other endianness.
+    if words.first() == Some(&K_SPIRV_MAGIC.swap_bytes()) {
+        words.iter_mut().for_each(|word| *word = word.swap_bytes());
+    }
+    Ok(words)
+}
+
+// GLSL needs #version first, so the define goes right after it. Returns the line the define was inserted at.
+fn add_stage_define(source: &str, define: &str, language: shader_language::Enum) -> (String, u32) {
+    let version_line = match language {
+        shader_language::Enum::Glsl => source.lines().position(|line| line.trim_start().starts_with("#version")),
+        _ => None,
+    };
+    let define_line = version_line.map_or(0, |line| line + 1);
+
+    let mut result = String::with_capacity(source.len() + define.len() + 16);
+    for (index, line) in source.lines().enumerate() {
+        if index == define_line {
+            result.push_str(&format!("#define {}\n", define));
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    if define_line >= source.lines().count() {
+        result.push_str(&format!("#define {}\n", define));
+    }
+    (result, define_line as u32 + 1)
+}
+
+// Keeps the compiler messages pointing into the temp file and rewrites them as shader_name:line, with line
+// numbers of the original source. Messages about the injected define point at shader_name:stage define.
+fn map_compiler_diagnostics(output: &str, temp_path: &str, shader_name: &str, define_line: u32) -> Vec<String> {
+    output.lines().filter_map(|line| {
+        let position = line.find(temp_path)?;
+        let rest = line[position + temp_path.len()..].strip_prefix(':')?;
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let line_number = match rest[..digits].parse::<u32>() {
+            Ok(number) if number > define_line => number - 1,
+            Ok(number) if number == define_line => return Some(format!("{}{}:stage define{}", &line[..position], shader_name, &rest[digits..])),
+            Ok(number) => number,
+            Err(_) => return Some(format!("{}{}:{}", &line[..position], shader_name, rest)),
+        };
+        Some(format!("{}{}:{}{}", &line[..position], shader_name, line_number, &rest[digits..]))
+    }).collect()
+}
+
+fn to_vk_present_mode(mode: present_mode::Enum) -> vk::PresentModeKHR {
+    match mode {
+        present_mode::Enum::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        present_mode::Enum::VSyncFast => vk::PresentModeKHR::MAILBOX,
+        present_mode::Enum::VSyncRelaxed => vk::PresentModeKHR::FIFO_RELAXED,
+        _ => vk::PresentModeKHR::FIFO,
+    }
+}
+
+impl<'a> GpuDevice<'a> {
+    pub(crate) fn init(creation: &DeviceCreation) -> Result<GpuDevice<'a>, DeviceError> {
+        info!("Gpu Device init");
+        let vulkan_allocation_callbacks = None;
+
+        let vulkan_entry = unsafe { ash::Entry::load() }.map_err(DeviceError::Loading)?;
+
+        // 1.3 when the loader has it, lavapipe and recent drivers all do.
+        let instance_version = unsafe { vulkan_entry.try_enumerate_instance_version()? }.unwrap_or(vk::API_VERSION_1_0);
+        let api_version = instance_version.min(vk::API_VERSION_1_3);
+
+        let application_info = vk::ApplicationInfo::default()
+            .application_name(c"Lynch Graphics Device")
+            .application_version(1)
+            .engine_name(c"Lynch")
+            .engine_version(1)
+            .api_version(api_version);
+
+        let mut instance_extensions = Vec::new();
+        if let Some((display, _)) = &creation.window {
+            let Some(surface_extension) = surface_extension_name(display) else {
+                return Err(DeviceError::UnsupportedWindow);
+            };
+            instance_extensions.push(khr::surface::NAME.as_ptr());
+            instance_extensions.push(surface_extension.as_ptr());
+        }
+
+        // Debug mode uses whatever is installed, a missing SDK only costs the messages.
+        let mut instance_layers = Vec::new();
+        let mut debug_utils_extension_present = false;
+        if creation.debug {
+            let layers = unsafe { vulkan_entry.enumerate_instance_layer_properties()? };
+            if layers.iter().any(|layer| layer.layer_name_as_c_str() == Ok(K_VALIDATION_LAYER_NAME)) {
+                instance_layers.push(K_VALIDATION_LAYER_NAME.as_ptr());
+            } else {
+                warn!("Debug device requested but {} is not installed", K_VALIDATION_LAYER_NAME.to_string_lossy());
+            }
+
+            let mut extensions = unsafe { vulkan_entry.enumerate_instance_extension_properties(None)? };
+            if !instance_layers.is_empty() {
+                extensions.extend(unsafe { vulkan_entry.enumerate_instance_extension_properties(Some(K_VALIDATION_LAYER_NAME))? });
+            }
+            debug_utils_extension_present = extensions.iter().any(|extension| extension.extension_name_as_c_str() == Ok(ext::debug_utils::NAME));
+            if debug_utils_extension_present {
+                instance_extensions.push(ext::debug_utils::NAME.as_ptr());
+            } else {
+                warn!("{} is not available, no debug messages or object names", ext::debug_utils::NAME.to_string_lossy());
+            }
+        }
+
+        let debug_messenger_state = Box::new(DebugMessengerState {
+            panic_on_validation_error: creation.panic_on_validation_error,
+            first_validation_error: Mutex::new(None),
+        });
+        // Chained to the instance so its own creation and destruction are reported too.
+        let mut instance_debug_create_info = debug_messenger_create_info(&debug_messenger_state);
+
+        let mut instance_create_info = vk::InstanceCreateInfo::default()
+            .application_info(&application_info)
+            .enabled_layer_names(&instance_layers)
+            .enabled_extension_names(&instance_extensions);
+        if debug_utils_extension_present {
+            instance_create_info = instance_create_info.push_next(&mut instance_debug_create_info);
+        }
+        let vulkan_instance = unsafe { vulkan_entry.create_instance(&instance_create_info, vulkan_allocation_callbacks)? };
+
+        let mut vulkan_debug_utils_messenger = vk::DebugUtilsMessengerEXT::null();
+        let vulkan_debug_utils_instance = if debug_utils_extension_present {
+            let debug_utils = ext::debug_utils::Instance::new(&vulkan_entry, &vulkan_instance);
+            match unsafe { debug_utils.create_debug_utils_messenger(&debug_messenger_create_info(&debug_messenger_state), vulkan_allocation_callbacks) } {
+                Ok(messenger) => vulkan_debug_utils_messenger = messenger,
+                Err(result) => error!("Error creating debug utils messenger: {}", result),
+            }
+            Some(debug_utils)
+        } else {
+            None
+        };
+
+        // Surface first, the device we pick has to be able to present to it.
+        let (vulkan_surface_loader, vulkan_window_surface) = match &creation.window {
+            Some((display, window)) => {
+                let surface = unsafe { create_window_surface(&vulkan_entry, &vulkan_instance, display, window, vulkan_allocation_callbacks) };
+                match surface {
+                    Ok(surface) => (Some(khr::surface::Instance::new(&vulkan_entry, &vulkan_instance)), surface),
+                    Err(error) => {
+                        unsafe {
+                            if let Some(debug_utils) = &vulkan_debug_utils_instance {
+                                debug_utils.destroy_debug_utils_messenger(vulkan_debug_utils_messenger, vulkan_allocation_callbacks);
+                            }
+                            vulkan_instance.destroy_instance(vulkan_allocation_callbacks);
+                        }
+                        return Err(error);
+                    }
+                }
+            }
+            None => (None, vk::SurfaceKHR::null()),
+        };
+
+        let destroy_instance = |instance: &ash::Instance| unsafe {
+            if let Some(surface_loader) = &vulkan_surface_loader {
+                surface_loader.destroy_surface(vulkan_window_surface, vulkan_allocation_callbacks);
+            }
+            if let Some(debug_utils) = &vulkan_debug_utils_instance {
+                debug_utils.destroy_debug_utils_messenger(vulkan_debug_utils_messenger, vulkan_allocation_callbacks);
+            }
+            instance.destroy_instance(vulkan_allocation_callbacks);
+        };
+
+        let Some((vulkan_physical_device, vulkan_queue_family)) = Self::select_physical_device(&vulkan_instance, vulkan_surface_loader.as_ref(), vulkan_window_surface) else {
+            destroy_instance(&vulkan_instance);
+            return Err(DeviceError::NoSuitableDevice);
+        };
+        let vulkan_physical_properties = unsafe { vulkan_instance.get_physical_device_properties(vulkan_physical_device) };
+        let device_name = unsafe { CStr::from_ptr(vulkan_physical_properties.device_name.as_ptr()) };
+        info!("GPU Used: {}", device_name.to_string_lossy());
+
+        // Device
+        let queue_priorities = [1.0];
+        let queue_create_infos = [vk::DeviceQueueCreateInfo::default()
+            .queue_family_index(vulkan_queue_family)
+            .queue_priorities(&queue_priorities)];
+
+        let vulkan_api_version = api_version.min(vulkan_physical_properties.api_version);
+        let mut device_extensions = Vec::new();
+        if creation.window.is_some() {
+            device_extensions.push(khr::swapchain::NAME.as_ptr());
+        }
+        let mut pipeline_creation_feedback = vulkan_api_version >= vk::API_VERSION_1_3;
+        if !pipeline_creation_feedback && Self::supports_extension(&vulkan_instance, vulkan_physical_device, ext::pipeline_creation_feedback::NAME) {
+            device_extensions.push(ext::pipeline_creation_feedback::NAME.as_ptr());
+            pipeline_creation_feedback = true;
+        }
+
+        // Optional features are enabled when supported, users check vulkan_enabled_features.
+        let supported_features = unsafe { vulkan_instance.get_physical_device_features(vulkan_physical_device) };
+        let vulkan_enabled_features = vk::PhysicalDeviceFeatures::default()
+            .image_cube_array(supported_features.image_cube_array == vk::TRUE)
+            .sampler_anisotropy(supported_features.sampler_anisotropy == vk::TRUE)
+            .fill_mode_non_solid(supported_features.fill_mode_non_solid == vk::TRUE)
+            .logic_op(supported_features.logic_op == vk::TRUE);
+
+        // Bindless needs partially bound, update after bind arrays of sampled images and samplers indexed non uniformly.
+        let bindless_enabled = creation.bindless && Self::supports_bindless(&vulkan_instance, vulkan_physical_device, vulkan_api_version);
+        if creation.bindless && !bindless_enabled {
+            info!("Bindless requested but not supported by the device, using descriptor sets only");
+        }
+        if bindless_enabled && vulkan_api_version < vk::API_VERSION_1_2 {
+            device_extensions.push(ext::descriptor_indexing::NAME.as_ptr());
+        }
+        let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default()
+            .shader_sampled_image_array_non_uniform_indexing(true)
+            .descriptor_binding_sampled_image_update_after_bind(true)
+            .descriptor_binding_partially_bound(true)
+            .descriptor_binding_update_unused_while_pending(true)
+            .runtime_descriptor_array(true);
+
+        // Left all false below 1.3.
+        let mut vulkan_13_features = vk::PhysicalDeviceVulkan13Features::default();
+        if vulkan_api_version >= vk::API_VERSION_1_3 {
+            let mut features = vk::PhysicalDeviceFeatures2::default().push_next(&mut vulkan_13_features);
+            unsafe { vulkan_instance.get_physical_device_features2(vulkan_physical_device, &mut features) };
+        }
+        let dynamic_rendering_enabled = creation.dynamic_rendering && vulkan_13_features.dynamic_rendering == vk::TRUE;
+        let mut dynamic_rendering_features = vk::PhysicalDeviceDynamicRenderingFeatures::default().dynamic_rendering(true);
+        let synchronization2_enabled = creation.synchronization2 && vulkan_13_features.synchronization2 == vk::TRUE;
+        let mut synchronization2_features = vk::PhysicalDeviceSynchronization2Features::default().synchronization2(true);
+
+        let mut device_create_info = vk::DeviceCreateInfo::default()
+            .queue_create_infos(&queue_create_infos)
+            .enabled_extension_names(&device_extensions)
+            .enabled_features(&vulkan_enabled_features);
+        if bindless_enabled {
+            device_create_info = device_create_info.push_next(&mut indexing_features);
+        }
+        if dynamic_rendering_enabled {
+            device_create_info = device_create_info.push_next(&mut dynamic_rendering_features);
+        }
+        if synchronization2_enabled {
+            device_create_info = device_create_info.push_next(&mut synchronization2_features);
+        }
+        let vulkan_device = match unsafe { vulkan_instance.create_device(vulkan_physical_device, &device_create_info, vulkan_allocation_callbacks) } {
+            Ok(device) => device,
+            Err(result) => {
+                destroy_instance(&vulkan_instance);
+                return Err(result.into());
+            }
+        };
+        let vulkan_queue = unsafe { vulkan_device.get_device_queue(vulkan_queue_family, 0) };
+
+        // VMA
+        let mut allocator_create_info = vk_mem::AllocatorCreateInfo::new(&vulkan_instance, &vulkan_device, vulkan_physical_device);
+        allocator_create_info.vulkan_api_version = vulkan_api_version;
+        let vma_allocator = match unsafe { vk_mem::Allocator::new(allocator_create_info) } {
+            Ok(allocator) => allocator,
+            Err(result) => {
+                unsafe { vulkan_device.destroy_device(vulkan_allocation_callbacks) };
+                destroy_instance(&vulkan_instance);
+                return Err(result.into());
+            }
+        };
+
+        let vulkan_swapchain_loader = creation.window.as_ref().map(|_| khr::swapchain::Device::new(&vulkan_instance, &vulkan_device));
+        let vulkan_debug_utils_device = vulkan_debug_utils_instance.as_ref().map(|_| ext::debug_utils::Device::new(&vulkan_instance, &vulkan_device));
+
+        let mut gpu_device = GpuDevice {
+            buffers: ResourcePool::new(),
+            textures: ResourcePool::new(),
+            pipelines: ResourcePool::new(),
+            samplers: ResourcePool::new(),
+            sampler_cache: HashMap::new(),
+            render_pass_cache: HashMap::new(),
+            descriptor_set_layouts: ResourcePool::new(),
+            descriptor_sets: ResourcePool::new(),
+            render_passes: ResourcePool::new(),
+            shaders: ResourcePool::new(),
+            fullscreen_vertex_buffer: K_INVALID_BUFFER,
+            swapchain_pass: K_INVALID_PASS,
+            default_sampler: K_INVALID_SAMPLER,
+            dummy_texture: K_INVALID_TEXTURE,
+            dummy_constant_buffer: K_INVALID_BUFFER,
+            swapchain_output: RenderPassOutput::default(),
+            string_buffer: StringBuffer::new(1024 * 1024, creation.allocator),
+            allocator: creation.allocator,
+            temporary_allocator: creation.temporary_allocator,
+            dynamic_max_per_frame_size: 0,
+            dynamic_buffer: K_INVALID_BUFFER,
+            dynamic_mapped_memory: std::ptr::null_mut(),
+            dynamic_allocated_size: 0,
+            dynamic_per_frame_size: 0,
+            dynamic_alignment: 1,
+            command_buffer_ring: Arc::new(CommandBufferRing::new(vulkan_device.clone())),
+            present_mode: present_mode::Enum::VSync,
+            current_frame: 0,
+            previous_frame: 0,
+            absolute_frame: 0,
+            swapchain_width: creation.width,
+            swapchain_height: creation.height,
+            headless: creation.window.is_none(),
+            depth_texture: K_INVALID_TEXTURE,
+            offscreen_texture: K_INVALID_TEXTURE,
+            vulkan_allocation_callbacks,
+            vulkan_entry,
+            vulkan_instance,
+            vulkan_physical_device,
+            vulkan_physical_properties,
+            vulkan_api_version,
+            vulkan_enabled_features,
+            vulkan_device,
+            vulkan_queue,
+            vulkan_queue_family,
+            vulkan_descriptor_pool: vk::DescriptorPool::null(),
+            vulkan_upload_command_pool: vk::CommandPool::null(),
+            vulkan_upload_command_buffer: vk::CommandBuffer::null(),
+            vulkan_swapchain_images: [vk::Image::null(); K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_swapchain_images_presented: [false; K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_swapchain_image_views: [vk::ImageView::null(); K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_swapchain_framebuffers: [vk::Framebuffer::null(); K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_timestamp_query_pool: std::ptr::null_mut(),
+            vulkan_render_complete_semaphore: [vk::Semaphore::null(); K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_image_acquired_semaphore: [vk::Semaphore::null(); K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_command_buffer_executed_fence: [vk::Fence::null(); K_MAX_SWAPCHAIN_IMAGES],
+            vulkan_surface_loader,
+            vulkan_swapchain_loader,
+            vulkan_window_surface,
+            vulkan_surface_format: vk::SurfaceFormatKHR::default(),
+            vulkan_present_mode: vk::PresentModeKHR::FIFO,
+            vulkan_swapchain: vk::SwapchainKHR::null(),
+            vulkan_swapchain_image_count: 0,
+            vulkan_debug_utils_instance,
+            vulkan_debug_utils_device,
+            vulkan_debug_utils_messenger,
+            debug_messenger_state,
+            vulkan_image_index: 0,
+            vma_allocator: ManuallyDrop::new(vma_allocator),
+            resource_deletion_queue: Vec::new(),
+            descriptor_set_updates: Vec::new(),
+            gpu_timestamp_frequency: 0.0,
+            gpu_timestamp_reset: true,
+            debug_utils_extension_present,
+            vulkan_binaries_path: std::env::var_os("VULKAN_SDK").map(|sdk| PathBuf::from(sdk).join(if cfg!(target_os = "windows") { "Bin" } else { "bin" })),
+            vulkan_pipeline_cache: vk::PipelineCache::null(),
+            pipeline_cache_path: creation.pipeline_cache_path.clone(),
+            pipeline_creation_feedback,
+            pipeline_cache_statistics: HashMap::new(),
+            unnamed_pipeline_cache_statistics: PipelineCacheStatistics::default(),
+            bindless_enabled,
+            vulkan_bindless_descriptor_pool: vk::DescriptorPool::null(),
+            vulkan_bindless_descriptor_set: vk::DescriptorSet::null(),
+            bindless_descriptor_set_layout: K_INVALID_LAYOUT,
+            dynamic_rendering_enabled,
+            synchronization2_enabled,
+            auto_barriers: creation.auto_barriers,
+            frame_arenas: FrameArenas::new(),
+            shader_reload: creation.shader_reload.as_ref().map(|configuration| {
+                let mut shader_reload = ShaderReloadService::new();
+                shader_reload.init(configuration);
+                shader_reload
+            }),
+        };
+        gpu_device.frame_arenas.init(&creation.frame_arenas);
+
+        // From here on shutdown knows how to release whatever got created.
+        if let Err(error) = gpu_device.init_resources() {
+            gpu_device.shutdown();
+            return Err(error);
+        }
+
+        info!("Gpu Device init done, {}{}{}{}", if gpu_device.headless { "headless" } else { "windowed" }, if gpu_device.bindless_enabled { ", bindless" } else { "" },
+              if gpu_device.dynamic_rendering_enabled { ", dynamic rendering" } else { "" }, if gpu_device.synchronization2_enabled { ", synchronization2" } else { "" });
+        gpu_device.check_validation_errors();
+        Ok(gpu_device)
+    }
+
+    fn init_resources(&mut self) -> Result<(), DeviceError> {
+        // Global descriptor pool
+        let pool_sizes = [
+            vk::DescriptorType::SAMPLER,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+            vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            vk::DescriptorType::STORAGE_BUFFER,
+            vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            vk::DescriptorType::STORAGE_BUFFER_DYNAMIC,
+            vk::DescriptorType::INPUT_ATTACHMENT,
+        ].map(|type_| vk::DescriptorPoolSize { ty: type_, descriptor_count: K_GLOBAL_POOL_ELEMENTS });
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+            .max_sets(K_GLOBAL_POOL_ELEMENTS * pool_sizes.len() as u32)
+            .pool_sizes(&pool_sizes);
+        self.vulkan_descriptor_pool = unsafe { self.vulkan_device.create_descriptor_pool(&pool_info, self.vulkan_allocation_callbacks)? };
+        self.set_resource_name(self.vulkan_descriptor_pool, "Global descriptor pool");
+
+        self.create_pipeline_cache()?;
+
+        let command_pool_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(self.vulkan_queue_family);
+        self.vulkan_upload_command_pool = unsafe { self.vulkan_device.create_command_pool(&command_pool_info, self.vulkan_allocation_callbacks)? };
+        let command_buffer_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(self.vulkan_upload_command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        self.vulkan_upload_command_buffer = unsafe { self.vulkan_device.allocate_command_buffers(&command_buffer_info)?[0] };
+
+        self.init_pools();
+
+        // Dynamic buffer, split in one slice per frame in flight for stream buffers.
+        let limits = &self.vulkan_physical_properties.limits;
+        self.dynamic_alignment = limits.min_uniform_buffer_offset_alignment.max(limits.min_storage_buffer_offset_alignment) as u32;
+        self.dynamic_per_frame_size = K_DYNAMIC_PER_FRAME_SIZE;
+        let mut dynamic_creation = BufferCreation::default();
+        dynamic_creation.set(vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                             resource_usage_type::Enum::Dynamic, self.dynamic_per_frame_size * K_MAX_SWAPCHAIN_IMAGES as u32)
+            .set_name("Dynamic_Persistent_Buffer");
+        self.dynamic_buffer = self.create_buffer(&dynamic_creation)?;
+        self.dynamic_mapped_memory = self.map_buffer(&MapBufferParameters { buffer: self.dynamic_buffer, offset: 0, size: 0 });
+        self.dynamic_allocated_size = self.dynamic_per_frame_size * self.current_frame;
+
+        // Before any sampler or texture, they are written to the bindless set as they get created.
+        if self.bindless_enabled {
+            self.create_bindless_resources()?;
+        }
+
+        let mut sampler_creation = SamplerCreation::new();
+        sampler_creation.set_address_mode_uvw(vk::SamplerAddressMode::REPEAT, vk::SamplerAddressMode::REPEAT, vk::SamplerAddressMode::REPEAT)
+            .set_min_mag_mip(vk::Filter::LINEAR, vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR)
+            .set_name("Sampler Default");
+        self.default_sampler = self.create_sampler(&sampler_creation)?;
+
+        // Bound wherever a descriptor set leaves a slot empty.
+        let mut dummy_data = [0u8; 16];
+        let mut texture_creation = TextureCreation::default();
+        texture_creation.set_size(1, 1, 1)
+            .set_format_type(vk::Format::R8G8B8A8_UNORM, texture_type::Enum::Texture2D)
+            .set_data(dummy_data.as_mut_ptr() as *mut c_void)
+            .set_name("Dummy_Texture");
+        self.dummy_texture = self.create_texture(&texture_creation)?;
+
+        let mut buffer_creation = BufferCreation::default();
+        buffer_creation.set(vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER, resource_usage_type::Enum::Immutable, dummy_data.len() as u32)
+            .set_data(dummy_data.as_mut_ptr() as *mut c_void)
+            .set_name("Dummy_Constant_Buffer");
+        self.dummy_constant_buffer = self.create_buffer(&buffer_creation)?;
+
+        self.create_frame_resources()?;
+        if self.headless {
+            self.create_offscreen_pass()?;
+        } else {
+            self.create_swapchain()?;
+            self.create_swapchain_pass()?;
+        }
+        Ok(())
+    }
+
+    // Fences start signaled so the first wait of every frame slot returns straight away.
+    fn create_frame_resources(&mut self) -> Result<(), DeviceError> {
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        let fence_info = vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
+        for frame in 0..K_MAX_SWAPCHAIN_IMAGES {
+            unsafe {
+                self.vulkan_render_complete_semaphore[frame] = self.vulkan_device.create_semaphore(&semaphore_info, self.vulkan_allocation_callbacks)?;
+                self.vulkan_image_acquired_semaphore[frame] = self.vulkan_device.create_semaphore(&semaphore_info, self.vulkan_allocation_callbacks)?;
+                self.vulkan_command_buffer_executed_fence[frame] = self.vulkan_device.create_fence(&fence_info, self.vulkan_allocation_callbacks)?;
+            }
+        }
+        self.command_buffer_ring.init(self.vulkan_queue_family, self.vulkan_allocation_callbacks)?;
+        Ok(())
+    }
+
+    fn destroy_frame_resources(&mut self) {
+        for frame in 0..K_MAX_SWAPCHAIN_IMAGES {
+            unsafe {
+                if self.vulkan_render_complete_semaphore[frame] != vk::Semaphore::null() {
+                    self.vulkan_device.destroy_semaphore(self.vulkan_render_complete_semaphore[frame], self.vulkan_allocation_callbacks);
+                }
+                if self.vulkan_image_acquired_semaphore[frame] != vk::Semaphore::null() {
+                    self.vulkan_device.destroy_semaphore(self.vulkan_image_acquired_semaphore[frame], self.vulkan_allocation_callbacks);
+                }
+                if self.vulkan_command_buffer_executed_fence[frame] != vk::Fence::null() {
+                    self.vulkan_device.destroy_fence(self.vulkan_command_buffer_executed_fence[frame], self.vulkan_allocation_callbacks);
+                }
+            }
+            self.vulkan_render_complete_semaphore[frame] = vk::Semaphore::null();
+            self.vulkan_image_acquired_semaphore[frame] = vk::Semaphore::null();
+            self.vulkan_command_buffer_executed_fence[frame] = vk::Fence::null();
+        }
+        self.command_buffer_ring.shutdown(self.vulkan_allocation_callbacks);
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        unsafe {
+            if let Err(result) = self.vulkan_device.device_wait_idle() {
+                error!("Error waiting for the device to be idle on shutdown: {}", result);
+            }
+        }
+
+        if self.buffers.is_valid(self.dynamic_buffer) {
+            self.destroy_buffer(self.dynamic_buffer);
+        }
+        if self.samplers.is_valid(self.default_sampler) {
+            self.destroy_sampler(self.default_sampler);
+        }
+        if self.textures.is_valid(self.dummy_texture) {
+            self.destroy_texture(self.dummy_texture);
+        }
+        if self.buffers.is_valid(self.dummy_constant_buffer) {
+            self.destroy_buffer(self.dummy_constant_buffer);
+        }
+        if self.descriptor_set_layouts.is_valid(self.bindless_descriptor_set_layout) {
+            self.destroy_descriptor_set_layout(self.bindless_descriptor_set_layout);
+        }
+        if self.render_passes.is_valid(self.swapchain_pass) {
+            self.destroy_render_pass(self.swapchain_pass);
+        }
+        if self.textures.is_valid(self.depth_texture) {
+            self.destroy_texture(self.depth_texture);
+        }
+        if self.textures.is_valid(self.offscreen_texture) {
+            self.destroy_texture(self.offscreen_texture);
+        }
+        self.descriptor_set_updates.clear();
+        self.dynamic_mapped_memory = std::ptr::null_mut();
+        self.process_all_resource_deletions();
+        for (_, vk_render_pass) in self.render_pass_cache.drain() {
+            unsafe { self.vulkan_device.destroy_render_pass(vk_render_pass, self.vulkan_allocation_callbacks) };
+        }
+
+        self.shutdown_pools();
+        self.destroy_swapchain();
+        self.destroy_frame_resources();
+        self.destroy_pipeline_cache();
+        self.frame_arenas.shutdown();
+        if let Some(mut shader_reload) = self.shader_reload.take() {
+            shader_reload.shutdown();
+        }
+
+        unsafe {
+            if self.vulkan_upload_command_pool != vk::CommandPool::null() {
+                self.vulkan_device.destroy_command_pool(self.vulkan_upload_command_pool, self.vulkan_allocation_callbacks);
+                self.vulkan_upload_command_pool = vk::CommandPool::null();
+            }
+            if self.vulkan_descriptor_pool != vk::DescriptorPool::null() {
+                self.vulkan_device.destroy_descriptor_pool(self.vulkan_descriptor_pool, self.vulkan_allocation_callbacks);
+                self.vulkan_descriptor_pool = vk::DescriptorPool::null();
+            }
+            if self.vulkan_bindless_descriptor_pool != vk::DescriptorPool::null() {
+                self.vulkan_device.destroy_descriptor_pool(self.vulkan_bindless_descriptor_pool, self.vulkan_allocation_callbacks);
+                self.vulkan_bindless_descriptor_pool = vk::DescriptorPool::null();
+                self.vulkan_bindless_descriptor_set = vk::DescriptorSet::null();
+            }
+
+            ManuallyDrop::drop(&mut self.vma_allocator);
+
+            self.vulkan_device.destroy_device(self.vulkan_allocation_callbacks);
+
+            if let Some(surface_loader) = self.vulkan_surface_loader.take() {
+                surface_loader.destroy_surface(self.vulkan_window_surface, self.vulkan_allocation_callbacks);
+                self.vulkan_window_surface = vk::SurfaceKHR::null();
+            }
+            if let Some(debug_utils) = self.vulkan_debug_utils_instance.take() {
+                debug_utils.destroy_debug_utils_messenger(self.vulkan_debug_utils_messenger, self.vulkan_allocation_callbacks);
+                self.vulkan_debug_utils_messenger = vk::DebugUtilsMessengerEXT::null();
+            }
+            self.vulkan_debug_utils_device = None;
+            self.vulkan_instance.destroy_instance(self.vulkan_allocation_callbacks);
+        }
+
+        info!("Gpu Device shutdown");
+        self.check_validation_errors();
+    }
+
+    fn create_pipeline_cache(&mut self) -> Result<(), DeviceError> {
+        let initial_data = self.pipeline_cache_path.as_deref().and_then(|path| load_pipeline_cache_data(path, &self.vulkan_physical_properties));
+        if let Some(data) = &initial_data {
+            let create_info = vk::PipelineCacheCreateInfo::default().initial_data(data);
+            match unsafe { self.vulkan_device.create_pipeline_cache(&create_info, self.vulkan_allocation_callbacks) } {
+                Ok(cache) => {
+                    self.vulkan_pipeline_cache = cache;
+                    info!("Loaded pipeline cache, {} bytes", data.len());
+                    return Ok(());
+                }
+                // A header that checks out does not make the rest of the file valid.
+                Err(result) => info!("Discarding pipeline cache the driver rejected: {}", result),
+            }
+        }
+
+        let create_info = vk::PipelineCacheCreateInfo::default();
+        self.vulkan_pipeline_cache = unsafe { self.vulkan_device.create_pipeline_cache(&create_info, self.vulkan_allocation_callbacks)? };
+        Ok(())
+    }
+
+    // Saves the cache to pipeline_cache_path first, when there is one.
+    fn destroy_pipeline_cache(&mut self) {
+        if self.vulkan_pipeline_cache == vk::PipelineCache::null() {
+            return;
+        }
+
+        if let Some(path) = &self.pipeline_cache_path {
+            match unsafe { self.vulkan_device.get_pipeline_cache_data(self.vulkan_pipeline_cache) } {
+                // Written next to the old file and renamed, so a crash mid write does not leave a truncated cache.
+                Ok(data) => {
+                    let temp_path = path.with_extension("tmp");
+                    match fs::write(&temp_path, &data).and_then(|_| fs::rename(&temp_path, path)) {
+                        Ok(()) => info!("Saved pipeline cache to {}, {} bytes", path.display(), data.len()),
+                        Err(error) => error!("Error saving pipeline cache to {}: {}", path.display(), error),
+                    }
+                }
+                Err(result) => error!("Error reading pipeline cache data: {}", result),
+            }
+        }
+
+        unsafe { self.vulkan_device.destroy_pipeline_cache(self.vulkan_pipeline_cache, self.vulkan_allocation_callbacks) };
+        self.vulkan_pipeline_cache = vk::PipelineCache::null();
+    }
+
+    // Cache hits and misses of every named pipeline created so far, by PipelineCreation name. Empty when the device
+    // cannot report them.
+    pub(crate) fn pipeline_cache_statistics(&self) -> &HashMap<String, PipelineCacheStatistics> {
+        &self.pipeline_cache_statistics
+    }
+
+    pub(crate) fn pipeline_cache_total_statistics(&self) -> PipelineCacheStatistics {
+        self.pipeline_cache_statistics.values().fold(self.unnamed_pipeline_cache_statistics, |total, statistics| PipelineCacheStatistics {
+            hits: total.hits + statistics.hits,
+            misses: total.misses + statistics.misses,
+        })
+    }
+
+    fn record_pipeline_cache_feedback(&mut self, name: Option<&str>, feedback: &vk::PipelineCreationFeedback) {
+        if !feedback.flags.contains(vk::PipelineCreationFeedbackFlags::VALID) {
+            return;
+        }
+        let statistics = match name {
+            Some(name) => self.pipeline_cache_statistics.entry(name.to_string()).or_default(),
+            None => &mut self.unnamed_pipeline_cache_statistics,
+        };
+        if feedback.flags.contains(vk::PipelineCreationFeedbackFlags::APPLICATION_PIPELINE_CACHE_HIT) {
+            statistics.hits += 1;
+        } else {
+            statistics.misses += 1;
+        }
+    }
+
+    // Raises the first validation error reported so far when the device was created with panic_on_validation_error.
+    pub(crate) fn check_validation_errors(&self) {
+        if let Some(message) = self.debug_messenger_state.first_validation_error.lock().unwrap().take() {
+            panic!("Vulkan validation error: {}", message);
+        }
+    }
+
+    // Names show up in validation messages and graphics debuggers. Does nothing without debug utils.
+    pub(crate) fn set_resource_name<H: vk::Handle>(&self, handle: H, name: &str) {
+        let Some(debug_utils) = &self.vulkan_debug_utils_device else {
+            return;
+        };
+        let Ok(name) = CString::new(name) else {
+            return;
+        };
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::default()
+            .object_handle(handle)
+            .object_name(&name);
+        if let Err(result) = unsafe { debug_utils.set_debug_utils_object_name(&name_info) } {
+            error!("Error naming Vulkan object {:?}: {}", name, result);
+        }
+    }
+
+    // Prefers discrete GPUs, then integrated ones, then anything else with a graphics queue,
+    // which is how software rasterizers like lavapipe get picked on machines without a GPU.
+    fn select_physical_device(instance: &ash::Instance, surface_loader: Option<&khr::surface::Instance>, surface: vk::SurfaceKHR) -> Option<(vk::PhysicalDevice, u32)> {
+        let physical_devices = match unsafe { instance.enumerate_physical_devices() } {
+            Ok(physical_devices) => physical_devices,
+            Err(result) => {
+                error!("Error enumerating physical devices: {}", result);
+                return None;
+            }
+        };
+
+        let mut selected = None;
+        let mut selected_score = 0;
+        for physical_device in physical_devices {
+            let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+            let score = match properties.device_type {
+                vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+                vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+                vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+                _ => 1,
+            };
+            if score <= selected_score {
+                continue;
+            }
+
+            if surface_loader.is_some() && !Self::supports_extension(instance, physical_device, khr::swapchain::NAME) {
+                continue;
+            }
+
+            let queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+            // A single queue does graphics, compute and transfers.
+            let queue_family = (0..queue_families.len() as u32).find(|&index| {
+                let family = &queue_families[index as usize];
+                if family.queue_count == 0 || !family.queue_flags.contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE) {
+                    return false;
+                }
+                match surface_loader {
+                    Some(surface_loader) => unsafe {
+                        surface_loader.get_physical_device_surface_support(physical_device, index, surface).unwrap_or(false)
+                    },
+                    None => true,
+                }
+            });
+
+            if let Some(queue_family) = queue_family {
+                selected = Some((physical_device, queue_family));
+                selected_score = score;
+            }
+        }
+        selected
+    }
+
+    fn supports_bindless(instance: &ash::Instance, physical_device: vk::PhysicalDevice, api_version: u32) -> bool {
+        if api_version < vk::API_VERSION_1_1 {
+            return false;
+        }
+        if api_version < vk::API_VERSION_1_2 && !Self::supports_extension(instance, physical_device, ext::descriptor_indexing::NAME) {
+            return false;
+        }
+
+        let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let mut features = vk::PhysicalDeviceFeatures2::default().push_next(&mut indexing_features);
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+        let features_supported = indexing_features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+            && indexing_features.descriptor_binding_sampled_image_update_after_bind == vk::TRUE
+            && indexing_features.descriptor_binding_partially_bound == vk::TRUE
+            && indexing_features.descriptor_binding_update_unused_while_pending == vk::TRUE
+            && indexing_features.runtime_descriptor_array == vk::TRUE;
+
+        let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut properties = vk::PhysicalDeviceProperties2::default().push_next(&mut indexing_properties);
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut properties) };
+        let limits_supported = indexing_properties.max_descriptor_set_update_after_bind_sampled_images >= K_MAX_BINDLESS_RESOURCES
+            && indexing_properties.max_per_stage_descriptor_update_after_bind_sampled_images >= K_MAX_BINDLESS_RESOURCES
+            && indexing_properties.max_descriptor_set_update_after_bind_samplers >= K_MAX_BINDLESS_SAMPLERS
+            && indexing_properties.max_per_stage_descriptor_update_after_bind_samplers >= K_MAX_BINDLESS_SAMPLERS;
+
+        features_supported && limits_supported
+    }
+
+    fn supports_extension(instance: &ash::Instance, physical_device: vk::PhysicalDevice, name: &CStr) -> bool {
+        let extensions = unsafe { instance.enumerate_device_extension_properties(physical_device) }.unwrap_or_default();
+        extensions.iter().any(|extension| extension.extension_name_as_c_str() == Ok(name))
+    }
+
+    fn create_swapchain(&mut self) -> Result<(), DeviceError> {
+        let (Some(surface_loader), Some(swapchain_loader)) = (&self.vulkan_surface_loader, &self.vulkan_swapchain_loader) else {
+            return Ok(());
+        };
+
+        let surface_formats = unsafe { surface_loader.get_physical_device_surface_formats(self.vulkan_physical_device, self.vulkan_window_surface)? };
+        let surface_image_formats = [vk::Format::B8G8R8A8_UNORM, vk::Format::R8G8B8A8_UNORM, vk::Format::B8G8R8_UNORM, vk::Format::R8G8B8_UNORM];
+        self.vulkan_surface_format = surface_image_formats.iter()
+            .find_map(|format| surface_formats.iter().find(|surface_format| surface_format.format == *format && surface_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR))
+            .copied()
+            .unwrap_or(surface_formats[0]);
+
+        let present_modes = unsafe { surface_loader.get_physical_device_surface_present_modes(self.vulkan_physical_device, self.vulkan_window_surface)? };
+        let requested_mode = to_vk_present_mode(self.present_mode);
+        self.vulkan_present_mode = if present_modes.contains(&requested_mode) {
+            requested_mode
+        } else {
+            warn!("Present mode {:?} not supported, falling back to FIFO", requested_mode);
+            // FIFO is the only mode every driver has to support.
+            self.present_mode = present_mode::Enum::VSync;
+            vk::PresentModeKHR::FIFO
+        };
+
+        let surface_capabilities = unsafe { surface_loader.get_physical_device_surface_capabilities(self.vulkan_physical_device, self.vulkan_window_surface)? };
+        let swapchain_extent = if surface_capabilities.current_extent.width != u32::MAX {
+            surface_capabilities.current_extent
+        } else {
+            vk::Extent2D {
+                width: (self.swapchain_width as u32).clamp(surface_capabilities.min_image_extent.width, surface_capabilities.max_image_extent.width),
+                height: (self.swapchain_height as u32).clamp(surface_capabilities.min_image_extent.height, surface_capabilities.max_image_extent.height),
+            }
+        };
+        self.swapchain_width = swapchain_extent.width as u16;
+        self.swapchain_height = swapchain_extent.height as u16;
+
+        let mut image_count = surface_capabilities.min_image_count + 1;
+        if surface_capabilities.max_image_count > 0 {
+            image_count = image_count.min(surface_capabilities.max_image_count);
+        }
+        image_count = image_count.min(K_MAX_SWAPCHAIN_IMAGES as u32);
+
+        let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
+            .surface(self.vulkan_window_surface)
+            .min_image_count(image_count)
+            .image_format(self.vulkan_surface_format.format)
+            .image_color_space(self.vulkan_surface_format.color_space)
+            .image_extent(swapchain_extent)
+            .image_array_layers(1)
+            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST)
+            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .pre_transform(surface_capabilities.current_transform)
+            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+            .present_mode(self.vulkan_present_mode)
+            .clipped(true);
+        self.vulkan_swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, self.vulkan_allocation_callbacks)? };
+
+        let swapchain_images = unsafe { swapchain_loader.get_swapchain_images(self.vulkan_swapchain)? };
+        if swapchain_images.len() > K_MAX_SWAPCHAIN_IMAGES {
+            error!("Swapchain created {} images, only {} are supported", swapchain_images.len(), K_MAX_SWAPCHAIN_IMAGES);
+        }
+        self.vulkan_swapchain_image_count = swapchain_images.len().min(K_MAX_SWAPCHAIN_IMAGES) as u32;
+
+        for (index, image) in swapchain_images.iter().take(K_MAX_SWAPCHAIN_IMAGES).enumerate() {
+            self.vulkan_swapchain_images[index] = *image;
+
+            let view_info = vk::ImageViewCreateInfo::default()
+                .image(*image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(self.vulkan_surface_format.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+            self.vulkan_swapchain_image_views[index] = unsafe { self.vulkan_device.create_image_view(&view_info, self.vulkan_allocation_callbacks)? };
+            self.set_resource_name(*image, &format!("Swapchain image {}", index));
+        }
+
+        info!("Swapchain created {}x{}, {} images", self.swapchain_width, self.swapchain_height, self.vulkan_swapchain_image_count);
+        Ok(())
+    }
+
+    fn destroy_swapchain(&mut self) {
+        for framebuffer in self.vulkan_swapchain_framebuffers.iter_mut() {
+            if *framebuffer != vk::Framebuffer::null() {
+                unsafe { self.vulkan_device.destroy_framebuffer(*framebuffer, self.vulkan_allocation_callbacks) };
+                *framebuffer = vk::Framebuffer::null();
+            }
+        }
+        for image_view in self.vulkan_swapchain_image_views.iter_mut() {
+            if *image_view != vk::ImageView::null() {
+                unsafe { self.vulkan_device.destroy_image_view(*image_view, self.vulkan_allocation_callbacks) };
+                *image_view = vk::ImageView::null();
+            }
+        }
+        self.vulkan_swapchain_images = [vk::Image::null(); K_MAX_SWAPCHAIN_IMAGES];
+        self.vulkan_swapchain_images_presented = [false; K_MAX_SWAPCHAIN_IMAGES];
+
+        if let Some(swapchain_loader) = &self.vulkan_swapchain_loader {
+            if self.vulkan_swapchain != vk::SwapchainKHR::null() {
+                unsafe { swapchain_loader.destroy_swapchain(self.vulkan_swapchain, self.vulkan_allocation_callbacks) };
+            }
+        }
+        self.vulkan_swapchain = vk::SwapchainKHR::null();
+        self.vulkan_swapchain_image_count = 0;
+    }
+
+    // Records commands and runs them straight away, waiting for the queue to finish. Meant for loading, not for frames.
+    fn submit_immediate<F: FnOnce(&ash::Device, vk::CommandBuffer)>(&self, record: F) -> Result<(), DeviceError> {
+        let command_buffer = self.vulkan_upload_command_buffer;
+        unsafe {
+            self.vulkan_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+            let begin_info = vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            self.vulkan_device.begin_command_buffer(command_buffer, &begin_info)?;
+            record(&self.vulkan_device, command_buffer);
+            self.vulkan_device.end_command_buffer(command_buffer)?;
+
+            let command_buffers = [command_buffer];
+            let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffers);
+            self.vulkan_device.queue_submit(self.vulkan_queue, &[submit_info], vk::Fence::null())?;
+            self.vulkan_device.queue_wait_idle(self.vulkan_queue)?;
+        }
+        Ok(())
+    }
+
+    // Host visible buffer for copies to the GPU, the caller destroys it with vma_allocator.destroy_buffer.
+    fn create_staging_buffer(&self, data: *const u8, size: usize) -> Result<(vk::Buffer, vk_mem::Allocation), DeviceError> {
+        let buffer_info = vk::BufferCreateInfo::default()
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .size(size as vk::DeviceSize)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let allocation_info = vk_mem::AllocationCreateInfo {
+            flags: vk_mem::AllocationCreateFlags::HOST_ACCESS_SEQUENTIAL_WRITE | vk_mem::AllocationCreateFlags::MAPPED,
+            usage: vk_mem::MemoryUsage::Auto,
+            ..Default::default()
+        };
+        let (staging_buffer, staging_allocation) = unsafe { self.vma_allocator.create_buffer(&buffer_info, &allocation_info)? };
+        let mapped_data = self.vma_allocator.get_allocation_info(&staging_allocation).mapped_data as *mut u8;
+        unsafe { mapped_data.copy_from_nonoverlapping(data, size) };
+        if let Err(result) = self.vma_allocator.flush_allocation(&staging_allocation, 0, vk::WHOLE_SIZE) {
+            error!("Error flushing staging buffer: {}", result);
+        }
+        Ok((staging_buffer, staging_allocation))
+    }
+
+    fn upload_buffer_data(&self, vk_buffer: vk::Buffer, data: *const u8, size: usize) -> Result<(), DeviceError> {
+        let (staging_buffer, mut staging_allocation) = self.create_staging_buffer(data, size)?;
+
+        let result = self.submit_immediate(|device, command_buffer| unsafe {
+            let region = vk::BufferCopy { src_offset: 0, dst_offset: 0, size: size as vk::DeviceSize };
+            device.cmd_copy_buffer(command_buffer, staging_buffer, vk_buffer, &[region]);
+
+            // Make the copy visible to whatever reads the buffer next.
+            let barrier = vk::BufferMemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(vk_buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE);
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::ALL_COMMANDS,
+                                        vk::DependencyFlags::empty(), &[], &[barrier], &[]);
+        });
+
+        unsafe { self.vma_allocator.destroy_buffer(staging_buffer, &mut staging_allocation) };
+        result
+    }
+
+    // Immutable buffers live in device memory and get initial_data through a staging buffer,
+    // Dynamic buffers are persistently mapped, Stream buffers are sub-allocated from the dynamic buffer every time they are mapped.
+    pub(crate) fn create_buffer(&mut self, creation: &BufferCreation) -> Result<BufferHandle, DeviceError> {
+        let Some(handle) = self.buffers.obtain_resource(Buffer::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::Buffer));
+        };
+
+        let mut buffer = Buffer {
+            type_flags: creation.type_flags,
+            usage: creation.usage,
+            size: creation.size,
+            global_offset: 0,
+            handle,
+            parent_buffer: K_INVALID_BUFFER,
+            name: creation.name.clone(),
+            ..Default::default()
+        };
+
+        if creation.usage == resource_usage_type::Enum::Stream {
+            if creation.initial_data.is_some() {
+                warn!("Stream buffer {:?} ignores initial data, write it through map_buffer every frame", creation.name);
+            }
+            buffer.parent_buffer = self.dynamic_buffer;
+        } else if let Err(error) = self.create_vk_buffer(&mut buffer, creation.initial_data.map(|data| data as *const u8)) {
+            self.buffers.release_resource(handle);
+            return Err(error);
+        }
+
+        *self.buffers.access_resource_mut(handle).unwrap() = buffer;
+        Ok(handle)
+    }
+
+    fn create_vk_buffer(&self, buffer: &mut Buffer, initial_data: Option<*const u8>) -> Result<(), DeviceError> {
+        let size = buffer.size.max(1) as usize;
+        let buffer_info = vk::BufferCreateInfo::default()
+            .usage(vk::BufferUsageFlags::TRANSFER_DST | buffer.type_flags)
+            .size(size as vk::DeviceSize)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let allocation_info = if buffer.usage == resource_usage_type::Enum::Dynamic {
+            vk_mem::AllocationCreateInfo {
+                flags: vk_mem::AllocationCreateFlags::HOST_ACCESS_SEQUENTIAL_WRITE | vk_mem::AllocationCreateFlags::MAPPED,
+                usage: vk_mem::MemoryUsage::Auto,
+                ..Default::default()
+            }
+        } else {
+            vk_mem::AllocationCreateInfo {
+                usage: vk_mem::MemoryUsage::AutoPreferDevice,
+                ..Default::default()
+            }
+        };
+
+        let (vk_buffer, allocation) = unsafe { self.vma_allocator.create_buffer(&buffer_info, &allocation_info)? };
+        let vma_allocation_info = self.vma_allocator.get_allocation_info(&allocation);
+        buffer.vk_buffer = vk_buffer;
+        buffer.vk_device_memory = vma_allocation_info.device_memory;
+        buffer.vk_device_size = vma_allocation_info.size;
+        buffer.mapped_data = vma_allocation_info.mapped_data as *mut u8;
+        buffer.vma_allocation = Some(allocation);
+        if let Some(name) = &buffer.name {
+            self.set_resource_name(vk_buffer, name);
+        }
+
+        let Some(data) = initial_data else {
+            return Ok(());
+        };
+        let result = if buffer.usage == resource_usage_type::Enum::Dynamic {
+            unsafe { buffer.mapped_data.copy_from_nonoverlapping(data, buffer.size as usize) };
+            self.vma_allocator.flush_allocation(buffer.vma_allocation.as_ref().unwrap(), 0, vk::WHOLE_SIZE).map_err(DeviceError::from)
+        } else {
+            self.upload_buffer_data(vk_buffer, data, buffer.size as usize)
+        };
+        match result {
+            // Uploads end with a barrier to any read.
+            Ok(()) => buffer.state = ResourceState::RESOURCE_STATE_GENERIC_READ,
+            Err(_) => self.destroy_vk_buffer(buffer),
+        }
+        result
+    }
+
+    fn destroy_vk_buffer(&self, buffer: &mut Buffer) {
+        if let Some(mut allocation) = buffer.vma_allocation.take() {
+            unsafe { self.vma_allocator.destroy_buffer(buffer.vk_buffer, &mut allocation) };
+        }
+        buffer.vk_buffer = vk::Buffer::null();
+        buffer.mapped_data = std::ptr::null_mut();
+    }
+
+    // Stream buffers get a new slice of the current frame's part of the dynamic buffer, valid until the frame comes around again.
+    // Returns null for Immutable buffers, those are only written through initial_data.
+    pub(crate) fn map_buffer(&mut self, parameters: &MapBufferParameters) -> *mut u8 {
+        let Some(buffer) = self.buffers.access_resource(parameters.buffer) else {
+            error!("Trying to map invalid buffer {:?}", parameters.buffer);
+            return std::ptr::null_mut();
+        };
+        let size = if parameters.size == 0 { buffer.size } else { parameters.size };
+
+        match buffer.usage {
+            resource_usage_type::Enum::Stream => {
+                let Some(offset) = self.dynamic_allocate(size) else {
+                    return std::ptr::null_mut();
+                };
+                self.buffers.access_resource_mut(parameters.buffer).unwrap().global_offset = offset;
+                unsafe { self.dynamic_mapped_memory.add(offset as usize) }
+            }
+            resource_usage_type::Enum::Dynamic => {
+                if dynamic_map_size(buffer.size, parameters.offset, parameters.size).is_none() {
+                    error!("Mapping {} bytes at offset {} of buffer {:?}, only {} bytes big", parameters.size, parameters.offset, buffer.name, buffer.size);
+                    return std::ptr::null_mut();
+                }
+                unsafe { buffer.mapped_data.add(parameters.offset as usize) }
+            }
+            _ => {
+                error!("Immutable buffer {:?} cannot be mapped", buffer.name);
+                std::ptr::null_mut()
+            }
+        }
+    }
+
+    // Mappings are persistent, this only flushes the written range for memory that is not host coherent.
+    pub(crate) fn unmap_buffer(&mut self, parameters: &MapBufferParameters) {
+        let Some(buffer) = self.buffers.access_resource(parameters.buffer) else {
+            error!("Trying to unmap invalid buffer {:?}", parameters.buffer);
+            return;
+        };
+        let size = if parameters.size == 0 { buffer.size } else { parameters.size };
+
+        let (allocation_buffer, offset, size) = match buffer.usage {
+            resource_usage_type::Enum::Stream => (self.dynamic_buffer, buffer.global_offset, size),
+            resource_usage_type::Enum::Dynamic => match dynamic_map_size(buffer.size, parameters.offset, parameters.size) {
+                Some(size) => (parameters.buffer, parameters.offset, size),
+                None => return,
+            },
+            _ => return,
+        };
+        let Some(allocation) = self.buffers.access_resource(allocation_buffer).and_then(|buffer| buffer.vma_allocation.as_ref()) else {
+            return;
+        };
+        if let Err(result) = self.vma_allocator.flush_allocation(allocation, offset as vk::DeviceSize, size as vk::DeviceSize) {
+            error!("Error flushing buffer {:?}: {}", parameters.buffer, result);
+        }
+    }
+
+    // Offset into the dynamic buffer, inside the slice of the current frame.
+    fn dynamic_allocate(&mut self, size: u32) -> Option<u32> {
+        let frame_end = self.dynamic_per_frame_size * (self.current_frame + 1);
+        if self.dynamic_allocated_size.checked_add(size).is_none_or(|end| end > frame_end) {
+            error!("Dynamic buffer out of memory, {} bytes requested with {} left this frame", size, frame_end - self.dynamic_allocated_size);
+            return None;
+        }
+        let offset = self.dynamic_allocated_size;
+        // Alignment padding may run past the frame, which only makes the next request fail.
+        let aligned_size = u32::try_from(memory_align(size as usize, self.dynamic_alignment as usize)).unwrap_or(u32::MAX);
+        self.dynamic_allocated_size = offset.saturating_add(aligned_size).min(frame_end);
+        Some(offset)
+    }
+
+    // Actual destruction is deferred until the GPU can no longer be using the buffer.
+    pub(crate) fn destroy_buffer(&mut self, buffer: BufferHandle) {
+        if !self.buffers.is_valid(buffer) {
+            error!("Trying to free invalid buffer {:?}", buffer);
+            return;
+        }
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::Buffer,
+            handle: buffer.index(),
+            generation: buffer.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    fn destroy_buffer_instant(&mut self, buffer: BufferHandle) {
+        if let Some(mut buffer) = self.buffers.release_resource(buffer) {
+            self.destroy_vk_buffer(&mut buffer);
+        }
+    }
+
+    fn create_bindless_resources(&mut self) -> Result<(), DeviceError> {
+        let pool_sizes = [
+            vk::DescriptorPoolSize { ty: vk::DescriptorType::SAMPLED_IMAGE, descriptor_count: K_MAX_BINDLESS_RESOURCES },
+            vk::DescriptorPoolSize { ty: vk::DescriptorType::SAMPLER, descriptor_count: K_MAX_BINDLESS_SAMPLERS },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        self.vulkan_bindless_descriptor_pool = unsafe { self.vulkan_device.create_descriptor_pool(&pool_info, self.vulkan_allocation_callbacks)? };
+        self.set_resource_name(self.vulkan_bindless_descriptor_pool, "Bindless descriptor pool");
+
+        let Some(handle) = self.descriptor_set_layouts.obtain_resource(DescriptorSetLayout::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::DescriptorSetLayout));
+        };
+        let bindings = vec![
+            DescriptorBinding {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                start: K_BINDLESS_TEXTURE_BINDING as u16,
+                count: K_MAX_BINDLESS_RESOURCES as u16,
+                set: K_BINDLESS_SET_INDEX as u16,
+                name: Some("textures".to_string()),
+            },
+            DescriptorBinding {
+                ty: vk::DescriptorType::SAMPLER,
+                start: K_BINDLESS_SAMPLER_BINDING as u16,
+                count: K_MAX_BINDLESS_SAMPLERS as u16,
+                set: K_BINDLESS_SET_INDEX as u16,
+                name: Some("samplers".to_string()),
+            },
+        ];
+        let vk_bindings: Vec<_> = bindings.iter().map(|binding| vk::DescriptorSetLayoutBinding::default()
+            .binding(binding.start as u32)
+            .descriptor_type(binding.ty)
+            .descriptor_count(binding.count as u32)
+            .stage_flags(vk::ShaderStageFlags::ALL)).collect();
+        // Textures and samplers can come and go while the set is bound, and shaders only touch the elements they index.
+        let binding_flags = [vk::DescriptorBindingFlags::PARTIALLY_BOUND | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+            | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING; 2];
+        let mut binding_flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::default().binding_flags(&binding_flags);
+        let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .bindings(&vk_bindings)
+            .push_next(&mut binding_flags_info);
+        let vk_descriptor_set_layout = match unsafe { self.vulkan_device.create_descriptor_set_layout(&create_info, self.vulkan_allocation_callbacks) } {
+            Ok(layout) => layout,
+            Err(result) => {
+                self.descriptor_set_layouts.release_resource(handle);
+                return Err(result.into());
+            }
+        };
+        self.set_resource_name(vk_descriptor_set_layout, "Bindless descriptor set layout");
+        *self.descriptor_set_layouts.access_resource_mut(handle).unwrap() = DescriptorSetLayout {
+            vk_descriptor_set_layout,
+            vk_binding: Some(vk_bindings),
+            num_bindings: bindings.len() as u16,
+            bindings: Some(bindings),
+            set_index: K_BINDLESS_SET_INDEX as u16,
+            handle,
+        };
+        self.bindless_descriptor_set_layout = handle;
+
+        let layouts = [vk_descriptor_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.vulkan_bindless_descriptor_pool)
+            .set_layouts(&layouts);
+        self.vulkan_bindless_descriptor_set = unsafe { self.vulkan_device.allocate_descriptor_sets(&allocate_info)?[0] };
+        self.set_resource_name(self.vulkan_bindless_descriptor_set, "Bindless descriptor set");
+        Ok(())
+    }
+
+    // Points element texture.index() of the bindless texture array at the texture, in the layout of its sampled state.
+    // Textures whose contents are still undefined, and gone ones, get the dummy texture instead. Command buffers
+    // call it again when a texture leaves the undefined state. Update after bind, so this is fine while the set is
+    // bound in recorded command buffers.
+    pub(crate) fn write_bindless_texture(&self, element: u32, texture: TextureHandle) {
+        if !self.bindless_enabled {
+            return;
+        }
+        let texture = match self.textures.access_resource(texture) {
+            Some(texture) if texture.state != ResourceState::RESOURCE_STATE_UNDEFINED => texture,
+            _ => match self.textures.access_resource(self.dummy_texture) {
+                Some(dummy_texture) => dummy_texture,
+                None => return,
+            },
+        };
+        let image_layout = util_to_vk_image_layout(util_sampled_state(texture.vk_format));
+        let image_info = [vk::DescriptorImageInfo { sampler: vk::Sampler::null(), image_view: texture.vk_image_view, image_layout }];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(self.vulkan_bindless_descriptor_set)
+            .dst_binding(K_BINDLESS_TEXTURE_BINDING)
+            .dst_array_element(element)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&image_info);
+        unsafe { self.vulkan_device.update_descriptor_sets(&[write], &[]) };
+    }
+
+    // Points element sampler.index() of the bindless sampler array at the sampler, or at the default sampler when
+    // the sampler is gone.
+    fn write_bindless_sampler(&self, element: u32, sampler: SamplerHandle) {
+        if !self.bindless_enabled {
+            return;
+        }
+        let Some(sampler) = self.samplers.access_resource(sampler).or_else(|| self.samplers.access_resource(self.default_sampler)) else {
+            return;
+        };
+        let image_info = [vk::DescriptorImageInfo { sampler: sampler.vk_sampler, image_view: vk::ImageView::null(), image_layout: vk::ImageLayout::UNDEFINED }];
+        let write = vk::WriteDescriptorSet::default()
+            .dst_set(self.vulkan_bindless_descriptor_set)
+            .dst_binding(K_BINDLESS_SAMPLER_BINDING)
+            .dst_array_element(element)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .image_info(&image_info);
+        unsafe { self.vulkan_device.update_descriptor_sets(&[write], &[]) };
+    }
+
+    #[inline]
+    pub(crate) fn is_bindless_enabled(&self) -> bool {
+        self.bindless_enabled
+    }
+
+    // Bound at K_BINDLESS_SET_INDEX. None without bindless.
+    pub(crate) fn bindless_descriptor_set(&self) -> Option<vk::DescriptorSet> {
+        self.bindless_enabled.then_some(self.vulkan_bindless_descriptor_set)
+    }
+
+    // For pipelines that list their own layouts. Pipelines with generated layouts use it for K_BINDLESS_SET_INDEX on their own.
+    pub(crate) fn bindless_descriptor_set_layout(&self) -> Option<DescriptorSetLayoutHandle> {
+        self.bindless_enabled.then_some(self.bindless_descriptor_set_layout)
+    }
+
+    pub(crate) fn create_texture(&mut self, creation: &TextureCreation) -> Result<TextureHandle, DeviceError> {
+        let Some(handle) = self.textures.obtain_resource(Texture::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::Texture));
+        };
+
+        let mut texture = Texture {
+            vk_format: creation.format,
+            width: creation.width,
+            height: creation.height,
+            depth: creation.depth,
+            array_layer_count: creation.array_layer_count,
+            mipmaps: creation.mipmaps,
+            flags: creation.flags,
+            handle,
+            ty: creation.texture_type,
+            name: creation.name.clone(),
+            ..Default::default()
+        };
+
+        if let Err(error) = self.create_vk_texture(&mut texture, creation.initial_data.map(|data| data as *const u8)) {
+            self.textures.release_resource(handle);
+            return Err(error);
+        }
+
+        *self.textures.access_resource_mut(handle).unwrap() = texture;
+        self.write_bindless_texture(handle.index(), handle);
+        Ok(handle)
+    }
+
+    fn create_vk_texture(&self, texture: &mut Texture, initial_data: Option<*const u8>) -> Result<(), DeviceError> {
+        let is_cube = texture.ty == texture_type::Enum::TextureCubeArray;
+        if texture.array_layer_count == 0 {
+            return Err(DeviceError::InvalidCreation("textures need at least one layer"));
+        }
+        if is_cube && !texture.array_layer_count.is_multiple_of(6) {
+            return Err(DeviceError::InvalidCreation("cube textures need 6 layers per cube"));
+        }
+        if texture.ty == texture_type::Enum::Texture3D && texture.array_layer_count != 1 {
+            return Err(DeviceError::InvalidCreation("3D textures cannot have layers"));
+        }
+        // Their views are not arrays, layered textures use the array types.
+        if matches!(texture.ty, texture_type::Enum::Texture1D | texture_type::Enum::Texture2D) && texture.array_layer_count != 1 {
+            return Err(DeviceError::InvalidCreation("1D and 2D textures cannot have layers, use the array types"));
+        }
+        if is_cube && texture.array_layer_count > 6 && self.vulkan_enabled_features.image_cube_array != vk::TRUE {
+            return Err(DeviceError::InvalidCreation("cube arrays are not supported by this device"));
+        }
+        // Depth and stencil images are only ever written as attachments, the upload path copies color aspects.
+        if initial_data.is_some() && texture_format::has_depth_or_stencil(texture.vk_format) {
+            return Err(DeviceError::InvalidCreation("depth and stencil textures cannot have initial data"));
+        }
+
+        // Mips stop at 1x1.
+        let max_mipmaps = 32 - (texture.width.max(texture.height).max(texture.depth).max(1) as u32).leading_zeros();
+        texture.mipmaps = texture.mipmaps.clamp(1, max_mipmaps as u8);
+
+        let is_render_target = texture.flags & texture_flags::Mask::RenderTargetMask as u8 != 0;
+        let is_compute_used = texture.flags & texture_flags::Mask::ComputeMask as u8 != 0;
+
+        let mut usage = vk::ImageUsageFlags::SAMPLED;
+        if is_compute_used {
+            usage |= vk::ImageUsageFlags::STORAGE;
+        }
+        if texture_format::has_depth_or_stencil(texture.vk_format) {
+            usage |= vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
+        } else {
+            usage |= vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC;
+            if is_render_target {
+                usage |= vk::ImageUsageFlags::COLOR_ATTACHMENT;
+            }
+        }
+
+        let image_info = vk::ImageCreateInfo::default()
+            .flags(if is_cube { vk::ImageCreateFlags::CUBE_COMPATIBLE } else { vk::ImageCreateFlags::empty() })
+            .image_type(to_vk_image_type(texture.ty))
+            .format(texture.vk_format)
+            .extent(vk::Extent3D { width: texture.width as u32, height: texture.height as u32, depth: texture.depth as u32 })
+            .mip_levels(texture.mipmaps as u32)
+            .array_layers(texture.array_layer_count as u32)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let allocation_info = vk_mem::AllocationCreateInfo {
+            usage: vk_mem::MemoryUsage::AutoPreferDevice,
+            ..Default::default()
+        };
+        let (vk_image, allocation) = unsafe { self.vma_allocator.create_image(&image_info, &allocation_info)? };
+        texture.vk_image = vk_image;
+        texture.vma_allocation = Some(allocation);
+        texture.vk_image_layout = vk::ImageLayout::UNDEFINED;
+        texture.state = ResourceState::RESOURCE_STATE_UNDEFINED;
+
+        // A single cube gets a cube view, more of them a cube array view.
+        let view_type = if is_cube && texture.array_layer_count == 6 { vk::ImageViewType::CUBE } else { to_vk_image_view_type(texture.ty) };
+        let view_info = vk::ImageViewCreateInfo::default()
+            .image(vk_image)
+            .view_type(view_type)
+            .format(texture.vk_format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: texture_aspect_flags(texture.vk_format),
+                base_mip_level: 0,
+                level_count: texture.mipmaps as u32,
+                base_array_layer: 0,
+                layer_count: texture.array_layer_count as u32,
+            });
+        texture.vk_image_view = match unsafe { self.vulkan_device.create_image_view(&view_info, self.vulkan_allocation_callbacks) } {
+            Ok(image_view) => image_view,
+            Err(result) => {
+                self.destroy_vk_texture(texture);
+                return Err(result.into());
+            }
+        };
+
+        if let Some(name) = &texture.name {
+            self.set_resource_name(texture.vk_image, name);
+            self.set_resource_name(texture.vk_image_view, name);
+        }
+
+        if let Some(data) = initial_data {
+            if let Err(error) = self.upload_texture_data(texture, data) {
+                self.destroy_vk_texture(texture);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    // Copies mip 0 of every layer, generates the other mips with blits and leaves the texture ready to be sampled.
+    fn upload_texture_data(&self, texture: &mut Texture, data: *const u8) -> Result<(), DeviceError> {
+        let bytes_per_pixel = texture_format::bytes_per_pixel(texture.vk_format);
+        if bytes_per_pixel == 0 {
+            return Err(DeviceError::InvalidCreation("texture format does not support initial data"));
+        }
+        let layer_size = texture.width as usize * texture.height as usize * texture.depth as usize * bytes_per_pixel as usize;
+        let (staging_buffer, mut staging_allocation) = self.create_staging_buffer(data, layer_size * texture.array_layer_count as usize)?;
+
+        let format_properties = unsafe { self.vulkan_instance.get_physical_device_format_properties(self.vulkan_physical_device, texture.vk_format) };
+        let blit_features = vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST;
+        let generate_mipmaps = texture.mipmaps > 1 && format_properties.optimal_tiling_features.contains(blit_features);
+        if texture.mipmaps > 1 && !generate_mipmaps {
+            warn!("Format {:?} of texture {:?} cannot be blitted, mips are left empty", texture.vk_format, texture.name);
+        }
+        let filter = if format_properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR) { vk::Filter::LINEAR } else { vk::Filter::NEAREST };
+
+        let image = texture.vk_image;
+        let layers = texture.array_layer_count as u32;
+        let mipmaps = texture.mipmaps as u32;
+        let mip_extent = |mip: u32| vk::Offset3D {
+            x: (texture.width as i32 >> mip).max(1),
+            y: (texture.height as i32 >> mip).max(1),
+            z: (texture.depth as i32 >> mip).max(1),
+        };
+        let layers_at = |mip: u32| vk::ImageSubresourceLayers { aspect_mask: vk::ImageAspectFlags::COLOR, mip_level: mip, base_array_layer: 0, layer_count: layers };
+
+        let result = self.submit_immediate(|device, command_buffer| unsafe {
+            let to_transfer = color_image_barrier(image, 0..mipmaps, layers, vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                  vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE);
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER,
+                                        vk::DependencyFlags::empty(), &[], &[], &[to_transfer]);
+
+            let extent = mip_extent(0);
+            let region = vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: layers_at(0),
+                image_offset: vk::Offset3D::default(),
+                image_extent: vk::Extent3D { width: extent.x as u32, height: extent.y as u32, depth: extent.z as u32 },
+            };
+            device.cmd_copy_buffer_to_image(command_buffer, staging_buffer, image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[region]);
+
+            // Each mip is blitted from the previous one, which becomes a transfer source first.
+            let blitted_mipmaps = if generate_mipmaps { mipmaps } else { 1 };
+            for mip in 1..blitted_mipmaps {
+                let to_source = color_image_barrier(image, mip - 1..mip, layers, vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                                    vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::TRANSFER_READ);
+                device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::TRANSFER,
+                                            vk::DependencyFlags::empty(), &[], &[], &[to_source]);
+
+                let blit = vk::ImageBlit {
+                    src_subresource: layers_at(mip - 1),
+                    src_offsets: [vk::Offset3D::default(), mip_extent(mip - 1)],
+                    dst_subresource: layers_at(mip),
+                    dst_offsets: [vk::Offset3D::default(), mip_extent(mip)],
+                };
+                device.cmd_blit_image(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[blit], filter);
+            }
+
+            // Blitted mips are transfer sources, the last one and any left empty are still destinations.
+            let mut to_shader_read = vec![color_image_barrier(image, blitted_mipmaps - 1..mipmaps, layers, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                              vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::SHADER_READ)];
+            if blitted_mipmaps > 1 {
+                to_shader_read.push(color_image_barrier(image, 0..blitted_mipmaps - 1, layers, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::TRANSFER_READ, vk::AccessFlags::SHADER_READ));
+            }
+            device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                                        vk::DependencyFlags::empty(), &[], &[], &to_shader_read);
+        });
+
+        unsafe { self.vma_allocator.destroy_buffer(staging_buffer, &mut staging_allocation) };
+        result?;
+        texture.vk_image_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        texture.state = ResourceState::RESOURCE_STATE_SHADER_RESOURCE;
+        Ok(())
+    }
+
+    fn destroy_vk_texture(&self, texture: &mut Texture) {
+        unsafe {
+            if texture.vk_image_view != vk::ImageView::null() {
+                self.vulkan_device.destroy_image_view(texture.vk_image_view, self.vulkan_allocation_callbacks);
+            }
+            if let Some(mut allocation) = texture.vma_allocation.take() {
+                self.vma_allocator.destroy_image(texture.vk_image, &mut allocation);
+            }
+        }
+        texture.vk_image_view = vk::ImageView::null();
+        texture.vk_image = vk::Image::null();
+    }
+
+    // Deferred like destroy_buffer.
+    pub(crate) fn destroy_texture(&mut self, texture: TextureHandle) {
+        if !self.textures.is_valid(texture) {
+            error!("Trying to free invalid texture {:?}", texture);
+            return;
+        }
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::Texture,
+            handle: texture.index(),
+            generation: texture.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    fn destroy_texture_instant(&mut self, texture: TextureHandle) {
+        if self.textures.is_valid(texture) && texture != self.dummy_texture {
+            self.write_bindless_texture(texture.index(), self.dummy_texture);
+        }
+        if let Some(mut texture) = self.textures.release_resource(texture) {
+            self.destroy_vk_texture(&mut texture);
+        }
+    }
+
+    // Returns the existing sampler when one with the same parameters is alive, every call needs its own destroy_sampler.
+    // A shared sampler keeps the name it was first created with.
+    pub(crate) fn create_sampler(&mut self, creation: &SamplerCreation) -> Result<SamplerHandle, DeviceError> {
+        let key = creation.key();
+        if let Some(entry) = self.sampler_cache.get_mut(&key) {
+            entry.references += 1;
+            let existing_name = self.samplers.access_resource(entry.handle).and_then(|sampler| sampler.name.as_deref());
+            if let Some(name) = creation.name.filter(|name| Some(*name) != existing_name) {
+                debug!("Sampler {:?} shares sampler {:?}, which keeps its name", name, existing_name.unwrap_or("unnamed"));
+            }
+            return Ok(entry.handle);
+        }
+
+        let anisotropy_enable = creation.max_anisotropy > 1.0 && self.vulkan_enabled_features.sampler_anisotropy == vk::TRUE;
+        let max_anisotropy = creation.max_anisotropy.min(self.vulkan_physical_properties.limits.max_sampler_anisotropy);
+        let create_info = vk::SamplerCreateInfo::default()
+            .mag_filter(creation.mag_filter)
+            .min_filter(creation.min_filter)
+            .mipmap_mode(creation.mip_filter)
+            .address_mode_u(creation.address_mode_u)
+            .address_mode_v(creation.address_mode_v)
+            .address_mode_w(creation.address_mode_w)
+            .mip_lod_bias(creation.mip_lod_bias)
+            .anisotropy_enable(anisotropy_enable)
+            .max_anisotropy(if anisotropy_enable { max_anisotropy } else { 1.0 })
+            .compare_enable(creation.compare_op.is_some())
+            .compare_op(creation.compare_op.unwrap_or(vk::CompareOp::NEVER))
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .border_color(creation.border_color)
+            .unnormalized_coordinates(false);
+
+        let Some(handle) = self.samplers.obtain_resource(Sampler::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::Sampler));
+        };
+        let vk_sampler = match unsafe { self.vulkan_device.create_sampler(&create_info, self.vulkan_allocation_callbacks) } {
+            Ok(vk_sampler) => vk_sampler,
+            Err(result) => {
+                self.samplers.release_resource(handle);
+                return Err(result.into());
+            }
+        };
+        if let Some(name) = creation.name {
+            self.set_resource_name(vk_sampler, name);
+        }
+
+        *self.samplers.access_resource_mut(handle).unwrap() = Sampler {
+            vk_sampler,
+            min_filter: creation.min_filter,
+            mag_filter: creation.mag_filter,
+            mip_filter: creation.mip_filter,
+            address_mode_u: creation.address_mode_u,
+            address_mode_v: creation.address_mode_v,
+            address_mode_w: creation.address_mode_w,
+            max_anisotropy: create_info.max_anisotropy,
+            compare_op: creation.compare_op,
+            border_color: creation.border_color,
+            mip_lod_bias: creation.mip_lod_bias,
+            name: creation.name.map(str::to_string),
+        };
+        self.sampler_cache.insert(key, SamplerCacheEntry { handle, references: 1 });
+        self.write_bindless_sampler(handle.index(), handle);
+        Ok(handle)
+    }
+
+    // Drops one reference, the sampler is destroyed with the last one.
+    pub(crate) fn destroy_sampler(&mut self, sampler: SamplerHandle) {
+        if !self.samplers.is_valid(sampler) {
+            error!("Trying to free invalid sampler {:?}", sampler);
+            return;
+        }
+
+        let Some((&key, entry)) = self.sampler_cache.iter_mut().find(|(_, entry)| entry.handle == sampler) else {
+            return;
+        };
+        entry.references -= 1;
+        if entry.references > 0 {
+            return;
+        }
+        self.sampler_cache.remove(&key);
+
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::Sampler,
+            handle: sampler.index(),
+            generation: sampler.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    fn destroy_sampler_instant(&mut self, sampler: SamplerHandle) {
+        if self.samplers.is_valid(sampler) && sampler != self.default_sampler {
+            self.write_bindless_sampler(sampler.index(), self.default_sampler);
+        }
+        if let Some(sampler) = self.samplers.release_resource(sampler) {
+            unsafe { self.vulkan_device.destroy_sampler(sampler.vk_sampler, self.vulkan_allocation_callbacks) };
+        }
+    }
+
+    // Creates one module per stage and reflects their SPIR-V into shader_state.parse_result, the descriptor set
+    // layouts of pipelines using this shader state are generated from it. Without spv_input the stages hold
+    // source code, compiled here with the SDK tools. Stages read from a path are watched when shader reload is on.
+    pub(crate) fn create_shader_state(&mut self, creation: &ShaderStateCreation) -> Result<ShaderStateHandle, DeviceError> {
+        let handle = self.create_vk_shader_state(creation)?;
+        if let Some(shader_reload) = self.shader_reload.as_mut() {
+            if creation.stages[..creation.stages_count as usize].iter().any(|stage| stage.path.is_some()) {
+                shader_reload.watch(handle, creation);
+            }
+        }
+        Ok(handle)
+    }
+
+    fn create_vk_shader_state(&mut self, creation: &ShaderStateCreation) -> Result<ShaderStateHandle, DeviceError> {
+        if creation.stages_count == 0 {
+            return Err(DeviceError::InvalidCreation("shader state has no stages"));
+        }
+
+        let Some(handle) = self.shaders.obtain_resource(ShaderState::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::ShaderState));
+        };
+
+        let mut shader_state = ShaderState {
+            name: creation.name.map(str::to_string),
+            graphics_pipeline: true,
+            ..Default::default()
+        };
+        for stage in &creation.stages[..creation.stages_count as usize] {
+            let spirv = if creation.spv_input {
+                shader_stage_code(stage).and_then(spirv_words)
+            } else {
+                self.compile_shader(stage, creation.source_language, creation.name)
+            };
+            let module = match spirv.and_then(|spirv| self.create_shader_module(&spirv, stage.ty, &mut shader_state.parse_result, creation.name)) {
+                Ok(module) => module,
+                Err(error) => {
+                    self.destroy_shader_modules(&shader_state);
+                    self.shaders.release_resource(handle);
+                    return Err(error);
+                }
+            };
+            shader_state.shader_stage_info[shader_state.active_shaders as usize] = vk::PipelineShaderStageCreateInfo::default()
+                .stage(stage.ty)
+                .module(module)
+                .name(c"main");
+            shader_state.active_shaders += 1;
+            if stage.ty == vk::ShaderStageFlags::COMPUTE {
+                shader_state.graphics_pipeline = false;
+            }
+        }
+
+        let parse_result = &shader_state.parse_result;
+        info!("Created shader state {}, {} stages, {} descriptor sets, {} vertex inputs", creation.name.unwrap_or("unnamed"),
+              shader_state.active_shaders, parse_result.set_count, parse_result.vertex_inputs.len());
+
+        *self.shaders.access_resource_mut(handle).unwrap() = shader_state;
+        Ok(handle)
+    }
+
+    // Recreates the shader state from creation and swaps it in under the same handle, then rebuilds every pipeline
+    // using it. Old modules and pipelines go through the deletion queue. On error everything stays as it was.
+    pub(crate) fn reload_shader_state(&mut self, shader: ShaderStateHandle, creation: &ShaderStateCreation) -> Result<(), DeviceError> {
+        if !self.shaders.is_valid(shader) {
+            error!("Trying to reload invalid shader state {:?}", shader);
+            return Err(DeviceError::InvalidCreation("shader state handle is not valid"));
+        }
+
+        let reloaded = self.create_vk_shader_state(creation)?;
+
+        // All pipelines are rebuilt before anything is swapped, so a failure leaves every one of them untouched.
+        let pipelines: Vec<PipelineHandle> = self.pipelines.iter()
+            .filter(|(_, pipeline)| pipeline.shader_state == shader)
+            .map(|(handle, _)| handle)
+            .collect();
+        let mut rebuilt = Vec::with_capacity(pipelines.len());
+        for handle in pipelines {
+            let old = self.pipelines.access_resource(handle).unwrap();
+            // Descriptor sets are created against the generated layouts, they are kept and the reload is refused
+            // when the shaders changed them.
+            let layouts_match = !old.owns_descriptor_set_layouts || self.generated_layouts_match(old, reloaded);
+            let mut pipeline = Pipeline {
+                shader_state: reloaded,
+                descriptor_set_layout_handle: old.descriptor_set_layout_handle,
+                num_active_layouts: old.num_active_layouts,
+                // Until swapped in, so destroying it on failure leaves the shared layouts alone.
+                owns_descriptor_set_layouts: false,
+                depth_stencil: old.depth_stencil,
+                blend_state: old.blend_state.clone(),
+                rasterization: old.rasterization,
+                vertex_input: old.vertex_input.clone(),
+                render_pass_output: old.render_pass_output,
+                topology: old.topology,
+                handle,
+                name: old.name.clone(),
+                ..Default::default()
+            };
+            let result = if layouts_match {
+                self.create_vk_pipeline(&mut pipeline)
+            } else {
+                Err(DeviceError::InvalidCreation("reloaded shaders change the descriptor set layouts of a pipeline"))
+            };
+            if let Err(error) = result {
+                for (pipeline, _) in &rebuilt {
+                    self.destroy_vk_pipeline(pipeline);
+                }
+                self.destroy_shader_state_instant(reloaded);
+                return Err(error);
+            }
+            let owns_descriptor_set_layouts = self.pipelines.access_resource(handle).unwrap().owns_descriptor_set_layouts;
+            rebuilt.push((pipeline, owns_descriptor_set_layouts));
+        }
+
+        let new_state = std::mem::take(self.shaders.access_resource_mut(reloaded).unwrap());
+        let old_state = std::mem::replace(self.shaders.access_resource_mut(shader).unwrap(), new_state);
+        *self.shaders.access_resource_mut(reloaded).unwrap() = old_state;
+        self.destroy_shader_state(reloaded);
+
+        for (mut pipeline, owns_descriptor_set_layouts) in rebuilt {
+            pipeline.shader_state = shader;
+            pipeline.owns_descriptor_set_layouts = owns_descriptor_set_layouts;
+            let handle = pipeline.handle;
+            let old = std::mem::replace(self.pipelines.access_resource_mut(handle).unwrap(), pipeline);
+            // The descriptor set layouts moved to the new version, only the Vulkan objects are retired.
+            self.retire_vk_handle(resource_deletion_type::Enum::VkPipeline, old.vk_pipeline);
+            self.retire_vk_handle(resource_deletion_type::Enum::VkPipelineLayout, old.vk_pipeline_layout);
+        }
+        Ok(())
+    }
+
+    // Whether the reflection of the reloaded shader state gives the layouts the pipeline generated at creation.
+    fn generated_layouts_match(&self, pipeline: &Pipeline, shader: ShaderStateHandle) -> bool {
+        let parse_result = &self.shaders.access_resource(shader).unwrap().parse_result;
+        if parse_result.set_count != pipeline.num_active_layouts {
+            return false;
+        }
+        (0..parse_result.set_count as usize).all(|set| {
+            let reflected = &parse_result.sets[set];
+            let reflected_bindings = &reflected.bindings[..reflected.num_bindings as usize];
+            let layout = pipeline.descriptor_set_layout_handle[set];
+            if layout == self.bindless_descriptor_set_layout {
+                return reflected_bindings.iter().any(|binding| matches!(binding.start as u32, K_BINDLESS_TEXTURE_BINDING | K_BINDLESS_SAMPLER_BINDING));
+            }
+            let Some(bindings) = self.descriptor_set_layouts.access_resource(layout).and_then(|layout| layout.bindings.as_ref()) else {
+                return false;
+            };
+            let mut expected: Vec<_> = reflected_bindings.iter()
+                .filter_map(|binding| binding.ty.map(|ty| (binding.start, ty, binding.count)))
+                .collect();
+            let mut current: Vec<_> = bindings.iter().map(|binding| (binding.start, binding.ty, binding.count)).collect();
+            expected.sort_by_key(|(start, _, _)| *start);
+            current.sort_by_key(|(start, _, _)| *start);
+            expected == current
+        })
+    }
+
+    fn create_shader_module(&self, spirv: &[u32], stage: vk::ShaderStageFlags, parse_result: &mut ParseResult, name: Option<&str>) -> Result<vk::ShaderModule, DeviceError> {
+        let reflected_stage = parse_binary(spirv, parse_result).map_err(DeviceError::InvalidCreation)?;
+        if reflected_stage != stage {
+            warn!("Shader {} declares stage {:?} but its entry point is {:?}", name.unwrap_or("unnamed"), stage, reflected_stage);
+        }
+
+        let create_info = vk::ShaderModuleCreateInfo::default().code(spirv);
+        let module = unsafe { self.vulkan_device.create_shader_module(&create_info, self.vulkan_allocation_callbacks)? };
+        if let Some(name) = name {
+            self.set_resource_name(module, name);
+        }
+        Ok(module)
+    }
+
+    // Writes the stage source to a temp file with the stage define added, runs glslangValidator or dxc
+    // from vulkan_binaries_path on it and reads back the SPIR-V. Diagnostics are logged against the shader name.
+    fn compile_shader(&self, stage: &ShaderStage, language: shader_language::Enum, name: Option<&str>) -> Result<Vec<u32>, DeviceError> {
+        let extension = to_compiler_extension(stage.ty);
+        if extension.is_empty() {
+            return Err(DeviceError::InvalidCreation("only vertex, fragment and compute shaders can be compiled"));
+        }
+        let source = std::str::from_utf8(shader_stage_code(stage)?)
+            .map_err(|_| DeviceError::InvalidCreation("shader source is not valid UTF-8"))?;
+        let (source, define_line) = add_stage_define(source, to_stage_defines(stage.ty), language);
+
+        let file_stem = std::env::temp_dir().join(format!("lynch_shader_{}_{}", std::process::id(), SHADER_TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let source_path = file_stem.with_extension(extension);
+        let spirv_path = file_stem.with_extension("spv");
+        fs::write(&source_path, source)
+            .map_err(|error| DeviceError::ShaderCompilation(format!("could not write {}: {}", source_path.display(), error)))?;
+
+        let target_env = match vk::api_version_minor(self.vulkan_api_version) {
+            0 => "vulkan1.0",
+            1 => "vulkan1.1",
+            2 => "vulkan1.2",
+            _ => "vulkan1.3",
+        };
+        let mut command = match language {
+            shader_language::Enum::Hlsl => {
+                let profile = match stage.ty {
+                    vk::ShaderStageFlags::VERTEX => "vs_6_0",
+                    vk::ShaderStageFlags::FRAGMENT => "ps_6_0",
+                    _ => "cs_6_0",
+                };
+                let mut command = Command::new(self.shader_compiler_path("dxc"));
+                command.args(["-spirv", "-E", "main", "-T", profile]).arg(format!("-fspv-target-env={}", target_env))
+                    .arg("-Fo").arg(&spirv_path).arg(&source_path);
+                command
+            }
+            _ => {
+                let mut command = Command::new(self.shader_compiler_path("glslangValidator"));
+                command.args(["-V", "--target-env", target_env, "-S", extension]).arg("-o").arg(&spirv_path).arg(&source_path);
+                command
+            }
+        };
+        let output = command.output();
+        let spirv = fs::read(&spirv_path);
+        let _ = fs::remove_file(&source_path);
+        let _ = fs::remove_file(&spirv_path);
+
+        let output = output.map_err(|error| DeviceError::ShaderCompilation(format!("could not run {}: {}", command.get_program().to_string_lossy(), error)))?;
+        let shader_name = format!("{}.{}", name.unwrap_or("shader"), extension);
+        let compiler_output = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        let diagnostics = map_compiler_diagnostics(&compiler_output, &source_path.to_string_lossy(), &shader_name, define_line);
+        for diagnostic in &diagnostics {
+            if diagnostic.to_ascii_lowercase().contains("error") {
+                error!("{}", diagnostic);
+            } else {
+                warn!("{}", diagnostic);
+            }
+        }
+
+        if !output.status.success() {
+            let details = if diagnostics.is_empty() { compiler_output.trim().to_string() } else { diagnostics.join("\n") };
+            return Err(DeviceError::ShaderCompilation(format!("{} failed to compile:\n{}", shader_name, details)));
+        }
+        let spirv = spirv.map_err(|error| DeviceError::ShaderCompilation(format!("could not read {}: {}", spirv_path.display(), error)))?;
+        spirv_words(&spirv)
+    }
+
+    fn shader_compiler_path(&self, compiler: &str) -> PathBuf {
+        let executable = format!("{}{}", compiler, std::env::consts::EXE_SUFFIX);
+        match &self.vulkan_binaries_path {
+            Some(path) => path.join(executable),
+            None => PathBuf::from(executable),
+        }
+    }
+
+    fn destroy_shader_modules(&self, shader_state: &ShaderState) {
+        for stage_info in &shader_state.shader_stage_info[..shader_state.active_shaders as usize] {
+            unsafe { self.vulkan_device.destroy_shader_module(stage_info.module, self.vulkan_allocation_callbacks) };
+        }
+    }
+
+    // Deferred like destroy_buffer.
+    pub(crate) fn destroy_shader_state(&mut self, shader: ShaderStateHandle) {
+        if !self.shaders.is_valid(shader) {
+            error!("Trying to free invalid shader state {:?}", shader);
+            return;
+        }
+        if let Some(shader_reload) = self.shader_reload.as_mut() {
+            shader_reload.unwatch(shader);
+        }
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::ShaderState,
+            handle: shader.index(),
+            generation: shader.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    fn destroy_shader_state_instant(&mut self, shader: ShaderStateHandle) {
+        if let Some(shader_state) = self.shaders.release_resource(shader) {
+            self.destroy_shader_modules(&shader_state);
+        }
+    }
+
+    pub(crate) fn create_descriptor_set_layout(&mut self, creation: &DescriptorSetLayoutCreation) -> Result<DescriptorSetLayoutHandle, DeviceError> {
+        // Descriptor sets keep their resources indexed by binding.
+        if creation.bindings[..creation.num_bindings as usize].iter().any(|binding| binding.start as usize >= K_MAX_DESCRIPTORS_PER_SET) {
+            return Err(DeviceError::InvalidCreation("descriptor set layout binding out of range"));
+        }
+        let Some(handle) = self.descriptor_set_layouts.obtain_resource(DescriptorSetLayout::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::DescriptorSetLayout));
+        };
+
+        let mut bindings = Vec::with_capacity(creation.num_bindings as usize);
+        let mut vk_bindings = Vec::with_capacity(creation.num_bindings as usize);
+        for binding in &creation.bindings[..creation.num_bindings as usize] {
+            let Some(ty) = binding.ty else {
+                continue;
+            };
+            bindings.push(DescriptorBinding {
+                ty,
+                start: binding.start,
+                count: binding.count,
+                set: creation.set_index as u16,
+                name: binding.name.clone(),
+            });
+            vk_bindings.push(vk::DescriptorSetLayoutBinding::default()
+                .binding(binding.start as u32)
+                .descriptor_type(ty)
+                .descriptor_count(binding.count as u32)
+                .stage_flags(vk::ShaderStageFlags::ALL));
+        }
+
+        let create_info = vk::DescriptorSetLayoutCreateInfo::default().bindings(&vk_bindings);
+        let vk_descriptor_set_layout = match unsafe { self.vulkan_device.create_descriptor_set_layout(&create_info, self.vulkan_allocation_callbacks) } {
+            Ok(layout) => layout,
+            Err(result) => {
+                self.descriptor_set_layouts.release_resource(handle);
+                return Err(result.into());
+            }
+        };
+        if let Some(name) = creation.name {
+            self.set_resource_name(vk_descriptor_set_layout, name);
+        }
+
+        *self.descriptor_set_layouts.access_resource_mut(handle).unwrap() = DescriptorSetLayout {
+            vk_descriptor_set_layout,
+            num_bindings: bindings.len() as u16,
+            vk_binding: Some(vk_bindings),
+            bindings: Some(bindings),
+            set_index: creation.set_index as u16,
+            handle,
+        };
+        Ok(handle)
+    }
+
+    // Deferred like destroy_buffer.
+    pub(crate) fn destroy_descriptor_set_layout(&mut self, layout: DescriptorSetLayoutHandle) {
+        if !self.descriptor_set_layouts.is_valid(layout) {
+            error!("Trying to free invalid descriptor set layout {:?}", layout);
+            return;
+        }
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::DescriptorSetLayout,
+            handle: layout.index(),
+            generation: layout.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    fn destroy_descriptor_set_layout_instant(&mut self, layout: DescriptorSetLayoutHandle) {
+        if let Some(layout) = self.descriptor_set_layouts.release_resource(layout) {
+            unsafe { self.vulkan_device.destroy_descriptor_set_layout(layout.vk_descriptor_set_layout, self.vulkan_allocation_callbacks) };
+        }
+    }
+
+    // A render pass with one subpass writing every output. Swapchain passes leave the color output ready to present.
+    fn create_vk_render_pass(&self, output: &RenderPassOutput, ty: render_pass_type::Enum, name: Option<&str>) -> Result<vk::RenderPass, DeviceError> {
+        let color_final_layout = if ty == render_pass_type::Enum::Swapchain { vk::ImageLayout::PRESENT_SRC_KHR } else { vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL };
+        let to_vk_load_op = |operation: render_pass_operation::Enum| match operation {
+            render_pass_operation::Enum::Load => vk::AttachmentLoadOp::LOAD,
+            render_pass_operation::Enum::Clear => vk::AttachmentLoadOp::CLEAR,
+            _ => vk::AttachmentLoadOp::DONT_CARE,
+        };
+
+        let mut attachments = Vec::with_capacity(output.num_color_formats as usize + 1);
+        let mut color_references = Vec::with_capacity(output.num_color_formats as usize);
+        for format in &output.color_formats[..output.num_color_formats as usize] {
+            let initial_layout = match output.color_operation {
+                render_pass_operation::Enum::Load => color_final_layout,
+                _ => vk::ImageLayout::UNDEFINED,
+            };
+            color_references.push(vk::AttachmentReference {
+                attachment: attachments.len() as u32,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            });
+            attachments.push(vk::AttachmentDescription::default()
+                .format(*format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(to_vk_load_op(output.color_operation))
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(initial_layout)
+                .final_layout(color_final_layout));
+        }
+
+        let depth_reference = vk::AttachmentReference {
+            attachment: attachments.len() as u32,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+        let has_depth = output.depth_stencil_format != vk::Format::UNDEFINED;
+        if has_depth {
+            let initial_layout = match output.depth_operation {
+                render_pass_operation::Enum::Load => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                _ => vk::ImageLayout::UNDEFINED,
+            };
+            attachments.push(vk::AttachmentDescription::default()
+                .format(output.depth_stencil_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(to_vk_load_op(output.depth_operation))
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .stencil_load_op(to_vk_load_op(output.stencil_operation))
+                .stencil_store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(initial_layout)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL));
+        }
+
+        let mut subpass = vk::SubpassDescription::default()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_references);
+        if has_depth {
+            subpass = subpass.depth_stencil_attachment(&depth_reference);
+        }
+
+        // The implicit external dependency starts at top of pipe, which leaves the layout transitions unordered
+        // against the acquire semaphore wait at color attachment output, and the depth writes against the previous
+        // frame's. Cached passes are shared by every pass with the same output, so all of them get it.
+        let mut stage_mask = vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+        let mut src_access_mask = vk::AccessFlags::empty();
+        let mut dst_access_mask = vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+        if has_depth {
+            stage_mask |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
+            src_access_mask |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+            dst_access_mask |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        }
+        let dependency = vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(stage_mask)
+            .dst_stage_mask(stage_mask)
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask);
+        let create_info = vk::RenderPassCreateInfo::default()
+            .attachments(&attachments)
+            .subpasses(std::slice::from_ref(&subpass))
+            .dependencies(std::slice::from_ref(&dependency));
+        let vk_render_pass = unsafe { self.vulkan_device.create_render_pass(&create_info, self.vulkan_allocation_callbacks)? };
+        if let Some(name) = name {
+            self.set_resource_name(vk_render_pass, name);
+        }
+        Ok(vk_render_pass)
+    }
+
+    // The cached render pass for output, created on first use and kept until shutdown.
+    fn get_vk_render_pass(&mut self, output: &RenderPassOutput, name: Option<&str>) -> Result<vk::RenderPass, DeviceError> {
+        if let Some(vk_render_pass) = self.render_pass_cache.get(output) {
+            return Ok(*vk_render_pass);
+        }
+        let vk_render_pass = self.create_vk_render_pass(output, render_pass_type::Enum::Geometry, name)?;
+        self.render_pass_cache.insert(*output, vk_render_pass);
+        Ok(vk_render_pass)
+    }
+
+    // Geometry passes draw into their output textures, compute passes only carry a size. The swapchain pass belongs
+    // to the device, see get_swapchain_pass.
+    pub(crate) fn create_render_pass(&mut self, creation: &RenderPassCreation) -> Result<RenderPassHandle, DeviceError> {
+        if creation.ty == render_pass_type::Enum::Swapchain {
+            return Err(DeviceError::InvalidCreation("the swapchain pass is created by the device"));
+        }
+        let Some(handle) = self.render_passes.obtain_resource(RenderPass::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::RenderPass));
+        };
+
+        let num_render_targets = (creation.num_render_targets as usize).min(K_MAX_IMAGE_OUTPUTS);
+        let mut render_pass = RenderPass {
+            output_textures: creation.output_textures,
+            output_depth: creation.depth_stencil_texture,
+            ty: creation.ty,
+            scale_x: creation.scale_x,
+            scale_y: creation.scale_y,
+            width: ((self.swapchain_width as f32 * creation.scale_x) as u16).max(1),
+            height: ((self.swapchain_height as f32 * creation.scale_y) as u16).max(1),
+            resize: creation.resize,
+            num_render_targets: num_render_targets as u8,
+            name: creation.name.map(str::to_string),
+            ..Default::default()
+        };
+
+        if creation.ty == render_pass_type::Enum::Geometry {
+            let result = self.render_pass_output(&render_pass, creation).and_then(|output| {
+                render_pass.output = output;
+                if !self.dynamic_rendering_enabled {
+                    render_pass.vk_render_pass = self.get_vk_render_pass(&output, creation.name)?;
+                }
+                self.create_vk_framebuffer(&mut render_pass)
+            });
+            if let Err(error) = result {
+                self.render_passes.release_resource(handle);
+                return Err(error);
+            }
+        }
+
+        *self.render_passes.access_resource_mut(handle).unwrap() = render_pass;
+        Ok(handle)
+    }
+
+    // Formats of the output textures, which have to be valid color and depth targets.
+    fn render_pass_output(&self, render_pass: &RenderPass, creation: &RenderPassCreation) -> Result<RenderPassOutput, DeviceError> {
+        let mut output = RenderPassOutput::new();
+        for texture in &render_pass.output_textures[..render_pass.num_render_targets as usize] {
+            let Some(texture) = self.textures.access_resource(*texture) else {
+                return Err(DeviceError::InvalidCreation("render pass output texture is not valid"));
+            };
+            if texture_format::has_depth_or_stencil(texture.vk_format) {
+                return Err(DeviceError::InvalidCreation("depth textures go in the depth stencil output"));
+            }
+            output.color(texture.vk_format);
+        }
+        if render_pass.output_depth != K_INVALID_TEXTURE && render_pass.output_depth != TextureHandle::default() {
+            let Some(texture) = self.textures.access_resource(render_pass.output_depth) else {
+                return Err(DeviceError::InvalidCreation("render pass depth stencil texture is not valid"));
+            };
+            if !texture_format::has_depth_or_stencil(texture.vk_format) {
+                return Err(DeviceError::InvalidCreation("render pass depth stencil texture has no depth or stencil"));
+            }
+            output.depth(texture.vk_format);
+        }
+        output.set_operations(creation.color_operation, creation.depth_operation, creation.stencil_operation);
+        Ok(output)
+    }
+
+    // Takes the size from the attachments, which all have to match and have a single mip and layer. With dynamic
+    // rendering only the size is set.
+    fn create_vk_framebuffer(&self, render_pass: &mut RenderPass) -> Result<(), DeviceError> {
+        let mut attachments = render_pass.output_textures[..render_pass.num_render_targets as usize].to_vec();
+        if render_pass.output.depth_stencil_format != vk::Format::UNDEFINED {
+            attachments.push(render_pass.output_depth);
+        }
+
+        let mut image_views = Vec::with_capacity(attachments.len());
+        let mut size = None;
+        for texture in attachments {
+            let Some(texture) = self.textures.access_resource(texture) else {
+                return Err(DeviceError::InvalidCreation("render pass attachment is not valid"));
+            };
+            if texture.mipmaps != 1 || texture.array_layer_count != 1 {
+                return Err(DeviceError::InvalidCreation("render pass attachments need a single mip and layer"));
+            }
+            if size.is_some_and(|size| size != (texture.width, texture.height)) {
+                return Err(DeviceError::InvalidCreation("render pass attachments differ in size"));
+            }
+            size = Some((texture.width, texture.height));
+            image_views.push(texture.vk_image_view);
+        }
+        let (width, height) = size.unwrap_or((render_pass.width, render_pass.height));
+        if self.dynamic_rendering_enabled {
+            render_pass.width = width;
+            render_pass.height = height;
+            return Ok(());
+        }
+
+        let create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass.vk_render_pass)
+            .attachments(&image_views)
+            .width(width as u32)
+            .height(height as u32)
+            .layers(1);
+        render_pass.vk_frame_buffer = unsafe { self.vulkan_device.create_framebuffer(&create_info, self.vulkan_allocation_callbacks)? };
+        render_pass.width = width;
+        render_pass.height = height;
+        if let Some(name) = &render_pass.name {
+            self.set_resource_name(render_pass.vk_frame_buffer, name);
+        }
+        Ok(())
+    }
+
+    // Shared by the swapchain and offscreen passes, at the swapchain size.
+    fn create_or_resize_depth_texture(&mut self) -> Result<vk::Format, DeviceError> {
+        let (width, height) = (self.swapchain_width, self.swapchain_height);
+        if self.textures.is_valid(self.depth_texture) {
+            self.resize_texture(self.depth_texture, width, height)?;
+        } else {
+            let depth_format = [vk::Format::D32_SFLOAT, vk::Format::D24_UNORM_S8_UINT, vk::Format::D16_UNORM].into_iter()
+                .find(|format| {
+                    let properties = unsafe { self.vulkan_instance.get_physical_device_format_properties(self.vulkan_physical_device, *format) };
+                    properties.optimal_tiling_features.contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
+                })
+                .unwrap_or(vk::Format::D32_SFLOAT);
+            let mut depth_creation = TextureCreation::default();
+            depth_creation.set_size(width, height, 1)
+                .set_flags(1, texture_flags::Mask::RenderTargetMask as u8)
+                .set_format_type(depth_format, texture_type::Enum::Texture2D)
+                .set_name("DepthImage_Texture");
+            self.depth_texture = self.create_texture(&depth_creation)?;
+        }
+        Ok(self.textures.access_resource(self.depth_texture).unwrap().vk_format)
+    }
+
+    // Headless devices draw the frame into offscreen_texture through a geometry pass standing in for the swapchain
+    // pass. It is marked resize, so resize recreates its textures like those of any other pass.
+    fn create_offscreen_pass(&mut self) -> Result<(), DeviceError> {
+        let (width, height) = (self.swapchain_width, self.swapchain_height);
+        self.create_or_resize_depth_texture()?;
+
+        let mut texture_creation = TextureCreation::default();
+        texture_creation.set_size(width, height, 1)
+            .set_flags(1, texture_flags::Mask::RenderTargetMask as u8)
+            .set_format_type(vk::Format::R8G8B8A8_UNORM, texture_type::Enum::Texture2D)
+            .set_name("Offscreen_Texture");
+        self.offscreen_texture = self.create_texture(&texture_creation)?;
+
+        let mut pass_creation = RenderPassCreation::new();
+        pass_creation.add_render_texture(self.offscreen_texture)
+            .set_depth_stencil_texture(self.depth_texture)
+            .set_scaling(1.0, 1.0, 1)
+            .set_operations(render_pass_operation::Enum::Clear, render_pass_operation::Enum::Clear, render_pass_operation::Enum::Clear)
+            .set_name("Offscreen");
+        self.swapchain_pass = self.create_render_pass(&pass_creation)?;
+        self.swapchain_output = self.render_passes.access_resource(self.swapchain_pass).unwrap().output;
+        Ok(())
+    }
+
+    // Depth texture, render pass and a framebuffer per swapchain image. Called again every time the swapchain is
+    // recreated, with the device idle.
+    fn create_swapchain_pass(&mut self) -> Result<(), DeviceError> {
+        let (width, height) = (self.swapchain_width, self.swapchain_height);
+        let depth_format = self.create_or_resize_depth_texture()?;
+
+        let mut output = RenderPassOutput::new();
+        output.color(self.vulkan_surface_format.format)
+            .depth(depth_format)
+            .set_operations(render_pass_operation::Enum::Clear, render_pass_operation::Enum::Clear, render_pass_operation::Enum::Clear);
+
+        if !self.render_passes.is_valid(self.swapchain_pass) {
+            let swapchain_pass = RenderPass {
+                ty: render_pass_type::Enum::Swapchain,
+                resize: 1,
+                num_render_targets: 1,
+                name: Some("Swapchain".to_string()),
+                ..Default::default()
+            };
+            self.swapchain_pass = self.render_passes.obtain_resource(swapchain_pass)
+                .ok_or(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::RenderPass))?;
+        }
+        // The surface format can change along with the swapchain.
+        let swapchain_pass = self.render_passes.access_resource(self.swapchain_pass).unwrap();
+        let needs_render_pass = !self.dynamic_rendering_enabled;
+        if needs_render_pass && (swapchain_pass.vk_render_pass == vk::RenderPass::null() || swapchain_pass.output != output) {
+            let old_render_pass = swapchain_pass.vk_render_pass;
+            let vk_render_pass = self.create_vk_render_pass(&output, render_pass_type::Enum::Swapchain, Some("Swapchain"))?;
+            unsafe { self.vulkan_device.destroy_render_pass(old_render_pass, self.vulkan_allocation_callbacks) };
+            self.render_passes.access_resource_mut(self.swapchain_pass).unwrap().vk_render_pass = vk_render_pass;
+        }
+
+        let depth_view = self.textures.access_resource(self.depth_texture).unwrap().vk_image_view;
+        let vk_render_pass = self.render_passes.access_resource(self.swapchain_pass).unwrap().vk_render_pass;
+        for index in 0..self.vulkan_swapchain_image_count as usize {
+            if !needs_render_pass {
+                break;
+            }
+            let image_views = [self.vulkan_swapchain_image_views[index], depth_view];
+            let create_info = vk::FramebufferCreateInfo::default()
+                .render_pass(vk_render_pass)
+                .attachments(&image_views)
+                .width(width as u32)
+                .height(height as u32)
+                .layers(1);
+            self.vulkan_swapchain_framebuffers[index] = unsafe { self.vulkan_device.create_framebuffer(&create_info, self.vulkan_allocation_callbacks)? };
+            self.set_resource_name(self.vulkan_swapchain_framebuffers[index], &format!("Swapchain framebuffer {}", index));
+        }
+
+        let swapchain_pass = self.render_passes.access_resource_mut(self.swapchain_pass).unwrap();
+        swapchain_pass.output = output;
+        swapchain_pass.output_depth = self.depth_texture;
+        swapchain_pass.width = width;
+        swapchain_pass.height = height;
+        self.swapchain_output = output;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_dynamic_rendering_enabled(&self) -> bool {
+        self.dynamic_rendering_enabled
+    }
+
+    #[inline]
+    pub(crate) fn is_synchronization2_enabled(&self) -> bool {
+        self.synchronization2_enabled
+    }
+
+    #[inline]
+    pub(crate) fn is_auto_barriers_enabled(&self) -> bool {
+        self.auto_barriers
+    }
+
+    #[inline]
+    pub(crate) fn vulkan_device(&self) -> &ash::Device {
+        &self.vulkan_device
+    }
+
+    // Image, view and framebuffer of the swapchain image acquired for the current frame.
+    pub(crate) fn current_swapchain_attachment(&self) -> (vk::Image, vk::ImageView, vk::Framebuffer) {
+        let index = self.vulkan_image_index as usize;
+        (self.vulkan_swapchain_images[index], self.vulkan_swapchain_image_views[index], self.vulkan_swapchain_framebuffers[index])
+    }
+
+    // Layout the acquired swapchain image is in, the contents a pass loading it gets.
+    pub(crate) fn current_swapchain_image_layout(&self) -> vk::ImageLayout {
+        if self.vulkan_swapchain_images_presented[self.vulkan_image_index as usize] {
+            vk::ImageLayout::PRESENT_SRC_KHR
+        } else {
+            vk::ImageLayout::UNDEFINED
+        }
+    }
+
+    #[inline]
+    pub(crate) fn get_swapchain_pass(&self) -> RenderPassHandle {
+        self.swapchain_pass
+    }
+
+    // Transient memory of the current frame, alive until the GPU is done with it.
+    #[inline]
+    pub(crate) fn frame_arenas(&mut self) -> &mut FrameArenas {
+        &mut self.frame_arenas
+    }
+
+    // What headless devices render the swapchain pass into, K_INVALID_TEXTURE with a window.
+    #[inline]
+    pub(crate) fn offscreen_texture(&self) -> TextureHandle {
+        self.offscreen_texture
+    }
+
+    // Waits until the GPU is done with the last frame that used this frame slot, then acquires the next swapchain
+    // image and recycles the slot: command buffers, dynamic buffer memory, deferred deletions and descriptor set
+    // updates. An out of date swapchain is recreated at the current size.
+    pub(crate) fn new_frame(&mut self) -> Result<(), DeviceError> {
+        let frame = self.current_frame as usize;
+        let fence = self.vulkan_command_buffer_executed_fence[frame];
+        unsafe { self.vulkan_device.wait_for_fences(&[fence], true, u64::MAX)? };
+        // The fence covers the last frame that used this slot, frame arenas share the device's frame numbers.
+        if let Some(completed_frame) = self.absolute_frame.checked_sub(K_MAX_SWAPCHAIN_IMAGES as u32) {
+            self.frame_arenas.frame_completed(completed_frame as u64);
+        }
+        self.frame_arenas.new_frame(self.absolute_frame as u64);
+
+        if !self.headless {
+            self.acquire_swapchain_image()?;
+        }
+        // Only once a submit is sure to follow, a fence left unsignaled would block this slot forever.
+        unsafe { self.vulkan_device.reset_fences(&[fence])? };
+        self.command_buffer_ring.reset_frame(self.current_frame)?;
+        self.dynamic_allocated_size = self.dynamic_per_frame_size * self.current_frame;
+
+        self.process_resource_deletions();
+        self.apply_descriptor_set_updates();
+        // Taken out while it runs, reloading goes through the device.
+        if let Some(mut shader_reload) = self.shader_reload.take() {
+            shader_reload.update(self);
+            self.shader_reload = Some(shader_reload);
+        }
+        self.check_validation_errors();
+        Ok(())
+    }
+
+    fn acquire_swapchain_image(&mut self) -> Result<(), DeviceError> {
+        let semaphore = self.vulkan_image_acquired_semaphore[self.current_frame as usize];
+        for _ in 0..2 {
+            let swapchain_loader = self.vulkan_swapchain_loader.as_ref().unwrap();
+            match unsafe { swapchain_loader.acquire_next_image(self.vulkan_swapchain, u64::MAX, semaphore, vk::Fence::null()) } {
+                // Suboptimal images can still be presented, present recreates the swapchain afterwards.
+                Ok((image_index, _)) => {
+                    self.vulkan_image_index = image_index;
+                    return Ok(());
+                }
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.resize(self.swapchain_width as u32, self.swapchain_height as u32)?,
+                Err(result) => return Err(result.into()),
+            }
+        }
+        Err(vk::Result::ERROR_OUT_OF_DATE_KHR.into())
+    }
+
+    // Allocated from the pool of thread_index for the current frame, valid until new_frame comes back to this frame
+    // slot. Queue it once done. A CommandBuffer borrows the device mutably, so it only records on the thread owning
+    // the device, other threads record raw buffers through command_buffer_ring.
+    pub(crate) fn get_command_buffer(&mut self, thread_index: usize, begin: bool) -> Result<CommandBuffer<'_, 'a>, DeviceError> {
+        let vk_command_buffer = self.command_buffer_ring.get_command_buffer(thread_index, false)?;
+        let mut command_buffer = CommandBuffer::new(self, vk_command_buffer, thread_index as u32, 0, false);
+        if begin {
+            command_buffer.begin().map_err(|error| match error {
+                CommandBufferError::Vulkan(result) => DeviceError::Vulkan(result),
+                _ => DeviceError::InvalidCreation("command buffer could not begin"),
+            })?;
+        }
+        Ok(command_buffer)
+    }
+
+    // Submitted in queue order by the next present.
+    pub(crate) fn queue_command_buffer(&self, vk_command_buffer: vk::CommandBuffer) {
+        self.command_buffer_ring.queue(vk_command_buffer);
+    }
+
+    // For job threads recording in parallel, each with its own thread_index. Buffers are taken after new_frame and
+    // queued before present, from whichever thread. Their commands are not validated or tracked, see
+    // CommandBufferRing.
+    #[inline]
+    pub(crate) fn command_buffer_ring(&self) -> Arc<CommandBufferRing> {
+        Arc::clone(&self.command_buffer_ring)
+    }
+
+    // Submits the queued command buffers and presents the acquired image, then moves on to the next frame slot.
+    // Headless devices only submit, the frame stays in offscreen_texture. An out of date or suboptimal swapchain is
+    // recreated at the current size.
+    pub(crate) fn present(&mut self) -> Result<(), DeviceError> {
+        let frame = self.current_frame as usize;
+        let command_buffers = self.command_buffer_ring.take_queued();
+        let fence = self.vulkan_command_buffer_executed_fence[frame];
+
+        if self.headless {
+            let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffers);
+            unsafe { self.vulkan_device.queue_submit(self.vulkan_queue, &[submit_info], fence)? };
+        } else {
+            let wait_semaphores = [self.vulkan_image_acquired_semaphore[frame]];
+            let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+            let render_complete_semaphores = [self.vulkan_render_complete_semaphore[self.vulkan_image_index as usize]];
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_stages)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&render_complete_semaphores);
+            unsafe { self.vulkan_device.queue_submit(self.vulkan_queue, &[submit_info], fence)? };
+
+            let swapchains = [self.vulkan_swapchain];
+            let image_indices = [self.vulkan_image_index];
+            let present_info = vk::PresentInfoKHR::default()
+                .wait_semaphores(&render_complete_semaphores)
+                .swapchains(&swapchains)
+                .image_indices(&image_indices);
+            let swapchain_loader = self.vulkan_swapchain_loader.as_ref().unwrap();
+            let result = unsafe { swapchain_loader.queue_present(self.vulkan_queue, &present_info) };
+            if result.is_ok() {
+                self.vulkan_swapchain_images_presented[self.vulkan_image_index as usize] = true;
+            }
+            match result {
+                Ok(false) => {}
+                Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.resize(self.swapchain_width as u32, self.swapchain_height as u32)?,
+                Err(result) => return Err(result.into()),
+            }
+        }
+
+        self.previous_frame = self.current_frame;
+        self.current_frame = (self.current_frame + 1) % K_MAX_SWAPCHAIN_IMAGES as u32;
+        self.absolute_frame += 1;
+        Ok(())
+    }
+
+    // For pipelines drawing into the swapchain pass.
+    #[inline]
+    pub(crate) fn get_swapchain_output(&self) -> &RenderPassOutput {
+        &self.swapchain_output
+    }
+
+    // Recreates the swapchain at the new size, then every render pass marked resize at its scale, output textures
+    // included. Waits for the device to be idle. A zero size, like a minimized window, is ignored, sizes above
+    // u16::MAX are rejected.
+    pub(crate) fn resize(&mut self, width: u32, height: u32) -> Result<(), DeviceError> {
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            error!("Cannot resize to {}x{}, sizes are limited to {}", width, height, u16::MAX);
+            return Err(DeviceError::InvalidCreation("resize is larger than u16::MAX"));
+        };
+        unsafe { self.vulkan_device.device_wait_idle()? };
+
+        self.swapchain_width = width;
+        self.swapchain_height = height;
+        if !self.headless {
+            self.destroy_swapchain();
+            self.create_swapchain()?;
+            self.create_swapchain_pass()?;
+        }
+
+        let passes: Vec<RenderPassHandle> = self.render_passes.iter()
+            .filter(|(_, render_pass)| render_pass.resize != 0 && render_pass.ty != render_pass_type::Enum::Swapchain)
+            .map(|(handle, _)| handle)
+            .collect();
+        let mut resized_textures = Vec::new();
+        for handle in passes {
+            let render_pass = self.render_passes.access_resource_mut(handle).unwrap();
+            render_pass.width = ((self.swapchain_width as f32 * render_pass.scale_x) as u16).max(1);
+            render_pass.height = ((self.swapchain_height as f32 * render_pass.scale_y) as u16).max(1);
+            let (width, height) = (render_pass.width, render_pass.height);
+            if render_pass.ty != render_pass_type::Enum::Geometry {
+                continue;
+            }
+
+            let mut attachments = render_pass.output_textures[..render_pass.num_render_targets as usize].to_vec();
+            if render_pass.output.depth_stencil_format != vk::Format::UNDEFINED {
+                attachments.push(render_pass.output_depth);
+            }
+            for texture in attachments {
+                if !resized_textures.contains(&texture) {
+                    self.resize_texture(texture, width, height)?;
+                    resized_textures.push(texture);
+                }
+            }
+        }
+        if resized_textures.is_empty() {
+            return Ok(());
+        }
+
+        // Framebuffers and descriptor sets still point at the old image views, passes sharing a resized texture
+        // with a resizing pass included.
+        let stale_passes: Vec<RenderPassHandle> = self.render_passes.iter()
+            .filter(|(_, render_pass)| render_pass.ty == render_pass_type::Enum::Geometry)
+            .filter(|(_, render_pass)| {
+                render_pass.output_textures[..render_pass.num_render_targets as usize].iter().chain(std::iter::once(&render_pass.output_depth))
+                    .any(|texture| resized_textures.contains(texture))
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in stale_passes {
+            let mut render_pass = std::mem::take(self.render_passes.access_resource_mut(handle).unwrap());
+            unsafe { self.vulkan_device.destroy_framebuffer(render_pass.vk_frame_buffer, self.vulkan_allocation_callbacks) };
+            render_pass.vk_frame_buffer = vk::Framebuffer::null();
+            let result = self.create_vk_framebuffer(&mut render_pass);
+            *self.render_passes.access_resource_mut(handle).unwrap() = render_pass;
+            result?;
+        }
+
+        let stale_sets: Vec<DescriptorSetHandle> = self.descriptor_sets.iter()
+            .filter(|(_, descriptor_set)| descriptor_set.resources.iter().any(|resource| matches!(resource, DescriptorResource::Texture(texture) if resized_textures.contains(texture))))
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in stale_sets {
+            let descriptor_set = self.descriptor_sets.access_resource(handle).unwrap();
+            self.write_vk_descriptor_set(descriptor_set.vk_descriptor_set, descriptor_set.layout, &descriptor_set.resources, &descriptor_set.samplers, descriptor_set.name.as_deref());
+        }
+        info!("Resized to {}x{}, {} textures recreated", self.swapchain_width, self.swapchain_height, resized_textures.len());
+        Ok(())
+    }
+
+    // Recreates the texture at a new size under the same handle, contents are lost. Only with the device idle.
+    fn resize_texture(&mut self, handle: TextureHandle, width: u16, height: u16) -> Result<(), DeviceError> {
+        let Some(texture) = self.textures.access_resource_mut(handle) else {
+            return Err(DeviceError::InvalidCreation("texture to resize is not valid"));
+        };
+        let mut texture = std::mem::take(texture);
+        self.destroy_vk_texture(&mut texture);
+        texture.width = width;
+        texture.height = height;
+        let result = self.create_vk_texture(&mut texture, None);
+        *self.textures.access_resource_mut(handle).unwrap() = texture;
+        // The old view is gone either way, a failed texture must not be left in the bindless array.
+        let bindless_texture = if result.is_ok() { handle } else { self.dummy_texture };
+        self.write_bindless_texture(handle.index(), bindless_texture);
+        result
+    }
+
+    // Deferred like destroy_buffer.
+    pub(crate) fn destroy_render_pass(&mut self, render_pass: RenderPassHandle) {
+        if !self.render_passes.is_valid(render_pass) {
+            error!("Trying to free invalid render pass {:?}", render_pass);
+            return;
+        }
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::RenderPass,
+            handle: render_pass.index(),
+            generation: render_pass.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    // Cached render passes stay, only the swapchain pass owns its own.
+    fn destroy_render_pass_instant(&mut self, render_pass: RenderPassHandle) {
+        if let Some(render_pass) = self.render_passes.release_resource(render_pass) {
+            unsafe {
+                if render_pass.vk_frame_buffer != vk::Framebuffer::null() {
+                    self.vulkan_device.destroy_framebuffer(render_pass.vk_frame_buffer, self.vulkan_allocation_callbacks);
+                }
+                if render_pass.ty == render_pass_type::Enum::Swapchain {
+                    self.vulkan_device.destroy_render_pass(render_pass.vk_render_pass, self.vulkan_allocation_callbacks);
+                }
+            }
+        }
+    }
+
+    // Creates the shader state of creation.shaders and a graphics pipeline from it, or a compute pipeline when the
+    // only stage is a compute shader. Descriptor set layouts are generated from the shader reflection unless
+    // creation lists its own.
+    pub(crate) fn create_pipeline(&mut self, creation: &PipelineCreation) -> Result<PipelineHandle, DeviceError> {
+        if matches!(creation.topology, topology_type::Enum::Unknown | topology_type::Enum::Count) {
+            return Err(DeviceError::InvalidCreation("pipeline topology is not set"));
+        }
+
+        let shader_state = self.create_shader_state(&creation.shaders)?;
+        let Some(handle) = self.pipelines.obtain_resource(Pipeline::default()) else {
+            self.destroy_shader_state_instant(shader_state);
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::Pipeline));
+        };
+
+        let mut pipeline = Pipeline {
+            shader_state,
+            descriptor_set_layout_handle: creation.descriptor_set_layout,
+            num_active_layouts: creation.num_active_layouts,
+            owns_descriptor_set_layouts: creation.num_active_layouts == 0,
+            depth_stencil: creation.depth_stencil,
+            blend_state: creation.blend_state.clone(),
+            rasterization: creation.rasterization,
+            vertex_input: creation.vertex_input.clone(),
+            render_pass_output: creation.render_pass,
+            topology: creation.topology,
+            handle,
+            name: creation.name.map(str::to_string),
+            ..Default::default()
+        };
+        if let Err(error) = self.create_vk_pipeline(&mut pipeline) {
+            self.destroy_shader_state_instant(shader_state);
+            self.pipelines.release_resource(handle);
+            return Err(error);
+        }
+
+        *self.pipelines.access_resource_mut(handle).unwrap() = pipeline;
+        Ok(handle)
+    }
+
+    // Builds the layouts, pipeline layout and pipeline from the state stored in pipeline. Nothing is left behind on error.
+    fn create_vk_pipeline(&mut self, pipeline: &mut Pipeline<'a>) -> Result<(), DeviceError> {
+        let shader_state = self.shaders.access_resource(pipeline.shader_state)
+            .ok_or(DeviceError::InvalidCreation("pipeline shader state is not valid"))?;
+        let parse_result = shader_state.parse_result.clone();
+        let stage_infos = shader_state.shader_stage_info[..shader_state.active_shaders as usize].to_vec();
+        pipeline.graphics_pipeline = shader_state.graphics_pipeline;
+        if !pipeline.graphics_pipeline && stage_infos.len() != 1 {
+            return Err(DeviceError::InvalidCreation("compute shaders cannot be combined with other stages"));
+        }
+
+        if pipeline.owns_descriptor_set_layouts {
+            for set in 0..parse_result.set_count as usize {
+                let bindings = &parse_result.sets[set].bindings[..parse_result.sets[set].num_bindings as usize];
+                let is_bindless_binding = |start: u16| matches!(start as u32, K_BINDLESS_TEXTURE_BINDING | K_BINDLESS_SAMPLER_BINDING);
+                if self.bindless_enabled && set as u32 == K_BINDLESS_SET_INDEX && bindings.iter().any(|binding| is_bindless_binding(binding.start)) {
+                    if !bindings.iter().all(|binding| is_bindless_binding(binding.start)) {
+                        warn!("Pipeline {} declares more than the bindless textures and samplers in set {}, only those are available", pipeline.name.as_deref().unwrap_or("unnamed"), set);
+                    }
+                    pipeline.descriptor_set_layout_handle[set] = self.bindless_descriptor_set_layout;
+                    pipeline.num_active_layouts = set as u32 + 1;
+                    continue;
+                }
+
+                // Sets skipped by the shaders still need an (empty) layout.
+                let mut layout_creation = parse_result.sets[set].clone();
+                layout_creation.set_set_index(set as u32);
+                match self.create_descriptor_set_layout(&layout_creation) {
+                    Ok(layout) => {
+                        pipeline.descriptor_set_layout_handle[set] = layout;
+                        pipeline.num_active_layouts = set as u32 + 1;
+                    }
+                    Err(error) => {
+                        self.destroy_owned_descriptor_set_layouts(pipeline);
+                        return Err(error);
+                    }
+                }
+            }
+        } else if pipeline.num_active_layouts < parse_result.set_count {
+            warn!("Pipeline {} lists {} descriptor set layouts but its shaders use {}", pipeline.name.as_deref().unwrap_or("unnamed"),
+                  pipeline.num_active_layouts, parse_result.set_count);
+        }
+
+        let vk_layouts = pipeline.descriptor_set_layout_handle[..pipeline.num_active_layouts as usize].iter()
+            .map(|layout| self.access_descriptor_set_layout(*layout).map(|layout| layout.vk_descriptor_set_layout))
+            .collect::<Option<Vec<_>>>();
+        let Some(vk_layouts) = vk_layouts else {
+            self.destroy_owned_descriptor_set_layouts(pipeline);
+            return Err(DeviceError::InvalidCreation("pipeline uses an invalid descriptor set layout"));
+        };
+        let push_constants: Vec<vk::PushConstantRange> = parse_result.push_constants.into_iter().collect();
+
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&vk_layouts)
+            .push_constant_ranges(&push_constants);
+        pipeline.vk_pipeline_layout = match unsafe { self.vulkan_device.create_pipeline_layout(&layout_info, self.vulkan_allocation_callbacks) } {
+            Ok(layout) => layout,
+            Err(result) => {
+                self.destroy_owned_descriptor_set_layouts(pipeline);
+                return Err(result.into());
+            }
+        };
+
+        let mut feedback = vk::PipelineCreationFeedback::default();
+        let result = if pipeline.graphics_pipeline {
+            let render_pass = if self.dynamic_rendering_enabled { Ok(vk::RenderPass::null()) } else { self.get_vk_render_pass(&pipeline.render_pass_output, pipeline.name.as_deref()) };
+            render_pass.and_then(|render_pass| self.create_vk_graphics_pipeline(pipeline, &stage_infos, &parse_result, render_pass, &mut feedback))
+        } else {
+            let mut feedback_info = vk::PipelineCreationFeedbackCreateInfo::default().pipeline_creation_feedback(&mut feedback);
+            let mut create_info = vk::ComputePipelineCreateInfo::default()
+                .stage(stage_infos[0])
+                .layout(pipeline.vk_pipeline_layout);
+            if self.pipeline_creation_feedback {
+                create_info = create_info.push_next(&mut feedback_info);
+            }
+            unsafe { self.vulkan_device.create_compute_pipelines(self.vulkan_pipeline_cache, &[create_info], self.vulkan_allocation_callbacks) }
+                .map(|pipelines| pipelines[0])
+                .map_err(|(_, result)| result.into())
+        };
+        match result {
+            Ok(vk_pipeline) => {
+                self.record_pipeline_cache_feedback(pipeline.name.as_deref(), &feedback);
+                pipeline.vk_pipeline = vk_pipeline;
+                pipeline.vk_bind_point = if pipeline.graphics_pipeline { vk::PipelineBindPoint::GRAPHICS } else { vk::PipelineBindPoint::COMPUTE };
+                if let Some(name) = pipeline.name.as_deref() {
+                    self.set_resource_name(vk_pipeline, name);
+                }
+                Ok(())
+            }
+            Err(error) => {
+                unsafe { self.vulkan_device.destroy_pipeline_layout(pipeline.vk_pipeline_layout, self.vulkan_allocation_callbacks) };
+                pipeline.vk_pipeline_layout = vk::PipelineLayout::null();
+                self.destroy_owned_descriptor_set_layouts(pipeline);
+                Err(error)
+            }
+        }
+    }
+
+    fn create_vk_graphics_pipeline(&self, pipeline: &Pipeline, stage_infos: &[vk::PipelineShaderStageCreateInfo], parse_result: &ParseResult,
+                                   render_pass: vk::RenderPass, feedback: &mut vk::PipelineCreationFeedback) -> Result<vk::Pipeline, DeviceError> {
+        let name = pipeline.name.as_deref().unwrap_or("unnamed");
+
+        // Vertex input
+        let vertex_input = &pipeline.vertex_input;
+        let vertex_bindings: Vec<vk::VertexInputBindingDescription> = vertex_input.vertex_streams[..vertex_input.num_vertex_streams as usize].iter()
+            .map(|stream| vk::VertexInputBindingDescription {
+                binding: stream.binding as u32,
+                stride: stream.stride as u32,
+                input_rate: to_vk_vertex_input_rate(stream.input_rate),
+            })
+            .collect();
+        let mut vertex_attributes = Vec::with_capacity(vertex_input.num_vertex_attributes as usize);
+        for attribute in &vertex_input.vertex_attributes[..vertex_input.num_vertex_attributes as usize] {
+            // Matrices take one location per column.
+            let columns = if attribute.format == vertex_component_format::Enum::Mat4 { 4 } else { 1 };
+            for column in 0..columns {
+                vertex_attributes.push(vk::VertexInputAttributeDescription {
+                    location: attribute.location as u32 + column,
+                    binding: attribute.binding as u32,
+                    format: to_vk_vertex_format(attribute.format),
+                    offset: attribute.offset + column * 16,
+                });
+            }
+        }
+        for input in &parse_result.vertex_inputs {
+            if !vertex_attributes.iter().any(|attribute| attribute.location == input.location) {
+                warn!("Pipeline {} has no vertex attribute for shader input {} at location {}", name, input.name.as_deref().unwrap_or("unnamed"), input.location);
+            }
+        }
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&vertex_attributes);
+
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
+            .topology(to_vk_topology(pipeline.topology))
+            .primitive_restart_enable(false);
+
+        // Viewport and scissor are dynamic.
+        let viewport_state = vk::PipelineViewportStateCreateInfo::default()
+            .viewport_count(1)
+            .scissor_count(1);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state = vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_states);
+
+        let mut polygon_mode = to_vk_polygon_mode(pipeline.rasterization.fill);
+        if polygon_mode != vk::PolygonMode::FILL && self.vulkan_enabled_features.fill_mode_non_solid != vk::TRUE {
+            warn!("Pipeline {} uses {} fill mode, not supported by the device, falling back to solid", name, fill_mode::to_string(pipeline.rasterization.fill));
+            polygon_mode = vk::PolygonMode::FILL;
+        }
+        let rasterizer = vk::PipelineRasterizationStateCreateInfo::default()
+            .polygon_mode(polygon_mode)
+            .cull_mode(to_vk_cull_mode(pipeline.rasterization.cull_mode))
+            .front_face(pipeline.rasterization.front)
+            .line_width(1.0);
+
+        let multisampling = vk::PipelineMultisampleStateCreateInfo::default()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+        let depth_stencil = &pipeline.depth_stencil;
+        let to_vk_stencil_op_state = |state: &StencilOperationState| vk::StencilOpState {
+            fail_op: state.fail,
+            pass_op: state.pass,
+            depth_fail_op: state.depth_fail,
+            compare_op: state.compare,
+            compare_mask: state.compare_mask,
+            write_mask: state.write_mask,
+            reference: state.reference,
+        };
+        let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(depth_stencil.depth_enable)
+            .depth_write_enable(depth_stencil.depth_write_enable)
+            .depth_compare_op(depth_stencil.depth_comparison)
+            .stencil_test_enable(depth_stencil.stencil_enable)
+            .front(to_vk_stencil_op_state(&depth_stencil.front))
+            .back(to_vk_stencil_op_state(&depth_stencil.back));
+
+        // One blend attachment per color output, outputs without a blend state are written unblended.
+        let blend_state = &pipeline.blend_state;
+        let color_count = pipeline.render_pass_output.num_color_formats as usize;
+        if blend_state.active_states as usize > color_count {
+            warn!("Pipeline {} has {} blend states for {} color outputs", name, blend_state.active_states, color_count);
+        }
+        let blend_attachments: Vec<vk::PipelineColorBlendAttachmentState> = (0..color_count).map(|index| {
+            let state = if index < blend_state.active_states as usize { blend_state.blend_states[index] } else { BlendState::default() };
+            let (source_alpha, destination_alpha, alpha_operation) = if state.separate_blend {
+                (state.source_alpha, state.destination_alpha, state.alpha_operation)
+            } else {
+                (state.source_color, state.destination_color, state.color_operation)
+            };
+            vk::PipelineColorBlendAttachmentState::default()
+                .blend_enable(state.blend_enabled)
+                .src_color_blend_factor(state.source_color)
+                .dst_color_blend_factor(state.destination_color)
+                .color_blend_op(state.color_operation)
+                .src_alpha_blend_factor(source_alpha)
+                .dst_alpha_blend_factor(destination_alpha)
+                .alpha_blend_op(alpha_operation)
+                .color_write_mask(vk::ColorComponentFlags::from_raw(state.color_write_mask as u32))
+        }).collect();
+        let logic_operation = blend_state.logic_operation.filter(|operation| {
+            let supported = self.vulkan_enabled_features.logic_op == vk::TRUE;
+            if !supported {
+                warn!("Pipeline {} uses logic operation {}, not supported by the device, ignored", name, logic_operation::to_string(*operation));
+            }
+            supported
+        });
+        let color_blending = vk::PipelineColorBlendStateCreateInfo::default()
+            .logic_op_enable(logic_operation.is_some())
+            .logic_op(logic_operation.map_or(vk::LogicOp::COPY, to_vk_logic_op))
+            .attachments(&blend_attachments);
+
+        // Pipelines work with any render pass of the same output, with dynamic rendering they only need the formats.
+        let output = &pipeline.render_pass_output;
+        let depth_stencil_format = output.depth_stencil_format;
+        let mut rendering_info = vk::PipelineRenderingCreateInfo::default()
+            .color_attachment_formats(&output.color_formats[..output.num_color_formats as usize])
+            .depth_attachment_format(if texture_format::has_depth(depth_stencil_format) { depth_stencil_format } else { vk::Format::UNDEFINED })
+            .stencil_attachment_format(if texture_format::has_stencil(depth_stencil_format) { depth_stencil_format } else { vk::Format::UNDEFINED });
+        let mut feedback_info = vk::PipelineCreationFeedbackCreateInfo::default().pipeline_creation_feedback(feedback);
+        let mut create_info = vk::GraphicsPipelineCreateInfo::default()
+            .stages(stage_infos)
+            .vertex_input_state(&vertex_input_info)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterizer)
+            .multisample_state(&multisampling)
+            .depth_stencil_state(&depth_stencil_info)
+            .color_blend_state(&color_blending)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline.vk_pipeline_layout)
+            .render_pass(render_pass);
+        if self.pipeline_creation_feedback {
+            create_info = create_info.push_next(&mut feedback_info);
+        }
+        if self.dynamic_rendering_enabled {
+            create_info = create_info.push_next(&mut rendering_info);
+        }
+        let result = unsafe { self.vulkan_device.create_graphics_pipelines(self.vulkan_pipeline_cache, &[create_info], self.vulkan_allocation_callbacks) };
+        result.map(|pipelines| pipelines[0]).map_err(|(_, result)| result.into())
+    }
+
+    // Generated layouts of the pipeline, the bindless one is shared and stays with the device.
+    fn owned_descriptor_set_layouts(&self, pipeline: &Pipeline) -> Vec<DescriptorSetLayoutHandle> {
+        if !pipeline.owns_descriptor_set_layouts {
+            return Vec::new();
+        }
+        pipeline.descriptor_set_layout_handle[..pipeline.num_active_layouts as usize].iter()
+            .copied()
+            .filter(|layout| *layout != self.bindless_descriptor_set_layout)
+            .collect()
+    }
+
+    fn destroy_owned_descriptor_set_layouts(&mut self, pipeline: &mut Pipeline) {
+        for layout in self.owned_descriptor_set_layouts(pipeline) {
+            self.destroy_descriptor_set_layout_instant(layout);
+        }
+        if pipeline.owns_descriptor_set_layouts {
+            pipeline.num_active_layouts = 0;
+        }
+    }
+
+    // Immediately, for pipelines the GPU never saw.
+    fn destroy_vk_pipeline(&mut self, pipeline: &Pipeline) {
+        unsafe {
+            self.vulkan_device.destroy_pipeline(pipeline.vk_pipeline, self.vulkan_allocation_callbacks);
+            self.vulkan_device.destroy_pipeline_layout(pipeline.vk_pipeline_layout, self.vulkan_allocation_callbacks);
+        }
+        for layout in self.owned_descriptor_set_layouts(pipeline) {
+            self.destroy_descriptor_set_layout_instant(layout);
+        }
+    }
+
+    // Deferred like destroy_buffer, takes the shader state and generated layouts with it.
+    pub(crate) fn destroy_pipeline(&mut self, pipeline: PipelineHandle) {
+        let Some(data) = self.pipelines.access_resource(pipeline) else {
+            error!("Trying to free invalid pipeline {:?}", pipeline);
+            return;
+        };
+        let shader_state = data.shader_state;
+        let owned_layouts = self.owned_descriptor_set_layouts(data);
+
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::Pipeline,
+            handle: pipeline.index(),
+            generation: pipeline.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+        if shader_state != K_INVALID_SHADER {
+            self.destroy_shader_state(shader_state);
+        }
+        for layout in owned_layouts {
+            self.destroy_descriptor_set_layout(layout);
+        }
+    }
+
+    fn destroy_pipeline_instant(&mut self, pipeline: PipelineHandle) {
+        if let Some(pipeline) = self.pipelines.release_resource(pipeline) {
+            unsafe {
+                self.vulkan_device.destroy_pipeline(pipeline.vk_pipeline, self.vulkan_allocation_callbacks);
+                self.vulkan_device.destroy_pipeline_layout(pipeline.vk_pipeline_layout, self.vulkan_allocation_callbacks);
+            }
+        }
+    }
+
+    pub(crate) fn create_descriptor_set(&mut self, creation: &DescriptorSetCreation) -> Result<DescriptorSetHandle, DeviceError> {
+        let Some(layout) = self.descriptor_set_layouts.access_resource(creation.layout) else {
+            return Err(DeviceError::InvalidCreation("descriptor set layout is not valid"));
+        };
+        if creation.layout == self.bindless_descriptor_set_layout {
+            return Err(DeviceError::InvalidCreation("the bindless layout only has the set from bindless_descriptor_set"));
+        }
+        let vk_layout = layout.vk_descriptor_set_layout;
+        let Some(handle) = self.descriptor_sets.obtain_resource(DescriptorSet::default()) else {
+            return Err(DeviceError::ResourcePoolFull(resource_deletion_type::Enum::DescriptorSet));
+        };
+
+        let vk_descriptor_set = match self.allocate_vk_descriptor_set(vk_layout) {
+            Ok(vk_descriptor_set) => vk_descriptor_set,
+            Err(error) => {
+                self.descriptor_sets.release_resource(handle);
+                return Err(error);
+            }
+        };
+        self.write_vk_descriptor_set(vk_descriptor_set, creation.layout, &creation.resources, &creation.samplers, creation.name);
+        if let Some(name) = creation.name {
+            self.set_resource_name(vk_descriptor_set, name);
+        }
+
+        *self.descriptor_sets.access_resource_mut(handle).unwrap() = DescriptorSet {
+            vk_descriptor_set,
+            resources: creation.resources,
+            samplers: creation.samplers,
+            layout: creation.layout,
+            num_resources: creation.num_resources,
+            handle,
+            name: creation.name.map(str::to_string),
+        };
+        Ok(handle)
+    }
+
+    fn allocate_vk_descriptor_set(&self, vk_layout: vk::DescriptorSetLayout) -> Result<vk::DescriptorSet, DeviceError> {
+        let layouts = [vk_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.vulkan_descriptor_pool)
+            .set_layouts(&layouts);
+        Ok(unsafe { self.vulkan_device.allocate_descriptor_sets(&allocate_info)?[0] })
+    }
+
+    // Writes every binding of the layout. Empty bindings, and array elements past the first, get the dummy resources
+    // so shaders never read an unwritten descriptor.
+    fn write_vk_descriptor_set(&self, vk_descriptor_set: vk::DescriptorSet, layout: DescriptorSetLayoutHandle, resources: &[DescriptorResource; K_MAX_DESCRIPTORS_PER_SET],
+                               samplers: &[SamplerHandle; K_MAX_DESCRIPTORS_PER_SET], name: Option<&str>) {
+        let Some(bindings) = self.descriptor_set_layouts.access_resource(layout).and_then(|layout| layout.bindings.as_ref()) else {
+            return;
+        };
+        let name = name.unwrap_or("unnamed");
+
+        let mut image_infos = Vec::with_capacity(bindings.len());
+        let mut buffer_infos = Vec::with_capacity(bindings.len());
+        for binding in bindings {
+            let resource = resources.get(binding.start as usize).copied().unwrap_or_default();
+            let sampler = samplers.get(binding.start as usize).copied().unwrap_or(K_INVALID_SAMPLER);
+            match binding.ty {
+                vk::DescriptorType::SAMPLER | vk::DescriptorType::COMBINED_IMAGE_SAMPLER | vk::DescriptorType::SAMPLED_IMAGE | vk::DescriptorType::STORAGE_IMAGE => {
+                    let texture = match resource {
+                        DescriptorResource::Texture(texture) if self.textures.is_valid(texture) => Some(texture),
+                        DescriptorResource::Empty => None,
+                        _ => {
+                            error!("Descriptor set {} binding {} needs a valid texture, using the dummy one", name, binding.start);
+                            None
+                        }
+                    };
+                    if texture.is_none() && binding.ty == vk::DescriptorType::STORAGE_IMAGE {
+                        // The dummy texture is never in the general layout storage images need.
+                        error!("Descriptor set {} leaves storage image binding {} empty", name, binding.start);
+                        continue;
+                    }
+                    let element = |texture: TextureHandle| {
+                        let texture = self.textures.access_resource(texture).unwrap();
+                        let sampler = self.samplers.access_resource(sampler)
+                            .or_else(|| self.samplers.access_resource(self.default_sampler))
+                            .map_or(vk::Sampler::null(), |sampler| sampler.vk_sampler);
+                        let image_layout = if binding.ty == vk::DescriptorType::STORAGE_IMAGE {
+                            vk::ImageLayout::GENERAL
+                        } else {
+                            util_to_vk_image_layout(util_sampled_state(texture.vk_format))
+                        };
+                        vk::DescriptorImageInfo { sampler, image_view: texture.vk_image_view, image_layout }
+                    };
+                    let mut infos = vec![element(self.dummy_texture); binding.count.max(1) as usize];
+                    infos[0] = element(texture.unwrap_or(self.dummy_texture));
+                    image_infos.push((binding.start, binding.ty, infos));
+                }
+                vk::DescriptorType::UNIFORM_BUFFER | vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC | vk::DescriptorType::STORAGE_BUFFER | vk::DescriptorType::STORAGE_BUFFER_DYNAMIC => {
+                    let buffer = match resource {
+                        DescriptorResource::Buffer(buffer) if self.buffers.is_valid(buffer) => buffer,
+                        DescriptorResource::Empty => self.dummy_constant_buffer,
+                        _ => {
+                            error!("Descriptor set {} binding {} needs a valid buffer, using the dummy one", name, binding.start);
+                            self.dummy_constant_buffer
+                        }
+                    };
+                    let element = |buffer: BufferHandle| {
+                        let buffer = self.buffers.access_resource(buffer).unwrap();
+                        // Stream buffers live in the dynamic buffer, their offset is given when the set is bound.
+                        let vk_buffer = self.buffers.access_resource(buffer.parent_buffer).map_or(buffer.vk_buffer, |parent| parent.vk_buffer);
+                        vk::DescriptorBufferInfo { buffer: vk_buffer, offset: 0, range: buffer.size.max(1) as vk::DeviceSize }
+                    };
+                    let is_dynamic = matches!(binding.ty, vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC | vk::DescriptorType::STORAGE_BUFFER_DYNAMIC);
+                    if !is_dynamic && self.buffers.access_resource(buffer).is_some_and(|buffer| buffer.usage == resource_usage_type::Enum::Stream) {
+                        warn!("Descriptor set {} binds stream buffer at binding {}, which is not a dynamic buffer binding", name, binding.start);
+                    }
+                    let mut infos = vec![element(self.dummy_constant_buffer); binding.count.max(1) as usize];
+                    infos[0] = element(buffer);
+                    buffer_infos.push((binding.start, binding.ty, infos));
+                }
+                _ => warn!("Descriptor set {} binding {} has unsupported type {:?}, left unwritten", name, binding.start, binding.ty),
+            }
+        }
+
+        let mut writes = Vec::with_capacity(image_infos.len() + buffer_infos.len());
+        for (binding, ty, infos) in &image_infos {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(vk_descriptor_set)
+                .dst_binding(*binding as u32)
+                .descriptor_type(*ty)
+                .image_info(infos));
+        }
+        for (binding, ty, infos) in &buffer_infos {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(vk_descriptor_set)
+                .dst_binding(*binding as u32)
+                .descriptor_type(*ty)
+                .buffer_info(infos));
+        }
+        unsafe { self.vulkan_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    // Queues new contents for the set, applied at the start of the next frame. Until then the set keeps its old
+    // contents. A later update of the same set replaces a pending one.
+    pub(crate) fn update_descriptor_set(&mut self, descriptor_set: DescriptorSetHandle, creation: &DescriptorSetCreation) {
+        if !self.descriptor_sets.is_valid(descriptor_set) {
+            error!("Trying to update invalid descriptor set {:?}", descriptor_set);
+            return;
+        }
+        let update = DescriptorSetUpdate::new(descriptor_set, creation);
+        match self.descriptor_set_updates.iter_mut().find(|pending| pending.descriptor_set == descriptor_set) {
+            Some(pending) => *pending = update,
+            None => self.descriptor_set_updates.push(update),
+        }
+    }
+
+    // Called at frame start, before any command buffer of the frame can bind the sets.
+    pub(crate) fn apply_descriptor_set_updates(&mut self) {
+        for update in std::mem::take(&mut self.descriptor_set_updates) {
+            self.update_descriptor_set_instant(&update);
+        }
+    }
+
+    // Frames in flight may still use the set, so the new contents go into a fresh vk::DescriptorSet and the old
+    // one goes through the deletion queue.
+    fn update_descriptor_set_instant(&mut self, update: &DescriptorSetUpdate) {
+        // Destroyed while the update was pending.
+        let Some(descriptor_set) = self.descriptor_sets.access_resource(update.descriptor_set) else {
+            return;
+        };
+        let layout = descriptor_set.layout;
+        let name = descriptor_set.name.clone();
+        let Some(vk_layout) = self.descriptor_set_layouts.access_resource(layout).map(|layout| layout.vk_descriptor_set_layout) else {
+            error!("Descriptor set {:?} outlived its layout, update dropped", update.descriptor_set);
+            return;
+        };
+
+        let vk_descriptor_set = match self.allocate_vk_descriptor_set(vk_layout) {
+            Ok(vk_descriptor_set) => vk_descriptor_set,
+            Err(error) => {
+                error!("Error allocating descriptor set {:?}, update dropped: {}", update.descriptor_set, error);
+                return;
+            }
+        };
+        self.write_vk_descriptor_set(vk_descriptor_set, layout, &update.resources, &update.samplers, name.as_deref());
+        if let Some(name) = &name {
+            self.set_resource_name(vk_descriptor_set, name);
+        }
+
+        let descriptor_set = self.descriptor_sets.access_resource_mut(update.descriptor_set).unwrap();
+        let retired = std::mem::replace(&mut descriptor_set.vk_descriptor_set, vk_descriptor_set);
+        descriptor_set.resources = update.resources;
+        descriptor_set.samplers = update.samplers;
+        descriptor_set.num_resources = update.resources.iter().filter(|resource| **resource != DescriptorResource::Empty).count() as u32;
+        self.retire_vk_handle(resource_deletion_type::Enum::VkDescriptorSet, retired);
+    }
+
+    // Deferred like destroy_buffer. Pending updates of the set are dropped.
+    pub(crate) fn destroy_descriptor_set(&mut self, descriptor_set: DescriptorSetHandle) {
+        if !self.descriptor_sets.is_valid(descriptor_set) {
+            error!("Trying to free invalid descriptor set {:?}", descriptor_set);
+            return;
+        }
+        self.descriptor_set_updates.retain(|update| update.descriptor_set != descriptor_set);
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty: resource_deletion_type::Enum::DescriptorSet,
+            handle: descriptor_set.index(),
+            generation: descriptor_set.generation(),
+            frame_issued: self.absolute_frame,
+            vk_handle: 0,
+        });
+    }
+
+    fn destroy_descriptor_set_instant(&mut self, descriptor_set: DescriptorSetHandle) {
+        if let Some(descriptor_set) = self.descriptor_sets.release_resource(descriptor_set) {
+            if let Err(result) = unsafe { self.vulkan_device.free_descriptor_sets(self.vulkan_descriptor_pool, &[descriptor_set.vk_descriptor_set]) } {
+                error!("Error freeing descriptor set {:?}: {}", descriptor_set.name, result);
+            }
+        }
+    }
+
+    fn destroy_resource_instant(&mut self, update: &ResourceUpdate) {
+        match update.ty {
+            resource_deletion_type::Enum::Buffer => self.destroy_buffer_instant(BufferHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::Texture => self.destroy_texture_instant(TextureHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::Sampler => self.destroy_sampler_instant(SamplerHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::ShaderState => self.destroy_shader_state_instant(ShaderStateHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::Pipeline => self.destroy_pipeline_instant(PipelineHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::DescriptorSetLayout => self.destroy_descriptor_set_layout_instant(DescriptorSetLayoutHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::DescriptorSet => self.destroy_descriptor_set_instant(DescriptorSetHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::RenderPass => self.destroy_render_pass_instant(RenderPassHandle::from_parts(update.handle, update.generation)),
+            resource_deletion_type::Enum::VkPipeline => unsafe {
+                self.vulkan_device.destroy_pipeline(vk::Pipeline::from_raw(update.vk_handle), self.vulkan_allocation_callbacks);
+            },
+            resource_deletion_type::Enum::VkPipelineLayout => unsafe {
+                self.vulkan_device.destroy_pipeline_layout(vk::PipelineLayout::from_raw(update.vk_handle), self.vulkan_allocation_callbacks);
+            },
+            resource_deletion_type::Enum::VkDescriptorSet => {
+                if let Err(result) = unsafe { self.vulkan_device.free_descriptor_sets(self.vulkan_descriptor_pool, &[vk::DescriptorSet::from_raw(update.vk_handle)]) } {
+                    error!("Error freeing retired descriptor set: {}", result);
+                }
+            }
+            _ => error!("Cannot destroy resources of type {:?}", update.ty),
+        }
+    }
+
+    // For Vulkan objects replaced under a live handle, they may still be in use by frames in flight.
+    fn retire_vk_handle<H: vk::Handle>(&mut self, ty: resource_deletion_type::Enum, vk_handle: H) {
+        self.resource_deletion_queue.push(ResourceUpdate {
+            ty,
+            handle: 0,
+            generation: 0,
+            frame_issued: self.absolute_frame,
+            vk_handle: vk_handle.as_raw(),
+        });
+    }
+
+    // Deletions requested K_MAX_SWAPCHAIN_IMAGES frames ago or more, whose frames new_frame waited for.
+    fn process_resource_deletions(&mut self) {
+        let absolute_frame = self.absolute_frame;
+        let (ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.resource_deletion_queue).into_iter()
+            .partition(|update| update.frame_issued + K_MAX_SWAPCHAIN_IMAGES as u32 <= absolute_frame);
+        self.resource_deletion_queue = pending;
+        for update in &ready {
+            self.destroy_resource_instant(update);
+        }
+    }
+
+    fn process_all_resource_deletions(&mut self) {
+        let updates = std::mem::take(&mut self.resource_deletion_queue);
+        for update in &updates {
+            self.destroy_resource_instant(update);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_headless(&self) -> bool {
+        self.headless
+    }
+
+    #[inline]
+    pub(crate) fn swapchain_extent(&self) -> (u16, u16) {
+        (self.swapchain_width, self.swapchain_height)
+    }
+
+    fn init_pools(&mut self) {
+        self.buffers.init(K_BUFFERS_POOL_SIZE);
+        self.textures.init(K_TEXTURES_POOL_SIZE);
+        self.render_passes.init(K_RENDER_PASSES_POOL_SIZE);
+        self.descriptor_set_layouts.init(K_DESCRIPTOR_SET_LAYOUTS_POOL_SIZE);
+        self.pipelines.init(K_PIPELINES_POOL_SIZE);
+        self.shaders.init(K_SHADERS_POOL_SIZE);
+        self.descriptor_sets.init(K_DESCRIPTOR_SETS_POOL_SIZE);
+        self.samplers.init(K_SAMPLERS_POOL_SIZE);
+    }
+
+    fn shutdown_pools(&mut self) {
+        self.buffers.shutdown();
+        self.textures.shutdown();
+        self.render_passes.shutdown();
+        self.descriptor_set_layouts.shutdown();
+        self.pipelines.shutdown();
+        self.shaders.shutdown();
+        self.descriptor_sets.shutdown();
+        self.samplers.shutdown();
+    }
+
+    resource_access! {
+        buffers: Buffer, BufferHandle => access_buffer, access_buffer_mut;
+        textures: Texture, TextureHandle => access_texture, access_texture_mut;
+        pipelines: Pipeline<'a>, PipelineHandle => access_pipeline, access_pipeline_mut;
+        samplers: Sampler, SamplerHandle => access_sampler, access_sampler_mut;
+        descriptor_set_layouts: DescriptorSetLayout<'a>, DescriptorSetLayoutHandle => access_descriptor_set_layout, access_descriptor_set_layout_mut;
+        descriptor_sets: DescriptorSet, DescriptorSetHandle => access_descriptor_set, access_descriptor_set_mut;
+        render_passes: RenderPass, RenderPassHandle => access_render_pass, access_render_pass_mut;
+        shaders: ShaderState<'a>, ShaderStateHandle => access_shader_state, access_shader_state_mut;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::{command_type, K_MAX_THREADS};
+
+    // Machines without a Vulkan driver skip the GPU tests instead of failing them.
+    fn has_vulkan_device() -> bool {
+        let Ok(entry) = (unsafe { ash::Entry::load() }) else {
+            return false;
+        };
+        let Ok(instance) = (unsafe { entry.create_instance(&vk::InstanceCreateInfo::default(), None) }) else {
+            return false;
+        };
+        let physical_devices = unsafe { instance.enumerate_physical_devices() }.unwrap_or_default();
+        unsafe { instance.destroy_instance(None) };
+        !physical_devices.is_empty()
+    }
+
+    #[test]
+    fn device_sizes_are_clamped_to_16_bits() {
+        let mut creation = DeviceCreation::default();
+        creation.set_headless(70000, 480);
+        assert_eq!((creation.width, creation.height), (u16::MAX, 480));
+        creation.set_headless(1280, u32::MAX);
+        assert_eq!((creation.width, creation.height), (1280, u16::MAX));
+    }
+
+    #[test]
+    fn dynamic_map_size_checks_the_range() {
+        assert_eq!(dynamic_map_size(256, 0, 0), Some(256));
+        assert_eq!(dynamic_map_size(256, 64, 0), Some(192));
+        assert_eq!(dynamic_map_size(256, 64, 192), Some(192));
+        assert_eq!(dynamic_map_size(256, 64, 193), None);
+        assert_eq!(dynamic_map_size(256, 257, 0), None);
+        assert_eq!(dynamic_map_size(256, u32::MAX, 16), None);
+        assert_eq!(dynamic_map_size(256, 16, u32::MAX), None);
+    }
+
+    #[test]
+    fn glsl_define_goes_after_version() {
+        let source = "// header\n#version 450\nvoid main() {\n}\n";
+        let (result, define_line) = add_stage_define(source, "VERTEX", shader_language::Enum::Glsl);
+        assert_eq!(result, "// header\n#version 450\n#define VERTEX\nvoid main() {\n}\n");
+        assert_eq!(define_line, 3);
+
+        // Nothing after #version, the define is appended.
+        let (result, define_line) = add_stage_define("#version 460", "COMPUTE", shader_language::Enum::Glsl);
+        assert_eq!(result, "#version 460\n#define COMPUTE\n");
+        assert_eq!(define_line, 2);
+    }
+
+    #[test]
+    fn hlsl_define_goes_first() {
+        let source = "float4 main() : SV_Target {\n    return 0;\n}";
+        let (result, define_line) = add_stage_define(source, "FRAGMENT", shader_language::Enum::Hlsl);
+        assert_eq!(result, "#define FRAGMENT\nfloat4 main() : SV_Target {\n    return 0;\n}\n");
+        assert_eq!(define_line, 1);
+    }
+
+    #[test]
+    fn diagnostics_point_at_original_lines() {
+        let output = "ERROR: /tmp/shader.vert:5: 'x' : undeclared identifier\n\
+                      ERROR: /tmp/shader.vert:2: '' : syntax error\n\
+                      ERROR: /tmp/shader.vert:3: 'VERTEX' : redefinition\n\
+                      ERROR: 3 compilation errors.  No code generated.";
+        let diagnostics = map_compiler_diagnostics(output, "/tmp/shader.vert", "shaders/mesh.vert", 3);
+        assert_eq!(diagnostics, [
+            "ERROR: shaders/mesh.vert:4: 'x' : undeclared identifier",
+            "ERROR: shaders/mesh.vert:2: '' : syntax error",
+            "ERROR: shaders/mesh.vert:stage define: 'VERTEX' : redefinition",
+        ]);
+
+        // dxc style, line and column, define on the first line.
+        let diagnostics = map_compiler_diagnostics("/tmp/shader.hlsl:7:12: error: unknown type", "/tmp/shader.hlsl", "mesh.hlsl", 1);
+        assert_eq!(diagnostics, ["mesh.hlsl:6:12: error: unknown type"]);
+    }
+
+    fn pipeline_cache_properties() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x2684,
+            pipeline_cache_uuid: [7; vk::UUID_SIZE],
+            ..Default::default()
+        }
+    }
+
+    fn pipeline_cache_header(properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(K_PIPELINE_CACHE_HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_le_bytes());
+        data.extend_from_slice(&properties.vendor_id.to_le_bytes());
+        data.extend_from_slice(&properties.device_id.to_le_bytes());
+        data.extend_from_slice(&properties.pipeline_cache_uuid);
+        // Driver data after the header.
+        data.extend_from_slice(&[0xab; 16]);
+        data
+    }
+
+    fn load_pipeline_cache_bytes(name: &str, data: &[u8], properties: &vk::PhysicalDeviceProperties) -> Option<Vec<u8>> {
+        let path = std::env::temp_dir().join(format!("lynch_pipeline_cache_{}_{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        let result = load_pipeline_cache_data(&path, properties);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn pipeline_cache_accepts_matching_header() {
+        let properties = pipeline_cache_properties();
+        let data = pipeline_cache_header(&properties);
+        assert_eq!(load_pipeline_cache_bytes("valid", &data, &properties), Some(data));
+        assert_eq!(load_pipeline_cache_data(Path::new("/nonexistent/lynch/pipeline.cache"), &properties), None);
+    }
+
+    #[test]
+    fn pipeline_cache_rejects_foreign_headers() {
+        let properties = pipeline_cache_properties();
+        let data = pipeline_cache_header(&properties);
+        assert_eq!(load_pipeline_cache_bytes("truncated", &data[..K_PIPELINE_CACHE_HEADER_SIZE - 1], &properties), None);
+        assert_eq!(load_pipeline_cache_bytes("empty", &[], &properties), None);
+
+        let other_vendor = vk::PhysicalDeviceProperties { vendor_id: 0x1002, ..properties };
+        assert_eq!(load_pipeline_cache_bytes("vendor", &data, &other_vendor), None);
+        let other_device = vk::PhysicalDeviceProperties { device_id: 0x2685, ..properties };
+        assert_eq!(load_pipeline_cache_bytes("device", &data, &other_device), None);
+        let mut other_uuid = properties;
+        other_uuid.pipeline_cache_uuid[15] = 8;
+        assert_eq!(load_pipeline_cache_bytes("uuid", &data, &other_uuid), None);
+
+        // Fields written big endian are not the same header.
+        let mut big_endian = data.clone();
+        big_endian[8..12].copy_from_slice(&properties.vendor_id.to_be_bytes());
+        assert_eq!(load_pipeline_cache_bytes("big_endian", &big_endian, &properties), None);
+    }
+
+    // Compute shader with an empty main and a 1x1x1 local size.
+    fn empty_compute_shader() -> Vec<u8> {
+        let words = [
+            K_SPIRV_MAGIC, 0x0001_0000, 0, 5, 0,
+            (2 << 16) | 17, 1,                                     // OpCapability Shader
+            (3 << 16) | 14, 0, 1,                                  // OpMemoryModel Logical GLSL450
+            (5 << 16) | 15, 5, 1, u32::from_le_bytes(*b"main"), 0, // OpEntryPoint GLCompute %1 "main"
+            (6 << 16) | 16, 1, 17, 1, 1, 1,                        // OpExecutionMode %1 LocalSize 1 1 1
+            (2 << 16) | 19, 2,                                     // %2 = OpTypeVoid
+            (3 << 16) | 33, 3, 2,                                  // %3 = OpTypeFunction %2
+            (5 << 16) | 54, 2, 1, 0, 3,                            // %1 = OpFunction %2 None %3
+            (2 << 16) | 248, 4,                                    // %4 = OpLabel
+            (1 << 16) | 253,                                       // OpReturn
+            (1 << 16) | 56,                                        // OpFunctionEnd
+        ];
+        words.iter().flat_map(|word: &u32| word.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn headless_device_creates_resources_and_runs_a_frame() {
+        if !has_vulkan_device() {
+            eprintln!("No Vulkan device available, skipping");
+            return;
+        }
+
+        let mut creation = DeviceCreation::default();
+        creation.set_headless(64, 64);
+        let mut gpu = GpuDevice::init(&creation).unwrap();
+        assert!(gpu.is_headless());
+
+        let mut buffer_creation = BufferCreation::default();
+        buffer_creation.set(vk::BufferUsageFlags::UNIFORM_BUFFER, resource_usage_type::Enum::Dynamic, 256).set_name("test_buffer");
+        let buffer = gpu.create_buffer(&buffer_creation).unwrap();
+
+        let mut texture_creation = TextureCreation::default();
+        texture_creation.set_size(16, 16, 1).set_flags(1, 0).set_format_type(vk::Format::R8G8B8A8_UNORM, texture_type::Enum::Texture2D).set_name("test_texture");
+        let texture = gpu.create_texture(&texture_creation).unwrap();
+
+        let shader = empty_compute_shader();
+        let mut pipeline_creation = PipelineCreation::new();
+        pipeline_creation.shaders.set_name("test_compute").add_stage(&shader, shader.len() as u32, vk::ShaderStageFlags::COMPUTE).set_spv_input(true);
+        pipeline_creation.set_name("test_pipeline");
+        let pipeline = gpu.create_pipeline(&pipeline_creation).unwrap();
+
+        for _ in 0..K_MAX_SWAPCHAIN_IMAGES + 1 {
+            gpu.new_frame().unwrap();
+            gpu.present().unwrap();
+        }
+
+        gpu.destroy_pipeline(pipeline);
+        gpu.destroy_texture(texture);
+        gpu.destroy_buffer(buffer);
+        gpu.shutdown();
+    }
+
+    #[test]
+    fn command_buffer_rejects_misuse() {
+        if !has_vulkan_device() {
+            eprintln!("No Vulkan device available, skipping");
+            return;
+        }
+
+        let mut creation = DeviceCreation::default();
+        creation.set_headless(64, 64);
+        let mut gpu = GpuDevice::init(&creation).unwrap();
+        let shader = empty_compute_shader();
+        let mut pipeline_creation = PipelineCreation::new();
+        pipeline_creation.shaders.set_name("misuse_compute").add_stage(&shader, shader.len() as u32, vk::ShaderStageFlags::COMPUTE).set_spv_input(true);
+        pipeline_creation.set_name("misuse_pipeline");
+        let pipeline = gpu.create_pipeline(&pipeline_creation).unwrap();
+        let mut buffer_creation = BufferCreation::default();
+        buffer_creation.set(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST, resource_usage_type::Enum::Immutable, 64).set_name("misuse_buffer");
+        let buffer = gpu.create_buffer(&buffer_creation).unwrap();
+        let pass = gpu.get_swapchain_pass();
+        gpu.new_frame().unwrap();
+
+        let mut command_buffer = gpu.get_command_buffer(0, false).unwrap();
+        assert!(matches!(command_buffer.dispatch(1, 1, 1), Err(CommandBufferError::NotRecording(command_type::Enum::Dispatch))));
+        assert!(matches!(command_buffer.bind_pipeline(pipeline), Err(CommandBufferError::NotRecording(command_type::Enum::BindPipeline))));
+
+        command_buffer.begin().unwrap();
+        assert!(matches!(command_buffer.dispatch(1, 1, 1), Err(CommandBufferError::NoPipeline(command_type::Enum::Dispatch))));
+        assert!(matches!(command_buffer.bind_descriptor_set(&[], &[]), Err(CommandBufferError::NoPipeline(command_type::Enum::BindResourceSet))));
+        assert!(matches!(command_buffer.draw(0, 3, 0, 1), Err(CommandBufferError::NoRenderPass(command_type::Enum::Draw))));
+
+        command_buffer.bind_pipeline(pipeline).unwrap();
+        command_buffer.dispatch(1, 1, 1).unwrap();
+        // A compute pipeline drawing in a graphics pass, and compute work or copies inside one.
+        command_buffer.bind_pass(pass).unwrap();
+        assert!(matches!(command_buffer.draw_indexed(3, 1, 0, 0, 0), Err(CommandBufferError::WrongPipelineType(command_type::Enum::DrawIndexed))));
+        assert!(matches!(command_buffer.dispatch(1, 1, 1), Err(CommandBufferError::InsideRenderPass(command_type::Enum::Dispatch))));
+        assert!(matches!(command_buffer.copy_buffer(buffer, 0, buffer, 32, 16), Err(CommandBufferError::InsideRenderPass(command_type::Enum::CopyResource))));
+        command_buffer.end().unwrap();
+        assert!(matches!(command_buffer.draw(0, 3, 0, 1), Err(CommandBufferError::NotRecording(command_type::Enum::Draw))));
+        command_buffer.queue().unwrap();
+        gpu.present().unwrap();
+
+        gpu.destroy_buffer(buffer);
+        gpu.destroy_pipeline(pipeline);
+        gpu.shutdown();
+    }
+
+    #[test]
+    fn command_buffer_ring_records_in_parallel() {
+        if !has_vulkan_device() {
+            eprintln!("No Vulkan device available, skipping");
+            return;
+        }
+
+        let mut creation = DeviceCreation::default();
+        creation.set_headless(64, 64);
+        let mut gpu = GpuDevice::init(&creation).unwrap();
+        gpu.new_frame().unwrap();
+
+        const BUFFERS_PER_THREAD: usize = 3;
+        let ring = gpu.command_buffer_ring();
+        let handed_out: Vec<vk::CommandBuffer> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..K_MAX_THREADS).map(|thread_index| {
+                let ring = Arc::clone(&ring);
+                scope.spawn(move || (0..BUFFERS_PER_THREAD).map(|_| {
+                    let vk_command_buffer = ring.get_command_buffer(thread_index, true).unwrap();
+                    unsafe { ring.vulkan_device().end_command_buffer(vk_command_buffer).unwrap() };
+                    ring.queue(vk_command_buffer);
+                    vk_command_buffer
+                }).collect::<Vec<_>>())
+            }).collect();
+            threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+        });
+
+        let mut distinct = handed_out.clone();
+        distinct.sort_by_key(|vk_command_buffer| vk_command_buffer.as_raw());
+        distinct.dedup();
+        assert_eq!(distinct.len(), K_MAX_THREADS * BUFFERS_PER_THREAD);
+        let mut queued = ring.take_queued();
+        queued.sort_by_key(|vk_command_buffer| vk_command_buffer.as_raw());
+        assert_eq!(queued, distinct);
+        assert!(ring.take_queued().is_empty());
+
+        drop(ring);
+        gpu.present().unwrap();
+        gpu.shutdown();
+    }
+
+    // Saves data to path with a modification time offset seconds from now, so every save is seen as a change.
+    fn save_shader(path: &Path, data: &[u8], offset: u64) {
+        fs::write(path, data).unwrap();
+        let modified = std::time::SystemTime::now() + std::time::Duration::from_secs(offset);
+        fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn shader_reload_keeps_the_previous_version_on_errors() {
+        if !has_vulkan_device() {
+            eprintln!("No Vulkan device available, skipping");
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!("lynch_shader_reload_{}.comp.spv", std::process::id()));
+        let shader = empty_compute_shader();
+        save_shader(&path, &shader, 0);
+        let mut creation = DeviceCreation::default();
+        creation.set_headless(64, 64).set_shader_reload(ShaderReloadConfiguration { poll_interval: std::time::Duration::ZERO });
+        let mut gpu = GpuDevice::init(&creation).unwrap();
+
+        let mut pipeline_creation = PipelineCreation::new();
+        pipeline_creation.shaders.set_name("reloaded_compute").add_stage(&shader, shader.len() as u32, vk::ShaderStageFlags::COMPUTE)
+            .set_stage_path(&path).set_spv_input(true);
+        pipeline_creation.set_name("reloaded_pipeline");
+        let pipeline = gpu.create_pipeline(&pipeline_creation).unwrap();
+        let shader_state = gpu.access_pipeline(pipeline).unwrap().shader_state;
+        let vk_pipeline = gpu.access_pipeline(pipeline).unwrap().vk_pipeline;
+
+        // Driven by hand instead of through new_frame, to see what it reloads.
+        let mut shader_reload = gpu.shader_reload.take().unwrap();
+        assert!(shader_reload.update(&mut gpu).is_empty());
+
+        save_shader(&path, b"not spir-v", 10);
+        assert!(shader_reload.update(&mut gpu).is_empty());
+        assert!(gpu.access_shader_state(shader_state).is_some());
+        assert_eq!(gpu.access_pipeline(pipeline).unwrap().vk_pipeline, vk_pipeline);
+        // The broken file is not retried until it is saved again.
+        assert!(shader_reload.update(&mut gpu).is_empty());
+
+        save_shader(&path, &shader, 20);
+        assert_eq!(shader_reload.update(&mut gpu), [shader_state]);
+        assert_eq!(gpu.access_pipeline(pipeline).unwrap().shader_state, shader_state);
+        assert_ne!(gpu.access_pipeline(pipeline).unwrap().vk_pipeline, vk_pipeline);
+        let is_retired = |gpu: &GpuDevice| gpu.resource_deletion_queue.iter().any(|update| update.vk_handle == vk_pipeline.as_raw());
+        assert!(is_retired(&gpu));
+        gpu.shader_reload = Some(shader_reload);
+
+        // The old pipeline is destroyed once the frames that could use it are done.
+        for _ in 0..K_MAX_SWAPCHAIN_IMAGES + 1 {
+            gpu.new_frame().unwrap();
+            gpu.present().unwrap();
+        }
+        assert!(!is_retired(&gpu));
+
+        gpu.destroy_pipeline(pipeline);
+        gpu.shutdown();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn barriers_are_recorded_until_the_bound_pass_begins() {
+        if !has_vulkan_device() {
+            eprintln!("No Vulkan device available, skipping");
+            return;
+        }
+
+        let mut creation = DeviceCreation::default();
+        creation.set_headless(64, 64).set_auto_barriers(true);
+        let mut gpu = GpuDevice::init(&creation).unwrap();
+
+        let mut texture_creation = TextureCreation::default();
+        texture_creation.set_size(16, 16, 1).set_flags(1, texture_flags::Mask::RenderTargetMask as u8)
+            .set_format_type(vk::Format::R8G8B8A8_UNORM, texture_type::Enum::Texture2D).set_name("previous_output");
+        let previous_output = gpu.create_texture(&texture_creation).unwrap();
+        texture_creation.set_name("output");
+        let output = gpu.create_texture(&texture_creation).unwrap();
+        let mut pass_creation = RenderPassCreation::new();
+        pass_creation.add_render_texture(output).set_scaling(1.0, 1.0, 0).set_name("test_pass");
+        pass_creation.color_operation = render_pass_operation::Enum::Clear;
+        let pass = gpu.create_render_pass(&pass_creation).unwrap();
+
+        gpu.new_frame().unwrap();
+        let mut command_buffer = gpu.get_command_buffer(0, true).unwrap();
+        command_buffer.bind_pass(pass).unwrap();
+        // The usual bind_pass first order, the previous output is still moved to a shader read before the pass begins.
+        command_buffer.transition(previous_output, ResourceState::RESOURCE_STATE_SHADER_RESOURCE).unwrap();
+        // Ending a pass without draws still begins it for its clear.
+        command_buffer.end_current_render_pass();
+        command_buffer.queue().unwrap();
+        assert_eq!(gpu.access_texture(previous_output).unwrap().state, ResourceState::RESOURCE_STATE_SHADER_RESOURCE);
+        assert_eq!(gpu.access_texture(output).unwrap().state, ResourceState::RESOURCE_STATE_RENDER_TARGET);
+        gpu.present().unwrap();
+
+        gpu.destroy_render_pass(pass);
+        gpu.destroy_texture(output);
+        gpu.destroy_texture(previous_output);
+        gpu.shutdown();
+    }
+}
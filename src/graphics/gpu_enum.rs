@@ -485,13 +485,14 @@ pub(crate) mod command_type {
         ClearStencil,
         BeginPass,
         EndPass,
+        Barrier,
         Count,
     }
 
     pub const S_VALUE_NAMES: [&str; (Enum::Count as usize) + 1] = [
         "BindPipeline", "BindResourceTable", "BindVertexBuffer", "BindIndexBuffer", "BindResourceSet",
         "Draw", "DrawIndexed", "DrawInstanced", "DrawIndexedInstanced", "Dispatch", "CopyResource",
-        "SetScissor", "SetViewport", "Clear", "ClearDepth", "ClearStencil", "BeginPass", "EndPass", "Count"
+        "SetScissor", "SetViewport", "Clear", "ClearDepth", "ClearStencil", "BeginPass", "EndPass", "Barrier", "Count"
     ];
 
     pub fn to_string(e: Enum) -> &'static str {
@@ -537,6 +538,50 @@ pub(crate) mod texture_flags {
     }
 }
 
+pub(crate) mod texture_format {
+    use ash::vk;
+
+    pub fn is_depth_stencil(value: vk::Format) -> bool {
+        matches!(value, vk::Format::D16_UNORM_S8_UINT | vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT)
+    }
+
+    pub fn is_depth_only(value: vk::Format) -> bool {
+        matches!(value, vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT)
+    }
+
+    pub fn is_stencil_only(value: vk::Format) -> bool {
+        value == vk::Format::S8_UINT
+    }
+
+    pub fn has_depth(value: vk::Format) -> bool {
+        is_depth_only(value) || is_depth_stencil(value)
+    }
+
+    pub fn has_stencil(value: vk::Format) -> bool {
+        is_stencil_only(value) || is_depth_stencil(value)
+    }
+
+    pub fn has_depth_or_stencil(value: vk::Format) -> bool {
+        has_depth(value) || has_stencil(value)
+    }
+
+    // Bytes per texel of uncompressed color formats, 0 for the ones uploads do not support.
+    pub fn bytes_per_pixel(value: vk::Format) -> u32 {
+        match value {
+            vk::Format::R8_UNORM | vk::Format::R8_SNORM | vk::Format::R8_UINT | vk::Format::R8_SINT | vk::Format::R8_SRGB => 1,
+            vk::Format::R8G8_UNORM | vk::Format::R8G8_SNORM | vk::Format::R8G8_UINT | vk::Format::R8G8_SINT
+            | vk::Format::R16_UNORM | vk::Format::R16_SFLOAT | vk::Format::R16_UINT | vk::Format::R16_SINT => 2,
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SNORM | vk::Format::R8G8B8A8_UINT | vk::Format::R8G8B8A8_SINT | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB | vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::B10G11R11_UFLOAT_PACK32
+            | vk::Format::R16G16_UNORM | vk::Format::R16G16_SFLOAT | vk::Format::R32_SFLOAT | vk::Format::R32_UINT | vk::Format::R32_SINT => 4,
+            vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_SFLOAT | vk::Format::R16G16B16A16_UINT
+            | vk::Format::R32G32_SFLOAT | vk::Format::R32G32_UINT | vk::Format::R32G32_SINT => 8,
+            vk::Format::R32G32B32A32_SFLOAT | vk::Format::R32G32B32A32_UINT | vk::Format::R32G32B32A32_SINT => 16,
+            _ => 0,
+        }
+    }
+}
+
 pub(crate) mod pipeline_stage {
     #[derive(Debug, Copy, Clone, PartialEq)]
     pub enum Enum {
@@ -581,6 +626,19 @@ pub(crate) mod resource_deletion_type {
         DescriptorSet,
         RenderPass,
         ShaderState,
+        VkPipeline,
+        VkPipelineLayout,
+        VkDescriptorSet,
+        Count,
+    }
+}
+
+// Source language of shaders compiled at runtime, GLSL goes through glslangValidator and HLSL through dxc.
+pub(crate) mod shader_language {
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum Enum {
+        Glsl,
+        Hlsl,
         Count,
     }
 }
@@ -597,7 +655,7 @@ pub(crate) mod present_mode {
 }
 
 pub(crate) mod render_pass_operation {
-    #[derive(Debug, Copy, Clone, PartialEq)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub enum Enum {
         DontCare,
         Load,
@@ -606,78 +664,36 @@ pub(crate) mod render_pass_operation {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-#[allow(non_camel_case_types)]
-pub(crate) enum ResourceState {
-    RESOURCE_STATE_UNDEFINED = 0,
-    RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER = 0x1,
-    RESOURCE_STATE_INDEX_BUFFER = 0x2,
-    RESOURCE_STATE_RENDER_TARGET = 0x4,
-    RESOURCE_STATE_UNORDERED_ACCESS = 0x8,
-    RESOURCE_STATE_DEPTH_WRITE = 0x10,
-    RESOURCE_STATE_DEPTH_READ = 0x20,
-    RESOURCE_STATE_NON_PIXEL_SHADER_RESOURCE = 0x40,
-    RESOURCE_STATE_PIXEL_SHADER_RESOURCE = 0x80,
-    RESOURCE_STATE_SHADER_RESOURCE = 0x40 | 0x80,
-    RESOURCE_STATE_STREAM_OUT = 0x100,
-    RESOURCE_STATE_INDIRECT_ARGUMENT = 0x200,
-    RESOURCE_STATE_COPY_DEST = 0x400,
-    RESOURCE_STATE_COPY_SOURCE = 0x800,
-    RESOURCE_STATE_GENERIC_READ = 0x1 | 0x2 | 0x40 | 0x80 | 0x200 | 0x800,
-    RESOURCE_STATE_PRESENT = 0x1000,
-    RESOURCE_STATE_COMMON = 0x2000,
-    RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE = 0x4000,
-    RESOURCE_STATE_SHADING_RATE_SOURCE = 0x8000,
-}
-#[deny(non_camel_case_types)]
-
-impl std::ops::BitOr for ResourceState {
-    type Output = Self;
-
-    fn bitor(self, rhs: Self) -> Self::Output {
-        unsafe {
-            std::mem::transmute(self as u16 | rhs as u16)
-        }
-    }
-}
-
-
-impl std::ops::BitAnd for ResourceState {
-    type Output = Self;
-
-    fn bitand(self, rhs: Self) -> Self::Output {
-        unsafe {
-            std::mem::transmute(self as u16 & rhs as u16)
-        }
+bitflags::bitflags! {
+    // Combinations are valid states, GENERIC_READ for example is every read only state at once.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+    pub(crate) struct ResourceState: u32 {
+        const RESOURCE_STATE_UNDEFINED = 0;
+        const RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER = 0x1;
+        const RESOURCE_STATE_INDEX_BUFFER = 0x2;
+        const RESOURCE_STATE_RENDER_TARGET = 0x4;
+        const RESOURCE_STATE_UNORDERED_ACCESS = 0x8;
+        const RESOURCE_STATE_DEPTH_WRITE = 0x10;
+        const RESOURCE_STATE_DEPTH_READ = 0x20;
+        const RESOURCE_STATE_NON_PIXEL_SHADER_RESOURCE = 0x40;
+        const RESOURCE_STATE_PIXEL_SHADER_RESOURCE = 0x80;
+        const RESOURCE_STATE_SHADER_RESOURCE = 0x40 | 0x80;
+        const RESOURCE_STATE_STREAM_OUT = 0x100;
+        const RESOURCE_STATE_INDIRECT_ARGUMENT = 0x200;
+        const RESOURCE_STATE_COPY_DEST = 0x400;
+        const RESOURCE_STATE_COPY_SOURCE = 0x800;
+        const RESOURCE_STATE_GENERIC_READ = 0x1 | 0x2 | 0x40 | 0x80 | 0x200 | 0x800;
+        const RESOURCE_STATE_PRESENT = 0x1000;
+        const RESOURCE_STATE_COMMON = 0x2000;
+        const RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE = 0x4000;
+        const RESOURCE_STATE_SHADING_RATE_SOURCE = 0x8000;
     }
 }
 
 impl ResourceState {
-    pub fn from_bits(bits: u32) -> ResourceState {
-        match bits {
-            0 => ResourceState::RESOURCE_STATE_UNDEFINED,
-            0x1 => ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER,
-            0x2 => ResourceState::RESOURCE_STATE_INDEX_BUFFER,
-            0x4 => ResourceState::RESOURCE_STATE_RENDER_TARGET,
-            0x8 => ResourceState::RESOURCE_STATE_UNORDERED_ACCESS,
-            0x10 => ResourceState::RESOURCE_STATE_DEPTH_WRITE,
-            0x20 => ResourceState::RESOURCE_STATE_DEPTH_READ,
-            0x40 => ResourceState::RESOURCE_STATE_NON_PIXEL_SHADER_RESOURCE,
-            0x80 => ResourceState::RESOURCE_STATE_PIXEL_SHADER_RESOURCE,
-            0xC0 => ResourceState::RESOURCE_STATE_SHADER_RESOURCE,
-            0x100 => ResourceState::RESOURCE_STATE_STREAM_OUT,
-            0x200 => ResourceState::RESOURCE_STATE_INDIRECT_ARGUMENT,
-            0x400 => ResourceState::RESOURCE_STATE_COPY_DEST,
-            0x800 => ResourceState::RESOURCE_STATE_COPY_SOURCE,
-            0xAC3 => ResourceState::RESOURCE_STATE_GENERIC_READ,
-            0x1000 => ResourceState::RESOURCE_STATE_PRESENT,
-            0x2000 => ResourceState::RESOURCE_STATE_COMMON,
-            0x4000 => ResourceState::RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE,
-            0x8000 => ResourceState::RESOURCE_STATE_SHADING_RATE_SOURCE,
-            _ => ResourceState::RESOURCE_STATE_UNDEFINED, // Handle unknown bits
-        }
+    // States the GPU writes in, going in or out of them always needs a barrier.
+    pub fn is_write(&self) -> bool {
+        self.intersects(ResourceState::RESOURCE_STATE_RENDER_TARGET | ResourceState::RESOURCE_STATE_UNORDERED_ACCESS | ResourceState::RESOURCE_STATE_DEPTH_WRITE
+                        | ResourceState::RESOURCE_STATE_STREAM_OUT | ResourceState::RESOURCE_STATE_COPY_DEST)
     }
-    pub fn contains(&self, other: ResourceState) -> bool {
-        (*self & other) == other
-    }   
-}
\ No newline at end of file
+}
@@ -1,39 +1,49 @@
+use std::path::Path;
+
 use ash::vk;
-use super::{color_write_enabled, fill_mode, pipeline_stage, queue_type, render_pass_operation, render_pass_type, resource_deletion_type, resource_usage_type, texture_type, vertex_component_format, vertex_input_rate, ResourceState};
+use crate::fundamental::PoolHandle;
+use super::{color_write_enabled, cull_mode, fill_mode, logic_operation, topology_type, pipeline_stage, queue_type, render_pass_operation, render_pass_type, resource_deletion_type, resource_usage_type, shader_language, texture_format, texture_type, vertex_component_format, vertex_input_rate, ResourceState};
+use super::spirv_parser::ParseResult;
 
 const K_INVALID_INDEX: u32 = 0xffffffff;
 
 pub(crate) type ResourceHandle = u32;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct BufferHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 impl Default for BufferHandle {
     #[inline]
     fn default() -> Self {
         BufferHandle {
             index: Default::default(),
+            generation: 0,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+// In bindless mode index() is also where the texture sits in the global texture array.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct TextureHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 impl Default for TextureHandle{
     #[inline]
     fn default() -> Self {
         TextureHandle {
             index: Default::default(),
+            generation: 0,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct ShaderStateHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 
 impl Default for ShaderStateHandle {
@@ -41,43 +51,50 @@ impl Default for ShaderStateHandle {
     fn default() -> Self {
         ShaderStateHandle {
             index: Default::default(),
+            generation: 0,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct SamplerHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 impl  Default for SamplerHandle{
     #[inline]
     fn default() -> Self {
         SamplerHandle {
             index: Default::default(),
+            generation: 0,
         }
     }
 }
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct DescriptorSetLayoutHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 impl Default for DescriptorSetLayoutHandle{
     #[inline]
     fn default() -> Self {
         DescriptorSetLayoutHandle {
             index: Default::default(),
+            generation: 0,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct DescriptorSetHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct PipelineHandle {
     index: ResourceHandle,
+    generation: u32,
 }
 
 impl Default for PipelineHandle{
@@ -85,34 +102,68 @@ impl Default for PipelineHandle{
     fn default() -> Self {
         PipelineHandle {
             index: Default::default(),
+            generation: 0,
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) struct RenderPassHandle {
     index: ResourceHandle,
+    generation: u32,
+}
+
+macro_rules! impl_pool_handle {
+    ($($handle:ty),*) => {
+        $(
+            impl PoolHandle for $handle {
+                #[inline]
+                fn from_parts(index: u32, generation: u32) -> Self {
+                    Self { index, generation }
+                }
+                #[inline]
+                fn index(&self) -> u32 {
+                    self.index
+                }
+                #[inline]
+                fn generation(&self) -> u32 {
+                    self.generation
+                }
+            }
+        )*
+    };
 }
 
+impl_pool_handle!(BufferHandle, TextureHandle, ShaderStateHandle, SamplerHandle, DescriptorSetLayoutHandle, DescriptorSetHandle, PipelineHandle, RenderPassHandle);
+
 // Invalid handles
-const K_INVALID_BUFFER: BufferHandle = BufferHandle { index: K_INVALID_INDEX };
-const K_INVALID_TEXTURE: TextureHandle = TextureHandle { index: K_INVALID_INDEX };
-const K_INVALID_SHADER: ShaderStateHandle = ShaderStateHandle { index: K_INVALID_INDEX };
-const K_INVALID_SAMPLER: SamplerHandle = SamplerHandle { index: K_INVALID_INDEX };
-const K_INVALID_LAYOUT: DescriptorSetLayoutHandle = DescriptorSetLayoutHandle { index: K_INVALID_INDEX };
-const K_INVALID_SET: DescriptorSetHandle = DescriptorSetHandle { index: K_INVALID_INDEX };
-const K_INVALID_PIPELINE: PipelineHandle = PipelineHandle { index: K_INVALID_INDEX };
-const K_INVALID_PASS: RenderPassHandle = RenderPassHandle { index: K_INVALID_INDEX };
-
-
-const K_MAX_IMAGE_OUTPUTS: usize = 8;               // Maximum number of images/render_targets/fbo attachments usable.
-const K_MAX_DESCRIPTOR_SET_LAYOUTS: usize = 8;      // Maximum number of layouts in the pipeline.
-const K_MAX_SHADER_STAGES: usize = 5;               // Maximum simultaneous shader stages. Applicable to all different types of pipelines.
-const K_MAX_DESCRIPTORS_PER_SET: usize = 16;        // Maximum list elements for both descriptor set layout and descriptor sets.
+pub(crate) const K_INVALID_BUFFER: BufferHandle = BufferHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_TEXTURE: TextureHandle = TextureHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_SHADER: ShaderStateHandle = ShaderStateHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_SAMPLER: SamplerHandle = SamplerHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_LAYOUT: DescriptorSetLayoutHandle = DescriptorSetLayoutHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_SET: DescriptorSetHandle = DescriptorSetHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_PIPELINE: PipelineHandle = PipelineHandle { index: K_INVALID_INDEX, generation: 0 };
+pub(crate) const K_INVALID_PASS: RenderPassHandle = RenderPassHandle { index: K_INVALID_INDEX, generation: 0 };
+
+
+pub(crate) const K_MAX_IMAGE_OUTPUTS: usize = 8;               // Maximum number of images/render_targets/fbo attachments usable.
+pub(crate) const K_MAX_DESCRIPTOR_SET_LAYOUTS: usize = 8;      // Maximum number of layouts in the pipeline.
+pub(crate) const K_MAX_SHADER_STAGES: usize = 5;               // Maximum simultaneous shader stages. Applicable to all different types of pipelines.
+pub(crate) const K_MAX_DESCRIPTORS_PER_SET: usize = 16;        // Maximum list elements for both descriptor set layout and descriptor sets.
 const K_MAX_VERTEX_STREAMS: usize = 16;
 const K_MAX_VERTEX_ATTRIBUTES: usize = 16;
 
 pub(crate) const K_MAX_SWAPCHAIN_IMAGES: usize = 3;
+// Threads recording command buffers at the same time, each gets its own command pools.
+pub(crate) const K_MAX_THREADS: usize = 8;
+
+// Where shaders find the global arrays in bindless mode, indexed by TextureHandle::index and SamplerHandle::index:
+// layout(set = 1, binding = 10) uniform texture2D textures[];
+// layout(set = 1, binding = 11) uniform sampler samplers[];
+pub(crate) const K_BINDLESS_SET_INDEX: u32 = 1;
+pub(crate) const K_BINDLESS_TEXTURE_BINDING: u32 = 10;
+pub(crate) const K_BINDLESS_SAMPLER_BINDING: u32 = 11;
 
 const K_SUBMIT_HEADER_SENTINEL: u32 = 0xfefeb7ba;
 const K_MAX_RESOURCE_DELETIONS: u32 = 64;
@@ -139,10 +190,10 @@ impl Default for Rect2D {
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct Rect2DInt {
-    x: i16,
-    y: i16,
-    width: u16,
-    height: u16,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
 }
 
 impl Default for Rect2DInt {
@@ -158,9 +209,9 @@ impl Default for Rect2DInt {
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct Viewport {
-    rect: Rect2DInt,
-    min_depth: f32,
-    max_depth: f32,
+    pub rect: Rect2DInt,
+    pub min_depth: f32,
+    pub max_depth: f32,
 }
 
 impl Default for Viewport {
@@ -194,13 +245,13 @@ impl Default for ViewportState {
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct StencilOperationState {
-    fail: vk::StencilOp,
-    pass: vk::StencilOp,
-    depth_fail: vk::StencilOp,
-    compare: vk::CompareOp,
-    compare_mask: u32,
-    write_mask: u32,
-    reference: u32,
+    pub fail: vk::StencilOp,
+    pub pass: vk::StencilOp,
+    pub depth_fail: vk::StencilOp,
+    pub compare: vk::CompareOp,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+    pub reference: u32,
 }
 
 impl Default for StencilOperationState {
@@ -219,12 +270,12 @@ impl Default for StencilOperationState {
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct DepthStencilCreation {
-    front: StencilOperationState,
-    back: StencilOperationState,
-    depth_comparison: vk::CompareOp,
-    depth_enable: bool,
-    depth_write_enable: bool,
-    stencil_enable: bool,
+    pub front: StencilOperationState,
+    pub back: StencilOperationState,
+    pub depth_comparison: vk::CompareOp,
+    pub depth_enable: bool,
+    pub depth_write_enable: bool,
+    pub stencil_enable: bool,
     pad: u8,
 }
 
@@ -243,24 +294,25 @@ impl Default for DepthStencilCreation {
 }
 
 impl DepthStencilCreation {
-    fn set_depth(&mut self, write: bool, comparison_test: vk::CompareOp) -> &mut Self {
+    pub(crate) fn set_depth(&mut self, write: bool, comparison_test: vk::CompareOp) -> &mut Self {
         self.depth_write_enable = write;
         self.depth_comparison = comparison_test;
+        self.depth_enable = true;
         self
     }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct BlendState {
-    source_color: vk::BlendFactor,
-    destination_color: vk::BlendFactor,
-    color_operation: vk::BlendOp,
-    source_alpha: vk::BlendFactor,
-    destination_alpha: vk::BlendFactor,
-    alpha_operation: vk::BlendOp,
-    color_write_mask: color_write_enabled::Mask,
-    blend_enabled: bool,
-    separate_blend: bool,
+    pub source_color: vk::BlendFactor,
+    pub destination_color: vk::BlendFactor,
+    pub color_operation: vk::BlendOp,
+    pub source_alpha: vk::BlendFactor,
+    pub destination_alpha: vk::BlendFactor,
+    pub alpha_operation: vk::BlendOp,
+    pub color_write_mask: color_write_enabled::Mask,
+    pub blend_enabled: bool,
+    pub separate_blend: bool,
     pad: u8,
 }
 
@@ -282,7 +334,7 @@ impl Default for BlendState {
 }
 
 impl BlendState {
-    fn set_color(&mut self, source_color: vk::BlendFactor, destination_color: vk::BlendFactor, color_operation: vk::BlendOp) -> &mut Self {
+    pub(crate) fn set_color(&mut self, source_color: vk::BlendFactor, destination_color: vk::BlendFactor, color_operation: vk::BlendOp) -> &mut Self {
         self.source_color = source_color;
         self.destination_color = destination_color;
         self.color_operation = color_operation;
@@ -290,7 +342,7 @@ impl BlendState {
         self
     }
 
-    fn set_alpha(&mut self, source_alpha: vk::BlendFactor, destination_alpha: vk::BlendFactor, alpha_operation: vk::BlendOp) -> &mut Self {
+    pub(crate) fn set_alpha(&mut self, source_alpha: vk::BlendFactor, destination_alpha: vk::BlendFactor, alpha_operation: vk::BlendOp) -> &mut Self {
         self.source_alpha = source_alpha;
         self.destination_alpha = destination_alpha;
         self.alpha_operation = alpha_operation;
@@ -298,7 +350,7 @@ impl BlendState {
         self
     }
 
-    fn set_color_write_mask(&mut self, value: color_write_enabled::Mask) -> &mut Self {
+    pub(crate) fn set_color_write_mask(&mut self, value: color_write_enabled::Mask) -> &mut Self {
         self.color_write_mask = value;
         self
     }
@@ -306,8 +358,10 @@ impl BlendState {
 
 #[derive(Debug, Clone)]
 pub(crate) struct BlendStateCreation {
-    blend_states: [BlendState; K_MAX_IMAGE_OUTPUTS as usize],
-    active_states: u32,
+    pub blend_states: [BlendState; K_MAX_IMAGE_OUTPUTS],
+    pub active_states: u32,
+    // Replaces blending on every attachment when set, needs the logic_op feature.
+    pub logic_operation: Option<logic_operation::Enum>,
 }
 
 impl Default for BlendStateCreation {
@@ -315,17 +369,24 @@ impl Default for BlendStateCreation {
         BlendStateCreation {
             blend_states: [BlendState::default(); K_MAX_IMAGE_OUTPUTS as usize],
             active_states: 0,
+            logic_operation: None,
         }
     }
 }
 
 impl BlendStateCreation {
-    fn reset(&mut self) -> &mut Self {
+    pub(crate) fn reset(&mut self) -> &mut Self {
         self.active_states = 0;
+        self.logic_operation = None;
+        self
+    }
+
+    pub(crate) fn set_logic_operation(&mut self, operation: logic_operation::Enum) -> &mut Self {
+        self.logic_operation = Some(operation);
         self
     }
 
-    fn add_blend_state(&mut self) -> &mut BlendState {
+    pub(crate) fn add_blend_state(&mut self) -> &mut BlendState {
         let state = &mut self.blend_states[self.active_states as usize];
         self.active_states += 1;
         state
@@ -334,15 +395,15 @@ impl BlendStateCreation {
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct RasterizationCreation {
-    cull_mode: vk::CullModeFlags,
-    front: vk::FrontFace,
-    fill: fill_mode::Enum,
+    pub cull_mode: cull_mode::Enum,
+    pub front: vk::FrontFace,
+    pub fill: fill_mode::Enum,
 }
 
 impl Default for RasterizationCreation {
     fn default() -> Self {
         RasterizationCreation {
-            cull_mode: vk::CullModeFlags::NONE,
+            cull_mode: cull_mode::Enum::None,
             front: vk::FrontFace::COUNTER_CLOCKWISE,
             fill: fill_mode::Enum::Solid,
         }
@@ -351,11 +412,11 @@ impl Default for RasterizationCreation {
 
 #[derive(Debug, Clone)]
 pub(crate) struct BufferCreation {
-    type_flags: vk::BufferUsageFlags,
-    usage: resource_usage_type::Enum,
-    size: u32,
-    initial_data: Option<*mut std::ffi::c_void>,
-    name: Option<String>,
+    pub type_flags: vk::BufferUsageFlags,
+    pub usage: resource_usage_type::Enum,
+    pub size: u32,
+    pub initial_data: Option<*mut std::ffi::c_void>,
+    pub name: Option<String>,
 }
 
 impl Default for BufferCreation {
@@ -371,26 +432,26 @@ impl Default for BufferCreation {
 }
 
 impl BufferCreation {
-    fn reset(&mut self) -> &mut Self {
+    pub(crate) fn reset(&mut self) -> &mut Self {
         self.size = 0;
         self.initial_data = None;
         self.name = None;
         self
     }
 
-    fn set(&mut self, flags: vk::BufferUsageFlags, usage: resource_usage_type::Enum, size: u32) -> &mut Self {
+    pub(crate) fn set(&mut self, flags: vk::BufferUsageFlags, usage: resource_usage_type::Enum, size: u32) -> &mut Self {
         self.type_flags = flags;
         self.usage = usage;
         self.size = size;
         self
     }
 
-    fn set_data(&mut self, data: *mut std::ffi::c_void) -> &mut Self {
+    pub(crate) fn set_data(&mut self, data: *mut std::ffi::c_void) -> &mut Self {
         self.initial_data = Some(data);
         self
     }
 
-    fn set_name(&mut self, name: &str) -> &mut Self {
+    pub(crate) fn set_name(&mut self, name: &str) -> &mut Self {
         self.name = Some(name.to_string());
         self
     }
@@ -398,15 +459,16 @@ impl BufferCreation {
 
 #[derive(Debug, Clone)]
 pub(crate) struct TextureCreation {
-    initial_data: Option<*mut std::ffi::c_void>,
-    width: u16,
-    height: u16,
-    depth: u16,
-    mipmaps: u8,
-    flags: u8,
-    format: vk::Format,
-    texture_type: texture_type::Enum,
-    name: Option<String>,
+    pub initial_data: Option<*mut std::ffi::c_void>,
+    pub width: u16,
+    pub height: u16,
+    pub depth: u16,
+    pub array_layer_count: u16,
+    pub mipmaps: u8,
+    pub flags: u8,
+    pub format: vk::Format,
+    pub texture_type: texture_type::Enum,
+    pub name: Option<String>,
 }
 
 impl Default for TextureCreation {
@@ -416,6 +478,7 @@ impl Default for TextureCreation {
             width: 1,
             height: 1,
             depth: 1,
+            array_layer_count: 1,
             mipmaps: 1,
             flags: 0,
             format: vk::Format::UNDEFINED,
@@ -426,46 +489,76 @@ impl Default for TextureCreation {
 }
 
 impl TextureCreation {
-    fn set_size(&mut self, width: u16, height: u16, depth: u16) -> &mut Self {
+    pub(crate) fn set_size(&mut self, width: u16, height: u16, depth: u16) -> &mut Self {
         self.width = width;
         self.height = height;
         self.depth = depth;
         self
     }
 
-    fn set_flags(&mut self, mipmaps: u8, flags: u8) -> &mut Self {
+    // Layers of array textures, 6 per cube for TextureCubeArray. Texture1D, Texture2D and Texture3D keep 1.
+    pub(crate) fn set_layers(&mut self, array_layer_count: u16) -> &mut Self {
+        self.array_layer_count = array_layer_count;
+        self
+    }
+
+    // Flags are texture_flags::Mask bits.
+    pub(crate) fn set_flags(&mut self, mipmaps: u8, flags: u8) -> &mut Self {
         self.mipmaps = mipmaps;
         self.flags = flags;
         self
     }
 
-    fn set_format_type(&mut self, format: vk::Format, texture_type: texture_type::Enum) -> &mut Self {
+    pub(crate) fn set_format_type(&mut self, format: vk::Format, texture_type: texture_type::Enum) -> &mut Self {
         self.format = format;
         self.texture_type = texture_type;
         self
     }
 
-    fn set_name(&mut self, name: &str) -> &mut Self {
+    pub(crate) fn set_name(&mut self, name: &str) -> &mut Self {
         self.name = Some(name.to_string());
         self
     }
 
-    fn set_data(&mut self, data: *mut std::ffi::c_void) -> &mut Self {
+    // Mip 0 of every layer, tightly packed one layer after the other.
+    pub(crate) fn set_data(&mut self, data: *mut std::ffi::c_void) -> &mut Self {
         self.initial_data = Some(data);
         self
     }
 }
 
 pub(crate) struct SamplerCreation<'a> {
+    pub min_filter: vk::Filter,
+    pub mag_filter: vk::Filter,
+    pub mip_filter: vk::SamplerMipmapMode,
+
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    pub address_mode_w: vk::SamplerAddressMode,
+
+    // 1 or less disables anisotropic filtering.
+    pub max_anisotropy: f32,
+    // Depth comparison, for shadow maps.
+    pub compare_op: Option<vk::CompareOp>,
+    pub border_color: vk::BorderColor,
+    pub mip_lod_bias: f32,
+
+    pub name: Option<&'a str>,
+}
+
+// Everything that makes two samplers different, the name aside.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct SamplerKey {
     min_filter: vk::Filter,
     mag_filter: vk::Filter,
     mip_filter: vk::SamplerMipmapMode,
-
     address_mode_u: vk::SamplerAddressMode,
     address_mode_v: vk::SamplerAddressMode,
     address_mode_w: vk::SamplerAddressMode,
-
-    name: Option<&'a str>,
+    max_anisotropy_bits: u32,
+    compare_op: Option<vk::CompareOp>,
+    border_color: vk::BorderColor,
+    mip_lod_bias_bits: u32,
 }
 
 impl<'a> SamplerCreation<'a> {
@@ -477,10 +570,32 @@ impl<'a> SamplerCreation<'a> {
             address_mode_u: vk::SamplerAddressMode::REPEAT,
             address_mode_v: vk::SamplerAddressMode::REPEAT,
             address_mode_w: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: 1.0,
+            compare_op: None,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            mip_lod_bias: 0.0,
             name: None,
         }
     }
 
+    pub(crate) fn key(&self) -> SamplerKey {
+        // Disabled anisotropy has a single key whatever value was used to disable it.
+        let max_anisotropy = if self.max_anisotropy > 1.0 { self.max_anisotropy } else { 1.0 };
+        SamplerKey {
+            min_filter: self.min_filter,
+            mag_filter: self.mag_filter,
+            mip_filter: self.mip_filter,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            address_mode_w: self.address_mode_w,
+            max_anisotropy_bits: max_anisotropy.to_bits(),
+            compare_op: self.compare_op,
+            border_color: self.border_color,
+            // Normalizes -0.0.
+            mip_lod_bias_bits: (self.mip_lod_bias + 0.0).to_bits(),
+        }
+    }
+
     pub fn set_min_mag_mip(&mut self, min: vk::Filter, mag: vk::Filter, mip: vk::SamplerMipmapMode) -> &mut Self {
         self.min_filter = min;
         self.mag_filter = mag;
@@ -506,15 +621,37 @@ impl<'a> SamplerCreation<'a> {
         self
     }
 
+    pub fn set_anisotropy(&mut self, max_anisotropy: f32) -> &mut Self {
+        self.max_anisotropy = max_anisotropy;
+        self
+    }
+
+    pub fn set_compare_op(&mut self, compare_op: vk::CompareOp) -> &mut Self {
+        self.compare_op = Some(compare_op);
+        self
+    }
+
+    pub fn set_border_color(&mut self, border_color: vk::BorderColor) -> &mut Self {
+        self.border_color = border_color;
+        self
+    }
+
+    pub fn set_lod_bias(&mut self, mip_lod_bias: f32) -> &mut Self {
+        self.mip_lod_bias = mip_lod_bias;
+        self
+    }
+
     pub fn set_name(&mut self, name: &'a str) -> &mut Self {
         self.name = Some(name);
         self
     }
 }
 pub(crate) struct ShaderStage<'a> {
-    code: Option<&'a [u8]>,
-    code_size: u32,
-    ty: vk::ShaderStageFlags,
+    pub code: Option<&'a [u8]>,
+    pub code_size: u32,
+    pub ty: vk::ShaderStageFlags,
+    // File the code was read from, watched by ShaderReloadService.
+    pub path: Option<&'a Path>,
 }
 
 impl<'a> ShaderStage<'a> {
@@ -523,6 +660,7 @@ impl<'a> ShaderStage<'a> {
             code: None,
             code_size: 0,
             ty: vk::ShaderStageFlags::ALL,
+            path: None,
         }
     }
 
@@ -536,15 +674,22 @@ impl<'a> ShaderStage<'a> {
         self.ty = ty;
         self
     }
+
+    pub fn set_path(&mut self, path: &'a Path) -> &mut Self {
+        self.path = Some(path);
+        self
+    }
 }
 
 pub(crate) struct ShaderStateCreation<'a> {
-    stages: [ShaderStage<'a>; K_MAX_SHADER_STAGES], 
+    pub stages: [ShaderStage<'a>; K_MAX_SHADER_STAGES], 
 
-    name: Option<&'a str>,
+    pub name: Option<&'a str>,
 
-    stages_count: u32,
-    spv_input: bool,
+    pub stages_count: u32,
+    pub spv_input: bool,
+    // Only used when spv_input is false.
+    pub source_language: shader_language::Enum,
 }
 
 impl<'a> ShaderStateCreation<'a> {
@@ -554,6 +699,7 @@ impl<'a> ShaderStateCreation<'a> {
             name: None,
             stages_count: 0,
             spv_input: false,
+            source_language: shader_language::Enum::Glsl,
         }
     }
 
@@ -562,6 +708,7 @@ impl<'a> ShaderStateCreation<'a> {
         self.name = None;
         self.stages_count = 0;
         self.spv_input = false;
+        self.source_language = shader_language::Enum::Glsl;
         self
     }
 
@@ -582,20 +729,47 @@ impl<'a> ShaderStateCreation<'a> {
         self.spv_input = value;
         self
     }
+
+    // Source file of the stage added last.
+    pub fn set_stage_path(&mut self, path: &'a Path) -> &mut Self {
+        if self.stages_count > 0 {
+            self.stages[self.stages_count as usize - 1].set_path(path);
+        }
+        self
+    }
+
+    pub fn set_source_language(&mut self, language: shader_language::Enum) -> &mut Self {
+        self.source_language = language;
+        self
+    }
 }
 
+#[derive(Clone)]
 pub(crate) struct DescriptorSetLayoutCreation {
-    bindings: [Binding; K_MAX_DESCRIPTORS_PER_SET],
-    num_bindings: u32,
-    set_index: u32,
-    name: Option<&'static str>,
+    pub bindings: [Binding; K_MAX_DESCRIPTORS_PER_SET],
+    pub num_bindings: u32,
+    pub set_index: u32,
+    pub name: Option<&'static str>,
 }
 
+#[derive(Clone)]
 pub(crate) struct Binding {
-    ty: Option<vk::DescriptorType>,
-    start: u16,
-    count: u16,
-    name: Option<&'static str>,
+    pub ty: Option<vk::DescriptorType>,
+    pub start: u16,
+    pub count: u16,
+    // Owned, names come from shader reflection as well.
+    pub name: Option<String>,
+}
+
+impl Binding {
+    pub fn new(ty: vk::DescriptorType, start: u16, count: u16, name: Option<&str>) -> Self {
+        Binding {
+            ty: Some(ty),
+            start,
+            count,
+            name: name.map(str::to_owned),
+        }
+    }
 }
 
 impl<'a> DescriptorSetLayoutCreation {
@@ -646,22 +820,42 @@ impl Default for Binding {
     }
 }
 
+// What a descriptor set binding points at. Empty bindings are filled with the dummy resources of GpuDevice.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DescriptorResource {
+    #[default]
+    Empty,
+    Texture(TextureHandle),
+    Buffer(BufferHandle),
+}
+
+impl From<TextureHandle> for DescriptorResource {
+    fn from(handle: TextureHandle) -> Self {
+        DescriptorResource::Texture(handle)
+    }
+}
+
+impl From<BufferHandle> for DescriptorResource {
+    fn from(handle: BufferHandle) -> Self {
+        DescriptorResource::Buffer(handle)
+    }
+}
+
 pub(crate) struct DescriptorSetCreation<'a> {
-    resources: [ResourceHandle; K_MAX_DESCRIPTORS_PER_SET], 
-    samplers: [SamplerHandle; K_MAX_DESCRIPTORS_PER_SET],   
-    bindings: [u16; K_MAX_DESCRIPTORS_PER_SET],
+    // Indexed by binding.
+    pub resources: [DescriptorResource; K_MAX_DESCRIPTORS_PER_SET],
+    pub samplers: [SamplerHandle; K_MAX_DESCRIPTORS_PER_SET],
 
-    layout: DescriptorSetLayoutHandle,
-    num_resources: u32,
-    name: Option<&'a str>,
+    pub layout: DescriptorSetLayoutHandle,
+    pub num_resources: u32,
+    pub name: Option<&'a str>,
 }
 
 impl<'a> DescriptorSetCreation<'a> {
     pub fn new() -> Self {
         DescriptorSetCreation {
-            resources: [ResourceHandle::default(); K_MAX_DESCRIPTORS_PER_SET],
-            samplers: [SamplerHandle::default(); K_MAX_DESCRIPTORS_PER_SET],
-            bindings: [0; K_MAX_DESCRIPTORS_PER_SET],
+            resources: [DescriptorResource::Empty; K_MAX_DESCRIPTORS_PER_SET],
+            samplers: [K_INVALID_SAMPLER; K_MAX_DESCRIPTORS_PER_SET],
             layout: DescriptorSetLayoutHandle::default(),
             num_resources: 0,
             name: None,
@@ -669,9 +863,8 @@ impl<'a> DescriptorSetCreation<'a> {
     }
 
     pub fn reset(&mut self) -> &mut Self {
-        self.resources.fill(ResourceHandle::default());
-        self.samplers.fill(SamplerHandle::default());
-        self.bindings.fill(0);
+        self.resources.fill(DescriptorResource::Empty);
+        self.samplers.fill(K_INVALID_SAMPLER);
         self.layout = DescriptorSetLayoutHandle::default();
         self.num_resources = 0;
         self.name = None;
@@ -683,31 +876,30 @@ impl<'a> DescriptorSetCreation<'a> {
         self
     }
 
-    pub fn texture(&mut self, texture: TextureHandle, binding: u16) -> &mut Self {
-        if binding < K_MAX_DESCRIPTORS_PER_SET as u16 {
-            self.resources[binding as usize] = texture.index;
-            self.bindings[binding as usize] = binding;
+    fn set_resource(&mut self, resource: DescriptorResource, sampler: SamplerHandle, binding: u16) {
+        let Some(slot) = self.resources.get_mut(binding as usize) else {
+            return;
+        };
+        if *slot == DescriptorResource::Empty {
             self.num_resources += 1;
         }
+        *slot = resource;
+        self.samplers[binding as usize] = sampler;
+    }
+
+    pub fn texture(&mut self, texture: TextureHandle, binding: u16) -> &mut Self {
+        self.set_resource(DescriptorResource::Texture(texture), K_INVALID_SAMPLER, binding);
         self
     }
 
     pub fn buffer(&mut self, buffer: BufferHandle, binding: u16) -> &mut Self {
-        if binding < K_MAX_DESCRIPTORS_PER_SET as u16 {
-            self.resources[binding as usize] = buffer.index;
-            self.bindings[binding as usize] = binding;
-            self.num_resources += 1;
-        }
+        self.set_resource(DescriptorResource::Buffer(buffer), K_INVALID_SAMPLER, binding);
         self
     }
 
+    // Without a sampler, textures are sampled with the default one.
     pub fn texture_sampler(&mut self, texture: TextureHandle, sampler: SamplerHandle, binding: u16) -> &mut Self {
-        if binding < K_MAX_DESCRIPTORS_PER_SET as u16 {
-            self.resources[binding as usize] = texture.index;
-            self.samplers[binding as usize] = sampler;
-            self.bindings[binding as usize] = binding;
-            self.num_resources += 1;
-        }
+        self.set_resource(DescriptorResource::Texture(texture), sampler, binding);
         self
     }
 
@@ -722,28 +914,30 @@ impl Default for DescriptorSetCreation<'_> {
         DescriptorSetCreation::new()
     }
 }
-pub(crate) struct DescriptorSetUpdate {
-    descriptor_set: DescriptorSetHandle,
 
-    frame_issued: u32,
+// New contents for a descriptor set, written into a fresh vk::DescriptorSet at the start of the next frame.
+pub(crate) struct DescriptorSetUpdate {
+    pub descriptor_set: DescriptorSetHandle,
+    pub resources: [DescriptorResource; K_MAX_DESCRIPTORS_PER_SET],
+    pub samplers: [SamplerHandle; K_MAX_DESCRIPTORS_PER_SET],
 }
 
 impl DescriptorSetUpdate {
-    pub fn new(descriptor_set: DescriptorSetHandle) -> Self {
+    pub fn new(descriptor_set: DescriptorSetHandle, creation: &DescriptorSetCreation) -> Self {
         DescriptorSetUpdate {
             descriptor_set,
-            frame_issued: 0,
+            resources: creation.resources,
+            samplers: creation.samplers,
         }
     }
 }
 
-
-
+#[derive(Debug, Copy, Clone)]
 pub(crate) struct VertexAttribute {
-    location: u16,
-    binding: u16,
-    offset: u32,
-    format: vertex_component_format::Enum,
+    pub location: u16,
+    pub binding: u16,
+    pub offset: u32,
+    pub format: vertex_component_format::Enum,
 }
 
 impl VertexAttribute {
@@ -759,10 +953,11 @@ impl VertexAttribute {
 
 
 
+#[derive(Debug, Copy, Clone)]
 pub(crate) struct VertexStream {
-    binding: u16,
-    stride: u16,
-    input_rate: vertex_input_rate::Enum,
+    pub binding: u16,
+    pub stride: u16,
+    pub input_rate: vertex_input_rate::Enum,
 }
 
 impl VertexStream {
@@ -775,12 +970,13 @@ impl VertexStream {
     }
 }
 
+#[derive(Debug, Clone)]
 pub(crate) struct VertexInputCreation {
-    num_vertex_streams: u32,
-    num_vertex_attributes: u32,
+    pub num_vertex_streams: u32,
+    pub num_vertex_attributes: u32,
 
-    vertex_streams: [VertexStream; K_MAX_VERTEX_STREAMS],
-    vertex_attributes: [VertexAttribute; K_MAX_VERTEX_ATTRIBUTES],
+    pub vertex_streams: [VertexStream; K_MAX_VERTEX_STREAMS],
+    pub vertex_attributes: [VertexAttribute; K_MAX_VERTEX_ATTRIBUTES],
 }
 
 impl VertexInputCreation {
@@ -820,14 +1016,16 @@ impl VertexInputCreation {
 
 
 
+// Also the key of the render pass cache, formats past num_color_formats stay UNDEFINED.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct RenderPassOutput {
-    color_formats: [vk::Format; K_MAX_IMAGE_OUTPUTS], 
-    depth_stencil_format: vk::Format,
-    num_color_formats: u32,
+    pub color_formats: [vk::Format; K_MAX_IMAGE_OUTPUTS], 
+    pub depth_stencil_format: vk::Format,
+    pub num_color_formats: u32,
 
-    color_operation: render_pass_operation::Enum,
-    depth_operation: render_pass_operation::Enum,
-    stencil_operation: render_pass_operation::Enum,
+    pub color_operation: render_pass_operation::Enum,
+    pub depth_operation: render_pass_operation::Enum,
+    pub stencil_operation: render_pass_operation::Enum,
 }
 
 impl Default for RenderPassOutput {
@@ -881,21 +1079,22 @@ impl RenderPassOutput {
 }
 
 pub(crate) struct RenderPassCreation<'a> {
-    num_render_targets: u16,
-    ty: render_pass_type::Enum,
+    pub num_render_targets: u16,
+    pub ty: render_pass_type::Enum,
 
-    output_textures: [TextureHandle; K_MAX_IMAGE_OUTPUTS], // Assuming TextureHandle is defined elsewhere
-    depth_stencil_texture: TextureHandle,
+    pub output_textures: [TextureHandle; K_MAX_IMAGE_OUTPUTS],
+    pub depth_stencil_texture: TextureHandle,
 
-    scale_x: f32,
-    scale_y: f32,
-    resize: u8,
+    // Size relative to the swapchain, the outputs are resized with it when resize is not 0.
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub resize: u8,
 
-    color_operation: render_pass_operation::Enum,
-    depth_operation: render_pass_operation::Enum,
-    stencil_operation: render_pass_operation::Enum,
+    pub color_operation: render_pass_operation::Enum,
+    pub depth_operation: render_pass_operation::Enum,
+    pub stencil_operation: render_pass_operation::Enum,
 
-    name: Option<&'a str>,
+    pub name: Option<&'a str>,
 }
 
 impl<'a> RenderPassCreation<'a> {
@@ -969,17 +1168,19 @@ impl<'a> RenderPassCreation<'a> {
 }
 
 pub(crate) struct PipelineCreation<'a> {
-    rasterization: RasterizationCreation,
-    depth_stencil: DepthStencilCreation,
-    blend_state: BlendStateCreation,
-    vertex_input: VertexInputCreation,
-    shaders: ShaderStateCreation<'a>,
-    render_pass: RenderPassOutput,
-    descriptor_set_layout: [DescriptorSetLayoutHandle; K_MAX_DESCRIPTOR_SET_LAYOUTS], // Assuming K_MAX_DESCRIPTOR_SET_LAYOUTS is defined
-    viewport: Option<&'a ViewportState>, // Assuming ViewportState is defined
+    pub rasterization: RasterizationCreation,
+    pub depth_stencil: DepthStencilCreation,
+    pub blend_state: BlendStateCreation,
+    pub vertex_input: VertexInputCreation,
+    pub shaders: ShaderStateCreation<'a>,
+    pub render_pass: RenderPassOutput,
+    // When empty the layouts are generated from the shader reflection.
+    pub descriptor_set_layout: [DescriptorSetLayoutHandle; K_MAX_DESCRIPTOR_SET_LAYOUTS], // Assuming K_MAX_DESCRIPTOR_SET_LAYOUTS is defined
+    pub viewport: Option<&'a ViewportState>, // Assuming ViewportState is defined
+    pub topology: topology_type::Enum,
 
-    num_active_layouts: u32,
-    name: Option<&'static str>,
+    pub num_active_layouts: u32,
+    pub name: Option<&'static str>,
 }
 
 impl<'a> PipelineCreation<'a> {
@@ -993,11 +1194,22 @@ impl<'a> PipelineCreation<'a> {
             render_pass: RenderPassOutput::new(),
             descriptor_set_layout: [DescriptorSetLayoutHandle::default(); K_MAX_DESCRIPTOR_SET_LAYOUTS], // Assuming DescriptorSetLayoutHandle has a default implementation
             viewport: None,
+            topology: topology_type::Enum::Triangle,
             num_active_layouts: 0,
             name: None,
         }
     }
 
+    pub fn set_topology(&mut self, topology: topology_type::Enum) -> &mut Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn set_name(&mut self, name: &'static str) -> &mut Self {
+        self.name = Some(name);
+        self
+    }
+
     pub fn add_descriptor_set_layout(&mut self, handle: DescriptorSetLayoutHandle) -> &mut Self {
         if self.num_active_layouts < K_MAX_DESCRIPTOR_SET_LAYOUTS as u32 {
             self.descriptor_set_layout[self.num_active_layouts as usize] = handle;
@@ -1319,7 +1531,11 @@ impl ExecutionBarrier {
 pub(crate) struct ResourceUpdate {
     pub ty: resource_deletion_type::Enum, // Assuming ResourceDeletionType::Enum is defined
     pub handle: ResourceHandle, // Assuming ResourceHandle is defined
-    pub current_frame: u32,
+    pub generation: u32,
+    // Absolute frame the deletion was requested in, it runs once that frame is done on the GPU.
+    pub frame_issued: u32,
+    // Raw handle of the Vk* types, objects replaced under a handle that stays alive.
+    pub vk_handle: u64,
 }
 
 pub(crate) struct DeviceStateVulkan {}
@@ -1336,6 +1552,10 @@ pub(crate) struct Buffer {
     pub global_offset: u32,
     pub handle: BufferHandle, // Assuming BufferHandle is defined
     pub parent_buffer: BufferHandle,
+    // Persistent mapping of Dynamic buffers.
+    pub mapped_data: *mut u8,
+    // Last state a barrier or pass left the buffer in.
+    pub state: ResourceState,
     pub name: Option<String>,
 }
 
@@ -1352,6 +1572,8 @@ impl Default for Buffer {
             global_offset: 0,
             handle: BufferHandle::default(),
             parent_buffer: BufferHandle::default(),
+            mapped_data: std::ptr::null_mut(),
+            state: ResourceState::RESOURCE_STATE_UNDEFINED,
             name: None,
         }
     }
@@ -1364,6 +1586,10 @@ pub(crate) struct Sampler {
     pub address_mode_u: vk::SamplerAddressMode,
     pub address_mode_v: vk::SamplerAddressMode,
     pub address_mode_w: vk::SamplerAddressMode,
+    pub max_anisotropy: f32,
+    pub compare_op: Option<vk::CompareOp>,
+    pub border_color: vk::BorderColor,
+    pub mip_lod_bias: f32,
     pub name: Option<String>,
 }
 
@@ -1377,6 +1603,10 @@ impl Default for Sampler {
             address_mode_u: vk::SamplerAddressMode::REPEAT,
             address_mode_v: vk::SamplerAddressMode::REPEAT,
             address_mode_w: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: 1.0,
+            compare_op: None,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+            mip_lod_bias: 0.0,
             name: None,
         }
     }
@@ -1391,11 +1621,14 @@ pub(crate) struct Texture {
     pub width: u16,
     pub height: u16,
     pub depth: u16,
+    pub array_layer_count: u16,
     pub mipmaps: u8,
     pub flags: u8,
     pub handle: TextureHandle, // Assuming TextureHandle is defined
     pub ty: texture_type::Enum, // Assuming TextureType is defined
     pub sampler: Option<Box<Sampler>>, // Using Box to own the sampler
+    // Last state a barrier or pass left the texture in, vk_image_layout follows it.
+    pub state: ResourceState,
     pub name: Option<String>,
 }
 
@@ -1410,11 +1643,13 @@ impl Default for Texture {
             width: 1,
             height: 1,
             depth: 1,
+            array_layer_count: 1,
             mipmaps: 1,
             flags: 0,
             handle: TextureHandle::default(),
             ty: texture_type::Enum::Texture2D,
             sampler: None,
+            state: ResourceState::RESOURCE_STATE_UNDEFINED,
             name: None,
         }
     }
@@ -1425,6 +1660,8 @@ pub(crate) struct ShaderState<'a> {
     pub name: Option<String>,
     pub active_shaders: u32,
     pub graphics_pipeline: bool,
+    // Reflected from the SPIR-V of every stage, pipeline layouts are built from this.
+    pub parse_result: ParseResult,
 }
 
 impl<'a> Default for ShaderState<'a> {
@@ -1434,6 +1671,7 @@ impl<'a> Default for ShaderState<'a> {
             name: None,
             active_shaders: 0,
             graphics_pipeline: false,
+            parse_result: ParseResult::default(),
         }
     }
 }
@@ -1480,24 +1718,27 @@ impl<'a> Default for DescriptorSetLayout<'a> {
     }
 }
 
-pub(crate) struct DescriptorSet<'a> {
+pub(crate) struct DescriptorSet {
     pub vk_descriptor_set: vk::DescriptorSet,
-    pub resources: Option<Vec<ResourceHandle>>, // Assuming ResourceHandle is defined
-    pub samplers: Option<Vec<SamplerHandle>>, // Assuming SamplerHandle is defined
-    pub bindings: Option<Vec<u16>>,
-    pub layout: Option<Box<DescriptorSetLayout<'a>>>, // Using Box to own the layout
+    // Indexed by binding, what was last written to the set.
+    pub resources: [DescriptorResource; K_MAX_DESCRIPTORS_PER_SET],
+    pub samplers: [SamplerHandle; K_MAX_DESCRIPTORS_PER_SET],
+    pub layout: DescriptorSetLayoutHandle,
     pub num_resources: u32,
+    pub handle: DescriptorSetHandle,
+    pub name: Option<String>,
 }
 
-impl<'a> Default for DescriptorSet<'a> {
+impl Default for DescriptorSet {
     fn default() -> Self {
         DescriptorSet {
             vk_descriptor_set: vk::DescriptorSet::null(),
-            resources: None,
-            samplers: None,
-            bindings: None,
-            layout: None,
+            resources: [DescriptorResource::Empty; K_MAX_DESCRIPTORS_PER_SET],
+            samplers: [K_INVALID_SAMPLER; K_MAX_DESCRIPTORS_PER_SET],
+            layout: K_INVALID_LAYOUT,
             num_resources: 0,
+            handle: K_INVALID_SET,
+            name: None,
         }
     }
 }
@@ -1513,8 +1754,15 @@ pub(crate) struct Pipeline<'a> {
     pub depth_stencil: DepthStencilCreation, // Assuming DepthStencilCreation is defined
     pub blend_state: BlendStateCreation, // Assuming BlendStateCreation is defined
     pub rasterization: RasterizationCreation, // Assuming RasterizationCreation is defined
+    // Kept with the blend, depth and rasterization state so the pipeline can be rebuilt when its shaders reload.
+    pub vertex_input: VertexInputCreation,
+    pub render_pass_output: RenderPassOutput,
+    pub topology: topology_type::Enum,
+    // Layouts generated from reflection belong to the pipeline, the ones passed in PipelineCreation do not.
+    pub owns_descriptor_set_layouts: bool,
     pub handle: PipelineHandle, // Assuming PipelineHandle is defined
     pub graphics_pipeline: bool,
+    pub name: Option<String>,
 }
 
 impl<'a> Default for Pipeline<'a> {
@@ -1530,8 +1778,13 @@ impl<'a> Default for Pipeline<'a> {
             depth_stencil: DepthStencilCreation::default(),
             blend_state: BlendStateCreation::default(),
             rasterization: RasterizationCreation::default(),
+            vertex_input: VertexInputCreation::new(),
+            render_pass_output: RenderPassOutput::new(),
+            topology: topology_type::Enum::Triangle,
+            owns_descriptor_set_layouts: false,
             handle: PipelineHandle::default(),
             graphics_pipeline: true,
+            name: None,
         }
     }
 }
@@ -1579,7 +1832,7 @@ impl Default for RenderPass {
 }
 
 
-fn to_compiler_extension(value: vk::ShaderStageFlags) -> &'static str {
+pub(crate) fn to_compiler_extension(value: vk::ShaderStageFlags) -> &'static str {
     match value {
         vk::ShaderStageFlags::VERTEX => "vert",
         vk::ShaderStageFlags::FRAGMENT => "frag",
@@ -1588,7 +1841,7 @@ fn to_compiler_extension(value: vk::ShaderStageFlags) -> &'static str {
     }
 }
 
-fn to_stage_defines(value: vk::ShaderStageFlags) -> &'static str {
+pub(crate) fn to_stage_defines(value: vk::ShaderStageFlags) -> &'static str {
     match value {
         vk::ShaderStageFlags::VERTEX => "VERTEX",
         vk::ShaderStageFlags::FRAGMENT => "FRAGMENT",
@@ -1597,16 +1850,16 @@ fn to_stage_defines(value: vk::ShaderStageFlags) -> &'static str {
     }
 }
 
-fn to_vk_image_type(ty: texture_type::Enum) -> vk::ImageType {
+pub(crate) fn to_vk_image_type(ty: texture_type::Enum) -> vk::ImageType {
     match ty {
-        texture_type::Enum::Texture1D => vk::ImageType::TYPE_1D,
-        texture_type::Enum::Texture2D => vk::ImageType::TYPE_2D,
+        texture_type::Enum::Texture1D | texture_type::Enum::Texture1DArray => vk::ImageType::TYPE_1D,
+        texture_type::Enum::Texture2D | texture_type::Enum::Texture2DArray | texture_type::Enum::TextureCubeArray => vk::ImageType::TYPE_2D,
         texture_type::Enum::Texture3D => vk::ImageType::TYPE_3D,
-        _ => todo!("Invalid image type")
+        texture_type::Enum::Count => todo!("Invalid image type")
     }
 }
 
-fn to_vk_image_view_type(ty: texture_type::Enum) -> vk::ImageViewType {
+pub(crate) fn to_vk_image_view_type(ty: texture_type::Enum) -> vk::ImageViewType {
     match ty {
         texture_type::Enum::Texture1D => vk::ImageViewType::TYPE_1D,
         texture_type::Enum::Texture2D => vk::ImageViewType::TYPE_2D,
@@ -1618,7 +1871,7 @@ fn to_vk_image_view_type(ty: texture_type::Enum) -> vk::ImageViewType {
     }
 }
 
-fn to_vk_vertex_format(value: vertex_component_format::Enum) -> vk::Format {
+pub(crate) fn to_vk_vertex_format(value: vertex_component_format::Enum) -> vk::Format {
     match value {
         vertex_component_format::Enum::Float => vk::Format::R32_SFLOAT,
         vertex_component_format::Enum::Float2 => vk::Format::R32G32_SFLOAT,
@@ -1628,7 +1881,7 @@ fn to_vk_vertex_format(value: vertex_component_format::Enum) -> vk::Format {
         vertex_component_format::Enum::Byte => vk::Format::R8_SINT,
         vertex_component_format::Enum::Byte4N => vk::Format::R8G8B8A8_SNORM,
         vertex_component_format::Enum::UByte => vk::Format::R8_UINT,
-        vertex_component_format::Enum::UByte4N => vk::Format::R8G8B8A8_UINT,
+        vertex_component_format::Enum::UByte4N => vk::Format::R8G8B8A8_UNORM,
         vertex_component_format::Enum::Short2 => vk::Format::R16G16_SINT,
         vertex_component_format::Enum::Short2N => vk::Format::R16G16_SNORM,
         vertex_component_format::Enum::Short4 => vk::Format::R16G16B16A16_SINT,
@@ -1640,6 +1893,61 @@ fn to_vk_vertex_format(value: vertex_component_format::Enum) -> vk::Format {
     }
 }
 
+pub(crate) fn to_vk_vertex_input_rate(value: vertex_input_rate::Enum) -> vk::VertexInputRate {
+    match value {
+        vertex_input_rate::Enum::PerInstance => vk::VertexInputRate::INSTANCE,
+        _ => vk::VertexInputRate::VERTEX,
+    }
+}
+
+pub(crate) fn to_vk_polygon_mode(value: fill_mode::Enum) -> vk::PolygonMode {
+    match value {
+        fill_mode::Enum::Wireframe => vk::PolygonMode::LINE,
+        fill_mode::Enum::Point => vk::PolygonMode::POINT,
+        _ => vk::PolygonMode::FILL,
+    }
+}
+
+pub(crate) fn to_vk_cull_mode(value: cull_mode::Enum) -> vk::CullModeFlags {
+    match value {
+        cull_mode::Enum::Front => vk::CullModeFlags::FRONT,
+        cull_mode::Enum::Back => vk::CullModeFlags::BACK,
+        _ => vk::CullModeFlags::NONE,
+    }
+}
+
+pub(crate) fn to_vk_topology(value: topology_type::Enum) -> vk::PrimitiveTopology {
+    match value {
+        topology_type::Enum::Point => vk::PrimitiveTopology::POINT_LIST,
+        topology_type::Enum::Line => vk::PrimitiveTopology::LINE_LIST,
+        topology_type::Enum::Triangle => vk::PrimitiveTopology::TRIANGLE_LIST,
+        topology_type::Enum::Patch => vk::PrimitiveTopology::PATCH_LIST,
+        topology_type::Enum::Unknown | topology_type::Enum::Count => panic!("Invalid topology type"),
+    }
+}
+
+pub(crate) fn to_vk_logic_op(value: logic_operation::Enum) -> vk::LogicOp {
+    match value {
+        logic_operation::Enum::Clear => vk::LogicOp::CLEAR,
+        logic_operation::Enum::Set => vk::LogicOp::SET,
+        logic_operation::Enum::Copy => vk::LogicOp::COPY,
+        logic_operation::Enum::CopyInverted => vk::LogicOp::COPY_INVERTED,
+        logic_operation::Enum::Noop => vk::LogicOp::NO_OP,
+        logic_operation::Enum::Invert => vk::LogicOp::INVERT,
+        logic_operation::Enum::And => vk::LogicOp::AND,
+        logic_operation::Enum::Nand => vk::LogicOp::NAND,
+        logic_operation::Enum::Or => vk::LogicOp::OR,
+        logic_operation::Enum::Nor => vk::LogicOp::NOR,
+        logic_operation::Enum::Xor => vk::LogicOp::XOR,
+        logic_operation::Enum::Equiv => vk::LogicOp::EQUIVALENT,
+        logic_operation::Enum::AndReverse => vk::LogicOp::AND_REVERSE,
+        logic_operation::Enum::AndInverted => vk::LogicOp::AND_INVERTED,
+        logic_operation::Enum::OrReverse => vk::LogicOp::OR_REVERSE,
+        logic_operation::Enum::OrInverted => vk::LogicOp::OR_INVERTED,
+        logic_operation::Enum::Count => panic!("Invalid logic operation"),
+    }
+}
+
 fn to_vk_pipeline_stage(value: pipeline_stage::Enum) -> vk::PipelineStageFlags {
     match value {
         pipeline_stage::Enum::DrawIndirect => vk::PipelineStageFlags::DRAW_INDIRECT,
@@ -1652,58 +1960,138 @@ fn to_vk_pipeline_stage(value: pipeline_stage::Enum) -> vk::PipelineStageFlags {
     }
 }
 
-fn util_to_vk_access_flags(state: ResourceState) -> vk::AccessFlags {
+pub(crate) fn util_to_vk_access_flags(state: ResourceState) -> vk::AccessFlags {
     let mut vk_access_flags = vk::AccessFlags::empty();
-    if state.contains(ResourceState::RESOURCE_STATE_COPY_SOURCE) {
+    if state.intersects(ResourceState::RESOURCE_STATE_COPY_SOURCE) {
         vk_access_flags |= vk::AccessFlags::TRANSFER_READ;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_COPY_DEST) {
+    if state.intersects(ResourceState::RESOURCE_STATE_COPY_DEST) {
         vk_access_flags |= vk::AccessFlags::TRANSFER_WRITE;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER) {
+    if state.intersects(ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER) {
         vk_access_flags |= vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_INDEX_BUFFER) {
+    if state.intersects(ResourceState::RESOURCE_STATE_INDEX_BUFFER) {
         vk_access_flags |= vk::AccessFlags::INDEX_READ;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_UNORDERED_ACCESS) {
+    if state.intersects(ResourceState::RESOURCE_STATE_UNORDERED_ACCESS) {
         vk_access_flags |= vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_INDIRECT_ARGUMENT) {
+    if state.intersects(ResourceState::RESOURCE_STATE_INDIRECT_ARGUMENT) {
         vk_access_flags |= vk::AccessFlags::INDIRECT_COMMAND_READ;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_RENDER_TARGET) {
+    if state.intersects(ResourceState::RESOURCE_STATE_RENDER_TARGET) {
         vk_access_flags |= vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_DEPTH_WRITE) {
+    if state.intersects(ResourceState::RESOURCE_STATE_DEPTH_WRITE) {
         vk_access_flags |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_SHADER_RESOURCE) {
+    if state.intersects(ResourceState::RESOURCE_STATE_DEPTH_READ) {
+        vk_access_flags |= vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::SHADER_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_SHADER_RESOURCE) {
         vk_access_flags |= vk::AccessFlags::SHADER_READ;
     }
-    if state.contains(ResourceState::RESOURCE_STATE_PRESENT) {
+    if state.intersects(ResourceState::RESOURCE_STATE_PRESENT) {
         vk_access_flags |= vk::AccessFlags::MEMORY_READ;
     }
     #[cfg(feature = "RAYTRACING")]
     {
-        if state.contains(RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE) {
+        if state.intersects(ResourceState::RESOURCE_STATE_RAYTRACING_ACCELERATION_STRUCTURE) {
             vk_access_flags |= vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV;
         }
     }
     vk_access_flags
 }
 
-fn util_determine_pipeline_stage_flags(access_flags: vk::AccessFlags, queue_type: queue_type::Enum) -> vk::PipelineStageFlags {
+// Depth read only can also be sampled, other mixes of states that need different layouts fall back to the general one.
+pub(crate) fn util_to_vk_image_layout(state: ResourceState) -> vk::ImageLayout {
+    let layouts = [
+        (ResourceState::RESOURCE_STATE_COPY_SOURCE, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+        (ResourceState::RESOURCE_STATE_COPY_DEST, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+        (ResourceState::RESOURCE_STATE_RENDER_TARGET, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+        (ResourceState::RESOURCE_STATE_DEPTH_WRITE, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+        (ResourceState::RESOURCE_STATE_DEPTH_READ, vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+        (ResourceState::RESOURCE_STATE_UNORDERED_ACCESS, vk::ImageLayout::GENERAL),
+        (ResourceState::RESOURCE_STATE_SHADER_RESOURCE, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        (ResourceState::RESOURCE_STATE_PRESENT, vk::ImageLayout::PRESENT_SRC_KHR),
+        (ResourceState::RESOURCE_STATE_COMMON, vk::ImageLayout::GENERAL),
+    ];
+    let mut state = state;
+    if state.contains(ResourceState::RESOURCE_STATE_DEPTH_WRITE) {
+        state.remove(ResourceState::RESOURCE_STATE_DEPTH_READ);
+    }
+    if state.contains(ResourceState::RESOURCE_STATE_DEPTH_READ) {
+        state.remove(ResourceState::RESOURCE_STATE_SHADER_RESOURCE);
+    }
+    let mut matching = layouts.iter().filter(|(flags, _)| state.intersects(*flags)).map(|(_, layout)| *layout);
+    match (matching.next(), matching.next()) {
+        (None, _) => vk::ImageLayout::UNDEFINED,
+        (Some(layout), None) => layout,
+        (Some(_), Some(_)) => vk::ImageLayout::GENERAL,
+    }
+}
+
+// State a texture of this format is sampled in. Its layout is the one descriptors sampling the texture claim.
+pub(crate) fn util_sampled_state(format: vk::Format) -> ResourceState {
+    if texture_format::has_depth_or_stencil(format) {
+        ResourceState::RESOURCE_STATE_DEPTH_READ | ResourceState::RESOURCE_STATE_SHADER_RESOURCE
+    } else {
+        ResourceState::RESOURCE_STATE_SHADER_RESOURCE
+    }
+}
+
+// Same mapping as util_to_vk_access_flags, with the finer synchronization2 bits.
+pub(crate) fn util_to_vk_access_flags2(state: ResourceState) -> vk::AccessFlags2 {
+    let mut vk_access_flags = vk::AccessFlags2::NONE;
+    if state.intersects(ResourceState::RESOURCE_STATE_COPY_SOURCE) {
+        vk_access_flags |= vk::AccessFlags2::TRANSFER_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_COPY_DEST) {
+        vk_access_flags |= vk::AccessFlags2::TRANSFER_WRITE;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER) {
+        vk_access_flags |= vk::AccessFlags2::UNIFORM_READ | vk::AccessFlags2::VERTEX_ATTRIBUTE_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_INDEX_BUFFER) {
+        vk_access_flags |= vk::AccessFlags2::INDEX_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_UNORDERED_ACCESS) {
+        vk_access_flags |= vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_INDIRECT_ARGUMENT) {
+        vk_access_flags |= vk::AccessFlags2::INDIRECT_COMMAND_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_RENDER_TARGET) {
+        vk_access_flags |= vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_DEPTH_WRITE) {
+        vk_access_flags |= vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_DEPTH_READ) {
+        vk_access_flags |= vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags2::SHADER_SAMPLED_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_SHADER_RESOURCE) {
+        vk_access_flags |= vk::AccessFlags2::SHADER_SAMPLED_READ;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_PRESENT) {
+        vk_access_flags |= vk::AccessFlags2::MEMORY_READ;
+    }
+    vk_access_flags
+}
+
+// Any of the access bits selects the stages, not all of them.
+pub(crate) fn util_determine_pipeline_stage_flags(access_flags: vk::AccessFlags, queue_type: queue_type::Enum) -> vk::PipelineStageFlags {
     let mut flags : vk::PipelineStageFlags = vk::PipelineStageFlags::empty();
 
     match queue_type {
         queue_type::Enum::Graphics => {
-            if access_flags.contains(vk::AccessFlags::INDEX_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ){
+            if access_flags.intersects(vk::AccessFlags::INDEX_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ){
                 flags |= vk::PipelineStageFlags::VERTEX_INPUT;
     
             }
 
-            if access_flags.contains(vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE){
+            if access_flags.intersects(vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE){
                 flags |= vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER;
                 // Uncomment if additional shader stages are supported
                 // flags |= vk::PipelineStageFlags::GEOMETRY_SHADER;
@@ -1713,27 +2101,27 @@ fn util_determine_pipeline_stage_flags(access_flags: vk::AccessFlags, queue_type
                 // flags |= vk::PipelineStageFlags::RAY_TRACING_SHADER_NV;
             }
 
-            if access_flags.contains(vk::AccessFlags::INPUT_ATTACHMENT_READ) {
+            if access_flags.intersects(vk::AccessFlags::INPUT_ATTACHMENT_READ) {
                 flags |= vk::PipelineStageFlags::FRAGMENT_SHADER;
             }
 
-            if access_flags.contains(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE) {
+            if access_flags.intersects(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE) {
                 flags |= vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
             }
 
-            if access_flags.contains(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE) {
+            if access_flags.intersects(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE) {
                 flags |= vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS;
             }
         }
         queue_type::Enum::Compute => {
-            if access_flags.contains(vk::AccessFlags::INDEX_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ)||
-               access_flags.contains(vk::AccessFlags::INPUT_ATTACHMENT_READ) ||
-               access_flags.contains(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE) ||
-               access_flags.contains(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE) {
+            if access_flags.intersects(vk::AccessFlags::INDEX_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ)||
+               access_flags.intersects(vk::AccessFlags::INPUT_ATTACHMENT_READ) ||
+               access_flags.intersects(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE) ||
+               access_flags.intersects(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE) {
                return vk::PipelineStageFlags::ALL_COMMANDS;
             }
 
-            if access_flags.contains(vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE) {
+            if access_flags.intersects(vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE) {
                 flags |= vk::PipelineStageFlags::COMPUTE_SHADER;
             }
         }
@@ -1742,15 +2130,15 @@ fn util_determine_pipeline_stage_flags(access_flags: vk::AccessFlags, queue_type
     }
 
     // Compatible with both compute and graphics queues
-    if access_flags.contains(vk::AccessFlags::INDIRECT_COMMAND_READ) {
+    if access_flags.intersects(vk::AccessFlags::INDIRECT_COMMAND_READ) {
         flags |= vk::PipelineStageFlags::DRAW_INDIRECT;
     }
 
-    if access_flags.contains(vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE) {
+    if access_flags.intersects(vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE) {
         flags |= vk::PipelineStageFlags::TRANSFER;
     }
 
-    if access_flags.contains(vk::AccessFlags::HOST_READ | vk::AccessFlags::HOST_WRITE) {
+    if access_flags.intersects(vk::AccessFlags::HOST_READ | vk::AccessFlags::HOST_WRITE) {
         flags |= vk::PipelineStageFlags::HOST;
     }
 
@@ -1760,3 +2148,151 @@ fn util_determine_pipeline_stage_flags(access_flags: vk::AccessFlags, queue_type
 
     flags
 }
+
+// Shader reads are split per pixel and non pixel stages here, which the original flags cannot express.
+pub(crate) fn util_determine_pipeline_stage_flags2(state: ResourceState, queue_type: queue_type::Enum) -> vk::PipelineStageFlags2 {
+    let mut flags = vk::PipelineStageFlags2::NONE;
+    let all_shaders = match queue_type {
+        queue_type::Enum::Compute => vk::PipelineStageFlags2::COMPUTE_SHADER,
+        _ => vk::PipelineStageFlags2::PRE_RASTERIZATION_SHADERS | vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER,
+    };
+    if state.intersects(ResourceState::RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER) {
+        flags |= all_shaders;
+        if queue_type != queue_type::Enum::Compute {
+            flags |= vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT;
+        }
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_INDEX_BUFFER) && queue_type != queue_type::Enum::Compute {
+        flags |= vk::PipelineStageFlags2::INDEX_INPUT;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_UNORDERED_ACCESS) {
+        flags |= all_shaders;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_NON_PIXEL_SHADER_RESOURCE) {
+        flags |= match queue_type {
+            queue_type::Enum::Compute => vk::PipelineStageFlags2::COMPUTE_SHADER,
+            _ => vk::PipelineStageFlags2::PRE_RASTERIZATION_SHADERS | vk::PipelineStageFlags2::COMPUTE_SHADER,
+        };
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_PIXEL_SHADER_RESOURCE) && queue_type != queue_type::Enum::Compute {
+        flags |= vk::PipelineStageFlags2::FRAGMENT_SHADER;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_RENDER_TARGET) {
+        flags |= vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_DEPTH_WRITE | ResourceState::RESOURCE_STATE_DEPTH_READ) {
+        flags |= vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_DEPTH_READ) {
+        flags |= all_shaders;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_INDIRECT_ARGUMENT) {
+        flags |= vk::PipelineStageFlags2::DRAW_INDIRECT;
+    }
+    if state.intersects(ResourceState::RESOURCE_STATE_COPY_SOURCE | ResourceState::RESOURCE_STATE_COPY_DEST) {
+        flags |= vk::PipelineStageFlags2::COPY;
+    }
+    // Attachment states need graphics stages the compute queue does not have.
+    if queue_type == queue_type::Enum::Compute && state.intersects(ResourceState::RESOURCE_STATE_RENDER_TARGET | ResourceState::RESOURCE_STATE_DEPTH_WRITE | ResourceState::RESOURCE_STATE_DEPTH_READ) {
+        return vk::PipelineStageFlags2::ALL_COMMANDS;
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_states_map_to_their_layout() {
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_UNDEFINED), vk::ImageLayout::UNDEFINED);
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_RENDER_TARGET), vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_COPY_DEST), vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_PIXEL_SHADER_RESOURCE), vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_PRESENT), vk::ImageLayout::PRESENT_SRC_KHR);
+    }
+
+    #[test]
+    fn combined_states_pick_a_shared_layout() {
+        // Copy source and shader reads need different layouts.
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_GENERIC_READ), vk::ImageLayout::GENERAL);
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_DEPTH_READ | ResourceState::RESOURCE_STATE_SHADER_RESOURCE),
+                   vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+        assert_eq!(util_to_vk_image_layout(ResourceState::RESOURCE_STATE_DEPTH_WRITE | ResourceState::RESOURCE_STATE_DEPTH_READ),
+                   vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    }
+
+    #[test]
+    fn generic_read_access_and_stages() {
+        let access = util_to_vk_access_flags(ResourceState::RESOURCE_STATE_GENERIC_READ);
+        assert_eq!(access, vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ
+                   | vk::AccessFlags::INDEX_READ | vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_READ);
+        assert_eq!(util_determine_pipeline_stage_flags(access, queue_type::Enum::Graphics),
+                   vk::PipelineStageFlags::VERTEX_INPUT | vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER
+                   | vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::TRANSFER);
+
+        let access2 = util_to_vk_access_flags2(ResourceState::RESOURCE_STATE_GENERIC_READ);
+        assert!(!access2.intersects(vk::AccessFlags2::SHADER_STORAGE_WRITE | vk::AccessFlags2::TRANSFER_WRITE));
+        assert_eq!(util_determine_pipeline_stage_flags2(ResourceState::RESOURCE_STATE_GENERIC_READ, queue_type::Enum::Graphics),
+                   vk::PipelineStageFlags2::PRE_RASTERIZATION_SHADERS | vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER
+                   | vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT | vk::PipelineStageFlags2::INDEX_INPUT | vk::PipelineStageFlags2::DRAW_INDIRECT
+                   | vk::PipelineStageFlags2::COPY);
+    }
+
+    #[test]
+    fn sampled_depth_access_and_stages() {
+        let state = ResourceState::RESOURCE_STATE_DEPTH_READ | ResourceState::RESOURCE_STATE_SHADER_RESOURCE;
+        let access = util_to_vk_access_flags(state);
+        assert_eq!(access, vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::SHADER_READ);
+        assert_eq!(util_determine_pipeline_stage_flags(access, queue_type::Enum::Graphics),
+                   vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER
+                   | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS);
+        assert_eq!(util_determine_pipeline_stage_flags(access, queue_type::Enum::Compute), vk::PipelineStageFlags::ALL_COMMANDS);
+
+        assert_eq!(util_to_vk_access_flags2(state), vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags2::SHADER_SAMPLED_READ);
+        assert!(util_determine_pipeline_stage_flags2(state, queue_type::Enum::Graphics)
+                .contains(vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags2::FRAGMENT_SHADER));
+        assert_eq!(util_determine_pipeline_stage_flags2(state, queue_type::Enum::Compute), vk::PipelineStageFlags2::ALL_COMMANDS);
+    }
+
+    #[test]
+    fn undefined_state_waits_on_nothing() {
+        let access = util_to_vk_access_flags(ResourceState::RESOURCE_STATE_UNDEFINED);
+        assert!(access.is_empty());
+        assert_eq!(util_determine_pipeline_stage_flags(access, queue_type::Enum::Graphics), vk::PipelineStageFlags::TOP_OF_PIPE);
+        assert_eq!(util_determine_pipeline_stage_flags2(ResourceState::RESOURCE_STATE_UNDEFINED, queue_type::Enum::Graphics), vk::PipelineStageFlags2::NONE);
+    }
+
+    #[test]
+    fn disabled_anisotropy_shares_a_sampler_key() {
+        let default_key = SamplerCreation::new().key();
+        for max_anisotropy in [0.0, 0.5, 1.0, -4.0, f32::NAN] {
+            assert_eq!(SamplerCreation::new().set_anisotropy(max_anisotropy).key(), default_key);
+        }
+        assert_ne!(SamplerCreation::new().set_anisotropy(8.0).key(), default_key);
+        assert_ne!(SamplerCreation::new().set_anisotropy(8.0).key(), SamplerCreation::new().set_anisotropy(16.0).key());
+    }
+
+    #[test]
+    fn signed_zero_lod_bias_shares_a_sampler_key() {
+        assert_eq!(SamplerCreation::new().set_lod_bias(-0.0).key(), SamplerCreation::new().set_lod_bias(0.0).key());
+        assert_ne!(SamplerCreation::new().set_lod_bias(0.5).key(), SamplerCreation::new().set_lod_bias(0.0).key());
+    }
+
+    #[test]
+    fn compare_op_and_name_in_sampler_key() {
+        let less = SamplerCreation::new().set_compare_op(vk::CompareOp::LESS).key();
+        assert_ne!(less, SamplerCreation::new().set_compare_op(vk::CompareOp::GREATER).key());
+        assert_ne!(less, SamplerCreation::new().key());
+        // NEVER as a compare op is still a comparison sampler.
+        assert_ne!(SamplerCreation::new().set_compare_op(vk::CompareOp::NEVER).key(), SamplerCreation::new().key());
+        assert_eq!(SamplerCreation::new().set_name("shadow").key(), SamplerCreation::new().set_name("other").key());
+    }
+
+    #[test]
+    fn sampled_state_layouts() {
+        assert_eq!(util_to_vk_image_layout(util_sampled_state(vk::Format::R8G8B8A8_UNORM)), vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        assert_eq!(util_to_vk_image_layout(util_sampled_state(vk::Format::D32_SFLOAT)), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+        assert_eq!(util_to_vk_image_layout(util_sampled_state(vk::Format::D24_UNORM_S8_UINT)), vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL);
+    }
+}
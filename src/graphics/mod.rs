@@ -2,7 +2,11 @@ mod gpu_device;
 mod gpu_resources;
 mod gpu_enum;
 mod command_buffer;
+mod spirv_parser;
+mod shader_reload;
 pub(crate) use gpu_device::*;
 pub(crate) use gpu_resources::*;
 pub(crate) use gpu_enum::*;
-pub(crate) use command_buffer::*;
\ No newline at end of file
+pub(crate) use command_buffer::*;
+pub(crate) use spirv_parser::*;
+pub(crate) use shader_reload::*;
\ No newline at end of file
@@ -0,0 +1,206 @@
+use std::{fs, path::PathBuf, time::{Duration, Instant, SystemTime}};
+
+use ash::vk;
+use log::{error, info, warn};
+
+use super::{shader_language, GpuDevice, ShaderStateCreation, ShaderStateHandle};
+
+#[derive(Debug, Clone)]
+pub(crate) struct ShaderReloadConfiguration {
+    // How often the watched files are checked for changes.
+    pub poll_interval: Duration,
+}
+
+impl Default for ShaderReloadConfiguration {
+    fn default() -> Self {
+        ShaderReloadConfiguration {
+            poll_interval: Duration::from_millis(250),
+        }
+    }
+}
+
+struct WatchedStage {
+    ty: vk::ShaderStageFlags,
+    path: Option<PathBuf>,
+    // Stages without a path keep their code, it is reused as is on every reload.
+    code: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+struct WatchedShader {
+    handle: ShaderStateHandle,
+    name: Option<String>,
+    spv_input: bool,
+    source_language: shader_language::Enum,
+    stages: Vec<WatchedStage>,
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Polls the source files of watched shader states and recompiles the ones that changed, see
+// GpuDevice::reload_shader_state. Call update once per frame.
+pub(crate) struct ShaderReloadService {
+    watched: Vec<WatchedShader>,
+    poll_interval: Duration,
+    last_poll: Option<Instant>,
+}
+
+impl ShaderReloadService {
+    pub(crate) fn new() -> Self {
+        ShaderReloadService {
+            watched: Vec::new(),
+            poll_interval: Duration::ZERO,
+            last_poll: None,
+        }
+    }
+
+    pub(crate) fn init(&mut self, configuration: &ShaderReloadConfiguration) {
+        self.poll_interval = configuration.poll_interval;
+        self.last_poll = None;
+        info!("ShaderReloadService initialized");
+    }
+
+    pub(crate) fn shutdown(&mut self) {
+        self.watched.clear();
+        info!("ShaderReloadService shutdown");
+    }
+
+    // Watches the stage paths of the creation that made shader. Stages without a path are never reloaded
+    // themselves, but are recompiled along with the others.
+    pub(crate) fn watch(&mut self, shader: ShaderStateHandle, creation: &ShaderStateCreation) {
+        let stages: Vec<WatchedStage> = creation.stages[..creation.stages_count as usize].iter().map(|stage| {
+            let path = stage.path.map(PathBuf::from);
+            let code = match path {
+                Some(_) => Vec::new(),
+                None => stage.code.map_or(Vec::new(), |code| code[..(stage.code_size as usize).min(code.len())].to_vec()),
+            };
+            WatchedStage {
+                ty: stage.ty,
+                modified: path.as_ref().and_then(modified_time),
+                path,
+                code,
+            }
+        }).collect();
+
+        if stages.iter().all(|stage| stage.path.is_none()) {
+            warn!("Shader {} has no stage paths, nothing to watch", creation.name.unwrap_or("unnamed"));
+            return;
+        }
+
+        self.unwatch(shader);
+        self.watched.push(WatchedShader {
+            handle: shader,
+            name: creation.name.map(str::to_string),
+            spv_input: creation.spv_input,
+            source_language: creation.source_language,
+            stages,
+        });
+    }
+
+    pub(crate) fn unwatch(&mut self, shader: ShaderStateHandle) {
+        self.watched.retain(|watched| watched.handle != shader);
+    }
+
+    // Returns the shader states that were reloaded. A shader that fails to compile keeps its previous version.
+    pub(crate) fn update(&mut self, gpu_device: &mut GpuDevice) -> Vec<ShaderStateHandle> {
+        let now = Instant::now();
+        if self.last_poll.is_some_and(|last_poll| now.duration_since(last_poll) < self.poll_interval) {
+            return Vec::new();
+        }
+        self.last_poll = Some(now);
+
+        // Destroyed shader states are not watched anymore.
+        self.watched.retain(|watched| gpu_device.access_shader_state(watched.handle).is_some());
+
+        let mut reloaded = Vec::new();
+        for watched in self.watched.iter_mut() {
+            let mut changed = false;
+            for stage in watched.stages.iter_mut() {
+                let Some(path) = &stage.path else {
+                    continue;
+                };
+                let modified = modified_time(path);
+                if modified.is_some() && modified != stage.modified {
+                    // Recorded before compiling, a broken file is only retried once it is saved again.
+                    stage.modified = modified;
+                    changed = true;
+                }
+            }
+            if !changed {
+                continue;
+            }
+
+            let name = watched.name.as_deref().unwrap_or("unnamed");
+            if let Err(reason) = Self::reload(watched, gpu_device) {
+                error!("Reloading shader {} failed, keeping the previous version: {}", name, reason);
+                continue;
+            }
+            info!("Reloaded shader {}", name);
+            reloaded.push(watched.handle);
+        }
+        reloaded
+    }
+
+    fn reload(watched: &WatchedShader, gpu_device: &mut GpuDevice) -> Result<(), String> {
+        let sources = watched.stages.iter().map(|stage| match &stage.path {
+            Some(path) => fs::read(path).map_err(|error| format!("could not read {}: {}", path.display(), error)),
+            None => Ok(stage.code.clone()),
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        let mut creation = ShaderStateCreation::new();
+        creation.set_spv_input(watched.spv_input).set_source_language(watched.source_language);
+        if let Some(name) = &watched.name {
+            creation.set_name(name);
+        }
+        for (stage, source) in watched.stages.iter().zip(&sources) {
+            creation.add_stage(source, source.len() as u32, stage.ty);
+            if let Some(path) = &stage.path {
+                creation.set_stage_path(path);
+            }
+        }
+
+        gpu_device.reload_shader_state(watched.handle, &creation).map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fundamental::PoolHandle;
+    use std::path::Path;
+
+    #[test]
+    fn watch_keeps_the_code_of_stages_without_a_path() {
+        let path = Path::new("shaders/mesh.frag");
+        let vertex_code = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let mut creation = ShaderStateCreation::new();
+        creation.set_name("mesh")
+            .add_stage(&vertex_code, 4, vk::ShaderStageFlags::VERTEX)
+            .add_stage(b"void main() {}", 14, vk::ShaderStageFlags::FRAGMENT).set_stage_path(path);
+
+        let mut service = ShaderReloadService::new();
+        let shader = ShaderStateHandle::from_parts(3, 1);
+        service.watch(shader, &creation);
+        // Watching again replaces the entry.
+        service.watch(shader, &creation);
+        assert_eq!(service.watched.len(), 1);
+        let stages = &service.watched[0].stages;
+        assert_eq!((stages[0].path.as_deref(), stages[0].code.as_slice()), (None, &vertex_code[..4]));
+        assert_eq!((stages[1].path.as_deref(), stages[1].code.len()), (Some(path), 0));
+
+        service.unwatch(shader);
+        assert!(service.watched.is_empty());
+    }
+
+    #[test]
+    fn shaders_without_paths_are_not_watched() {
+        let code = [0u8; 8];
+        let mut creation = ShaderStateCreation::new();
+        creation.add_stage(&code, 8, vk::ShaderStageFlags::COMPUTE);
+        let mut service = ShaderReloadService::new();
+        service.watch(ShaderStateHandle::from_parts(0, 0), &creation);
+        assert!(service.watched.is_empty());
+    }
+}
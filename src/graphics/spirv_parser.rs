@@ -0,0 +1,639 @@
+use ash::vk;
+use log::warn;
+
+use super::{Binding, DescriptorSetLayoutCreation, K_MAX_DESCRIPTORS_PER_SET, K_MAX_DESCRIPTOR_SET_LAYOUTS};
+
+pub(crate) const K_SPIRV_MAGIC: u32 = 0x07230203;
+
+// Opcodes, see the SPIR-V specification section 3.
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_VOID: u32 = 19;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_SPEC_CONSTANT: u32 = 50;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+
+// Decorations.
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILTIN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes.
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_INPUT: u32 = 1;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+// Image dimensions.
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Debug, Clone)]
+pub(crate) struct ShaderVertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+    pub name: Option<String>,
+}
+
+// Everything reflected from the stages of a shader state. Parsing several stages into the same
+// result merges them, bindings used by more than one stage only appear once.
+#[derive(Clone)]
+pub(crate) struct ParseResult {
+    pub set_count: u32,
+    pub sets: [DescriptorSetLayoutCreation; K_MAX_DESCRIPTOR_SET_LAYOUTS],
+    // A single range covering the push constant blocks of every stage.
+    pub push_constants: Option<vk::PushConstantRange>,
+    // Vertex stage inputs, sorted by location. Built-ins are skipped.
+    pub vertex_inputs: Vec<ShaderVertexInput>,
+}
+
+impl Default for ParseResult {
+    fn default() -> Self {
+        ParseResult {
+            set_count: 0,
+            sets: std::array::from_fn(|_| DescriptorSetLayoutCreation::new()),
+            push_constants: None,
+            vertex_inputs: Vec::new(),
+        }
+    }
+}
+
+// What the parser knows about a single result id. Fields are filled by whichever instruction
+// defines or decorates the id, their meaning depends on op.
+#[derive(Default, Clone)]
+struct Id {
+    op: u32,
+    name: Option<String>,
+    // Component, column, element, pointee or sampled type.
+    type_id: u32,
+    // Vector or matrix count, scalar width or array length id.
+    count: u32,
+    signed: bool,
+    storage_class: u32,
+    dim: u32,
+    sampled: u32,
+    value: u32,
+    members: Vec<u32>,
+    member_offsets: Vec<u32>,
+    member_matrix_strides: Vec<u32>,
+    array_stride: u32,
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    builtin: bool,
+    block: bool,
+    buffer_block: bool,
+}
+
+fn read_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words.iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|byte| *byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn set_member_decoration(values: &mut Vec<u32>, member: usize, value: u32) {
+    if values.len() <= member {
+        values.resize(member + 1, 0);
+    }
+    values[member] = value;
+}
+
+fn to_vk_shader_stage(execution_model: u32) -> Option<vk::ShaderStageFlags> {
+    match execution_model {
+        0 => Some(vk::ShaderStageFlags::VERTEX),
+        1 => Some(vk::ShaderStageFlags::TESSELLATION_CONTROL),
+        2 => Some(vk::ShaderStageFlags::TESSELLATION_EVALUATION),
+        3 => Some(vk::ShaderStageFlags::GEOMETRY),
+        4 => Some(vk::ShaderStageFlags::FRAGMENT),
+        5 => Some(vk::ShaderStageFlags::COMPUTE),
+        5267 | 5364 => Some(vk::ShaderStageFlags::TASK_EXT),
+        5268 | 5365 => Some(vk::ShaderStageFlags::MESH_EXT),
+        5313 => Some(vk::ShaderStageFlags::RAYGEN_KHR),
+        5314 => Some(vk::ShaderStageFlags::INTERSECTION_KHR),
+        5315 => Some(vk::ShaderStageFlags::ANY_HIT_KHR),
+        5316 => Some(vk::ShaderStageFlags::CLOSEST_HIT_KHR),
+        5317 => Some(vk::ShaderStageFlags::MISS_KHR),
+        5318 => Some(vk::ShaderStageFlags::CALLABLE_KHR),
+        _ => None,
+    }
+}
+
+fn to_vk_descriptor_type(ids: &[Id], storage_class: u32, type_id: u32) -> Option<vk::DescriptorType> {
+    let ty = &ids[type_id as usize];
+    match (storage_class, ty.op) {
+        (STORAGE_CLASS_UNIFORM_CONSTANT, OP_TYPE_SAMPLED_IMAGE) => Some(vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+        (STORAGE_CLASS_UNIFORM_CONSTANT, OP_TYPE_SAMPLER) => Some(vk::DescriptorType::SAMPLER),
+        (STORAGE_CLASS_UNIFORM_CONSTANT, OP_TYPE_ACCELERATION_STRUCTURE) => Some(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR),
+        (STORAGE_CLASS_UNIFORM_CONSTANT, OP_TYPE_IMAGE) => {
+            // Sampled 2 means the image is used without a sampler, i.e. read/write.
+            let storage = ty.sampled == 2;
+            Some(match ty.dim {
+                DIM_BUFFER if storage => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                DIM_BUFFER => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                DIM_SUBPASS_DATA => vk::DescriptorType::INPUT_ATTACHMENT,
+                _ if storage => vk::DescriptorType::STORAGE_IMAGE,
+                _ => vk::DescriptorType::SAMPLED_IMAGE,
+            })
+        }
+        // Before SPIR-V 1.3 storage buffers are Uniform blocks decorated BufferBlock.
+        (STORAGE_CLASS_UNIFORM, OP_TYPE_STRUCT) if ty.buffer_block => Some(vk::DescriptorType::STORAGE_BUFFER),
+        (STORAGE_CLASS_UNIFORM, OP_TYPE_STRUCT) => Some(vk::DescriptorType::UNIFORM_BUFFER),
+        (STORAGE_CLASS_STORAGE_BUFFER, OP_TYPE_STRUCT) => Some(vk::DescriptorType::STORAGE_BUFFER),
+        _ => None,
+    }
+}
+
+fn to_vk_vertex_format(ids: &[Id], type_id: u32) -> vk::Format {
+    const FLOAT_FORMATS: [vk::Format; 4] = [vk::Format::R32_SFLOAT, vk::Format::R32G32_SFLOAT, vk::Format::R32G32B32_SFLOAT, vk::Format::R32G32B32A32_SFLOAT];
+    const SINT_FORMATS: [vk::Format; 4] = [vk::Format::R32_SINT, vk::Format::R32G32_SINT, vk::Format::R32G32B32_SINT, vk::Format::R32G32B32A32_SINT];
+    const UINT_FORMATS: [vk::Format; 4] = [vk::Format::R32_UINT, vk::Format::R32G32_UINT, vk::Format::R32G32B32_UINT, vk::Format::R32G32B32A32_UINT];
+
+    let ty = &ids[type_id as usize];
+    let (component, count) = match ty.op {
+        OP_TYPE_VECTOR => (&ids[ty.type_id as usize], ty.count),
+        _ => (ty, 1),
+    };
+    if component.count != 32 || !(1..=4).contains(&count) {
+        return vk::Format::UNDEFINED;
+    }
+    let index = count as usize - 1;
+    match component.op {
+        OP_TYPE_FLOAT => FLOAT_FORMATS[index],
+        OP_TYPE_INT if component.signed => SINT_FORMATS[index],
+        OP_TYPE_INT => UINT_FORMATS[index],
+        _ => vk::Format::UNDEFINED,
+    }
+}
+
+// Deepest nesting of arrays and structs followed, a type referring back to itself is only possible in a
+// malformed module.
+const K_MAX_TYPE_DEPTH: u32 = 64;
+
+// Size in bytes of a type inside an explicitly laid out block.
+fn type_size(ids: &[Id], type_id: u32, matrix_stride: u32, depth: u32) -> Result<u32, &'static str> {
+    if depth > K_MAX_TYPE_DEPTH {
+        return Err("SPIR-V types nested too deep");
+    }
+    let ty = &ids[type_id as usize];
+    Ok(match ty.op {
+        OP_TYPE_BOOL => 4,
+        OP_TYPE_INT | OP_TYPE_FLOAT => ty.count / 8,
+        OP_TYPE_VECTOR => type_size(ids, ty.type_id, 0, depth + 1)?.saturating_mul(ty.count),
+        OP_TYPE_MATRIX if matrix_stride > 0 => matrix_stride.saturating_mul(ty.count),
+        OP_TYPE_MATRIX => type_size(ids, ty.type_id, 0, depth + 1)?.saturating_mul(ty.count),
+        OP_TYPE_ARRAY => {
+            let length = ids[ty.count as usize].value;
+            if ty.array_stride > 0 {
+                ty.array_stride.saturating_mul(length)
+            } else {
+                type_size(ids, ty.type_id, matrix_stride, depth + 1)?.saturating_mul(length)
+            }
+        }
+        OP_TYPE_STRUCT => struct_range(ids, type_id, depth + 1)?.map_or(0, |(_, end)| end),
+        _ => 0,
+    })
+}
+
+// Offset of the first member and end of the last one.
+fn struct_range(ids: &[Id], type_id: u32, depth: u32) -> Result<Option<(u32, u32)>, &'static str> {
+    let ty = &ids[type_id as usize];
+    let mut range: Option<(u32, u32)> = None;
+    for (member, member_type) in ty.members.iter().enumerate() {
+        let offset = ty.member_offsets.get(member).copied().unwrap_or(0);
+        let matrix_stride = ty.member_matrix_strides.get(member).copied().unwrap_or(0);
+        let end = offset.saturating_add(type_size(ids, *member_type, matrix_stride, depth)?);
+        range = Some(range.map_or((offset, end), |(start, range_end)| (start.min(offset), range_end.max(end))));
+    }
+    Ok(range)
+}
+
+fn add_binding(parse_result: &mut ParseResult, set: u32, binding: u32, count: u32, ty: vk::DescriptorType, name: Option<&str>) -> Result<(), &'static str> {
+    if set as usize >= K_MAX_DESCRIPTOR_SET_LAYOUTS {
+        return Err("descriptor set index exceeds K_MAX_DESCRIPTOR_SET_LAYOUTS");
+    }
+    let start = u16::try_from(binding).map_err(|_| "descriptor binding index out of range")?;
+    let count = u16::try_from(count).map_err(|_| "descriptor array too large")?;
+
+    let layout = &mut parse_result.sets[set as usize];
+    layout.set_set_index(set);
+    parse_result.set_count = parse_result.set_count.max(set + 1);
+
+    let num_bindings = layout.num_bindings as usize;
+    if let Some(existing) = layout.bindings[..num_bindings].iter_mut().find(|existing| existing.start == start) {
+        // Already used by another stage.
+        if existing.ty != Some(ty) {
+            return Err("descriptor binding declared with different types in different stages");
+        }
+        existing.count = existing.count.max(count);
+        return Ok(());
+    }
+    if num_bindings >= K_MAX_DESCRIPTORS_PER_SET {
+        return Err("descriptor set uses more than K_MAX_DESCRIPTORS_PER_SET bindings");
+    }
+    layout.add_binding(Binding::new(ty, start, count, name));
+    Ok(())
+}
+
+// Reflects descriptor bindings, push constants and vertex inputs of a SPIR-V module into parse_result
+// and returns the stage of its entry point.
+pub(crate) fn parse_binary(data: &[u32], parse_result: &mut ParseResult) -> Result<vk::ShaderStageFlags, &'static str> {
+    if data.len() < 5 {
+        return Err("SPIR-V binary is smaller than its header");
+    }
+    if data[0] != K_SPIRV_MAGIC {
+        return Err("invalid SPIR-V magic number");
+    }
+
+    // Every id takes at least a word to define, a larger bound can only come from a corrupt header.
+    let bound = data[3] as usize;
+    if bound > data.len() {
+        return Err("SPIR-V id bound larger than the module");
+    }
+    let mut ids = vec![Id::default(); bound];
+    let mut variables = Vec::new();
+    let mut execution_model = None;
+
+    let mut word_index = 5;
+    while word_index < data.len() {
+        let opcode = data[word_index] & 0xffff;
+        let word_count = (data[word_index] >> 16) as usize;
+        if word_count == 0 || word_index + word_count > data.len() {
+            return Err("malformed SPIR-V instruction");
+        }
+        let operands = &data[word_index + 1..word_index + word_count];
+        word_index += word_count;
+
+        let word = |index: usize| operands.get(index).copied().ok_or("truncated SPIR-V instruction");
+        let id = |index: usize| word(index).and_then(|value| if (value as usize) < bound { Ok(value as usize) } else { Err("SPIR-V id out of bounds") });
+
+        match opcode {
+            OP_NAME => {
+                ids[id(0)?].name = Some(read_string(&operands[1..]));
+            }
+            OP_ENTRY_POINT => {
+                if execution_model.is_some() {
+                    warn!("SPIR-V module has more than one entry point, only the first is reflected");
+                } else {
+                    execution_model = Some(word(0)?);
+                }
+            }
+            OP_DECORATE => {
+                let target = &mut ids[id(0)?];
+                match word(1)? {
+                    DECORATION_BLOCK => target.block = true,
+                    DECORATION_BUFFER_BLOCK => target.buffer_block = true,
+                    DECORATION_ARRAY_STRIDE => target.array_stride = word(2)?,
+                    DECORATION_BUILTIN => target.builtin = true,
+                    DECORATION_LOCATION => target.location = Some(word(2)?),
+                    DECORATION_BINDING => target.binding = Some(word(2)?),
+                    DECORATION_DESCRIPTOR_SET => target.set = Some(word(2)?),
+                    _ => {}
+                }
+            }
+            OP_MEMBER_DECORATE => {
+                let target = id(0)?;
+                let member = word(1)? as usize;
+                match word(2)? {
+                    DECORATION_OFFSET => set_member_decoration(&mut ids[target].member_offsets, member, word(3)?),
+                    DECORATION_MATRIX_STRIDE => set_member_decoration(&mut ids[target].member_matrix_strides, member, word(3)?),
+                    DECORATION_BUILTIN => ids[target].builtin = true,
+                    _ => {}
+                }
+            }
+            OP_TYPE_VOID | OP_TYPE_BOOL | OP_TYPE_SAMPLER | OP_TYPE_ACCELERATION_STRUCTURE => {
+                ids[id(0)?].op = opcode;
+            }
+            OP_TYPE_INT | OP_TYPE_FLOAT => {
+                let result = id(0)?;
+                ids[result].op = opcode;
+                ids[result].count = word(1)?;
+                ids[result].signed = opcode == OP_TYPE_INT && word(2)? != 0;
+            }
+            OP_TYPE_VECTOR | OP_TYPE_MATRIX => {
+                let result = id(0)?;
+                ids[result].op = opcode;
+                ids[result].type_id = id(1)? as u32;
+                ids[result].count = word(2)?;
+            }
+            OP_TYPE_IMAGE => {
+                let result = id(0)?;
+                ids[result].op = opcode;
+                ids[result].type_id = id(1)? as u32;
+                ids[result].dim = word(2)?;
+                ids[result].sampled = word(6)?;
+            }
+            OP_TYPE_SAMPLED_IMAGE | OP_TYPE_RUNTIME_ARRAY => {
+                let result = id(0)?;
+                ids[result].op = opcode;
+                ids[result].type_id = id(1)? as u32;
+            }
+            OP_TYPE_ARRAY => {
+                let result = id(0)?;
+                ids[result].op = opcode;
+                ids[result].type_id = id(1)? as u32;
+                ids[result].count = id(2)? as u32;
+            }
+            OP_TYPE_STRUCT => {
+                let result = id(0)?;
+                let members = (1..operands.len()).map(|index| id(index).map(|member| member as u32)).collect::<Result<Vec<_>, _>>()?;
+                ids[result].op = opcode;
+                ids[result].members = members;
+            }
+            OP_TYPE_POINTER => {
+                let result = id(0)?;
+                ids[result].op = opcode;
+                ids[result].storage_class = word(1)?;
+                ids[result].type_id = id(2)? as u32;
+            }
+            OP_CONSTANT | OP_SPEC_CONSTANT => {
+                // Only 32 bit constants are of interest, for array lengths.
+                let result = id(1)?;
+                ids[result].op = opcode;
+                ids[result].value = word(2)?;
+            }
+            OP_VARIABLE => {
+                let result = id(1)?;
+                ids[result].op = opcode;
+                ids[result].type_id = id(0)? as u32;
+                ids[result].storage_class = word(2)?;
+                variables.push(result);
+            }
+            _ => {}
+        }
+    }
+
+    let stage = execution_model.ok_or("SPIR-V module has no entry point")?;
+    let stage = to_vk_shader_stage(stage).ok_or("unsupported SPIR-V execution model")?;
+
+    for variable_id in variables {
+        let variable = &ids[variable_id];
+        let pointer = &ids[variable.type_id as usize];
+        if pointer.op != OP_TYPE_POINTER {
+            continue;
+        }
+        let type_id = pointer.type_id;
+        // Anonymous blocks are named after their type.
+        let name = variable.name.as_deref().filter(|name| !name.is_empty())
+            .or(ids[type_id as usize].name.as_deref());
+
+        match variable.storage_class {
+            STORAGE_CLASS_UNIFORM_CONSTANT | STORAGE_CLASS_UNIFORM | STORAGE_CLASS_STORAGE_BUFFER => {
+                let (Some(set), Some(binding)) = (variable.set, variable.binding) else {
+                    continue;
+                };
+
+                let mut element_type = type_id;
+                let mut count: u32 = 1;
+                for depth in 0.. {
+                    if depth > K_MAX_TYPE_DEPTH {
+                        return Err("SPIR-V types nested too deep");
+                    }
+                    let ty = &ids[element_type as usize];
+                    match ty.op {
+                        OP_TYPE_ARRAY => count = count.checked_mul(ids[ty.count as usize].value).ok_or("descriptor array too large")?,
+                        OP_TYPE_RUNTIME_ARRAY => warn!("Descriptor {} is a runtime array, reflected with a count of 1", name.unwrap_or("unnamed")),
+                        _ => break,
+                    }
+                    element_type = ty.type_id;
+                }
+
+                let Some(ty) = to_vk_descriptor_type(&ids, variable.storage_class, element_type) else {
+                    warn!("Descriptor {} (set {}, binding {}) has an unsupported type, skipped", name.unwrap_or("unnamed"), set, binding);
+                    continue;
+                };
+                add_binding(parse_result, set, binding, count, ty, name)?;
+            }
+            STORAGE_CLASS_PUSH_CONSTANT => {
+                let Some((offset, end)) = struct_range(&ids, type_id, 0)? else {
+                    continue;
+                };
+                let range = parse_result.push_constants.get_or_insert(vk::PushConstantRange { stage_flags: stage, offset, size: end - offset });
+                let range_end = (range.offset + range.size).max(end);
+                range.offset = range.offset.min(offset);
+                range.size = range_end - range.offset;
+                range.stage_flags |= stage;
+            }
+            STORAGE_CLASS_INPUT if stage == vk::ShaderStageFlags::VERTEX => {
+                if variable.builtin || ids[type_id as usize].builtin {
+                    continue;
+                }
+                let Some(location) = variable.location else {
+                    continue;
+                };
+                let format = to_vk_vertex_format(&ids, type_id);
+                if format == vk::Format::UNDEFINED {
+                    warn!("Vertex input {} at location {} has an unsupported type", name.unwrap_or("unnamed"), location);
+                }
+                parse_result.vertex_inputs.push(ShaderVertexInput { location, format, name: name.map(str::to_owned) });
+            }
+            _ => {}
+        }
+    }
+    parse_result.vertex_inputs.sort_by_key(|input| input.location);
+
+    Ok(stage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXECUTION_MODEL_VERTEX: u32 = 0;
+    const EXECUTION_MODEL_FRAGMENT: u32 = 4;
+
+    fn instruction(words: &mut Vec<u32>, opcode: u32, operands: &[u32]) {
+        words.push(((operands.len() as u32 + 1) << 16) | opcode);
+        words.extend_from_slice(operands);
+    }
+
+    // Null terminated, padded to whole words.
+    fn string(value: &str) -> Vec<u32> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+        bytes.chunks(4).map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap())).collect()
+    }
+
+    fn module(bound: u32, execution_model: u32, body: impl FnOnce(&mut Vec<u32>)) -> Vec<u32> {
+        let mut words = vec![K_SPIRV_MAGIC, 0x0001_0000, 0, bound, 0];
+        let mut entry_point = vec![execution_model, 1];
+        entry_point.extend(string("main"));
+        instruction(&mut words, OP_ENTRY_POINT, &entry_point);
+        body(&mut words);
+        words
+    }
+
+    fn name(words: &mut Vec<u32>, id: u32, value: &str) {
+        let mut operands = vec![id];
+        operands.extend(string(value));
+        instruction(words, OP_NAME, &operands);
+    }
+
+    #[test]
+    fn reflects_descriptors_and_push_constants() {
+        let data = module(17, EXECUTION_MODEL_FRAGMENT, |words| {
+            name(words, 6, "ubo");
+            name(words, 13, "textures");
+            instruction(words, OP_DECORATE, &[4, DECORATION_BLOCK]);
+            instruction(words, OP_MEMBER_DECORATE, &[4, 0, DECORATION_OFFSET, 0]);
+            instruction(words, OP_DECORATE, &[6, DECORATION_DESCRIPTOR_SET, 0]);
+            instruction(words, OP_DECORATE, &[6, DECORATION_BINDING, 1]);
+            instruction(words, OP_DECORATE, &[13, DECORATION_DESCRIPTOR_SET, 1]);
+            instruction(words, OP_DECORATE, &[13, DECORATION_BINDING, 0]);
+            instruction(words, OP_DECORATE, &[14, DECORATION_BLOCK]);
+            instruction(words, OP_MEMBER_DECORATE, &[14, 0, DECORATION_OFFSET, 0]);
+            instruction(words, OP_MEMBER_DECORATE, &[14, 1, DECORATION_OFFSET, 16]);
+
+            instruction(words, OP_TYPE_FLOAT, &[2, 32]);
+            instruction(words, OP_TYPE_VECTOR, &[3, 2, 4]);
+            instruction(words, OP_TYPE_STRUCT, &[4, 3]);
+            instruction(words, OP_TYPE_POINTER, &[5, STORAGE_CLASS_UNIFORM, 4]);
+            instruction(words, OP_VARIABLE, &[5, 6, STORAGE_CLASS_UNIFORM]);
+            // 2D, sampled.
+            instruction(words, OP_TYPE_IMAGE, &[7, 2, 1, 0, 0, 0, 1, 0]);
+            instruction(words, OP_TYPE_SAMPLED_IMAGE, &[8, 7]);
+            instruction(words, OP_TYPE_INT, &[9, 32, 0]);
+            instruction(words, OP_CONSTANT, &[9, 10, 4]);
+            instruction(words, OP_TYPE_ARRAY, &[11, 8, 10]);
+            instruction(words, OP_TYPE_POINTER, &[12, STORAGE_CLASS_UNIFORM_CONSTANT, 11]);
+            instruction(words, OP_VARIABLE, &[12, 13, STORAGE_CLASS_UNIFORM_CONSTANT]);
+            instruction(words, OP_TYPE_STRUCT, &[14, 2, 3]);
+            instruction(words, OP_TYPE_POINTER, &[15, STORAGE_CLASS_PUSH_CONSTANT, 14]);
+            instruction(words, OP_VARIABLE, &[15, 16, STORAGE_CLASS_PUSH_CONSTANT]);
+        });
+
+        let mut parse_result = ParseResult::default();
+        assert_eq!(parse_binary(&data, &mut parse_result), Ok(vk::ShaderStageFlags::FRAGMENT));
+        assert_eq!(parse_result.set_count, 2);
+
+        let set = &parse_result.sets[0];
+        assert_eq!(set.num_bindings, 1);
+        assert_eq!(set.bindings[0].ty, Some(vk::DescriptorType::UNIFORM_BUFFER));
+        assert_eq!((set.bindings[0].start, set.bindings[0].count), (1, 1));
+        assert_eq!(set.bindings[0].name.as_deref(), Some("ubo"));
+
+        let set = &parse_result.sets[1];
+        assert_eq!(set.num_bindings, 1);
+        assert_eq!(set.bindings[0].ty, Some(vk::DescriptorType::COMBINED_IMAGE_SAMPLER));
+        assert_eq!((set.bindings[0].start, set.bindings[0].count), (0, 4));
+
+        let push_constants = parse_result.push_constants.unwrap();
+        assert_eq!((push_constants.offset, push_constants.size), (0, 32));
+        assert_eq!(push_constants.stage_flags, vk::ShaderStageFlags::FRAGMENT);
+    }
+
+    #[test]
+    fn reflects_sorted_vertex_inputs() {
+        let data = module(10, EXECUTION_MODEL_VERTEX, |words| {
+            instruction(words, OP_DECORATE, &[7, DECORATION_LOCATION, 1]);
+            instruction(words, OP_DECORATE, &[8, DECORATION_LOCATION, 0]);
+            instruction(words, OP_DECORATE, &[9, DECORATION_BUILTIN, 42]);
+            instruction(words, OP_TYPE_FLOAT, &[2, 32]);
+            instruction(words, OP_TYPE_VECTOR, &[3, 2, 3]);
+            instruction(words, OP_TYPE_VECTOR, &[4, 2, 2]);
+            instruction(words, OP_TYPE_POINTER, &[5, STORAGE_CLASS_INPUT, 3]);
+            instruction(words, OP_TYPE_POINTER, &[6, STORAGE_CLASS_INPUT, 4]);
+            instruction(words, OP_VARIABLE, &[5, 7, STORAGE_CLASS_INPUT]);
+            instruction(words, OP_VARIABLE, &[6, 8, STORAGE_CLASS_INPUT]);
+            instruction(words, OP_VARIABLE, &[5, 9, STORAGE_CLASS_INPUT]);
+        });
+
+        let mut parse_result = ParseResult::default();
+        assert_eq!(parse_binary(&data, &mut parse_result), Ok(vk::ShaderStageFlags::VERTEX));
+        let inputs: Vec<_> = parse_result.vertex_inputs.iter().map(|input| (input.location, input.format)).collect();
+        assert_eq!(inputs, [(0, vk::Format::R32G32_SFLOAT), (1, vk::Format::R32G32B32_SFLOAT)]);
+    }
+
+    #[test]
+    fn stages_sharing_a_binding_must_agree_on_its_type() {
+        let stage = |storage_class: u32| module(6, EXECUTION_MODEL_FRAGMENT, |words| {
+            instruction(words, OP_DECORATE, &[3, DECORATION_BLOCK]);
+            instruction(words, OP_DECORATE, &[5, DECORATION_DESCRIPTOR_SET, 0]);
+            instruction(words, OP_DECORATE, &[5, DECORATION_BINDING, 0]);
+            instruction(words, OP_TYPE_FLOAT, &[2, 32]);
+            instruction(words, OP_TYPE_STRUCT, &[3, 2]);
+            instruction(words, OP_TYPE_POINTER, &[4, storage_class, 3]);
+            instruction(words, OP_VARIABLE, &[4, 5, storage_class]);
+        });
+
+        let mut parse_result = ParseResult::default();
+        assert!(parse_binary(&stage(STORAGE_CLASS_UNIFORM), &mut parse_result).is_ok());
+        assert!(parse_binary(&stage(STORAGE_CLASS_UNIFORM), &mut parse_result).is_ok());
+        assert_eq!(parse_result.sets[0].num_bindings, 1);
+        assert!(parse_binary(&stage(STORAGE_CLASS_STORAGE_BUFFER), &mut parse_result).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_modules() {
+        let mut parse_result = ParseResult::default();
+        assert!(parse_binary(&[K_SPIRV_MAGIC, 0, 0], &mut parse_result).is_err());
+
+        let mut data = module(4, EXECUTION_MODEL_FRAGMENT, |_| {});
+        data[0] = 0xdeadbeef;
+        assert!(parse_binary(&data, &mut parse_result).is_err());
+
+        let data = module(u32::MAX, EXECUTION_MODEL_FRAGMENT, |_| {});
+        assert_eq!(parse_binary(&data, &mut parse_result), Err("SPIR-V id bound larger than the module"));
+
+        let data = module(4, EXECUTION_MODEL_FRAGMENT, |words| instruction(words, OP_TYPE_FLOAT, &[7, 32]));
+        assert_eq!(parse_binary(&data, &mut parse_result), Err("SPIR-V id out of bounds"));
+
+        // Word count running past the end of the module.
+        let mut data = module(4, EXECUTION_MODEL_FRAGMENT, |_| {});
+        data.push((3 << 16) | OP_TYPE_FLOAT);
+        assert_eq!(parse_binary(&data, &mut parse_result), Err("malformed SPIR-V instruction"));
+
+        let data = module(4, EXECUTION_MODEL_FRAGMENT, |_| {});
+        let data: Vec<_> = data[..5].to_vec();
+        assert_eq!(parse_binary(&data, &mut parse_result), Err("SPIR-V module has no entry point"));
+    }
+
+    #[test]
+    fn rejects_self_referencing_types() {
+        // An array of itself as a descriptor.
+        let data = module(8, EXECUTION_MODEL_FRAGMENT, |words| {
+            instruction(words, OP_DECORATE, &[5, DECORATION_DESCRIPTOR_SET, 0]);
+            instruction(words, OP_DECORATE, &[5, DECORATION_BINDING, 0]);
+            instruction(words, OP_TYPE_INT, &[2, 32, 0]);
+            instruction(words, OP_CONSTANT, &[2, 6, 1]);
+            instruction(words, OP_TYPE_ARRAY, &[3, 3, 6]);
+            instruction(words, OP_TYPE_POINTER, &[4, STORAGE_CLASS_UNIFORM_CONSTANT, 3]);
+            instruction(words, OP_VARIABLE, &[4, 5, STORAGE_CLASS_UNIFORM_CONSTANT]);
+        });
+        let mut parse_result = ParseResult::default();
+        assert_eq!(parse_binary(&data, &mut parse_result), Err("SPIR-V types nested too deep"));
+
+        // A push constant block containing itself.
+        let data = module(6, EXECUTION_MODEL_FRAGMENT, |words| {
+            instruction(words, OP_TYPE_STRUCT, &[3, 3]);
+            instruction(words, OP_TYPE_POINTER, &[4, STORAGE_CLASS_PUSH_CONSTANT, 3]);
+            instruction(words, OP_VARIABLE, &[4, 5, STORAGE_CLASS_PUSH_CONSTANT]);
+        });
+        let mut parse_result = ParseResult::default();
+        assert_eq!(parse_binary(&data, &mut parse_result), Err("SPIR-V types nested too deep"));
+    }
+}